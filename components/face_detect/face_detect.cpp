@@ -0,0 +1,31 @@
+#include <list>
+
+#include "face_detect.h"
+#include "human_face_detect_mnp01.hpp"
+#include "human_face_detect_msr01.hpp"
+
+// The two stage detector from esp-who: MSR01 proposes candidates, MNP01 confirms them
+int32_t tigercam_detect_faces(const uint16_t *rgb565, int32_t width, int32_t height,
+                              tigercam_face_t *faces, int32_t max_faces)
+{
+    // The models are big, build them once and keep them around
+    static HumanFaceDetectMSR01 candidates_stage(0.1F, 0.5F, 10, 0.2F);
+    static HumanFaceDetectMNP01 confirm_stage(0.5F, 0.3F, 5);
+
+    uint16_t *input = const_cast<uint16_t *>(rgb565);
+    std::list<dl::detect::result_t> &candidates =
+        candidates_stage.infer(input, {(int)height, (int)width, 3});
+    std::list<dl::detect::result_t> &results =
+        confirm_stage.infer(input, {(int)height, (int)width, 3}, candidates);
+
+    int32_t count = 0;
+    for (const dl::detect::result_t &result : results) {
+        if (count == max_faces) {
+            break;
+        }
+        faces[count++] = {
+            result.box[0], result.box[1], result.box[2], result.box[3], result.score,
+        };
+    }
+    return count;
+}
@@ -0,0 +1,25 @@
+#pragma once
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Corners in pixels of the frame that was searched, and how sure the detector is, 0-1
+typedef struct {
+    int32_t x1;
+    int32_t y1;
+    int32_t x2;
+    int32_t y2;
+    float score;
+} tigercam_face_t;
+
+// Finds up to `max_faces` faces in a big endian RGB565 frame, as it comes from the camera.
+// Returns how many it wrote to `faces`.
+int32_t tigercam_detect_faces(const uint16_t *rgb565, int32_t width, int32_t height,
+                              tigercam_face_t *faces, int32_t max_faces);
+
+#ifdef __cplusplus
+}
+#endif
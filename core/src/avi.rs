@@ -0,0 +1,307 @@
+// Just enough of the AVI 1.0 (RIFF) container for a motion JPEG video players will open:
+//
+//   RIFF 'AVI '
+//     LIST 'hdrl'
+//       avih
+//       LIST 'strl'
+//         strh, strf
+//     LIST 'movi'
+//       00dc per frame
+//     idx1
+//
+// Every size goes in the headers, so the frame sizes have to be known before anything is sent.
+// The frames themselves can then be written out one at a time.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+static PAD: [u8; 1] = [0];
+// 'hdrl' plus the avih chunk and the whole strl list
+const HDRL_LEN: usize = 4 + (8 + 56) + (8 + 4 + (8 + 56) + (8 + 40));
+
+pub struct AviLayout {
+    width: u32,
+    height: u32,
+    fps: u32,
+    sizes: Vec<usize>,
+}
+
+impl AviLayout {
+    pub fn new(width: u32, height: u32, fps: u32, sizes: Vec<usize>) -> Self {
+        Self {
+            width,
+            height,
+            fps: fps.max(1),
+            sizes,
+        }
+    }
+
+    // Everything from the RIFF header up to the first frame chunk
+    pub fn header(&self) -> Vec<u8> {
+        let frames = self.sizes.len() as u32;
+        let largest = self.sizes.iter().copied().max().unwrap_or(0) as u32;
+        let movi_len = self.movi_len();
+
+        let mut out = Vec::with_capacity(12 + 8 + HDRL_LEN + 12);
+        out.extend_from_slice(b"RIFF");
+        put_u32(&mut out, (self.total_len() - 8) as u32);
+        out.extend_from_slice(b"AVI ");
+
+        out.extend_from_slice(b"LIST");
+        put_u32(&mut out, HDRL_LEN as u32);
+        out.extend_from_slice(b"hdrl");
+
+        out.extend_from_slice(b"avih");
+        put_u32(&mut out, 56);
+        put_u32(&mut out, 1_000_000 / self.fps);
+        put_u32(&mut out, largest * self.fps);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, AVIF_HASINDEX);
+        put_u32(&mut out, frames);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 1);
+        put_u32(&mut out, largest);
+        put_u32(&mut out, self.width);
+        put_u32(&mut out, self.height);
+        out.extend_from_slice(&[0; 16]);
+
+        out.extend_from_slice(b"LIST");
+        put_u32(&mut out, 4 + (8 + 56) + (8 + 40));
+        out.extend_from_slice(b"strl");
+
+        out.extend_from_slice(b"strh");
+        put_u32(&mut out, 56);
+        out.extend_from_slice(b"vids");
+        out.extend_from_slice(b"MJPG");
+        put_u32(&mut out, 0);
+        // Priority and language, then initial frames
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        // Scale and rate, frames per second is rate / scale
+        put_u32(&mut out, 1);
+        put_u32(&mut out, self.fps);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, frames);
+        put_u32(&mut out, largest);
+        // Default quality
+        put_u32(&mut out, u32::MAX);
+        put_u32(&mut out, 0);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, self.width as u16);
+        put_u16(&mut out, self.height as u16);
+
+        out.extend_from_slice(b"strf");
+        put_u32(&mut out, 40);
+        put_u32(&mut out, 40);
+        put_u32(&mut out, self.width);
+        put_u32(&mut out, self.height);
+        put_u16(&mut out, 1);
+        put_u16(&mut out, 24);
+        out.extend_from_slice(b"MJPG");
+        put_u32(&mut out, self.width * self.height * 3);
+        out.extend_from_slice(&[0; 16]);
+
+        out.extend_from_slice(b"LIST");
+        put_u32(&mut out, movi_len as u32);
+        out.extend_from_slice(b"movi");
+
+        out
+    }
+
+    // Goes in front of each frame, which is followed by `padding`
+    pub fn chunk_header(size: usize) -> [u8; 8] {
+        let mut out = [0; 8];
+        out[..4].copy_from_slice(b"00dc");
+        out[4..].copy_from_slice(&(size as u32).to_le_bytes());
+        out
+    }
+
+    pub fn padding(size: usize) -> &'static [u8] {
+        &PAD[..size % 2]
+    }
+
+    // Comes after the last frame, offsets count from the 'movi' tag
+    pub fn index(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + 16 * self.sizes.len());
+        out.extend_from_slice(b"idx1");
+        put_u32(&mut out, 16 * self.sizes.len() as u32);
+
+        let mut offset = 4;
+        for size in &self.sizes {
+            out.extend_from_slice(b"00dc");
+            put_u32(&mut out, AVIIF_KEYFRAME);
+            put_u32(&mut out, offset as u32);
+            put_u32(&mut out, *size as u32);
+            offset += 8 + padded(*size);
+        }
+
+        out
+    }
+
+    // The whole file, for Content-Length
+    pub fn total_len(&self) -> usize {
+        12 + (8 + HDRL_LEN) + (8 + self.movi_len()) + (8 + 16 * self.sizes.len())
+    }
+
+    fn movi_len(&self) -> usize {
+        4 + self.sizes.iter().map(|s| 8 + padded(*s)).sum::<usize>()
+    }
+}
+
+// Writes an AVI out a frame at a time when the sizes aren't known up front, like a recording.
+// The header goes out saying there are no frames and `finish` writes it again over itself,
+// which is the same length whatever the sizes. Players still find the frames of a file that was
+// never finished, they just can't seek in it.
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    width: u32,
+    height: u32,
+    fps: u32,
+    sizes: Vec<usize>,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    pub fn new(mut out: W, width: u32, height: u32, fps: u32) -> io::Result<Self> {
+        out.write_all(&AviLayout::new(width, height, fps, Vec::new()).header())?;
+        Ok(Self {
+            out,
+            width,
+            height,
+            fps,
+            sizes: Vec::new(),
+        })
+    }
+
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        self.out.write_all(&AviLayout::chunk_header(jpeg.len()))?;
+        self.out.write_all(jpeg)?;
+        self.out.write_all(AviLayout::padding(jpeg.len()))?;
+        self.sizes.push(jpeg.len());
+        Ok(())
+    }
+
+    // For when the frames came slower than planned, so they play back at the speed they were
+    // taken. Only goes in the header `finish` writes.
+    pub fn set_fps(&mut self, fps: u32) {
+        self.fps = fps.max(1);
+    }
+
+    // For syncing, writes have to go through `write_frame`
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let layout = self.layout();
+        self.out.write_all(&layout.index())?;
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&layout.header())?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(self.out)
+    }
+
+    fn layout(&self) -> AviLayout {
+        AviLayout::new(self.width, self.height, self.fps, self.sizes.clone())
+    }
+}
+
+// RIFF chunks are padded out to an even length
+fn padded(size: usize) -> usize {
+    size + size % 2
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // The whole file the way http.rs sends one
+    fn write_out(layout: &AviLayout, frames: &[&[u8]]) -> Vec<u8> {
+        let mut out = layout.header();
+        for frame in frames {
+            out.extend_from_slice(&AviLayout::chunk_header(frame.len()));
+            out.extend_from_slice(frame);
+            out.extend_from_slice(AviLayout::padding(frame.len()));
+        }
+        out.extend_from_slice(&layout.index());
+        out
+    }
+
+    #[test]
+    fn sizes_add_up() {
+        let frames: [&[u8]; 3] = [&[1; 5], &[2; 6], &[3; 7]];
+        let layout = AviLayout::new(640, 480, 10, frames.iter().map(|f| f.len()).collect());
+        let out = write_out(&layout, &frames);
+
+        assert_eq!(out.len(), layout.total_len());
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(u32_at(&out, 4) as usize, out.len() - 8);
+        assert_eq!(&out[8..12], b"AVI ");
+        // Frames in the main header, then width and height
+        assert_eq!(u32_at(&out, 48), 3);
+        assert_eq!(u32_at(&out, 64), 640);
+        assert_eq!(u32_at(&out, 68), 480);
+    }
+
+    #[test]
+    fn index_points_at_the_frames() {
+        let frames: [&[u8]; 3] = [&[1; 5], &[2; 6], &[3; 7]];
+        let layout = AviLayout::new(320, 240, 5, frames.iter().map(|f| f.len()).collect());
+        let out = write_out(&layout, &frames);
+
+        let movi = layout.header().len() - 4;
+        assert_eq!(&out[movi..movi + 4], b"movi");
+        let index = out.len() - (8 + 16 * frames.len());
+        assert_eq!(&out[index..index + 4], b"idx1");
+        for (i, frame) in frames.iter().enumerate() {
+            let entry = index + 8 + 16 * i;
+            let offset = u32_at(&out, entry + 8) as usize;
+            assert_eq!(u32_at(&out, entry + 12) as usize, frame.len());
+            assert_eq!(&out[movi + offset..movi + offset + 4], b"00dc");
+            assert_eq!(
+                &out[movi + offset + 8..movi + offset + 8 + frame.len()],
+                *frame
+            );
+        }
+    }
+
+    #[test]
+    fn odd_frames_are_padded() {
+        assert_eq!(AviLayout::padding(5), &[0]);
+        assert!(AviLayout::padding(6).is_empty());
+    }
+
+    #[test]
+    fn no_frames() {
+        let layout = AviLayout::new(160, 120, 0, Vec::new());
+        assert_eq!(write_out(&layout, &[]).len(), layout.total_len());
+    }
+
+    #[test]
+    fn writer_matches_the_layout() {
+        let frames: [&[u8]; 2] = [&[7; 9], &[8; 4]];
+        let mut writer = AviWriter::new(Cursor::new(Vec::new()), 800, 600, 10).unwrap();
+        for frame in frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.set_fps(4);
+        let written = writer.finish().unwrap().into_inner();
+
+        let layout = AviLayout::new(800, 600, 4, frames.iter().map(|f| f.len()).collect());
+        assert_eq!(written, write_out(&layout, &frames));
+    }
+}
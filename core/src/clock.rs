@@ -0,0 +1,70 @@
+// Dates and times off the wall clock, which SNTP keeps in UTC
+
+// Anything before this means SNTP hasn't set the clock yet
+pub const CLOCK_SET: u64 = 1_600_000_000;
+
+// `unix` moved to local time, for working out days and times of day
+pub fn local(unix: u64, utc_offset_minutes: i32) -> u64 {
+    unix.saturating_add_signed(utc_offset_minutes as i64 * 60)
+}
+
+// As "2024-01-31 13:45:07"
+pub fn format_time(unix: u64) -> String {
+    let (year, month, day) = civil_date(unix);
+    let seconds = unix % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// The year, month and day `unix` falls on
+pub fn civil_date(unix: u64) -> (i64, i64, i64) {
+    let days = unix / 86400;
+
+    // Howard Hinnant's days to civil date
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(951_782_400), (2000, 2, 29));
+        assert_eq!(civil_date(1_706_745_599), (2024, 1, 31));
+        assert_eq!(civil_date(1_709_164_800), (2024, 2, 29));
+        assert_eq!(civil_date(1_735_689_599), (2024, 12, 31));
+        assert_eq!(civil_date(1_735_689_600), (2025, 1, 1));
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(1_706_708_707), "2024-01-31 13:45:07");
+    }
+
+    #[test]
+    fn local_time() {
+        assert_eq!(local(1_000_000, 60), 1_003_600);
+        assert_eq!(local(1_000_000, -60), 996_400);
+        // Clamped rather than wrapping before the epoch
+        assert_eq!(local(60, -2), 0);
+    }
+}
@@ -0,0 +1,120 @@
+// The wire format of the camera's responses, written against `Exchange` rather than esp-idf's
+// server so it's the same code on the host. The firmware wraps its requests in one, the tests
+// in core/tests drive it with a client of their own.
+
+use anyhow::Result;
+use serde::Serialize;
+
+// One request and the response going back for it
+pub trait Exchange {
+    fn uri(&self) -> &str;
+
+    fn header(&self, name: &str) -> Option<&str>;
+
+    // Sends the status line and headers, the body follows with `write_all`
+    fn respond(&mut self, status: u16, headers: &[(&str, &str)]) -> Result<()>;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()>;
+}
+
+pub fn send_json<T: Serialize>(
+    ex: &mut impl Exchange,
+    status: u16,
+    value: &T,
+    extra_headers: &[(&str, &str)],
+) -> Result<()> {
+    let body = serde_json::to_vec(value)?;
+
+    let length = body.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", "application/json"),
+        ("Content-Length", length.as_str()),
+    ];
+    headers.extend_from_slice(extra_headers);
+
+    ex.respond(status, &headers)?;
+    ex.write_all(&body)
+}
+
+// A still, or 304 if `etag` is one the client already has. `timing` goes out as
+// Server-Timing, saying how much of the request was capture.
+pub fn send_jpeg(
+    ex: &mut impl Exchange,
+    jpeg: &[u8],
+    etag: Option<&str>,
+    timing: &str,
+) -> Result<()> {
+    if let Some(etag) = etag {
+        if ex
+            .header("If-None-Match")
+            .is_some_and(|tags| etag_matches(tags, etag))
+        {
+            return ex.respond(304, &[("ETag", etag)]);
+        }
+    }
+
+    let length = jpeg.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", "image/jpeg"),
+        ("Content-Length", length.as_str()),
+        ("Server-Timing", timing),
+        ("Vary", "Accept"),
+    ];
+    if let Some(etag) = etag {
+        headers.push(("ETag", etag));
+    }
+
+    ex.respond(200, &headers)?;
+    // A client going away mid-frame isn't an error on our side
+    let _ = ex.write_all(jpeg);
+    Ok(())
+}
+
+// MJPEG as multipart/x-mixed-replace, each frame replacing the last in the client's view
+pub struct Multipart<'a, E: Exchange> {
+    ex: &'a mut E,
+}
+
+impl<'a, E: Exchange> Multipart<'a, E> {
+    pub const BOUNDARY: &'static str = "frame";
+
+    pub fn start(ex: &'a mut E) -> Result<Self> {
+        let content_type = format!("multipart/x-mixed-replace;boundary={}", Self::BOUNDARY);
+        ex.respond(200, &[("Content-Type", &content_type)])?;
+        Ok(Self { ex })
+    }
+
+    // Fails once the client has gone away
+    pub fn send(&mut self, jpeg: &[u8]) -> Result<()> {
+        let part = format!(
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            Self::BOUNDARY,
+            jpeg.len()
+        );
+        self.ex.write_all(part.as_bytes())?;
+        self.ex.write_all(jpeg)?;
+        self.ex.write_all(b"\r\n")
+    }
+}
+
+// True if an If-None-Match header already names `etag`, weak or not
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_etags() {
+        assert!(etag_matches("\"7\"", "\"7\""));
+        assert!(etag_matches("\"3\", W/\"7\"", "\"7\""));
+        assert!(etag_matches("*", "\"7\""));
+        assert!(!etag_matches("\"70\"", "\"7\""));
+        assert!(!etag_matches("", "\"7\""));
+    }
+}
@@ -0,0 +1,12 @@
+// The parts of tigercam that don't need ESP-IDF or the camera, so they build and are tested on
+// the host, see the README
+
+pub mod avi;
+pub mod clock;
+pub mod http;
+pub mod media;
+pub mod motion;
+pub mod retention;
+pub mod schedule;
+pub mod settings;
+pub mod source;
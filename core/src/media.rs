@@ -0,0 +1,97 @@
+// How media is named and laid out on the SD card
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::path::{Component, Path, PathBuf};
+
+use crate::clock::civil_date;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub dir: bool,
+    // 0 for directories
+    pub size: u64,
+}
+
+// Where `relative` is under `root`. Anything climbing out of it with '..' or naming a path of
+// its own is refused, these come from HTTP requests.
+pub fn card_path(root: &str, relative: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::from(root);
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => bail!("'{}' isn't a path on the SD card", relative),
+        }
+    }
+    Ok(path)
+}
+
+// "2024/01/31/134507" for `unix`, how dated media is named once it's been moved to local
+// time, see `SdCard::dated`. Names sort as their times do.
+pub fn dated(unix: u64) -> String {
+    let (year, month, day) = civil_date(unix);
+    let seconds = unix % 86400;
+    format!(
+        "{:04}/{:02}/{:02}/{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// "clip" for "2024/01/31/134507_clip-2.avi", None for what `media_name` didn't name
+pub fn media_kind(name: &str) -> Option<&str> {
+    let name = name.rsplit('/').next().unwrap_or_default();
+    name.split_once('_')
+        .and_then(|(_, rest)| rest.split(['.', '-']).next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_on_the_card() {
+        assert_eq!(card_path("/sd", "").unwrap(), PathBuf::from("/sd"));
+        assert_eq!(
+            card_path("/sd", "2024/01/31/134507_clip.avi").unwrap(),
+            PathBuf::from("/sd/2024/01/31/134507_clip.avi")
+        );
+        // A leading slash is still on the card, not the root of the VFS
+        assert_eq!(
+            card_path("/sd", "/logs/./log.txt").unwrap(),
+            PathBuf::from("/sd/logs/log.txt")
+        );
+    }
+
+    #[test]
+    fn refuses_climbing_out() {
+        for relative in ["..", "../spiffs/index.html", "2024/../../spiffs", "logs/.."] {
+            assert!(card_path("/sd", relative).is_err(), "{}", relative);
+        }
+    }
+
+    #[test]
+    fn dated_names() {
+        assert_eq!(dated(1_706_708_707), "2024/01/31/134507");
+        assert_eq!(dated(0), "1970/01/01/000000");
+        // Sorting the names sorts the times
+        assert!(dated(1_706_708_707) < dated(1_706_708_708));
+        assert!(dated(1_704_067_199) < dated(1_704_067_200));
+    }
+
+    #[test]
+    fn media_kinds() {
+        assert_eq!(media_kind("2024/01/31/134507_clip.avi"), Some("clip"));
+        assert_eq!(media_kind("2024/01/31/134507_clip-2.avi"), Some("clip"));
+        assert_eq!(media_kind("undated/000042_still.jpg"), Some("still"));
+        assert_eq!(media_kind("134507_rec.avi"), Some("rec"));
+        assert_eq!(media_kind("2024/01/31/notes.txt"), None);
+        assert_eq!(media_kind("logs/log.txt"), None);
+    }
+}
@@ -0,0 +1,232 @@
+// Motion detection settings and the parts of /api/motion/config that don't need the camera
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// The frame is compared in GRID x GRID cells, each one a bit in `MotionEvent::cells`
+pub const GRID: usize = 8;
+// Every cell watched
+pub const FULL_MASK: u64 = u64::MAX;
+
+// Rows top to bottom, `true` for the cells that are watched
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mask {
+    cells: [[bool; GRID]; GRID],
+}
+
+impl Mask {
+    pub fn from_bits(bits: u64) -> Self {
+        let mut cells = [[false; GRID]; GRID];
+        for (row, cells) in cells.iter_mut().enumerate() {
+            for (column, cell) in cells.iter_mut().enumerate() {
+                *cell = bits & 1 << (row * GRID + column) != 0;
+            }
+        }
+        Self { cells }
+    }
+
+    pub fn bits(&self) -> u64 {
+        let mut bits = 0;
+        for (row, cells) in self.cells.iter().enumerate() {
+            for (column, &cell) in cells.iter().enumerate() {
+                if cell {
+                    bits |= 1 << (row * GRID + column);
+                }
+            }
+        }
+        bits
+    }
+}
+
+// The `motion_mask` config value, a row of 0s and 1s for each row of the grid separated by
+// ';' like "11111111;11111111;00000000;...". Empty watches everything.
+pub fn parse_mask(mask: &str) -> Result<u64> {
+    if mask.is_empty() {
+        return Ok(FULL_MASK);
+    }
+    let rows: Vec<&str> = mask.split(';').collect();
+    if rows.len() != GRID || rows.iter().any(|row| row.len() != GRID) {
+        bail!("A motion mask is {} rows of {} 0s and 1s", GRID, GRID);
+    }
+
+    let mut bits = 0;
+    for (row, cells) in rows.iter().enumerate() {
+        for (column, cell) in cells.chars().enumerate() {
+            match cell {
+                '1' => bits |= 1 << (row * GRID + column),
+                '0' => {}
+                other => bail!("Unexpected '{}' in the motion mask", other),
+            }
+        }
+    }
+    Ok(bits)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MotionSettings {
+    // How far a cell's pixels have to move from the reference on average to count as
+    // changed, in luma 0-255
+    pub sensitivity: u8,
+    // Changed cells it takes to be motion, out of the ones the mask leaves watched
+    pub threshold: u32,
+    pub interval: Duration,
+    // How long it has to go on before it's an event, so a single noisy frame isn't
+    pub debounce: Duration,
+    // Motion that keeps going raises an event at most this often
+    pub cooldown: Duration,
+}
+
+// The settings /api/motion/config can change, saved to NVS as they are. The interval stays
+// what cfg.toml says.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Tuning {
+    pub sensitivity: u8,
+    pub threshold: u32,
+    pub debounce_ms: u32,
+    pub cooldown_s: u32,
+}
+
+impl Tuning {
+    pub fn of(settings: &MotionSettings) -> Self {
+        Self {
+            sensitivity: settings.sensitivity,
+            threshold: settings.threshold,
+            debounce_ms: settings.debounce.as_millis() as u32,
+            cooldown_s: settings.cooldown.as_secs() as u32,
+        }
+    }
+
+    pub fn apply(&self, settings: &mut MotionSettings) {
+        settings.sensitivity = self.sensitivity;
+        settings.threshold = self.threshold;
+        settings.debounce = Duration::from_millis(self.debounce_ms as u64);
+        settings.cooldown = Duration::from_secs(self.cooldown_s as u64);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TuningUpdate {
+    sensitivity: Option<u8>,
+    threshold: Option<u32>,
+    debounce_ms: Option<u32>,
+    cooldown_s: Option<u32>,
+}
+
+impl TuningUpdate {
+    pub fn apply(&self, tuning: &mut Tuning) -> Result<()> {
+        if let Some(threshold) = self.threshold {
+            if !(1..=(GRID * GRID) as u32).contains(&threshold) {
+                bail!("threshold must be between 1 and {}", GRID * GRID);
+            }
+            tuning.threshold = threshold;
+        }
+        if let Some(sensitivity) = self.sensitivity {
+            tuning.sensitivity = sensitivity;
+        }
+        if let Some(debounce_ms) = self.debounce_ms {
+            tuning.debounce_ms = debounce_ms;
+        }
+        if let Some(cooldown_s) = self.cooldown_s {
+            tuning.cooldown_s = cooldown_s;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> MotionSettings {
+        MotionSettings {
+            sensitivity: 20,
+            threshold: 3,
+            interval: Duration::from_millis(500),
+            debounce: Duration::from_millis(1000),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn empty_mask_watches_everything() {
+        assert_eq!(parse_mask("").unwrap(), FULL_MASK);
+    }
+
+    #[test]
+    fn parses_masks_row_by_row() {
+        let mask = "10000000;00000000;00000000;00000000;00000000;00000000;00000000;00000001";
+        assert_eq!(parse_mask(mask).unwrap(), 1 | 1 << 63);
+        let top = "11111111;11111111;00000000;00000000;00000000;00000000;00000000;00000000";
+        assert_eq!(parse_mask(top).unwrap(), 0xffff);
+    }
+
+    #[test]
+    fn rejects_malformed_masks() {
+        let short = "11111111;11111111";
+        let wide = "111111111;11111111;11111111;11111111;11111111;11111111;11111111;11111111";
+        let other = "1111111x;11111111;11111111;11111111;11111111;11111111;11111111;11111111";
+        for mask in [short, wide, other] {
+            assert!(parse_mask(mask).is_err(), "{}", mask);
+        }
+    }
+
+    #[test]
+    fn mask_json_round_trips() {
+        let bits = 0x8000_0000_0000_00f1;
+        let json = serde_json::to_string(&Mask::from_bits(bits)).unwrap();
+        let mask: Mask = serde_json::from_str(&json).unwrap();
+        assert_eq!(mask.bits(), bits);
+
+        let rows: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows["cells"][0][0], true);
+        assert_eq!(rows["cells"][0][1], false);
+        assert_eq!(rows["cells"][7][7], true);
+    }
+
+    #[test]
+    fn mask_json_refuses_the_wrong_shape() {
+        assert!(serde_json::from_str::<Mask>(r#"{"cells": [[true]]}"#).is_err());
+        assert!(serde_json::from_str::<Mask>(r#"{"cells": [], "extra": 1}"#).is_err());
+    }
+
+    #[test]
+    fn tuning_updates_only_what_is_given() {
+        let mut settings = settings();
+        let mut tuning = Tuning::of(&settings);
+        let update: TuningUpdate =
+            serde_json::from_str(r#"{"threshold": 5, "cooldown_s": 10}"#).unwrap();
+        update.apply(&mut tuning).unwrap();
+        tuning.apply(&mut settings);
+
+        assert_eq!(settings.threshold, 5);
+        assert_eq!(settings.cooldown, Duration::from_secs(10));
+        assert_eq!(settings.sensitivity, 20);
+        assert_eq!(settings.debounce, Duration::from_millis(1000));
+        assert_eq!(settings.interval, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn tuning_threshold_stays_within_the_grid() {
+        let mut tuning = Tuning::of(&settings());
+        for threshold in [0, GRID * GRID + 1] {
+            let json = format!(r#"{{"threshold": {}, "sensitivity": 1}}"#, threshold);
+            let update: TuningUpdate = serde_json::from_str(&json).unwrap();
+            assert!(update.apply(&mut tuning).is_err());
+        }
+        // Nothing is changed by an update that's refused
+        assert_eq!(tuning.threshold, 3);
+        assert_eq!(tuning.sensitivity, 20);
+
+        let update: TuningUpdate = serde_json::from_str(r#"{"threshold": 64}"#).unwrap();
+        update.apply(&mut tuning).unwrap();
+        assert_eq!(tuning.threshold, 64);
+    }
+
+    #[test]
+    fn tuning_update_refuses_unknown_fields() {
+        assert!(serde_json::from_str::<TuningUpdate>(r#"{"interval_ms": 100}"#).is_err());
+    }
+}
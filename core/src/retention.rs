@@ -0,0 +1,264 @@
+// Which files on the SD card the retention rules say have to go
+
+use std::time::Duration;
+
+use crate::media::{media_kind, Entry};
+
+// What motion clips are saved as, see `SdCard::media_name`
+const CLIP_KIND: &str = "clip";
+
+// None or 0 leaves a rule out
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionSettings {
+    // Dated media older than this goes, the undated can't be told apart by age
+    pub max_age: Option<Duration>,
+    // What all the media may add up to, the oldest going first
+    pub max_bytes: Option<u64>,
+    // Motion clips past the newest this many go
+    pub keep_clips: usize,
+    // The oldest go until the card has at least this much free
+    pub min_free: Option<u64>,
+}
+
+impl RetentionSettings {
+    // Short of room by the byte limit or the free space floor
+    pub fn short(&self, total: u64, free: u64) -> bool {
+        self.max_bytes.is_some_and(|max| total > max) || self.min_free.is_some_and(|min| free < min)
+    }
+}
+
+pub struct Candidate {
+    pub entry: Entry,
+    // What candidates are ordered by, the dated part of the name and "" for anything that
+    // can't be told, which is taken as oldest
+    pub key: String,
+    // Still being written, like the recording segment, which keeps it whatever the rules say
+    pub writing: bool,
+}
+
+pub struct Plan {
+    // Lined up with the candidates, `true` for the ones to delete
+    pub doomed: Vec<bool>,
+    // What the ones kept add up to, with `other`
+    pub total: u64,
+    // What the card has free once the doomed are gone
+    pub free: u64,
+}
+
+// Goes over `files`, oldest first. Dated ones named before `cutoff` go, then clips past
+// `keep_clips`, then the oldest left until the total and free space are back within the rules.
+// `other` is what else counts toward the total, like logs, and `free` what the card has free
+// now, which only matters with a `min_free`.
+pub fn plan(
+    files: &[Candidate],
+    settings: &RetentionSettings,
+    cutoff: Option<&str>,
+    other: u64,
+    free: u64,
+) -> Plan {
+    let mut doomed = vec![false; files.len()];
+    if let Some(cutoff) = cutoff {
+        for (file, doomed) in files.iter().zip(doomed.iter_mut()) {
+            if !file.key.is_empty() && file.key.as_str() < cutoff {
+                *doomed = true;
+            }
+        }
+    }
+    if settings.keep_clips > 0 {
+        let clips: Vec<_> = (0..files.len())
+            .filter(|&i| is_clip(&files[i].entry))
+            .collect();
+        for &i in &clips[..clips.len().saturating_sub(settings.keep_clips)] {
+            doomed[i] = true;
+        }
+    }
+    for (doomed, file) in doomed.iter_mut().zip(files) {
+        *doomed &= !file.writing;
+    }
+
+    let size_of = |doomed: &[bool], want: bool| -> u64 {
+        files
+            .iter()
+            .zip(doomed)
+            .filter(|(_, &d)| d == want)
+            .map(|(file, _)| file.entry.size)
+            .sum()
+    };
+    let mut total = size_of(&doomed, false) + other;
+    let mut free = free + size_of(&doomed, true);
+    for (file, doomed) in files.iter().zip(doomed.iter_mut()) {
+        if !settings.short(total, free) {
+            break;
+        }
+        if !*doomed && !file.writing {
+            *doomed = true;
+            total -= file.entry.size;
+            free += file.entry.size;
+        }
+    }
+
+    Plan {
+        doomed,
+        total,
+        free,
+    }
+}
+
+fn is_clip(entry: &Entry) -> bool {
+    media_kind(&entry.name) == Some(CLIP_KIND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1 << 20;
+
+    fn settings() -> RetentionSettings {
+        RetentionSettings {
+            max_age: None,
+            max_bytes: None,
+            keep_clips: 0,
+            min_free: None,
+        }
+    }
+
+    fn file(name: &str, size: u64) -> Candidate {
+        let key = if name.starts_with(|c: char| c.is_ascii_digit()) {
+            name.to_string()
+        } else {
+            String::new()
+        };
+        Candidate {
+            entry: Entry {
+                name: name.to_string(),
+                dir: false,
+                size,
+            },
+            key,
+            writing: false,
+        }
+    }
+
+    fn files() -> Vec<Candidate> {
+        vec![
+            file("undated/000001_clip.avi", MB),
+            file("2024/01/30/120000_clip.avi", MB),
+            file("2024/01/30/130000_still.jpg", MB),
+            file("2024/01/31/120000_rec.avi", MB),
+            file("2024/01/31/130000_clip.avi", MB),
+        ]
+    }
+
+    fn doomed(plan: &Plan, files: &[Candidate]) -> Vec<String> {
+        files
+            .iter()
+            .zip(&plan.doomed)
+            .filter(|(_, &doomed)| doomed)
+            .map(|(file, _)| file.entry.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_everything_without_rules() {
+        let files = files();
+        let plan = plan(&files, &settings(), None, 0, 0);
+        assert!(doomed(&plan, &files).is_empty());
+        assert_eq!(plan.total, 5 * MB);
+    }
+
+    #[test]
+    fn too_old_goes_but_the_undated_stay() {
+        let files = files();
+        let plan = plan(&files, &settings(), Some("2024/01/31/000000"), 0, 0);
+        assert_eq!(
+            doomed(&plan, &files),
+            ["2024/01/30/120000_clip.avi", "2024/01/30/130000_still.jpg"]
+        );
+        assert_eq!(plan.total, 3 * MB);
+    }
+
+    #[test]
+    fn keeps_the_newest_clips() {
+        let files = files();
+        let settings = RetentionSettings {
+            keep_clips: 1,
+            ..settings()
+        };
+        let plan = plan(&files, &settings, None, 0, 0);
+        assert_eq!(
+            doomed(&plan, &files),
+            ["undated/000001_clip.avi", "2024/01/30/120000_clip.avi"]
+        );
+    }
+
+    #[test]
+    fn oldest_go_until_under_the_limit() {
+        let files = files();
+        let settings = RetentionSettings {
+            max_bytes: Some(3 * MB),
+            ..settings()
+        };
+        // The logs count too, so it takes one more to get under
+        let plan = plan(&files, &settings, None, MB / 2, 0);
+        assert_eq!(
+            doomed(&plan, &files),
+            [
+                "undated/000001_clip.avi",
+                "2024/01/30/120000_clip.avi",
+                "2024/01/30/130000_still.jpg"
+            ]
+        );
+        assert_eq!(plan.total, 2 * MB + MB / 2);
+    }
+
+    #[test]
+    fn oldest_go_until_there_is_room() {
+        let files = files();
+        let settings = RetentionSettings {
+            min_free: Some(3 * MB),
+            ..settings()
+        };
+        let plan = plan(&files, &settings, Some("2024/01/30/125959"), 0, MB / 2);
+        // The one too old already frees a megabyte, two more from the oldest makes room
+        assert_eq!(
+            doomed(&plan, &files),
+            [
+                "undated/000001_clip.avi",
+                "2024/01/30/120000_clip.avi",
+                "2024/01/30/130000_still.jpg"
+            ]
+        );
+        assert_eq!(plan.free, 3 * MB + MB / 2);
+        assert!(!settings.short(plan.total, plan.free));
+    }
+
+    #[test]
+    fn files_being_written_stay() {
+        let mut files = files();
+        files[1].writing = true;
+        let settings = RetentionSettings {
+            max_bytes: Some(MB),
+            keep_clips: 1,
+            ..settings()
+        };
+        let plan = plan(&files, &settings, Some("2024/02/01/000000"), 0, 0);
+        assert_eq!(plan.doomed, [true, false, true, true, true]);
+        assert_eq!(plan.total, MB);
+    }
+
+    #[test]
+    fn says_when_it_cant_get_under() {
+        let mut files = files();
+        for file in &mut files {
+            file.writing = true;
+        }
+        let settings = RetentionSettings {
+            max_bytes: Some(MB),
+            ..settings()
+        };
+        let plan = plan(&files, &settings, None, 0, 0);
+        assert!(plan.doomed.iter().all(|&doomed| !doomed));
+        assert!(settings.short(plan.total, plan.free));
+    }
+}
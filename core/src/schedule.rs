@@ -0,0 +1,124 @@
+// Switching night mode on at set times of day
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::clock::CLOCK_SET;
+
+// Night between two times of day, in minutes since midnight. `from` after `until` goes
+// through midnight, which is the usual case.
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    pub from: u32,
+    pub until: u32,
+    // The clock is UTC, this is added to get local time
+    pub utc_offset_minutes: i32,
+}
+
+impl Schedule {
+    // "19:30-06:45"
+    pub fn parse(spec: &str, utc_offset_minutes: i32) -> Result<Self> {
+        let minutes = |time: &str| -> Result<u32> {
+            let (hours, minutes) = time
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected HH:MM, not '{}'", time))?;
+            let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+            if hours > 23 || minutes > 59 {
+                bail!("'{}' isn't a time of day", time);
+            }
+            Ok(hours * 60 + minutes)
+        };
+        let (from, until) = spec
+            .split_once('-')
+            .ok_or_else(|| anyhow!("A night schedule looks like 19:30-06:45"))?;
+        Ok(Self {
+            from: minutes(from)?,
+            until: minutes(until)?,
+            utc_offset_minutes,
+        })
+    }
+
+    // Whether it's night at `unix`, None when that's before SNTP has set the clock
+    pub fn is_night(&self, unix: u64) -> Option<bool> {
+        if unix < CLOCK_SET {
+            return None;
+        }
+        let local = (unix / 60) as i64 + self.utc_offset_minutes as i64;
+        let minute = local.rem_euclid(24 * 60) as u32;
+        Some(if self.from <= self.until {
+            (self.from..self.until).contains(&minute)
+        } else {
+            minute >= self.from || minute < self.until
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-31 00:00:00 UTC
+    const MIDNIGHT: u64 = 1_706_659_200;
+
+    fn at(hours: u64, minutes: u64) -> u64 {
+        MIDNIGHT + hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        let schedule = Schedule::parse(" 19:30 - 06:45 ", 60).unwrap();
+        assert_eq!(schedule.from, 19 * 60 + 30);
+        assert_eq!(schedule.until, 6 * 60 + 45);
+        assert_eq!(schedule.utc_offset_minutes, 60);
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_schedule() {
+        for spec in [
+            "",
+            "19:30",
+            "19:30-24:00",
+            "19:60-06:00",
+            "7pm-6am",
+            "19-06",
+        ] {
+            assert!(Schedule::parse(spec, 0).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn night_through_midnight() {
+        let schedule = Schedule::parse("19:30-06:45", 0).unwrap();
+        assert_eq!(schedule.is_night(at(19, 29)), Some(false));
+        assert_eq!(schedule.is_night(at(19, 30)), Some(true));
+        assert_eq!(schedule.is_night(at(0, 0)), Some(true));
+        assert_eq!(schedule.is_night(at(6, 44)), Some(true));
+        assert_eq!(schedule.is_night(at(6, 45)), Some(false));
+        assert_eq!(schedule.is_night(at(12, 0)), Some(false));
+    }
+
+    #[test]
+    fn night_within_a_day() {
+        let schedule = Schedule::parse("01:00-05:00", 0).unwrap();
+        assert_eq!(schedule.is_night(at(0, 59)), Some(false));
+        assert_eq!(schedule.is_night(at(1, 0)), Some(true));
+        assert_eq!(schedule.is_night(at(5, 0)), Some(false));
+    }
+
+    #[test]
+    fn night_in_local_time() {
+        // 18:00 UTC is 19:30 at UTC+1:30, and 16:30 at UTC-1:30
+        let ahead = Schedule::parse("19:30-06:45", 90).unwrap();
+        let behind = Schedule::parse("19:30-06:45", -90).unwrap();
+        assert_eq!(ahead.is_night(at(18, 0)), Some(true));
+        assert_eq!(behind.is_night(at(18, 0)), Some(false));
+        assert_eq!(behind.is_night(at(21, 0)), Some(true));
+    }
+
+    #[test]
+    fn nothing_before_the_clock_is_set() {
+        let schedule = Schedule::parse("00:00-23:59", 0).unwrap();
+        assert_eq!(schedule.is_night(0), None);
+        assert_eq!(schedule.is_night(CLOCK_SET - 1), None);
+    }
+}
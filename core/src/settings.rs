@@ -0,0 +1,93 @@
+// The sensor settings every `FrameSource` has, as /api/camera shows and takes them. The
+// firmware adds the rest of the sensor's settings around these.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::source::{
+    framesize_from_name, framesize_name, FrameSize, FrameSource, WORST_SENSOR_QUALITY,
+};
+
+// A setting the client got wrong, as opposed to the camera failing to take it
+#[derive(Debug)]
+pub struct Invalid(pub String);
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+#[derive(Debug, Serialize)]
+pub struct SourceSettings {
+    pub framesize: &'static str,
+    // The sensor's JPEG quality, 0-63 and lower is better
+    pub quality: i32,
+    pub vflip: bool,
+    pub hmirror: bool,
+}
+
+impl SourceSettings {
+    pub fn read(cam: &impl FrameSource) -> Result<Self> {
+        Ok(Self {
+            framesize: framesize_name(cam.framesize()?),
+            quality: cam.quality()?,
+            vflip: cam.vflip()?,
+            hmirror: cam.hmirror()?,
+        })
+    }
+}
+
+// Every field is optional, only the ones present get changed
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceUpdate {
+    pub framesize: Option<String>,
+    pub quality: Option<i32>,
+    pub vflip: Option<bool>,
+    pub hmirror: Option<bool>,
+}
+
+impl SourceUpdate {
+    // Everything is checked before anything is changed, so a bad value fails with `Invalid`
+    // and leaves the camera as it was
+    pub fn apply(&self, cam: &impl FrameSource) -> Result<()> {
+        let framesize = self.framesize()?;
+        if let Some(quality) = self.quality {
+            if !(0..=WORST_SENSOR_QUALITY).contains(&quality) {
+                return Err(Invalid(format!(
+                    "quality must be between 0 and {}",
+                    WORST_SENSOR_QUALITY
+                ))
+                .into());
+            }
+        }
+
+        if let Some(framesize) = framesize {
+            cam.set_framesize(framesize)?;
+        }
+        if let Some(quality) = self.quality {
+            cam.set_quality(quality)?;
+        }
+        if let Some(vflip) = self.vflip {
+            cam.set_vflip(vflip)?;
+        }
+        if let Some(hmirror) = self.hmirror {
+            cam.set_hmirror(hmirror)?;
+        }
+        Ok(())
+    }
+
+    fn framesize(&self) -> Result<Option<FrameSize>, Invalid> {
+        self.framesize
+            .as_deref()
+            .map(|name| {
+                framesize_from_name(name)
+                    .ok_or_else(|| Invalid(format!("Unknown frame size '{}'", name)))
+            })
+            .transpose()
+    }
+}
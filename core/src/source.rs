@@ -0,0 +1,356 @@
+// The part of a camera the pipeline and the handlers need: frames out and the basic sensor
+// controls in. The firmware implements it for the real camera, `MockCamera` lets the same
+// code run on the host without one.
+
+use anyhow::{bail, Result};
+use std::{
+    fmt,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+// The driver's framesize_t and pixformat_t, plain numbers so this builds without it
+pub type FrameSize = u32;
+pub type PixFormat = u32;
+
+// Numbered as in esp32-camera's sensor.h, the firmware checks them against the driver's
+pub const FRAMESIZE_96X96: FrameSize = 0;
+pub const FRAMESIZE_QQVGA: FrameSize = 1;
+pub const FRAMESIZE_QCIF: FrameSize = 2;
+pub const FRAMESIZE_HQVGA: FrameSize = 3;
+pub const FRAMESIZE_240X240: FrameSize = 4;
+pub const FRAMESIZE_QVGA: FrameSize = 5;
+pub const FRAMESIZE_CIF: FrameSize = 6;
+pub const FRAMESIZE_HVGA: FrameSize = 7;
+pub const FRAMESIZE_VGA: FrameSize = 8;
+pub const FRAMESIZE_SVGA: FrameSize = 9;
+pub const FRAMESIZE_XGA: FrameSize = 10;
+pub const FRAMESIZE_HD: FrameSize = 11;
+pub const FRAMESIZE_SXGA: FrameSize = 12;
+pub const FRAMESIZE_UXGA: FrameSize = 13;
+pub const FRAMESIZE_FHD: FrameSize = 14;
+pub const FRAMESIZE_P_HD: FrameSize = 15;
+pub const FRAMESIZE_QXGA: FrameSize = 17;
+pub const FRAMESIZE_P_FHD: FrameSize = 20;
+pub const PIXFORMAT_JPEG: PixFormat = 4;
+
+// Names used for frame sizes in the config and the REST API, along with their dimensions
+const FRAMESIZES: &[(FrameSize, &str, u32, u32)] = &[
+    (FRAMESIZE_96X96, "96X96", 96, 96),
+    (FRAMESIZE_QQVGA, "QQVGA", 160, 120),
+    (FRAMESIZE_QCIF, "QCIF", 176, 144),
+    (FRAMESIZE_HQVGA, "HQVGA", 240, 176),
+    (FRAMESIZE_240X240, "240X240", 240, 240),
+    (FRAMESIZE_QVGA, "QVGA", 320, 240),
+    (FRAMESIZE_CIF, "CIF", 400, 296),
+    (FRAMESIZE_HVGA, "HVGA", 480, 320),
+    (FRAMESIZE_VGA, "VGA", 640, 480),
+    (FRAMESIZE_SVGA, "SVGA", 800, 600),
+    (FRAMESIZE_XGA, "XGA", 1024, 768),
+    (FRAMESIZE_HD, "HD", 1280, 720),
+    (FRAMESIZE_P_HD, "P_HD", 720, 1280),
+    (FRAMESIZE_SXGA, "SXGA", 1280, 1024),
+    (FRAMESIZE_UXGA, "UXGA", 1600, 1200),
+    // Only the 3 and 5 MP sensors go past UXGA
+    (FRAMESIZE_FHD, "FHD", 1920, 1080),
+    (FRAMESIZE_P_FHD, "P_FHD", 1080, 1920),
+    (FRAMESIZE_QXGA, "QXGA", 2048, 1536),
+];
+
+pub fn framesize_name(framesize: FrameSize) -> &'static str {
+    FRAMESIZES
+        .iter()
+        .find(|(f, ..)| *f == framesize)
+        .map_or("UNKNOWN", |(_, name, ..)| name)
+}
+
+pub fn framesize_from_name(name: &str) -> Option<FrameSize> {
+    FRAMESIZES
+        .iter()
+        .find(|(_, n, ..)| n.eq_ignore_ascii_case(name))
+        .map(|(f, ..)| *f)
+}
+
+// Width and height in pixels
+pub fn framesize_dimensions(framesize: FrameSize) -> Option<(u32, u32)> {
+    FRAMESIZES
+        .iter()
+        .find(|(f, ..)| *f == framesize)
+        .map(|(_, _, width, height)| (*width, *height))
+}
+
+// For comparing frame sizes, the enum's numbering doesn't go by size
+pub fn framesize_pixels(framesize: FrameSize) -> Option<u32> {
+    framesize_dimensions(framesize).map(|(width, height)| width * height)
+}
+
+// Every frame size with known dimensions, most pixels first. The enum isn't in that order, the
+// portrait sizes are numbered after QXGA.
+pub fn framesizes_largest_first() -> Vec<FrameSize> {
+    let mut framesizes = FRAMESIZES.to_vec();
+    framesizes.sort_by_key(|&(_, _, width, height)| std::cmp::Reverse(width * height));
+    framesizes.into_iter().map(|(f, ..)| f).collect()
+}
+
+// The sensor's own JPEG quality, 0-63 and lower is better
+pub const WORST_SENSOR_QUALITY: i32 = 63;
+
+// A frame as it came from the sensor, borrowed from wherever the source keeps it
+pub struct RawFrame<'a> {
+    pub data: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub format: PixFormat,
+    // When the driver took it, since boot
+    pub timestamp: Duration,
+}
+
+// `FrameSource::capture_jpeg_into` was handed a buffer too small for the frame, which was this
+// many bytes. That frame is gone, the caller grows the buffer and takes the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall(pub usize);
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The frame is {} bytes, more than the buffer holds",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for BufferTooSmall {}
+
+pub trait FrameSource {
+    // Hands `f` the next frame without copying it anywhere. The source gets its buffer back
+    // once `f` returns.
+    fn with_frame<R>(&self, f: impl FnOnce(&RawFrame) -> R) -> Result<R>;
+
+    // The next frame as JPEG. `quality` is our encoder's, 1-100 and higher is better, for
+    // sensors that don't produce JPEG themselves.
+    fn capture_jpeg(&self, quality: u8) -> Result<Vec<u8>>;
+
+    // The next frame as JPEG copied into `buf`, returning its length. A frame that doesn't fit
+    // fails with `BufferTooSmall`.
+    fn capture_jpeg_into(&self, quality: u8, buf: &mut [u8]) -> Result<usize> {
+        let jpeg = self.capture_jpeg(quality)?;
+        let Some(dest) = buf.get_mut(..jpeg.len()) else {
+            return Err(BufferTooSmall(jpeg.len()).into());
+        };
+        dest.copy_from_slice(&jpeg);
+        Ok(jpeg.len())
+    }
+
+    // Throws away any frame the source is holding on to, so the next one is taken after now
+    fn discard(&self);
+
+    // Whether the sensor produces JPEG itself, in which case it ignores our encoder's quality
+    // and has to be told its own instead
+    fn sensor_jpeg(&self) -> Result<bool>;
+
+    fn framesize(&self) -> Result<FrameSize>;
+
+    fn set_framesize(&self, framesize: FrameSize) -> Result<()>;
+
+    // The sensor's JPEG quality, see `WORST_SENSOR_QUALITY`
+    fn quality(&self) -> Result<i32>;
+
+    fn set_quality(&self, quality: i32) -> Result<()>;
+
+    fn vflip(&self) -> Result<bool>;
+
+    fn set_vflip(&self, vflip: bool) -> Result<()>;
+
+    fn hmirror(&self) -> Result<bool>;
+
+    fn set_hmirror(&self, hmirror: bool) -> Result<()>;
+}
+
+// Hands out canned JPEGs in turn and remembers what it was set to, for running the pipeline
+// and the handlers without a sensor
+pub struct MockCamera {
+    frames: Vec<Vec<u8>>,
+    state: Mutex<MockState>,
+}
+
+struct MockState {
+    next: usize,
+    // Frames handed out and thrown away so far
+    captures: u32,
+    discards: u32,
+    framesize: FrameSize,
+    quality: i32,
+    vflip: bool,
+    hmirror: bool,
+}
+
+impl MockCamera {
+    pub fn new(frames: Vec<Vec<u8>>) -> Self {
+        Self {
+            frames,
+            state: Mutex::new(MockState {
+                next: 0,
+                captures: 0,
+                discards: 0,
+                framesize: FRAMESIZE_VGA,
+                quality: 12,
+                vflip: false,
+                hmirror: false,
+            }),
+        }
+    }
+
+    pub fn captures(&self) -> u32 {
+        self.state().captures
+    }
+
+    pub fn discards(&self) -> u32 {
+        self.state().discards
+    }
+
+    // Nothing the mock keeps is left half updated by a panicking test
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn next_frame(&self) -> Result<&[u8]> {
+        if self.frames.is_empty() {
+            bail!("MockCamera has no frames");
+        }
+        let mut state = self.state();
+        let frame = &self.frames[state.next];
+        state.next = (state.next + 1) % self.frames.len();
+        state.captures += 1;
+        Ok(frame)
+    }
+}
+
+impl FrameSource for MockCamera {
+    fn with_frame<R>(&self, f: impl FnOnce(&RawFrame) -> R) -> Result<R> {
+        let data = self.next_frame()?;
+        Ok(f(&RawFrame {
+            data,
+            width: 640,
+            height: 480,
+            format: PIXFORMAT_JPEG,
+            timestamp: Duration::ZERO,
+        }))
+    }
+
+    fn capture_jpeg(&self, _quality: u8) -> Result<Vec<u8>> {
+        Ok(self.next_frame()?.to_vec())
+    }
+
+    fn discard(&self) {
+        self.state().discards += 1;
+    }
+
+    fn sensor_jpeg(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn framesize(&self) -> Result<FrameSize> {
+        Ok(self.state().framesize)
+    }
+
+    fn set_framesize(&self, framesize: FrameSize) -> Result<()> {
+        if framesize_dimensions(framesize).is_none() {
+            bail!("MockCamera can't do framesize {}", framesize);
+        }
+        self.state().framesize = framesize;
+        Ok(())
+    }
+
+    fn quality(&self) -> Result<i32> {
+        Ok(self.state().quality)
+    }
+
+    fn set_quality(&self, quality: i32) -> Result<()> {
+        if !(0..=WORST_SENSOR_QUALITY).contains(&quality) {
+            bail!("quality must be between 0 and {}", WORST_SENSOR_QUALITY);
+        }
+        self.state().quality = quality;
+        Ok(())
+    }
+
+    fn vflip(&self) -> Result<bool> {
+        Ok(self.state().vflip)
+    }
+
+    fn set_vflip(&self, vflip: bool) -> Result<()> {
+        self.state().vflip = vflip;
+        Ok(())
+    }
+
+    fn hmirror(&self) -> Result<bool> {
+        Ok(self.state().hmirror)
+    }
+
+    fn set_hmirror(&self, hmirror: bool) -> Result<()> {
+        self.state().hmirror = hmirror;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_come_round_in_turn() {
+        let cam = MockCamera::new(vec![vec![1], vec![2, 2]]);
+        assert_eq!(cam.capture_jpeg(80).unwrap(), [1]);
+        assert_eq!(cam.with_frame(|raw| raw.data.len()).unwrap(), 2);
+        assert_eq!(cam.capture_jpeg(80).unwrap(), [1]);
+        assert_eq!(cam.captures(), 3);
+
+        assert!(MockCamera::new(Vec::new()).capture_jpeg(80).is_err());
+    }
+
+    #[test]
+    fn small_buffers_say_how_big_the_frame_was() {
+        let cam = MockCamera::new(vec![vec![7; 10]]);
+        let mut buf = [0; 4];
+        let e = cam.capture_jpeg_into(80, &mut buf).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<BufferTooSmall>(),
+            Some(&BufferTooSmall(10))
+        );
+
+        let mut buf = [0; 16];
+        assert_eq!(cam.capture_jpeg_into(80, &mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], [7; 10]);
+    }
+
+    #[test]
+    fn framesizes_by_size() {
+        assert_eq!(framesize_from_name("vga"), Some(FRAMESIZE_VGA));
+        assert_eq!(framesize_name(FRAMESIZE_P_FHD), "P_FHD");
+        assert_eq!(framesize_name(16), "UNKNOWN");
+
+        let sizes = framesizes_largest_first();
+        assert_eq!(sizes.first(), Some(&FRAMESIZE_QXGA));
+        assert_eq!(sizes.last(), Some(&FRAMESIZE_96X96));
+        // Numbered after SXGA, but smaller
+        assert!(framesize_pixels(FRAMESIZE_P_HD) < framesize_pixels(FRAMESIZE_SXGA));
+    }
+
+    #[test]
+    fn remembers_settings() {
+        let cam = MockCamera::new(vec![vec![0]]);
+        cam.set_framesize(FRAMESIZE_QVGA).unwrap();
+        cam.set_quality(30).unwrap();
+        cam.set_vflip(true).unwrap();
+        assert_eq!(cam.framesize().unwrap(), FRAMESIZE_QVGA);
+        assert_eq!(cam.quality().unwrap(), 30);
+        assert!(cam.vflip().unwrap());
+        assert!(!cam.hmirror().unwrap());
+
+        // Out of range leaves the old value
+        assert!(cam.set_quality(64).is_err());
+        assert!(cam.set_framesize(16).is_err());
+        assert_eq!(cam.quality().unwrap(), 30);
+        assert_eq!(cam.framesize().unwrap(), FRAMESIZE_QVGA);
+    }
+}
@@ -0,0 +1,318 @@
+// Drives the snapshot, stream and settings responses with `MockCamera` behind them and checks
+// what a client would see on the wire, so protocol changes show up before flashing anything
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+use tigercam_core::http::{send_jpeg, send_json, Exchange, Multipart};
+use tigercam_core::settings::{Invalid, SourceSettings, SourceUpdate};
+use tigercam_core::source::{FrameSource, MockCamera, FRAMESIZE_QVGA, FRAMESIZE_VGA};
+
+// Golden frames, the mock hands them out in turn and they have to come out byte for byte
+const FRAMES: [&[u8]; 3] = [
+    b"\xff\xd8\xff\xe0first\xff\xd9",
+    b"\xff\xd8\xff\xe0second frame\xff\xd9",
+    b"\xff\xd8\xff\xe0\r\n--frame\r\n\xff\xd9",
+];
+
+fn camera() -> MockCamera {
+    MockCamera::new(FRAMES.iter().map(|frame| frame.to_vec()).collect())
+}
+
+// One request as a client would make it, and everything that came back
+#[derive(Default)]
+struct TestClient {
+    uri: String,
+    request_headers: Headers,
+    status: Option<u16>,
+    headers: Headers,
+    body: Vec<u8>,
+    // Hangs up once this much of the body has arrived
+    hang_up_after: Option<usize>,
+}
+
+impl TestClient {
+    fn get(uri: &str) -> Self {
+        Self {
+            uri: uri.into(),
+            ..Default::default()
+        }
+    }
+
+    fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.request_headers.push((name.into(), value.into()));
+        self
+    }
+
+    fn response_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Value {
+        assert_eq!(
+            self.response_header("Content-Type"),
+            Some("application/json")
+        );
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+impl Exchange for TestClient {
+    fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request_headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn respond(&mut self, status: u16, headers: &[(&str, &str)]) -> Result<()> {
+        if self.status.is_some() {
+            bail!("Response already started");
+        }
+        self.status = Some(status);
+        self.headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Ok(())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        if self.status.is_none() {
+            bail!("Body written before the headers");
+        }
+        if let Some(limit) = self.hang_up_after {
+            if self.body.len() + data.len() > limit {
+                self.body
+                    .extend_from_slice(&data[..limit - self.body.len()]);
+                bail!("Client hung up");
+            }
+        }
+        self.body.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+type Headers = Vec<(String, String)>;
+
+// Splits a multipart/x-mixed-replace body into its parts' headers and bodies, going by each
+// part's Content-Length the way MJPEG clients do
+fn parts(body: &[u8], boundary: &str) -> Vec<(Headers, Vec<u8>)> {
+    let delimiter = format!("--{}\r\n", boundary);
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        assert!(
+            rest.starts_with(delimiter.as_bytes()),
+            "no boundary at {:?}",
+            rest
+        );
+        rest = &rest[delimiter.len()..];
+
+        let end = rest
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("part headers end");
+        let headers: Headers = std::str::from_utf8(&rest[..end])
+            .unwrap()
+            .split("\r\n")
+            .map(|line| {
+                let (name, value) = line.split_once(": ").expect("header line");
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        rest = &rest[end + 4..];
+
+        let length: usize = headers
+            .iter()
+            .find(|(name, _)| name == "Content-Length")
+            .expect("part length")
+            .1
+            .parse()
+            .unwrap();
+        parts.push((headers, rest[..length].to_vec()));
+        assert_eq!(&rest[length..length + 2], b"\r\n");
+        rest = &rest[length + 2..];
+    }
+    parts
+}
+
+#[test]
+fn snapshot_headers_and_body() {
+    let cam = camera();
+    let mut client = TestClient::get("/");
+
+    let jpeg = cam.capture_jpeg(80).unwrap();
+    send_jpeg(&mut client, &jpeg, Some("\"1\""), "capture;dur=12").unwrap();
+
+    assert_eq!(client.status, Some(200));
+    assert_eq!(client.response_header("Content-Type"), Some("image/jpeg"));
+    assert_eq!(
+        client.response_header("Content-Length"),
+        Some(FRAMES[0].len().to_string().as_str())
+    );
+    assert_eq!(client.response_header("ETag"), Some("\"1\""));
+    assert_eq!(client.response_header("Vary"), Some("Accept"));
+    assert_eq!(
+        client.response_header("Server-Timing"),
+        Some("capture;dur=12")
+    );
+    assert_eq!(client.body, FRAMES[0]);
+}
+
+#[test]
+fn snapshot_not_modified() {
+    let cam = camera();
+    let jpeg = cam.capture_jpeg(80).unwrap();
+
+    let mut client = TestClient::get("/").with_header("If-None-Match", "W/\"1\"");
+    send_jpeg(&mut client, &jpeg, Some("\"1\""), "cache;dur=0").unwrap();
+    assert_eq!(client.status, Some(304));
+    assert_eq!(client.response_header("ETag"), Some("\"1\""));
+    assert!(client.body.is_empty());
+
+    // Without an ETag there's nothing for the client to match
+    let mut client = TestClient::get("/").with_header("If-None-Match", "*");
+    send_jpeg(&mut client, &jpeg, None, "prewarm;dur=0").unwrap();
+    assert_eq!(client.status, Some(200));
+    assert_eq!(client.response_header("ETag"), None);
+    assert_eq!(client.body, FRAMES[0]);
+}
+
+#[test]
+fn stream_framing() {
+    let cam = camera();
+    let mut client = TestClient::get("/stream?fps=10");
+
+    {
+        let mut stream = Multipart::start(&mut client).unwrap();
+        for _ in 0..4 {
+            stream.send(&cam.capture_jpeg(80).unwrap()).unwrap();
+        }
+    }
+
+    assert_eq!(client.status, Some(200));
+    assert_eq!(
+        client.response_header("Content-Type"),
+        Some("multipart/x-mixed-replace;boundary=frame")
+    );
+    let parts = parts(&client.body, "frame");
+    assert_eq!(parts.len(), 4);
+    for (i, (headers, body)) in parts.iter().enumerate() {
+        let golden = FRAMES[i % FRAMES.len()];
+        assert_eq!(
+            headers,
+            &[
+                ("Content-Type".to_string(), "image/jpeg".to_string()),
+                ("Content-Length".to_string(), golden.len().to_string()),
+            ]
+        );
+        assert_eq!(body, golden);
+    }
+    assert_eq!(cam.captures(), 4);
+}
+
+#[test]
+fn stream_notices_the_client_leaving() {
+    let cam = camera();
+    let mut client = TestClient {
+        hang_up_after: Some(100),
+        ..TestClient::get("/stream")
+    };
+
+    let mut stream = Multipart::start(&mut client).unwrap();
+    let sent = (0..10)
+        .take_while(|_| stream.send(&cam.capture_jpeg(80).unwrap()).is_ok())
+        .count();
+    assert!(sent < 10);
+    assert!(client.body.len() <= 100);
+}
+
+#[test]
+fn settings_schema() {
+    let cam = camera();
+    let mut client = TestClient::get("/api/camera");
+    send_json(&mut client, 200, &SourceSettings::read(&cam).unwrap(), &[]).unwrap();
+
+    assert_eq!(client.status, Some(200));
+    assert_eq!(
+        client.response_header("Content-Length"),
+        Some(client.body.len().to_string().as_str())
+    );
+    let json = client.json();
+    let settings = json.as_object().unwrap();
+    let mut keys: Vec<&str> = settings.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, ["framesize", "hmirror", "quality", "vflip"]);
+    assert_eq!(settings["framesize"], "VGA");
+    assert!(settings["quality"].is_i64());
+    assert!(settings["vflip"].is_boolean());
+    assert!(settings["hmirror"].is_boolean());
+}
+
+#[test]
+fn settings_update() {
+    let cam = camera();
+    let update: SourceUpdate =
+        serde_json::from_str(r#"{"framesize": "qvga", "quality": 20, "vflip": true}"#).unwrap();
+    update.apply(&cam).unwrap();
+
+    assert_eq!(cam.framesize().unwrap(), FRAMESIZE_QVGA);
+    let mut client = TestClient::get("/api/camera");
+    send_json(&mut client, 200, &SourceSettings::read(&cam).unwrap(), &[]).unwrap();
+    assert_eq!(
+        client.json(),
+        serde_json::json!({
+            "framesize": "QVGA",
+            "quality": 20,
+            "vflip": true,
+            "hmirror": false,
+        })
+    );
+}
+
+#[test]
+fn settings_refuse_bad_values() {
+    let cam = camera();
+
+    for body in [
+        r#"{"quality": 64}"#,
+        r#"{"framesize": "huge"}"#,
+        r#"{"framesize": "QVGA", "quality": -1}"#,
+    ] {
+        let update: SourceUpdate = serde_json::from_str(body).unwrap();
+        let e = update.apply(&cam).unwrap_err();
+        assert!(e.is::<Invalid>(), "{}: {:?}", body, e);
+    }
+    // Nothing was half applied
+    assert_eq!(cam.framesize().unwrap(), FRAMESIZE_VGA);
+    assert_eq!(cam.quality().unwrap(), 12);
+
+    assert!(serde_json::from_str::<SourceUpdate>(r#"{"brightness": 1}"#).is_err());
+}
+
+#[test]
+fn errors_as_json() {
+    let mut client = TestClient::get("/capture");
+    send_json(
+        &mut client,
+        503,
+        &serde_json::json!({"code": "camera_busy", "message": "Camera is busy", "retry_after": 1}),
+        &[("Retry-After", "1")],
+    )
+    .unwrap();
+
+    assert_eq!(client.status, Some(503));
+    assert_eq!(client.response_header("Retry-After"), Some("1"));
+    assert_eq!(client.json()["code"], "camera_busy");
+
+    // Failing to capture is the handler's to answer, nothing has gone out yet
+    let cam = MockCamera::new(Vec::new());
+    assert!(cam.capture_jpeg(80).is_err());
+}
@@ -0,0 +1,52 @@
+use esp_idf_svc::sys::cam::pixformat_t_PIXFORMAT_JPEG;
+use log::warn;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::camera::{self, FrameBuffer, Thumbnail};
+use crate::lock::lock;
+use crate::source::RawFrame;
+
+// A small grayscale copy of the newest frame through the pipeline, so motion and the other
+// watchers can look at frames the stream is taking anyway instead of capturing their own.
+// Only frames the sensor hands over unencoded are copied, their luma is read straight out
+// before the pipeline encodes them. Decoding every streamed JPEG would cost more than the
+// watchers capturing their own now and then, so with a JPEG sensor `fresh` stays None.
+// Clones share it.
+#[derive(Clone, Default)]
+pub struct Analysis {
+    latest: Arc<Mutex<Option<(Instant, Thumbnail)>>>,
+}
+
+impl Analysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called by the pipeline while it still has the driver's buffer
+    pub fn update(&self, raw: &RawFrame) {
+        if raw.format == pixformat_t_PIXFORMAT_JPEG {
+            return;
+        }
+        match camera::thumbnail(raw.data, raw.width, raw.height, raw.format, 8) {
+            Ok(frame) => *lock(&self.latest) = Some((Instant::now(), frame)),
+            Err(e) => warn!("No analysis copy of the frame: {:?}", e),
+        }
+    }
+
+    // The newest copy if it's no older than `max_age`. Anyone who gets None captures their
+    // own with `frame`, which comes out the same size.
+    pub fn fresh(&self, max_age: Duration) -> Option<Thumbnail> {
+        match &*lock(&self.latest) {
+            Some((at, frame)) if at.elapsed() <= max_age => Some(frame.clone()),
+            _ => None,
+        }
+    }
+
+    // An eighth of the frame's size, JPEG decoded straight to that
+    pub fn frame(fb: &FrameBuffer) -> camera::Result<Thumbnail> {
+        fb.thumbnail()
+    }
+}
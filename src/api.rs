@@ -0,0 +1,568 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    hal::{
+        delay::FreeRtos,
+        reset::{self, ResetReason},
+    },
+    http::{server::EspHttpServer, Method},
+    sys::{
+        esp, esp_get_free_heap_size, esp_netif_get_handle_from_ifkey, esp_netif_get_ip_info,
+        esp_netif_ip_info_t, esp_timer_get_time, esp_wifi_sta_get_ap_info, heap_caps_get_free_size,
+        nvs_flash_erase, wifi_ap_record_t, MALLOC_CAP_SPIRAM,
+    },
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+};
+
+use crate::auth::Auth;
+use crate::camera::{
+    capture_counters, fb_location_name, grab_mode_from_name, grab_mode_name, Camera, CameraConfig,
+    CameraExt, CaptureCounters,
+};
+use crate::http::{read_body, write_json, ApiError};
+use crate::night::{Mode, NightMode};
+use crate::pipeline::{lock_for_capture, GrabMode, Pipeline, PipelineStats};
+use crate::sensor::{
+    framesize_name, pixformat_from_name, pixformat_name, Exposure, Sensor, SensorInfo,
+    SpecialEffect, WhiteBalanceMode,
+};
+use tigercam_core::settings::{Invalid, SourceSettings, SourceUpdate};
+
+#[derive(Serialize)]
+struct CameraSettings {
+    sensor: SensorInfo,
+    pixformat: &'static str,
+    // framesize, quality, vflip and hmirror, as every frame source has them
+    #[serde(flatten)]
+    source: SourceSettings,
+    // Our own encoder's quality for sensors that don't produce JPEG, 1-100 and higher is better
+    encoder_quality: u8,
+    brightness: i8,
+    contrast: i8,
+    saturation: i8,
+    sharpness: i8,
+    aec: bool,
+    aec2: bool,
+    ae_level: i8,
+    aec_value: u16,
+    agc: bool,
+    agc_gain: u8,
+    gainceiling: u8,
+    awb: bool,
+    awb_gain: bool,
+    // None if the sensor reports a mode we don't know
+    wb_mode: Option<WhiteBalanceMode>,
+    denoise: u8,
+    lenc: bool,
+    bpc: bool,
+    wpc: bool,
+    // None if the sensor reports an effect we don't know
+    special_effect: Option<SpecialEffect>,
+    colorbar: bool,
+    grab_mode: GrabMode,
+    // What `grab_mode` currently resolves to, only differs from it in auto mode
+    active_grab_mode: GrabMode,
+    // How the driver was brought up, see `CameraConfig`
+    fb_count: usize,
+    fb_location: &'static str,
+    driver_grab_mode: &'static str,
+}
+
+impl CameraSettings {
+    fn read(pipeline: &Pipeline, sensor: &Sensor) -> Self {
+        let status = sensor.status();
+        let config = CameraConfig::current(sensor);
+        Self {
+            sensor: sensor.info(),
+            pixformat: pixformat_name(sensor.pixformat()),
+            source: SourceSettings {
+                framesize: framesize_name(status.framesize),
+                quality: status.quality.into(),
+                vflip: status.vflip != 0,
+                hmirror: status.hmirror != 0,
+            },
+            encoder_quality: pipeline.quality(),
+            brightness: status.brightness,
+            contrast: status.contrast,
+            saturation: status.saturation,
+            sharpness: status.sharpness,
+            aec: status.aec != 0,
+            aec2: status.aec2 != 0,
+            ae_level: status.ae_level,
+            aec_value: status.aec_value,
+            agc: status.agc != 0,
+            agc_gain: status.agc_gain,
+            gainceiling: status.gainceiling,
+            awb: status.awb != 0,
+            awb_gain: status.awb_gain != 0,
+            wb_mode: sensor.wb_mode(),
+            denoise: status.denoise,
+            lenc: status.lenc != 0,
+            bpc: status.bpc != 0,
+            wpc: status.wpc != 0,
+            special_effect: sensor.special_effect(),
+            colorbar: status.colorbar != 0,
+            grab_mode: pipeline.grab_mode(),
+            active_grab_mode: pipeline.effective_grab_mode(),
+            fb_count: config.fb_count,
+            fb_location: fb_location_name(config.fb_location),
+            driver_grab_mode: grab_mode_name(config.grab_mode),
+        }
+    }
+}
+
+// Every field is optional, only the ones present get changed
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraSettingsUpdate {
+    // These three re-initialise the camera driver, done before anything else
+    pixformat: Option<String>,
+    fb_count: Option<usize>,
+    driver_grab_mode: Option<String>,
+    framesize: Option<String>,
+    quality: Option<i32>,
+    encoder_quality: Option<u8>,
+    brightness: Option<i32>,
+    contrast: Option<i32>,
+    saturation: Option<i32>,
+    sharpness: Option<i32>,
+    aec: Option<bool>,
+    aec2: Option<bool>,
+    ae_level: Option<i32>,
+    aec_value: Option<i32>,
+    agc: Option<bool>,
+    agc_gain: Option<i32>,
+    gainceiling: Option<u32>,
+    awb: Option<bool>,
+    awb_gain: Option<bool>,
+    wb_mode: Option<WhiteBalanceMode>,
+    denoise: Option<i32>,
+    lenc: Option<bool>,
+    bpc: Option<bool>,
+    wpc: Option<bool>,
+    special_effect: Option<SpecialEffect>,
+    vflip: Option<bool>,
+    hmirror: Option<bool>,
+    colorbar: Option<bool>,
+    grab_mode: Option<GrabMode>,
+}
+
+impl CameraSettingsUpdate {
+    // Hands back the sensor, which is a new one if the driver was re-initialised
+    fn apply(&self, pipeline: &mut Pipeline, cam: &mut Camera) -> Result<Sensor> {
+        if self.pixformat.is_some() || self.fb_count.is_some() || self.driver_grab_mode.is_some() {
+            let mut config = CameraConfig::current(&Sensor::get()?);
+            if let Some(name) = &self.pixformat {
+                config.pixel_format = pixformat_from_name(name)
+                    .ok_or_else(|| Invalid(format!("Unknown pixel format '{}'", name)))?;
+            }
+            if let Some(fb_count) = self.fb_count {
+                config.fb_count = fb_count;
+            }
+            if let Some(name) = &self.driver_grab_mode {
+                config.grab_mode = grab_mode_from_name(name)?;
+            }
+            cam.reconfigure(&config)?;
+        }
+
+        let sensor = Sensor::get()?;
+        SourceUpdate {
+            framesize: self.framesize.clone(),
+            quality: self.quality,
+            vflip: self.vflip,
+            hmirror: self.hmirror,
+        }
+        .apply(&*cam)?;
+        if let Some(quality) = self.encoder_quality {
+            if !(1..=100).contains(&quality) {
+                bail!(Invalid("encoder_quality must be between 1 and 100".into()));
+            }
+            pipeline.set_quality(quality);
+        }
+        if let Some(brightness) = self.brightness {
+            sensor.set_brightness(adjustment("brightness", brightness)?)?;
+        }
+        if let Some(contrast) = self.contrast {
+            sensor.set_contrast(adjustment("contrast", contrast)?)?;
+        }
+        if let Some(saturation) = self.saturation {
+            sensor.set_saturation(adjustment("saturation", saturation)?)?;
+        }
+        if let Some(sharpness) = self.sharpness {
+            sensor.set_sharpness(adjustment("sharpness", sharpness)?)?;
+        }
+        if let Some(aec) = self.aec {
+            sensor.set_exposure_ctrl(aec)?;
+        }
+        if let Some(aec2) = self.aec2 {
+            sensor.set_aec2(aec2)?;
+        }
+        if let Some(ae_level) = self.ae_level {
+            sensor.set_ae_level(adjustment("ae_level", ae_level)?)?;
+        }
+        if let Some(aec_value) = self.aec_value {
+            if !(0..=1200).contains(&aec_value) {
+                bail!(Invalid("aec_value must be between 0 and 1200".into()));
+            }
+            sensor.set_aec_value(aec_value)?;
+        }
+        if let Some(agc) = self.agc {
+            sensor.set_gain_ctrl(agc)?;
+        }
+        if let Some(agc_gain) = self.agc_gain {
+            if !(0..=30).contains(&agc_gain) {
+                bail!(Invalid("agc_gain must be between 0 and 30".into()));
+            }
+            sensor.set_agc_gain(agc_gain)?;
+        }
+        if let Some(gainceiling) = self.gainceiling {
+            if gainceiling > 6 {
+                bail!(Invalid("gainceiling must be between 0 and 6".into()));
+            }
+            sensor.set_gainceiling(gainceiling)?;
+        }
+        if let Some(awb) = self.awb {
+            sensor.set_whitebal(awb)?;
+        }
+        if let Some(awb_gain) = self.awb_gain {
+            sensor.set_awb_gain(awb_gain)?;
+        }
+        if let Some(mode) = self.wb_mode {
+            sensor.set_wb_mode(mode)?;
+        }
+        if let Some(denoise) = self.denoise {
+            if !(0..=8).contains(&denoise) {
+                bail!(Invalid("denoise must be between 0 and 8".into()));
+            }
+            sensor.set_denoise(denoise)?;
+        }
+        if let Some(lenc) = self.lenc {
+            sensor.set_lenc(lenc)?;
+        }
+        if let Some(bpc) = self.bpc {
+            sensor.set_bpc(bpc)?;
+        }
+        if let Some(wpc) = self.wpc {
+            sensor.set_wpc(wpc)?;
+        }
+        if let Some(effect) = self.special_effect {
+            sensor.set_special_effect(effect)?;
+        }
+        if let Some(colorbar) = self.colorbar {
+            sensor.set_colorbar(colorbar)?;
+        }
+        if let Some(grab_mode) = self.grab_mode {
+            pipeline.set_grab_mode(grab_mode);
+        }
+        Ok(sensor)
+    }
+}
+
+// The sensor's image adjustments all run -2 to 2
+fn adjustment(name: &str, value: i32) -> Result<i32> {
+    if !(-2..=2).contains(&value) {
+        bail!(Invalid(format!("{} must be between -2 and 2", name)));
+    }
+    Ok(value)
+}
+
+// Reads the register when `value` is left out, writes it otherwise
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterAccess {
+    bank: u8,
+    reg: u8,
+    value: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct Register {
+    bank: u8,
+    reg: u8,
+    value: u8,
+}
+
+impl RegisterAccess {
+    fn apply(&self, sensor: &Sensor) -> Result<Register> {
+        if let Some(value) = self.value {
+            sensor.write_register(self.bank, self.reg, value)?;
+        }
+        let value = sensor.read_register((self.bank as u16) << 8 | self.reg as u16)?;
+        Ok(Register {
+            bank: self.bank,
+            reg: self.reg,
+            value,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Power {
+    asleep: bool,
+}
+
+#[derive(Serialize)]
+struct WifiStatus {
+    rssi: i8,
+    ip: String,
+}
+
+impl WifiStatus {
+    // None while we're not associated with an AP
+    fn read() -> Option<Self> {
+        let mut record: wifi_ap_record_t = Default::default();
+        if esp!(unsafe { esp_wifi_sta_get_ap_info(&mut record) }).is_err() {
+            return None;
+        }
+
+        let netif = unsafe { esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr() as _) };
+        let mut ip_info: esp_netif_ip_info_t = Default::default();
+        if netif.is_null() || esp!(unsafe { esp_netif_get_ip_info(netif, &mut ip_info) }).is_err() {
+            return None;
+        }
+
+        Some(Self {
+            rssi: record.rssi,
+            // Stored in network order
+            ip: Ipv4Addr::from(ip_info.ip.addr.to_le_bytes()).to_string(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceStatus {
+    uptime_s: u64,
+    free_heap: u32,
+    free_psram: usize,
+    wifi: Option<WifiStatus>,
+    reset_reason: String,
+    camera: CameraSettings,
+    frames: PipelineStats,
+    capture: CaptureCounters,
+    // None on sensors we can't read it back from
+    exposure: Option<Exposure>,
+    // None without night mode
+    night_mode: Option<Mode>,
+}
+
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    night: Option<NightMode>,
+    reset_reason: ResetReason,
+) -> Result<()> {
+    let status_cam = cam.clone();
+    let status_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/status",
+        Method::Get,
+        auth.protect(move |request| {
+            let (frames, camera, exposure) = match lock_for_capture(&status_pipeline, &status_cam) {
+                Ok((pipeline, _cam)) => {
+                    let sensor = Sensor::get()?;
+                    (
+                        pipeline.stats(),
+                        CameraSettings::read(&pipeline, &sensor),
+                        sensor.exposure().ok(),
+                    )
+                }
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            let status = DeviceStatus {
+                uptime_s: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+                free_heap: unsafe { esp_get_free_heap_size() },
+                free_psram: unsafe { heap_caps_get_free_size(MALLOC_CAP_SPIRAM) },
+                wifi: WifiStatus::read(),
+                reset_reason: format!("{:?}", reset_reason),
+                camera,
+                frames,
+                capture: capture_counters(),
+                exposure,
+                night_mode: night.as_ref().map(NightMode::mode),
+            };
+            write_json(request, 200, &status)
+        }),
+    )?;
+
+    // For sensor tweaks the settings don't cover. Nothing stops a write from breaking the
+    // camera until the next reboot, so it needs credentials configured.
+    let register_cam = cam.clone();
+    let register_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/register",
+        Method::Post,
+        auth.require(move |mut request| {
+            let body = match read_body(&mut request, 128) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let access: RegisterAccess = match serde_json::from_slice(&body) {
+                Ok(access) => access,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let result = lock_for_capture(&register_pipeline, &register_cam)
+                .and_then(|(_pipeline, _cam)| access.apply(&Sensor::get()?));
+            match result {
+                Ok(register) => write_json(request, 200, &register),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    // Power cycles the sensor and brings the driver back up with the settings it had, for a
+    // camera that has stopped delivering frames
+    let restart_cam = cam.clone();
+    let restart_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/camera/restart",
+        Method::Post,
+        auth.protect(move |request| {
+            let result = lock_for_capture(&restart_pipeline, &restart_cam).and_then(
+                |(pipeline, mut cam)| {
+                    cam.restart()?;
+                    Ok(CameraSettings::read(&pipeline, &Sensor::get()?))
+                },
+            );
+            match result {
+                Ok(settings) => write_json(request, 200, &settings),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    // Sleeping cuts the sensor's power draw between captures, which fail until it's woken. That
+    // takes the camera off the air, so unlike waking it needs credentials configured.
+    let sleep_cam = cam.clone();
+    let sleep_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/camera/sleep",
+        Method::Post,
+        auth.require(move |request| {
+            let result =
+                lock_for_capture(&sleep_pipeline, &sleep_cam).and_then(|(_, mut cam)| cam.sleep());
+            match result {
+                Ok(()) => write_json(request, 200, &Power { asleep: true }),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    let wake_cam = cam.clone();
+    let wake_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/camera/wake",
+        Method::Post,
+        auth.protect(move |request| {
+            let result =
+                lock_for_capture(&wake_pipeline, &wake_cam).and_then(|(_, mut cam)| cam.wake());
+            match result {
+                Ok(()) => write_json(request, 200, &Power { asleep: false }),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    let get_cam = cam.clone();
+    let get_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/api/camera",
+        Method::Get,
+        auth.protect(move |request| {
+            let result = lock_for_capture(&get_pipeline, &get_cam)
+                .and_then(|(pipeline, _cam)| Ok(CameraSettings::read(&pipeline, &Sensor::get()?)));
+            match result {
+                Ok(settings) => write_json(request, 200, &settings),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    server.fn_handler(
+        "/api/camera",
+        Method::Post,
+        auth.protect(move |mut request| {
+            let body = match read_body(&mut request, 1024) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let update: CameraSettingsUpdate = match serde_json::from_slice(&body) {
+                Ok(update) => update,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let result = match lock_for_capture(&pipeline, &cam) {
+                Ok((mut pipeline, mut cam)) => update
+                    .apply(&mut pipeline, &mut cam)
+                    .map(|sensor| CameraSettings::read(&pipeline, &sensor)),
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            // Only what the client got wrong is a 400, the sensor failing to take a setting is ours
+            match result {
+                Ok(settings) => write_json(request, 200, &settings),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    // Both of these only with HTTP credentials or an API key configured, otherwise anyone on
+    // the network could keep the camera down
+    server.fn_handler(
+        "/api/reboot",
+        Method::Post,
+        auth.require(|request| {
+            warn!("Rebooting on request");
+            write_json(
+                request,
+                200,
+                &Restarting {
+                    factory_reset: false,
+                },
+            )?;
+            restart();
+        }),
+    )?;
+
+    // Forgets the WiFi network, credentials, API key and frame key along with everything else
+    // in NVS, so the next boot is the setup access point unless cfg.toml has a network
+    server.fn_handler(
+        "/api/factory-reset",
+        Method::Post,
+        auth.require(|request| {
+            warn!("Factory reset on request, erasing NVS");
+            if let Err(e) = esp!(unsafe { nvs_flash_erase() }) {
+                return ApiError::new(500, "erase_failed", format!("Erasing NVS failed: {:?}", e))
+                    .send(request);
+            }
+            write_json(
+                request,
+                200,
+                &Restarting {
+                    factory_reset: true,
+                },
+            )?;
+            restart();
+        }),
+    )?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Restarting {
+    factory_reset: bool,
+}
+
+// Gives the response a moment to make it out before the network goes away
+fn restart() -> ! {
+    FreeRtos::delay_ms(1000);
+    reset::restart();
+}
@@ -0,0 +1,479 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use embedded_svc::http::server::Connection;
+use esp_idf_svc::{
+    http::server::{EspHttpConnection, HandlerResult, Request},
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+};
+use log::{info, warn};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::http::{peer_ip, query_param, ApiError};
+use crate::lock::lock;
+use crate::rate_limit::RateLimiter;
+use crate::request_log::{LoggedRequest, RequestLog};
+
+const NVS_NAMESPACE: &str = "auth";
+const REALM: &str = "tigercam";
+
+// Digest nonces are only honoured for this long before the client is told they're stale
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+// Every challenge hands out a nonce, cap how many we remember so nobody can eat the heap
+const MAX_NONCES: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuthMode {
+    Basic,
+    Digest,
+}
+
+impl FromStr for AuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "basic" => Ok(Self::Basic),
+            "digest" => Ok(Self::Digest),
+            other => bail!("Unknown HTTP auth mode '{}'", other),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    user: String,
+    pass: String,
+    // MD5(user:realm:pass), the only form of the password digest auth needs
+    ha1: String,
+}
+
+struct Nonce {
+    value: String,
+    issued: Instant,
+    // Highest nonce count accepted so far, anything at or below it is a replay
+    last_count: u32,
+}
+
+// How much a wrapped handler asks of the request before it runs
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Gate {
+    // Rate limited and logged only
+    Open,
+    // Authorized, with nothing configured that's anyone on the network
+    Protect,
+    // Authorized against credentials or an API key that have to be configured
+    Require,
+}
+
+// Guards the camera endpoints, with no username or API key configured everything is
+// allowed through, unless the camera is `exposed`
+#[derive(Clone)]
+pub struct Auth {
+    mode: AuthMode,
+    credentials: Option<Credentials>,
+    nonces: Arc<Mutex<Vec<Nonce>>>,
+    api_key: Arc<Mutex<Option<String>>>,
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+    log: RequestLog,
+    limiter: Option<RateLimiter>,
+    // Set while the fallback access point is up, anyone near enough could be on it
+    exposed: Arc<AtomicBool>,
+}
+
+impl Auth {
+    // Credentials stored in NVS take priority over the ones baked in at build time
+    pub fn load(
+        nvs: EspDefaultNvsPartition,
+        mode: AuthMode,
+        user: &str,
+        pass: &str,
+        api_key: &str,
+    ) -> Result<Self> {
+        let nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+
+        let mut user_buf = [0u8; 64];
+        let mut pass_buf = [0u8; 64];
+        let (user, pass) = match nvs.get_str("user", &mut user_buf)? {
+            Some(nvs_user) => {
+                info!("Using HTTP credentials from NVS");
+                (
+                    nvs_user.to_string(),
+                    nvs.get_str("pass", &mut pass_buf)?
+                        .unwrap_or("")
+                        .to_string(),
+                )
+            }
+            None => (user.to_string(), pass.to_string()),
+        };
+
+        let credentials = if user.is_empty() {
+            info!("HTTP authentication is disabled");
+            None
+        } else {
+            info!("HTTP authentication mode is {:?}", mode);
+            let ha1 = md5_hex(&format!("{}:{}:{}", user, REALM, pass));
+            Some(Credentials { user, pass, ha1 })
+        };
+
+        let mut key_buf = [0u8; 128];
+        let api_key = nvs
+            .get_str("api_key", &mut key_buf)?
+            .unwrap_or(api_key)
+            .to_string();
+        let api_key = (!api_key.is_empty()).then_some(api_key);
+
+        Ok(Self {
+            mode,
+            credentials,
+            nonces: Arc::new(Mutex::new(Vec::new())),
+            api_key: Arc::new(Mutex::new(api_key)),
+            nvs: Arc::new(Mutex::new(nvs)),
+            log: RequestLog::new(0),
+            limiter: None,
+            exposed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Every protected request ends up in `log`, set it before handing clones out
+    pub fn with_request_log(mut self, log: RequestLog) -> Self {
+        self.log = log;
+        self
+    }
+
+    pub fn request_log(&self) -> &RequestLog {
+        &self.log
+    }
+
+    // Checked before the credentials, so guessing passwords is throttled too
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    // Wraps a handler so it only runs once the request has been authorized and isn't over
+    // its client's rate limit, and so the request gets logged either way
+    pub fn protect<F>(
+        &self,
+        handler: F,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    where
+        F: for<'x, 'b, 'r> Fn(LoggedRequest<'x, 'b, 'r>) -> HandlerResult + Send + 'static,
+    {
+        self.gate(Gate::Protect, handler)
+    }
+
+    // Like `protect`, but refused outright unless credentials or an API key are configured.
+    // For whatever can take the camera over or off the air.
+    pub fn require<F>(
+        &self,
+        handler: F,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    where
+        F: for<'x, 'b, 'r> Fn(LoggedRequest<'x, 'b, 'r>) -> HandlerResult + Send + 'static,
+    {
+        self.gate(Gate::Require, handler)
+    }
+
+    // Rate limited and logged but open to anyone, even while exposed. Only for pages that
+    // show nothing and change nothing, like the /setup form.
+    pub fn open<F>(
+        &self,
+        handler: F,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    where
+        F: for<'x, 'b, 'r> Fn(LoggedRequest<'x, 'b, 'r>) -> HandlerResult + Send + 'static,
+    {
+        self.gate(Gate::Open, handler)
+    }
+
+    fn gate<F>(
+        &self,
+        gate: Gate,
+        handler: F,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    where
+        F: for<'x, 'b, 'r> Fn(LoggedRequest<'x, 'b, 'r>) -> HandlerResult + Send + 'static,
+    {
+        let auth = self.clone();
+        self.log.wrap(move |mut request: LoggedRequest| {
+            if let Some(limiter) = &auth.limiter {
+                if let Some(wait) = peer_ip(&mut request).and_then(|ip| limiter.check(ip).err()) {
+                    return ApiError::new(429, "rate_limited", "Too many requests")
+                        .retry_after((wait.as_secs_f32().ceil() as u32).max(1))
+                        .send(request);
+                }
+            }
+            if gate == Gate::Open {
+                return handler(request);
+            }
+            if !auth.is_configured() && (gate == Gate::Require || auth.is_exposed()) {
+                return ApiError::new(
+                    403,
+                    "auth_not_configured",
+                    "Configure HTTP credentials or an API key first",
+                )
+                .send(request);
+            }
+            if !auth.allows(&request) {
+                return auth.challenge(request);
+            }
+            handler(request)
+        })
+    }
+
+    // True if there's anything to check requests against
+    pub fn is_configured(&self) -> bool {
+        self.credentials.is_some() || lock(&self.api_key).is_some()
+    }
+
+    // While exposed, protected handlers are refused unless auth is configured rather than
+    // let through to anyone
+    pub fn set_exposed(&self, exposed: bool) {
+        let was = self.exposed.swap(exposed, Ordering::Relaxed);
+        if exposed && !was && !self.is_configured() {
+            warn!("No HTTP credentials or API key configured, refusing requests while exposed");
+        }
+    }
+
+    pub fn is_exposed(&self) -> bool {
+        self.exposed.load(Ordering::Relaxed)
+    }
+
+    // Replaces the API key used by automation clients, an empty key turns it off
+    pub fn set_api_key(&self, key: &str) -> Result<()> {
+        let mut nvs = lock(&self.nvs);
+        if key.is_empty() {
+            nvs.remove("api_key")?;
+            *lock(&self.api_key) = None;
+            info!("API key removed");
+        } else {
+            nvs.set_str("api_key", key)?;
+            *lock(&self.api_key) = Some(key.to_string());
+            info!("API key updated");
+        }
+        Ok(())
+    }
+
+    pub fn allows<C: Connection>(&self, request: &Request<C>) -> bool {
+        let api_key = lock(&self.api_key).clone();
+        if self.credentials.is_none() && api_key.is_none() {
+            return true;
+        }
+
+        if let Some(key) = &api_key {
+            let presented = request
+                .header("X-Api-Key")
+                .or_else(|| query_param(request.uri(), "key"));
+            if presented.map_or(false, |p| constant_time_eq(p.as_bytes(), key.as_bytes())) {
+                return true;
+            }
+        }
+
+        let (Some(credentials), Some(header)) =
+            (&self.credentials, request.header("Authorization"))
+        else {
+            return false;
+        };
+
+        match self.mode {
+            AuthMode::Basic => header
+                .strip_prefix("Basic ")
+                .map_or(false, |encoded| check_basic(credentials, encoded)),
+            AuthMode::Digest => header.strip_prefix("Digest ").map_or(false, |params| {
+                self.check_digest(credentials, &method_name(request), request.uri(), params)
+            }),
+        }
+    }
+
+    // Sends the 401 that makes browsers prompt for a username and password
+    pub fn challenge<C: Connection>(&self, request: Request<C>) -> HandlerResult {
+        let error = ApiError::new(401, "unauthorized", "Unauthorized");
+        if self.credentials.is_none() {
+            // API key only, there's nothing a browser could prompt for
+            return error.send(request);
+        }
+
+        let header = match self.mode {
+            AuthMode::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM),
+            AuthMode::Digest => {
+                // A nonce we no longer know about means the password may well be fine,
+                // stale=true lets the client retry without asking the user again
+                let stale = request
+                    .header("Authorization")
+                    .and_then(|h| h.strip_prefix("Digest "))
+                    .and_then(|params| param(&parse_params(params), "nonce").map(str::to_string))
+                    .map_or(false, |nonce| !self.nonce_is_live(&nonce));
+
+                format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                    REALM,
+                    self.issue_nonce(),
+                    if stale { ", stale=true" } else { "" }
+                )
+            }
+        };
+
+        error.send_with_headers(request, &[("WWW-Authenticate", &header)])
+    }
+
+    fn issue_nonce(&self) -> String {
+        let value = (0..4)
+            .map(|_| format!("{:08x}", unsafe { esp_idf_svc::sys::esp_random() }))
+            .collect::<String>();
+
+        let mut nonces = lock(&self.nonces);
+        nonces.retain(|n| n.issued.elapsed() < NONCE_LIFETIME);
+        if nonces.len() >= MAX_NONCES {
+            nonces.remove(0);
+        }
+        nonces.push(Nonce {
+            value: value.clone(),
+            issued: Instant::now(),
+            last_count: 0,
+        });
+
+        value
+    }
+
+    fn nonce_is_live(&self, nonce: &str) -> bool {
+        lock(&self.nonces)
+            .iter()
+            .any(|n| n.value == nonce && n.issued.elapsed() < NONCE_LIFETIME)
+    }
+
+    fn check_digest(
+        &self,
+        credentials: &Credentials,
+        method: &str,
+        request_uri: &str,
+        params: &str,
+    ) -> bool {
+        let params = parse_params(params);
+        let (
+            Some(user),
+            Some(realm),
+            Some(nonce),
+            Some(uri),
+            Some(response),
+            Some("auth"),
+            Some(nc),
+            Some(cnonce),
+        ) = (
+            param(&params, "username"),
+            param(&params, "realm"),
+            param(&params, "nonce"),
+            param(&params, "uri"),
+            param(&params, "response"),
+            param(&params, "qop"),
+            param(&params, "nc"),
+            param(&params, "cnonce"),
+        )
+        else {
+            return false;
+        };
+
+        if user != credentials.user || realm != REALM || uri != request_uri {
+            return false;
+        }
+        let Ok(count) = u32::from_str_radix(nc, 16) else {
+            return false;
+        };
+
+        let mut nonces = lock(&self.nonces);
+        let Some(entry) = nonces
+            .iter_mut()
+            .find(|n| n.value == nonce && n.issued.elapsed() < NONCE_LIFETIME)
+        else {
+            return false;
+        };
+        if count <= entry.last_count {
+            return false;
+        }
+
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+        let expected = md5_hex(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            credentials.ha1, nonce, nc, cnonce, ha2
+        ));
+        if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+            return false;
+        }
+
+        entry.last_count = count;
+        true
+    }
+}
+
+fn check_basic(credentials: &Credentials, encoded: &str) -> bool {
+    let Ok(decoded) = STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let Ok(decoded) = std::str::from_utf8(&decoded) else {
+        return false;
+    };
+
+    match decoded.split_once(':') {
+        Some((user, pass)) => {
+            // Don't short circuit so both halves take the same time to check
+            constant_time_eq(user.as_bytes(), credentials.user.as_bytes())
+                & constant_time_eq(pass.as_bytes(), credentials.pass.as_bytes())
+        }
+        None => false,
+    }
+}
+
+fn method_name<C: Connection>(request: &Request<C>) -> String {
+    format!("{:?}", request.method()).to_ascii_uppercase()
+}
+
+// Splits `key=value, key="quoted, value"` pairs from an Authorization header
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut rest = params.trim();
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (value, remainder) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match after.find(',') {
+                Some(end) => (after[..end].trim_end(), &after[end..]),
+                None => (after.trim_end(), ""),
+            },
+        };
+
+        result.push((key, value.to_string()));
+        rest = remainder.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    result
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn md5_hex(input: &str) -> String {
+    format!("{:x}", md5::compute(input))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{http::server::EspHttpServer, io::Write};
+use log::warn;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::http::{query_param, write_all_yielding, ApiError};
+use crate::pipeline::{lock_for_capture, Pipeline};
+
+const BOUNDARY: &str = "burst";
+const MAX_COUNT: u32 = 20;
+const MAX_INTERVAL: Duration = Duration::from_secs(10);
+
+// `/burst?count=5&interval_ms=200`, count defaults to 5 and the interval to as fast as the
+// camera goes
+fn parse(uri: &str) -> Result<(u32, Duration)> {
+    let count = match query_param(uri, "count") {
+        Some(count) => count.parse()?,
+        None => 5,
+    };
+    if !(1..=MAX_COUNT).contains(&count) {
+        bail!("count must be between 1 and {}", MAX_COUNT);
+    }
+
+    let interval = match query_param(uri, "interval_ms") {
+        Some(ms) => Duration::from_millis(ms.parse()?),
+        None => Duration::ZERO,
+    };
+    if interval > MAX_INTERVAL {
+        bail!("interval_ms must be at most {}", MAX_INTERVAL.as_millis());
+    }
+
+    Ok((count, interval))
+}
+
+// Sends `count` frames `interval` apart as one multipart/mixed response. Each part goes out as
+// soon as it's captured so only one frame is ever held in memory, and the camera is free for
+// other requests in between.
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+) -> Result<()> {
+    server.fn_handler(
+        "/burst",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let (count, interval) = match parse(request.uri()) {
+                Ok(params) => params,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let capture = || {
+                lock_for_capture(&pipeline, &cam).and_then(|(mut pipeline, cam)| pipeline.run(&*cam))
+            };
+
+            // Failing on the first frame still gets the client a proper error
+            let started = Instant::now();
+            let mut jpeg = match capture() {
+                Ok(jpeg) => jpeg,
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            let content_type = format!("multipart/mixed; boundary={}", BOUNDARY);
+            let mut response =
+                request.into_response(200, None, &[("Content-Type", &content_type)])?;
+
+            for index in 0..count {
+                if index > 0 {
+                    if let Some(left) = (interval * index).checked_sub(started.elapsed()) {
+                        thread::sleep(left);
+                    }
+                    jpeg = match capture() {
+                        Ok(jpeg) => jpeg,
+                        Err(e) => {
+                            // Too late for a status code, end the body early instead
+                            warn!("Burst capture {} of {} failed: {:?}", index + 1, count, e);
+                            break;
+                        }
+                    };
+                }
+
+                let part = format!(
+                    "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Offset-Ms: {}\r\n\r\n",
+                    BOUNDARY,
+                    jpeg.len(),
+                    started.elapsed().as_millis()
+                );
+                response.write_all(part.as_bytes())?;
+                write_all_yielding(&mut response, &jpeg)?;
+                response.write_all(b"\r\n")?;
+            }
+            write!(response, "--{}--\r\n", BOUNDARY)?;
+
+            Ok(())
+        }),
+    )?;
+
+    Ok(())
+}
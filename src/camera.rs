@@ -0,0 +1,1664 @@
+use esp_camera_rs::Camera as Driver;
+use esp_idf_svc::hal::{
+    delay::FreeRtos,
+    gpio::{AnyIOPin, AnyInputPin, AnyOutputPin, InputPin, OutputPin, Pin},
+    peripheral::{Peripheral, PeripheralRef},
+};
+use esp_idf_svc::sys::{
+    cam::{
+        camera_config_t, camera_config_t__bindgen_ty_1, camera_config_t__bindgen_ty_2,
+        camera_fb_location_t, camera_fb_location_t_CAMERA_FB_IN_DRAM,
+        camera_fb_location_t_CAMERA_FB_IN_PSRAM, camera_fb_t, camera_grab_mode_t,
+        camera_grab_mode_t_CAMERA_GRAB_LATEST, camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,
+        esp_camera_deinit, esp_camera_fb_get, esp_camera_fb_return, esp_camera_init, fmt2bmp,
+        fmt2jpg, fmt2rgb888, framesize_t, framesize_t_FRAMESIZE_QVGA, framesize_t_FRAMESIZE_UXGA,
+        jpg2rgb565, jpg_scale_t, pixformat_t, pixformat_t_PIXFORMAT_GRAYSCALE,
+        pixformat_t_PIXFORMAT_JPEG, pixformat_t_PIXFORMAT_RGB565, pixformat_t_PIXFORMAT_RGB888,
+        pixformat_t_PIXFORMAT_YUV422,
+    },
+    esp, esp_timer_get_time, free, gpio_mode_t_GPIO_MODE_OUTPUT, gpio_set_direction,
+    gpio_set_level, heap_caps_get_largest_free_block, heap_caps_get_total_size,
+    ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_timer_pause, ledc_timer_resume,
+    ledc_timer_t_LEDC_TIMER_0, EspError, MALLOC_CAP_8BIT, MALLOC_CAP_SPIRAM,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr,
+    ptr::NonNull,
+    slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::lock::lock;
+use crate::pipeline::Frame;
+use crate::sensor::{
+    framesize_dimensions, framesize_name, framesize_pixels, framesizes_largest_first,
+    pixformat_name, Sensor, SensorInfo, Unsupported,
+};
+use crate::source::RawFrame;
+
+pub type Result<T, E = CameraError> = std::result::Result<T, E>;
+
+// The driver's handle, wrapped so `CameraExt` and tigercam-core's `FrameSource` can be
+// implemented on it. Dropping it takes the driver down.
+pub struct Camera {
+    _driver: Driver,
+}
+
+#[derive(Debug)]
+pub enum CameraError {
+    // The driver wouldn't come up or go down
+    InitFailed(EspError),
+    // The driver had no frame for us, usually transient
+    NoFrame,
+    // A converter or encoder gave up on a frame
+    ConversionFailed(String),
+    // Nothing from the driver in time, the sensor has probably stopped
+    Timeout,
+    // Powered down with `CameraExt::sleep`
+    Asleep,
+    // No sensor to talk to, or it has stopped answering
+    NoSensor(String),
+    // The sensor can't do what was asked of it
+    SensorUnsupported(String),
+    // Settings the driver can't use
+    InvalidConfig(String),
+    // The thread doing timed fetches couldn't be started, is gone, or is stuck in the driver
+    FetchFailed(String),
+    // Any other ESP-IDF call, like driving PWDN or pausing XCLK
+    Esp(EspError),
+    // The frame didn't fit in the caller's buffer, this many bytes would have
+    BufferTooSmall(usize),
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CameraError::InitFailed(e) => write!(f, "Camera driver failed: {}", e),
+            CameraError::NoFrame => write!(f, "Unable to get framebuffer"),
+            CameraError::ConversionFailed(reason) => write!(f, "Conversion failed: {}", reason),
+            CameraError::Timeout => write!(f, "Timed out waiting for a frame"),
+            CameraError::Asleep => write!(f, "Camera is asleep"),
+            CameraError::NoSensor(reason) => write!(f, "{}", reason),
+            CameraError::SensorUnsupported(reason) => write!(f, "{}", reason),
+            CameraError::InvalidConfig(reason) => write!(f, "{}", reason),
+            CameraError::FetchFailed(reason) => write!(f, "{}", reason),
+            CameraError::Esp(e) => write!(f, "{}", e),
+            CameraError::BufferTooSmall(needed) => {
+                write!(f, "Frame needs a {} byte buffer", needed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CameraError {}
+
+impl From<EspError> for CameraError {
+    fn from(e: EspError) -> Self {
+        CameraError::Esp(e)
+    }
+}
+
+// The sensor code still speaks anyhow. What it fails at is either something the sensor's
+// driver doesn't have, or getting through to the sensor at all.
+fn sensor_error(e: anyhow::Error) -> CameraError {
+    match e.downcast::<CameraError>() {
+        Ok(e) => e,
+        Err(e) if e.is::<Unsupported>() => CameraError::SensorUnsupported(format!("{:#}", e)),
+        Err(e) => CameraError::NoSensor(format!("{:#}", e)),
+    }
+}
+
+fn current_sensor() -> Result<Sensor> {
+    Sensor::get().map_err(sensor_error)
+}
+
+fn invalid(reason: impl Into<String>) -> CameraError {
+    CameraError::InvalidConfig(reason.into())
+}
+
+const XCLK_HZ: i32 = 20_000_000;
+
+// How long `try_capture` waits for a frame. Even UXGA comes in well under a second, so this
+// only runs out when the sensor has stopped sending.
+pub const CAPTURE_TIMEOUT: Duration = Duration::from_secs(3);
+// The driver gives up on a frame after 4 seconds, so a fetch left behind is done by then
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the sensor is held powered down, and then given to wake up again
+const POWER_CYCLE_MS: u32 = 50;
+// Tries at bringing the driver up, with a power cycle before each retry
+const INIT_ATTEMPTS: u32 = 3;
+// Long enough after waking for the sensor to be sending whole frames again
+const WAKE_MS: u32 = 100;
+// The most a frame buffer gets out of internal RAM on boards without PSRAM, leaving the rest
+// for WiFi and the HTTP server
+const DRAM_FRAME_BUDGET: usize = 64 * 1024;
+
+// Set by `CameraExt::sleep`, captures fail straight away rather than waiting out a timeout
+static ASLEEP: AtomicBool = AtomicBool::new(false);
+
+// GPIO numbers for the camera bus, -1 where the board doesn't wire a pin up
+#[derive(Clone, Copy, Debug)]
+pub struct CameraPins {
+    pub pwdn: i32,
+    pub reset: i32,
+    pub xclk: i32,
+    // D0 to D7
+    pub data: [i32; 8],
+    pub vsync: i32,
+    pub href: i32,
+    pub pclk: i32,
+    pub sda: i32,
+    pub scl: i32,
+}
+
+impl CameraPins {
+    // Whether the camera bus has `pin`, -1 is never on it
+    pub fn uses(&self, pin: i32) -> bool {
+        let bus = [
+            self.pwdn, self.reset, self.xclk, self.vsync, self.href, self.pclk, self.sda, self.scl,
+        ];
+        pin >= 0 && (bus.contains(&pin) || self.data.contains(&pin))
+    }
+}
+
+// Boards we know the camera wiring of
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BoardPreset {
+    AiThinker,
+    M5StackTimerCam,
+    TtgoTCamera,
+    FreenoveWrover,
+    // Needs a build for the ESP32-S3
+    Esp32S3Eye,
+}
+
+impl BoardPreset {
+    pub const fn pins(self) -> CameraPins {
+        match self {
+            Self::AiThinker => CameraPins {
+                pwdn: 32,
+                reset: -1,
+                xclk: 0,
+                data: [5, 18, 19, 21, 36, 39, 34, 35],
+                vsync: 25,
+                href: 23,
+                pclk: 22,
+                sda: 26,
+                scl: 27,
+            },
+            Self::M5StackTimerCam => CameraPins {
+                pwdn: -1,
+                reset: 15,
+                xclk: 27,
+                data: [32, 35, 34, 5, 39, 18, 36, 19],
+                vsync: 22,
+                href: 26,
+                pclk: 21,
+                sda: 25,
+                scl: 23,
+            },
+            Self::TtgoTCamera => CameraPins {
+                pwdn: 26,
+                reset: -1,
+                xclk: 32,
+                data: [5, 14, 4, 15, 18, 23, 36, 39],
+                vsync: 27,
+                href: 25,
+                pclk: 19,
+                sda: 13,
+                scl: 12,
+            },
+            Self::FreenoveWrover => CameraPins {
+                pwdn: -1,
+                reset: -1,
+                xclk: 21,
+                data: [4, 5, 18, 19, 36, 39, 34, 35],
+                vsync: 25,
+                href: 23,
+                pclk: 22,
+                sda: 26,
+                scl: 27,
+            },
+            Self::Esp32S3Eye => CameraPins {
+                pwdn: -1,
+                reset: -1,
+                xclk: 15,
+                data: [11, 9, 8, 10, 12, 18, 17, 16],
+                vsync: 6,
+                href: 7,
+                pclk: 13,
+                sda: 4,
+                scl: 5,
+            },
+        }
+    }
+}
+
+impl FromStr for BoardPreset {
+    type Err = CameraError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ai_thinker" => Ok(Self::AiThinker),
+            "m5stack_timer_cam" => Ok(Self::M5StackTimerCam),
+            "ttgo_t_camera" => Ok(Self::TtgoTCamera),
+            "freenove_wrover" => Ok(Self::FreenoveWrover),
+            "esp32_s3_eye" => Ok(Self::Esp32S3Eye),
+            other => Err(invalid(format!("Unknown board '{}'", other))),
+        }
+    }
+}
+
+// What the driver was last brought up with, `reconfigure` needs them again
+static ACTIVE_PINS: Mutex<CameraPins> = Mutex::new(BoardPreset::AiThinker.pins());
+// The sensor can't tell us how many frame buffers there are or where they live, and it can't
+// tell us anything once it has stopped answering
+static ACTIVE_CONFIG: Mutex<CameraConfig> = Mutex::new(CameraConfig::DRIVER_DEFAULT);
+
+// What can change when the driver is brought back up, the pins stay as they are
+#[derive(Clone, Copy, Debug)]
+pub struct CameraConfig {
+    pub pixel_format: pixformat_t,
+    pub frame_size: framesize_t,
+    // The sensor's own scale, 0-63 and lower is better
+    pub jpeg_quality: i32,
+    pub fb_count: usize,
+    pub fb_location: camera_fb_location_t,
+    // How the driver itself fills its buffers. WhenEmpty stops capturing once they're full,
+    // so a frame can sit there for ages. Latest keeps overwriting the oldest, which costs
+    // DMA and PSRAM bandwidth all the time but keeps them fresh. Separate from the
+    // pipeline's `GrabMode`, which throws a frame away when it wants a newer one.
+    pub grab_mode: camera_grab_mode_t,
+}
+
+impl CameraConfig {
+    // What `Camera::new` sets up
+    pub const DRIVER_DEFAULT: Self = Self {
+        pixel_format: pixformat_t_PIXFORMAT_JPEG,
+        frame_size: framesize_t_FRAMESIZE_UXGA,
+        jpeg_quality: 12,
+        fb_count: 2,
+        fb_location: camera_fb_location_t_CAMERA_FB_IN_PSRAM,
+        grab_mode: camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,
+    };
+
+    // What the driver is running with now, to go back to if a change doesn't take
+    pub fn current(sensor: &Sensor) -> Self {
+        let status = sensor.status();
+        Self {
+            pixel_format: sensor.pixformat(),
+            frame_size: status.framesize,
+            jpeg_quality: status.quality as i32,
+            ..*lock(&ACTIVE_CONFIG)
+        }
+    }
+
+    // For computer vision rather than viewing: small grayscale frames in internal RAM, which
+    // is quicker for the CPU to get through than PSRAM. One buffer leaves room for the rest.
+    pub fn new_grayscale_qvga() -> Self {
+        Self {
+            pixel_format: pixformat_t_PIXFORMAT_GRAYSCALE,
+            frame_size: framesize_t_FRAMESIZE_QVGA,
+            jpeg_quality: 12,
+            fb_count: 1,
+            fb_location: camera_fb_location_t_CAMERA_FB_IN_DRAM,
+            grab_mode: camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,
+        }
+    }
+
+    // Without PSRAM the buffers have to come out of internal RAM, so this moves them there
+    // and steps the frame size down until one fits. Configs that don't ask for PSRAM, or
+    // boards that have it, come back unchanged.
+    pub fn fit_memory(&self) -> Result<Self> {
+        if self.fb_location != camera_fb_location_t_CAMERA_FB_IN_PSRAM || psram_available() {
+            return Ok(*self);
+        }
+
+        let mut fitted = Self {
+            fb_count: 1,
+            fb_location: camera_fb_location_t_CAMERA_FB_IN_DRAM,
+            grab_mode: camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,
+            ..*self
+        };
+        // Going by pixels, not the enum's numbering. Nothing bigger than what was asked for,
+        // unless that's a size we don't know the dimensions of.
+        let asked = framesize_pixels(self.frame_size).unwrap_or(u32::MAX);
+        let size = framesizes_largest_first()
+            .into_iter()
+            .filter(|&size| framesize_pixels(size).is_some_and(|pixels| pixels <= asked))
+            .find(|&size| {
+                fitted.frame_size = size;
+                fitted
+                    .frame_bytes()
+                    .is_some_and(|bytes| bytes <= DRAM_FRAME_BUDGET)
+            });
+        if size.is_none() {
+            return Err(invalid(format!(
+                "No PSRAM found, and no {} frame fits in internal RAM",
+                pixformat_name(self.pixel_format)
+            )));
+        }
+
+        warn!(
+            "No PSRAM found, using one frame buffer in internal RAM at {} instead of {}",
+            framesize_name(fitted.frame_size),
+            framesize_name(self.frame_size)
+        );
+        Ok(fitted)
+    }
+
+    // Roughly what the driver allocates for each buffer, a fifth of a byte a pixel for JPEG
+    fn frame_bytes(&self) -> Option<usize> {
+        let (width, height) = framesize_dimensions(self.frame_size)?;
+        let pixels = width as usize * height as usize;
+        match self.pixel_format {
+            pixformat_t_PIXFORMAT_JPEG => Some(pixels / 5),
+            pixformat_t_PIXFORMAT_GRAYSCALE => Some(pixels),
+            pixformat_t_PIXFORMAT_RGB565 | pixformat_t_PIXFORMAT_YUV422 => Some(pixels * 2),
+            pixformat_t_PIXFORMAT_RGB888 => Some(pixels * 3),
+            _ => None,
+        }
+    }
+
+    // Turns down combinations the driver would take but not do anything useful with
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=3).contains(&self.fb_count) {
+            return Err(invalid("fb_count must be between 1 and 3"));
+        }
+        // With one buffer the driver has nothing to overwrite, so Latest still waits for a
+        // capture to be taken like WhenEmpty does
+        if self.grab_mode == camera_grab_mode_t_CAMERA_GRAB_LATEST && self.fb_count < 2 {
+            return Err(invalid(
+                "The latest grab mode needs at least two frame buffers",
+            ));
+        }
+        if self.fb_location == camera_fb_location_t_CAMERA_FB_IN_DRAM && self.fb_count > 1 {
+            warn!(
+                "{} frame buffers in internal RAM may not fit",
+                self.fb_count
+            );
+        }
+        Ok(())
+    }
+
+    fn init(&self, pins: &CameraPins) -> Result<(), EspError> {
+        let config = camera_config_t {
+            pin_pwdn: pins.pwdn,
+            pin_reset: pins.reset,
+            pin_xclk: pins.xclk,
+            __bindgen_anon_1: camera_config_t__bindgen_ty_1 {
+                pin_sccb_sda: pins.sda,
+            },
+            __bindgen_anon_2: camera_config_t__bindgen_ty_2 {
+                pin_sccb_scl: pins.scl,
+            },
+            pin_d0: pins.data[0],
+            pin_d1: pins.data[1],
+            pin_d2: pins.data[2],
+            pin_d3: pins.data[3],
+            pin_d4: pins.data[4],
+            pin_d5: pins.data[5],
+            pin_d6: pins.data[6],
+            pin_d7: pins.data[7],
+            pin_vsync: pins.vsync,
+            pin_href: pins.href,
+            pin_pclk: pins.pclk,
+            xclk_freq_hz: XCLK_HZ,
+            pixel_format: self.pixel_format,
+            frame_size: self.frame_size,
+            jpeg_quality: self.jpeg_quality,
+            fb_count: self.fb_count,
+            fb_location: self.fb_location,
+            grab_mode: self.grab_mode,
+            // LEDC timer and channel 0 for XCLK, like the first init
+            ..Default::default()
+        };
+        esp!(unsafe { esp_camera_init(&config) })?;
+        // A fresh init starts XCLK and powers the sensor up again
+        ASLEEP.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// Whether the board has PSRAM and ESP-IDF brought it up
+pub fn psram_available() -> bool {
+    unsafe { heap_caps_get_total_size(MALLOC_CAP_SPIRAM) > 0 }
+}
+
+pub fn fb_location_name(location: camera_fb_location_t) -> &'static str {
+    match location {
+        camera_fb_location_t_CAMERA_FB_IN_PSRAM => "psram",
+        camera_fb_location_t_CAMERA_FB_IN_DRAM => "dram",
+        _ => "unknown",
+    }
+}
+
+pub fn grab_mode_name(mode: camera_grab_mode_t) -> &'static str {
+    match mode {
+        camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY => "when_empty",
+        camera_grab_mode_t_CAMERA_GRAB_LATEST => "latest",
+        _ => "unknown",
+    }
+}
+
+pub fn grab_mode_from_name(name: &str) -> Result<camera_grab_mode_t> {
+    [
+        camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY,
+        camera_grab_mode_t_CAMERA_GRAB_LATEST,
+    ]
+    .into_iter()
+    .find(|mode| grab_mode_name(*mode).eq_ignore_ascii_case(name))
+    .ok_or_else(|| invalid(format!("Unknown driver grab mode '{}'", name)))
+}
+
+// Cuts the sensor's power with PWDN, or pulses RESET on boards without one. Only while the
+// driver is down, it owns these pins otherwise.
+fn power_cycle(pins: &CameraPins) -> Result<()> {
+    // PWDN powers the sensor down while high, RESET holds it in reset while low
+    let (pin, off, on) = match (pins.pwdn, pins.reset) {
+        (pwdn, _) if pwdn >= 0 => (pwdn, 1, 0),
+        (_, reset) if reset >= 0 => (reset, 0, 1),
+        _ => {
+            warn!("Board has neither PWDN nor RESET wired up, can't power cycle the sensor");
+            return Ok(());
+        }
+    };
+
+    esp!(unsafe { gpio_set_direction(pin, gpio_mode_t_GPIO_MODE_OUTPUT) })?;
+    esp!(unsafe { gpio_set_level(pin, off) })?;
+    FreeRtos::delay_ms(POWER_CYCLE_MS);
+    esp!(unsafe { gpio_set_level(pin, on) })?;
+    FreeRtos::delay_ms(POWER_CYCLE_MS);
+    Ok(())
+}
+
+pub trait CameraExt {
+    // Hands `f` the next frame as JPEG without copying it anywhere. JPEG from the sensor is
+    // passed straight out of the driver's buffer, anything else is converted and the driver
+    // gets its buffer back before `f` runs.
+    fn with_jpeg<R>(&self, quality: u8, f: impl FnOnce(&[u8]) -> R) -> Result<R>;
+
+    // Takes the driver down and brings it back up with `config`, for changes the sensor can't
+    // make on the fly like switching between JPEG and RGB565. If that fails the previous
+    // config is put back so the camera keeps working. Orientation survives, the other sensor
+    // settings go back to the driver's defaults.
+    fn reconfigure(&mut self, config: &CameraConfig) -> Result<()>;
+
+    // Takes the driver down. Captures fail until `restart` brings it back, dropping the
+    // camera afterwards is fine.
+    fn deinit(&mut self) -> Result<()>;
+
+    // Takes the driver down if it's up, power cycles the sensor and brings it back with the
+    // config it had, retrying a couple of times. For a sensor that has stopped answering or
+    // never showed up.
+    fn restart(&mut self) -> Result<()>;
+
+    // Powers the sensor down with PWDN and stops XCLK, which saves most of what the camera
+    // draws while keeping the driver and the sensor's settings. Captures fail with
+    // `CameraError::Asleep` until `wake`.
+    fn sleep(&mut self) -> Result<()>;
+
+    fn wake(&mut self) -> Result<()>;
+
+    fn is_asleep(&self) -> bool;
+
+    // Which sensor the driver found. Some settings only exist on some of them.
+    fn sensor_info(&self) -> Result<SensorInfo>;
+
+    // The next frame as 24 bit pixels whatever the sensor produces, decoding JPEG if need be.
+    // The converter writes them blue first.
+    fn capture_rgb888(&self) -> Result<Frame>;
+
+    // The next frame straight from the driver, one byte per pixel. The camera has to be in
+    // grayscale already, see `CameraConfig::new_grayscale_qvga`.
+    fn capture_gray(&self) -> Result<FrameBuffer<'_>>;
+
+    // The next frame as JPEG copied into `buf`, returning its length. For callers that keep
+    // their buffers around rather than allocating one for every frame. A frame that doesn't
+    // fit is dropped with `CameraError::BufferTooSmall` saying how big it was.
+    fn capture_jpeg_into(&self, quality: u8, buf: &mut [u8]) -> Result<usize>;
+
+    // The next frame as JPEG, or `CameraError::Timeout` if none turned up in time
+    fn capture_jpeg_timeout(&self, quality: u8, timeout: Duration) -> Result<Vec<u8>>;
+
+    // The next frame, giving up after `CAPTURE_TIMEOUT` rather than hanging on a wedged
+    // sensor
+    fn try_capture(&self) -> Result<FrameBuffer<'_>>;
+}
+
+impl CameraExt for Camera {
+    fn with_jpeg<R>(&self, quality: u8, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        let fb = self.try_capture()?;
+        if fb.format() == pixformat_t_PIXFORMAT_JPEG {
+            return Ok(f(fb.data()));
+        }
+
+        let jpeg = fb.to_jpeg(quality)?;
+        drop(fb);
+        Ok(f(jpeg.data()))
+    }
+
+    fn reconfigure(&mut self, config: &CameraConfig) -> Result<()> {
+        let config = &config.fit_memory()?;
+        config.validate()?;
+        Fetcher::settle()?;
+
+        let pins = *lock(&ACTIVE_PINS);
+        let sensor = current_sensor()?;
+        let previous = CameraConfig::current(&sensor);
+        let status = sensor.status();
+        drop(sensor);
+
+        esp!(unsafe { esp_camera_deinit() }).map_err(CameraError::InitFailed)?;
+
+        let result = config.init(&pins);
+        if let Err(e) = &result {
+            warn!(
+                "Camera init with {} at {} failed: {}, going back to the previous config",
+                pixformat_name(config.pixel_format),
+                framesize_name(config.frame_size),
+                e
+            );
+            if let Err(restore) = previous.init(&pins) {
+                warn!(
+                    "Going back to {} at {} failed too: {}",
+                    pixformat_name(previous.pixel_format),
+                    framesize_name(previous.frame_size),
+                    restore
+                );
+                return Err(CameraError::InitFailed(restore));
+            }
+        }
+
+        let sensor = current_sensor()?;
+        sensor.set_vflip(status.vflip != 0).map_err(sensor_error)?;
+        sensor
+            .set_hmirror(status.hmirror != 0)
+            .map_err(sensor_error)?;
+
+        if result.is_ok() {
+            *lock(&ACTIVE_CONFIG) = *config;
+            info!(
+                "Camera reconfigured for {} at {}, {} buffers in {} grabbing {}",
+                pixformat_name(config.pixel_format),
+                framesize_name(config.frame_size),
+                config.fb_count,
+                fb_location_name(config.fb_location),
+                grab_mode_name(config.grab_mode)
+            );
+        }
+        result.map_err(CameraError::InitFailed)
+    }
+
+    fn deinit(&mut self) -> Result<()> {
+        Fetcher::settle()?;
+        // Whatever the sensor was set to is the config to come back with
+        if let Ok(sensor) = Sensor::get() {
+            *lock(&ACTIVE_CONFIG) = CameraConfig::current(&sensor);
+        }
+        esp!(unsafe { esp_camera_deinit() }).map_err(CameraError::InitFailed)?;
+        info!("Camera driver is down");
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        let orientation = Sensor::get()
+            .map(|sensor| {
+                let status = sensor.status();
+                (status.vflip != 0, status.hmirror != 0)
+            })
+            .ok();
+        if let Err(e) = self.deinit() {
+            // Most likely never came up in the first place
+            warn!("Taking the camera driver down failed: {:?}", e);
+        }
+
+        let pins = *lock(&ACTIVE_PINS);
+        let config = *lock(&ACTIVE_CONFIG);
+        let mut attempt = 1;
+        loop {
+            power_cycle(&pins)?;
+            match config.init(&pins) {
+                Ok(()) => break,
+                Err(e) if attempt < INIT_ATTEMPTS => {
+                    warn!("Camera init attempt {} failed: {}", attempt, e);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    warn!("Camera didn't come back after {} tries", attempt);
+                    return Err(CameraError::InitFailed(e));
+                }
+            }
+        }
+
+        if let Some((vflip, hmirror)) = orientation {
+            let sensor = current_sensor()?;
+            sensor.set_vflip(vflip).map_err(sensor_error)?;
+            sensor.set_hmirror(hmirror).map_err(sensor_error)?;
+        }
+        info!("Camera restarted after {} tries", attempt);
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        if self.is_asleep() {
+            return Ok(());
+        }
+        Fetcher::settle()?;
+
+        // Captures check this before touching the driver, so set it before anything stops
+        ASLEEP.store(true, Ordering::Relaxed);
+        let pwdn = lock(&ACTIVE_PINS).pwdn;
+        if pwdn >= 0 {
+            esp!(unsafe { gpio_set_level(pwdn, 1) })?;
+        }
+        // The driver runs XCLK off LEDC timer 0, see `CameraConfig::init`
+        esp!(unsafe {
+            ledc_timer_pause(ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_timer_t_LEDC_TIMER_0)
+        })?;
+        info!("Camera is asleep");
+        Ok(())
+    }
+
+    fn wake(&mut self) -> Result<()> {
+        if !self.is_asleep() {
+            return Ok(());
+        }
+
+        esp!(unsafe {
+            ledc_timer_resume(ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_timer_t_LEDC_TIMER_0)
+        })?;
+        let pwdn = lock(&ACTIVE_PINS).pwdn;
+        if pwdn >= 0 {
+            esp!(unsafe { gpio_set_level(pwdn, 0) })?;
+        }
+        FreeRtos::delay_ms(WAKE_MS);
+        ASLEEP.store(false, Ordering::Relaxed);
+        info!("Camera is awake");
+        Ok(())
+    }
+
+    fn is_asleep(&self) -> bool {
+        ASLEEP.load(Ordering::Relaxed)
+    }
+
+    fn sensor_info(&self) -> Result<SensorInfo> {
+        Ok(current_sensor()?.info())
+    }
+
+    fn capture_gray(&self) -> Result<FrameBuffer<'_>> {
+        let fb = self.try_capture()?;
+        if fb.format() != pixformat_t_PIXFORMAT_GRAYSCALE {
+            return Err(CameraError::SensorUnsupported(format!(
+                "Camera is capturing {}, not grayscale",
+                pixformat_name(fb.format())
+            )));
+        }
+        Ok(fb)
+    }
+
+    fn capture_rgb888(&self) -> Result<Frame> {
+        let fb = self.try_capture()?;
+        if fb.format() == pixformat_t_PIXFORMAT_RGB888 {
+            return Ok(fb.to_frame());
+        }
+        fb.to_rgb888()
+    }
+
+    fn capture_jpeg_into(&self, quality: u8, buf: &mut [u8]) -> Result<usize> {
+        self.with_jpeg(quality, |jpeg| {
+            let out = buf
+                .get_mut(..jpeg.len())
+                .ok_or(CameraError::BufferTooSmall(jpeg.len()))?;
+            out.copy_from_slice(jpeg);
+            Ok(jpeg.len())
+        })?
+    }
+
+    fn capture_jpeg_timeout(&self, quality: u8, timeout: Duration) -> Result<Vec<u8>> {
+        let fb = FrameBuffer::get_timeout(self, timeout)?;
+        if fb.format() == pixformat_t_PIXFORMAT_JPEG {
+            return Ok(fb.data().to_vec());
+        }
+
+        let jpeg = fb.to_jpeg(quality)?;
+        drop(fb);
+        Ok(jpeg.data().to_vec())
+    }
+
+    fn try_capture(&self) -> Result<FrameBuffer<'_>> {
+        FrameBuffer::get_timeout(self, CAPTURE_TIMEOUT)
+    }
+}
+
+// esp_camera_fb_get takes no timeout, so fetches that need one are done on a thread of their
+// own while the caller waits on the reply. A fetch left behind by a timeout is still pending
+// in the driver, the next caller waits on it instead of queueing another but only keeps its
+// frame if it was started after the caller asked.
+struct Fetcher {
+    requests: SyncSender<()>,
+    // Buffer pointers, null when the driver gave up
+    frames: Receiver<usize>,
+    outstanding: bool,
+    // The outstanding fetch is one a timeout gave up on
+    left_behind: bool,
+    // Woken by the fetch thread when a frame comes in, for `FrameBuffer::next`
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+static FETCHER: Mutex<Option<Fetcher>> = Mutex::new(None);
+
+impl Fetcher {
+    // Runs `f` with the fetcher once a fetch is on its way
+    fn with<R>(f: impl FnOnce(&mut Fetcher) -> Result<R>) -> Result<R> {
+        let mut fetcher = lock(&FETCHER);
+        let fetcher = match &mut *fetcher {
+            Some(fetcher) => fetcher,
+            None => fetcher.insert(Fetcher::spawn()?),
+        };
+
+        fetcher.request()?;
+        f(fetcher)
+    }
+
+    fn request(&mut self) -> Result<()> {
+        if !self.outstanding {
+            self.requests.send(()).map_err(|_| gone())?;
+            self.outstanding = true;
+        }
+        Ok(())
+    }
+
+    // Waits out a fetch left behind by a timeout. Tearing the driver down under it would have
+    // it return a buffer into freed memory.
+    fn settle() -> Result<()> {
+        let mut fetcher = lock(&FETCHER);
+        let Some(fetcher) = fetcher.as_mut().filter(|fetcher| fetcher.outstanding) else {
+            return Ok(());
+        };
+        match fetcher.frames.recv_timeout(SETTLE_TIMEOUT) {
+            Ok(fb) => {
+                if fetcher.received(fb).is_ok() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Err(_) => Err(CameraError::FetchFailed(
+                "Camera is stuck on a capture".into(),
+            )),
+        }
+    }
+
+    fn received<'cam>(&mut self, fb: usize) -> Result<FrameBuffer<'cam>> {
+        self.outstanding = false;
+        self.left_behind = false;
+        FrameBuffer::wrap(fb as *mut camera_fb_t).ok_or(CameraError::NoFrame)
+    }
+
+    fn spawn() -> Result<Self> {
+        let (requests, pending) = mpsc::sync_channel(1);
+        let (done, frames) = mpsc::sync_channel(1);
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let wake = waker.clone();
+        thread::Builder::new()
+            .name("fb_get".into())
+            .stack_size(3072)
+            .spawn(move || {
+                for () in pending {
+                    let fb = unsafe { esp_camera_fb_get() };
+                    if done.send(fb as usize).is_err() {
+                        break;
+                    }
+                    if let Some(waker) = lock(&wake).take() {
+                        waker.wake();
+                    }
+                }
+            })
+            .map_err(|e| {
+                CameraError::FetchFailed(format!("Can't start the fetch thread: {}", e))
+            })?;
+
+        Ok(Self {
+            requests,
+            frames,
+            outstanding: false,
+            left_behind: false,
+            waker,
+        })
+    }
+}
+
+fn gone() -> CameraError {
+    CameraError::FetchFailed("Frame fetch thread is gone".into())
+}
+
+pub struct NextFrame<'cam> {
+    // When it was asked for, on the driver's clock
+    asked: Duration,
+    done: bool,
+    _cam: PhantomData<&'cam Camera>,
+}
+
+impl<'cam> Future for NextFrame<'cam> {
+    type Output = Result<FrameBuffer<'cam>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if ASLEEP.load(Ordering::Relaxed) {
+            self.done = true;
+            return Poll::Ready(Err(CameraError::Asleep));
+        }
+        let asked = self.asked;
+        let result = Fetcher::with(|fetcher| loop {
+            // Registered before looking so a frame landing in between still wakes us
+            *lock(&fetcher.waker) = Some(cx.waker().clone());
+            match fetcher.frames.try_recv() {
+                // Same as `FrameBuffer::get_timeout`, a fetch left behind by a timeout can
+                // bring back a frame from before this was asked for
+                Ok(fb) => match (fetcher.left_behind, fetcher.received(fb)) {
+                    (true, Ok(fb)) if fb.timestamp() < asked => {
+                        drop(fb);
+                        DROPPED.fetch_add(1, Ordering::Relaxed);
+                        fetcher.request()?;
+                    }
+                    (_, received) => return received.map(Some),
+                },
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(gone()),
+            }
+        });
+        match result {
+            Ok(None) => Poll::Pending,
+            Ok(Some(fb)) => {
+                self.done = true;
+                Poll::Ready(Ok(fb))
+            }
+            Err(e) => {
+                self.done = true;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for NextFrame<'_> {
+    // Given up on before the frame came in, its fetch is left behind like a timed out one's
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(fetcher) = lock(&FETCHER).as_mut().filter(|f| f.outstanding) {
+            fetcher.left_behind = true;
+            lock(&fetcher.waker).take();
+        }
+    }
+}
+
+// `CameraExt::capture_jpeg_timeout` for async code, yielding to the executor until the fetch
+// thread has the frame. Keep the camera locked across the await so nothing reconfigures it
+// underneath.
+pub async fn capture_jpeg_async(cam: &Camera, quality: u8) -> Result<Vec<u8>> {
+    let fb = FrameBuffer::next(cam).await?;
+    if fb.format() == pixformat_t_PIXFORMAT_JPEG {
+        return Ok(fb.data().to_vec());
+    }
+
+    let jpeg = fb.to_jpeg(quality)?;
+    drop(fb);
+    Ok(jpeg.data().to_vec())
+}
+
+// Every frame the driver has handed out, whether or not anything used it
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+// Frames fetched only to be thrown away, and fetches the driver gave up on
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static MISSED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Serialize)]
+pub struct CaptureCounters {
+    // Sequence number of the newest frame
+    pub sequence: u32,
+    pub dropped: u32,
+    pub missed: u32,
+}
+
+pub fn capture_counters() -> CaptureCounters {
+    CaptureCounters {
+        sequence: SEQUENCE.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        missed: MISSED.load(Ordering::Relaxed),
+    }
+}
+
+// A frame on loan from the camera driver, handed back when this is dropped. Borrowing the
+// camera keeps it from being torn down while the buffer is still out.
+pub struct FrameBuffer<'cam> {
+    fb: NonNull<camera_fb_t>,
+    sequence: u32,
+    _cam: PhantomData<&'cam Camera>,
+}
+
+impl<'cam> FrameBuffer<'cam> {
+    // Blocks until the driver has a frame, None if it gave up waiting for one
+    pub fn get(_cam: &'cam Camera) -> Option<Self> {
+        Self::wrap(unsafe { esp_camera_fb_get() })
+    }
+
+    // Like `get`, but fails with `CameraError::Timeout` instead of waiting past `timeout`
+    pub fn get_timeout(_cam: &'cam Camera, timeout: Duration) -> Result<Self> {
+        if ASLEEP.load(Ordering::Relaxed) {
+            return Err(CameraError::Asleep);
+        }
+        // Same clock as the driver's timestamps
+        let asked = Duration::from_micros(unsafe { esp_timer_get_time() } as u64);
+        let deadline = Instant::now() + timeout;
+        Fetcher::with(|fetcher| loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match fetcher.frames.recv_timeout(left) {
+                // What a fetch left behind by an earlier timeout brings back can be from
+                // well before this was asked for, that one goes back for a newer frame
+                Ok(fb) => match (fetcher.left_behind, fetcher.received(fb)) {
+                    (true, Ok(fb)) if fb.timestamp() < asked => {
+                        drop(fb);
+                        DROPPED.fetch_add(1, Ordering::Relaxed);
+                        fetcher.request()?;
+                    }
+                    (_, received) => return received,
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!("No frame from the camera in {:?}", timeout);
+                    fetcher.left_behind = true;
+                    return Err(CameraError::Timeout);
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(gone()),
+            }
+        })
+    }
+
+    // The next frame without blocking the executor while the driver works on it. Only one
+    // task at a time can be waiting on this.
+    pub fn next(_cam: &'cam Camera) -> NextFrame<'cam> {
+        NextFrame {
+            asked: Duration::from_micros(unsafe { esp_timer_get_time() } as u64),
+            done: false,
+            _cam: PhantomData,
+        }
+    }
+
+    fn wrap(fb: *mut camera_fb_t) -> Option<Self> {
+        let Some(fb) = NonNull::new(fb) else {
+            MISSED.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        Some(Self {
+            fb,
+            sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed).wrapping_add(1),
+            _cam: PhantomData,
+        })
+    }
+
+    // Takes a frame and hands it straight back, for when the driver may be holding one
+    // that's stale
+    pub fn discard(cam: &Camera) {
+        if FrameBuffer::get(cam).is_some() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Counts up by one per frame taken from the driver, so a gap between two frames a client
+    // sees is how many it missed
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    fn raw(&self) -> &camera_fb_t {
+        unsafe { self.fb.as_ref() }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        let raw = self.raw();
+        unsafe { slice::from_raw_parts(raw.buf, raw.len) }
+    }
+
+    pub fn width(&self) -> usize {
+        self.raw().width
+    }
+
+    pub fn height(&self) -> usize {
+        self.raw().height
+    }
+
+    pub fn format(&self) -> pixformat_t {
+        self.raw().format
+    }
+
+    // A copy in our own memory, so the driver can have its buffer back
+    pub fn to_frame(&self) -> Frame {
+        Frame::from_raw(&self.as_raw())
+    }
+
+    // Borrowing the driver's buffer, for code written against `FrameSource`
+    pub fn as_raw(&self) -> RawFrame<'_> {
+        RawFrame {
+            data: self.data(),
+            width: self.width(),
+            height: self.height(),
+            format: self.format(),
+            timestamp: self.timestamp(),
+        }
+    }
+
+    pub fn to_rgb888(&self) -> Result<Frame> {
+        Ok(Frame {
+            data: to_rgb888(self.data(), self.width(), self.height(), self.format())?,
+            width: self.width(),
+            height: self.height(),
+            format: pixformat_t_PIXFORMAT_RGB888,
+            timestamp: self.timestamp(),
+        })
+    }
+
+    // JPEG frames are decoded at an eighth of their size for it first, which is a lot quicker
+    // and still has more pixels than the histogram needs
+    pub fn histogram(&self) -> Result<Histogram> {
+        if self.format() != pixformat_t_PIXFORMAT_JPEG {
+            return histogram(self.data(), self.width(), self.height(), self.format());
+        }
+        let (rgb, width, height) = self.decode_eighth()?;
+        histogram(&rgb, width, height, pixformat_t_PIXFORMAT_RGB565)
+    }
+
+    // Luma at an eighth of the frame's width and height
+    pub fn thumbnail(&self) -> Result<Thumbnail> {
+        if self.format() != pixformat_t_PIXFORMAT_JPEG {
+            return thumbnail(self.data(), self.width(), self.height(), self.format(), 8);
+        }
+        let (rgb, width, height) = self.decode_eighth()?;
+        thumbnail(&rgb, width, height, pixformat_t_PIXFORMAT_RGB565, 1)
+    }
+
+    // Luma at no more than `max_width` wide, full size when the frame already fits
+    pub fn grayscale(&self, max_width: usize) -> Result<Thumbnail> {
+        if self.format() != pixformat_t_PIXFORMAT_JPEG {
+            let step = (self.width() + max_width.max(1) - 1) / max_width.max(1);
+            return thumbnail(
+                self.data(),
+                self.width(),
+                self.height(),
+                self.format(),
+                step,
+            );
+        }
+        let rgb = self.rgb565_within(max_width)?;
+        thumbnail(
+            &rgb.data,
+            rgb.width,
+            rgb.height,
+            pixformat_t_PIXFORMAT_RGB565,
+            1,
+        )
+    }
+
+    // RGB565 at no more than `max_width` wide. JPEG frames are decoded at a half, quarter or
+    // eighth of their size for it, RGB565 ones are copied as they are.
+    pub fn rgb565_within(&self, max_width: usize) -> Result<Frame> {
+        let (data, width, height) = match self.format() {
+            pixformat_t_PIXFORMAT_JPEG => {
+                let scale = (0..3)
+                    .find(|scale| self.width() >> scale <= max_width)
+                    .unwrap_or(3);
+                self.decode_scaled(scale)?
+            }
+            pixformat_t_PIXFORMAT_RGB565 => (self.data().to_vec(), self.width(), self.height()),
+            _ => {
+                return Err(CameraError::ConversionFailed(
+                    "only JPEG and RGB565 frames can be scaled".into(),
+                ))
+            }
+        };
+        Ok(Frame {
+            data,
+            width,
+            height,
+            format: pixformat_t_PIXFORMAT_RGB565,
+            timestamp: self.timestamp(),
+        })
+    }
+
+    // RGB565 at an eighth of the size, the decoder can scale for free while it goes
+    fn decode_eighth(&self) -> Result<(Vec<u8>, usize, usize)> {
+        self.decode_scaled(3)
+    }
+
+    // Divided by 2^`scale` each way, 0-3
+    fn decode_scaled(&self, scale: u32) -> Result<(Vec<u8>, usize, usize)> {
+        let (width, height) = (self.width() >> scale, self.height() >> scale);
+        let mut rgb = vec![0; width * height * 2];
+        let ok = unsafe {
+            jpg2rgb565(
+                self.data().as_ptr(),
+                self.data().len(),
+                rgb.as_mut_ptr(),
+                scale as jpg_scale_t,
+            )
+        };
+        if !ok {
+            return Err(CameraError::ConversionFailed("jpg2rgb565 failed".into()));
+        }
+        Ok((rgb, width, height))
+    }
+
+    pub fn to_jpeg(&self, quality: u8) -> Result<Converted> {
+        Converted::jpeg(
+            self.data(),
+            self.width(),
+            self.height(),
+            self.format(),
+            quality,
+        )
+    }
+
+    // When the driver received the first byte of the frame, since boot
+    pub fn timestamp(&self) -> Duration {
+        let timestamp = self.raw().timestamp;
+        Duration::from_secs(timestamp.tv_sec as u64)
+            + Duration::from_micros(timestamp.tv_usec as u64)
+    }
+}
+
+impl Drop for FrameBuffer<'_> {
+    fn drop(&mut self) {
+        unsafe { esp_camera_fb_return(self.fb.as_ptr()) }
+    }
+}
+
+// 24 bit pixels, blue first, from any format the converters know including JPEG. A full
+// UXGA frame is nearly 6MB of them, so running out of memory is an error rather than an abort.
+pub fn to_rgb888(data: &[u8], width: usize, height: usize, format: pixformat_t) -> Result<Vec<u8>> {
+    let len = width * height * 3;
+    let mut out = Vec::new();
+    if out.try_reserve_exact(len).is_err() {
+        return Err(CameraError::ConversionFailed(format!(
+            "no memory for a {}x{} RGB888 frame",
+            width, height
+        )));
+    }
+    out.resize(len, 0);
+    let ok = unsafe { fmt2rgb888(data.as_ptr(), data.len(), format, out.as_mut_ptr()) };
+    if !ok {
+        return Err(CameraError::ConversionFailed("fmt2rgb888 failed".into()));
+    }
+    Ok(out)
+}
+
+// Whether there's a block free big enough to decode a `width` x `height` frame to RGB888
+pub fn rgb888_fits(width: usize, height: usize) -> bool {
+    unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) >= width * height * 3 }
+}
+
+// Every pixel of a grayscale frame counts, colour frames are sampled on a grid this many
+// pixels apart which is plenty for judging exposure
+const HISTOGRAM_STEP: usize = 4;
+
+// How many sampled pixels fell on each luma value
+#[derive(Clone, Debug, Serialize)]
+pub struct Histogram {
+    #[serde(with = "serde_bins")]
+    pub bins: [u32; 256],
+    pub samples: u32,
+}
+
+// serde only knows arrays up to 32 long
+mod serde_bins {
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(bins: &[u32; 256], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(bins)
+    }
+}
+
+impl Histogram {
+    // Average luma, 0 for an empty histogram
+    pub fn mean(&self) -> u8 {
+        if self.samples == 0 {
+            return 0;
+        }
+        let sum: u64 = (0u64..)
+            .zip(self.bins.iter())
+            .map(|(luma, &count)| luma * count as u64)
+            .sum();
+        (sum / self.samples as u64) as u8
+    }
+
+    // The luma value `percent` of the samples are at or below
+    pub fn percentile(&self, percent: u8) -> u8 {
+        let target = (self.samples as u64 * percent.min(100) as u64 + 99) / 100;
+        let mut seen = 0;
+        for (luma, &count) in self.bins.iter().enumerate() {
+            seen += count as u64;
+            if seen >= target.max(1) {
+                return luma as u8;
+            }
+        }
+        255
+    }
+}
+
+// A luma histogram of a grayscale, RGB888, RGB565 or YUV422 frame. JPEG has to be decoded
+// with `to_rgb888` first.
+pub fn histogram(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    format: pixformat_t,
+) -> Result<Histogram> {
+    let bytes_per_pixel = luma_layout(data, width, height, format)?;
+    let step = if bytes_per_pixel == 1 {
+        1
+    } else {
+        HISTOGRAM_STEP
+    };
+
+    let mut histogram = Histogram {
+        bins: [0; 256],
+        samples: 0,
+    };
+    for y in (0..height).step_by(step) {
+        for x in (0..width).step_by(step) {
+            let pixel = &data[(y * width + x) * bytes_per_pixel..][..bytes_per_pixel];
+            histogram.bins[luma(pixel, format) as usize] += 1;
+            histogram.samples += 1;
+        }
+    }
+    Ok(histogram)
+}
+
+// Just the luma of every `step`th pixel along and down, row by row
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    pub luma: Vec<u8>,
+}
+
+// Takes the same formats as `histogram`
+pub fn thumbnail(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    format: pixformat_t,
+    step: usize,
+) -> Result<Thumbnail> {
+    let bytes_per_pixel = luma_layout(data, width, height, format)?;
+    let step = step.max(1);
+    let (columns, rows) = (
+        (0..width).step_by(step).len(),
+        (0..height).step_by(step).len(),
+    );
+
+    let mut luma_values = Vec::with_capacity(columns * rows);
+    for y in (0..height).step_by(step) {
+        for x in (0..width).step_by(step) {
+            let pixel = &data[(y * width + x) * bytes_per_pixel..][..bytes_per_pixel];
+            luma_values.push(luma(pixel, format));
+        }
+    }
+    Ok(Thumbnail {
+        width: columns,
+        height: rows,
+        luma: luma_values,
+    })
+}
+
+// Bytes per pixel of a format we can read luma out of, checking the frame is all there
+fn luma_layout(data: &[u8], width: usize, height: usize, format: pixformat_t) -> Result<usize> {
+    let bytes_per_pixel = match format {
+        pixformat_t_PIXFORMAT_GRAYSCALE => 1,
+        pixformat_t_PIXFORMAT_RGB565 | pixformat_t_PIXFORMAT_YUV422 => 2,
+        pixformat_t_PIXFORMAT_RGB888 => 3,
+        _ => {
+            return Err(CameraError::ConversionFailed(format!(
+                "No luma from {} frames",
+                pixformat_name(format)
+            )))
+        }
+    };
+    if data.len() < width * height * bytes_per_pixel {
+        return Err(CameraError::ConversionFailed(format!(
+            "{} byte frame is too short for {}x{}",
+            data.len(),
+            width,
+            height
+        )));
+    }
+    Ok(bytes_per_pixel)
+}
+
+// BT.601 weights scaled by 256. RGB888 is blue first, RGB565 high byte first, and YUV422
+// carries luma in the first byte of every pixel.
+fn luma(pixel: &[u8], format: pixformat_t) -> u8 {
+    let (r, g, b) = match format {
+        pixformat_t_PIXFORMAT_RGB888 => (pixel[2], pixel[1], pixel[0]),
+        pixformat_t_PIXFORMAT_RGB565 => {
+            let rgb = u16::from_be_bytes([pixel[0], pixel[1]]);
+            (
+                (rgb >> 11 << 3) as u8,
+                (rgb >> 5 << 2) as u8,
+                (rgb << 3) as u8,
+            )
+        }
+        _ => return pixel[0],
+    };
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32) >> 8) as u8
+}
+
+// Output of the esp32-camera image converters, which malloc it. Freed when dropped.
+pub struct Converted {
+    buf: NonNull<u8>,
+    len: usize,
+}
+
+impl Converted {
+    pub fn jpeg(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        format: pixformat_t,
+        quality: u8,
+    ) -> Result<Self> {
+        let mut out = ptr::null_mut();
+        let mut out_len = 0;
+        let ok = unsafe {
+            fmt2jpg(
+                data.as_ptr() as *mut u8,
+                data.len(),
+                width as u16,
+                height as u16,
+                format,
+                quality,
+                &mut out,
+                &mut out_len,
+            )
+        };
+        Self::from_raw(ok, out, out_len, "fmt2jpg failed")
+    }
+
+    // Works from any format, JPEG gets decoded first
+    pub fn bmp(data: &[u8], width: usize, height: usize, format: pixformat_t) -> Result<Self> {
+        let mut out = ptr::null_mut();
+        let mut out_len = 0;
+        let ok = unsafe {
+            fmt2bmp(
+                data.as_ptr() as *mut u8,
+                data.len(),
+                width as u16,
+                height as u16,
+                format,
+                &mut out,
+                &mut out_len,
+            )
+        };
+        Self::from_raw(ok, out, out_len, "fmt2bmp failed")
+    }
+
+    fn from_raw(ok: bool, out: *mut u8, len: usize, failure: &str) -> Result<Self> {
+        match NonNull::new(out).filter(|_| ok) {
+            Some(buf) => Ok(Self { buf, len }),
+            None => {
+                if !out.is_null() {
+                    unsafe { free(out as *mut _) };
+                }
+                Err(CameraError::ConversionFailed(failure.into()))
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.buf.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Converted {
+    fn drop(&mut self) {
+        unsafe { free(self.buf.as_ptr() as *mut _) }
+    }
+}
+
+// Names every pin instead of `Camera::new`'s long positional list, which is easy to get out of
+// order. Borrowing the pins keeps anything else from using them while the camera has them.
+pub struct CameraBuilder<'d> {
+    pwdn: i32,
+    reset: i32,
+    xclk: Option<i32>,
+    data: [Option<i32>; 8],
+    vsync: Option<i32>,
+    href: Option<i32>,
+    pclk: Option<i32>,
+    sda: Option<i32>,
+    scl: Option<i32>,
+    config: Option<CameraConfig>,
+    _pins: PhantomData<&'d mut ()>,
+}
+
+impl<'d> Default for CameraBuilder<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> CameraBuilder<'d> {
+    pub fn new() -> Self {
+        Self {
+            pwdn: -1,
+            reset: -1,
+            xclk: None,
+            data: [None; 8],
+            vsync: None,
+            href: None,
+            pclk: None,
+            sda: None,
+            scl: None,
+            config: None,
+            _pins: PhantomData,
+        }
+    }
+
+    // Every pin from `preset`. Unsafe because nothing is borrowed, the caller has to make sure
+    // the pins aren't used for anything else.
+    pub unsafe fn for_board(preset: BoardPreset) -> Self {
+        let pins = preset.pins();
+        Self {
+            pwdn: pins.pwdn,
+            reset: pins.reset,
+            xclk: Some(pins.xclk),
+            data: pins.data.map(Some),
+            vsync: Some(pins.vsync),
+            href: Some(pins.href),
+            pclk: Some(pins.pclk),
+            sda: Some(pins.sda),
+            scl: Some(pins.scl),
+            config: None,
+            _pins: PhantomData,
+        }
+    }
+
+    // Not every board can power the sensor down or reset it
+    pub fn pwdn(mut self, pin: Option<PeripheralRef<'d, AnyOutputPin>>) -> Self {
+        self.pwdn = pin.map_or(-1, |pin| pin.pin());
+        self
+    }
+
+    pub fn reset(mut self, pin: Option<PeripheralRef<'d, AnyOutputPin>>) -> Self {
+        self.reset = pin.map_or(-1, |pin| pin.pin());
+        self
+    }
+
+    pub fn xclk(mut self, pin: impl Peripheral<P = impl OutputPin> + 'd) -> Self {
+        self.xclk = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn d0(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(0, pin)
+    }
+
+    pub fn d1(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(1, pin)
+    }
+
+    pub fn d2(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(2, pin)
+    }
+
+    pub fn d3(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(3, pin)
+    }
+
+    pub fn d4(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(4, pin)
+    }
+
+    pub fn d5(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(5, pin)
+    }
+
+    pub fn d6(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(6, pin)
+    }
+
+    pub fn d7(self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data_pin(7, pin)
+    }
+
+    fn data_pin(mut self, index: usize, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.data[index] = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn vsync(mut self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.vsync = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn href(mut self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.href = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn pclk(mut self, pin: impl Peripheral<P = impl InputPin> + 'd) -> Self {
+        self.pclk = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn sda(mut self, pin: impl Peripheral<P = impl InputPin + OutputPin> + 'd) -> Self {
+        self.sda = Some(pin.into_ref().pin());
+        self
+    }
+
+    pub fn scl(mut self, pin: impl Peripheral<P = impl InputPin + OutputPin> + 'd) -> Self {
+        self.scl = Some(pin.into_ref().pin());
+        self
+    }
+
+    // Pixel format, frame size and buffers, otherwise the driver's defaults are kept
+    pub fn config(mut self, config: CameraConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    fn pins(&self) -> Result<CameraPins> {
+        fn required(name: &str, pin: Option<i32>) -> Result<i32> {
+            pin.ok_or_else(|| invalid(format!("Camera pin {} is not set", name)))
+        }
+
+        let mut data = [0; 8];
+        for (index, pin) in self.data.iter().enumerate() {
+            data[index] = required(&format!("d{}", index), *pin)?;
+        }
+        Ok(CameraPins {
+            pwdn: self.pwdn,
+            reset: self.reset,
+            xclk: required("xclk", self.xclk)?,
+            data,
+            vsync: required("vsync", self.vsync)?,
+            href: required("href", self.href)?,
+            pclk: required("pclk", self.pclk)?,
+            sda: required("sda", self.sda)?,
+            scl: required("scl", self.scl)?,
+        })
+    }
+
+    pub fn build(self) -> Result<Camera> {
+        let pins = self.pins()?;
+
+        // The builder holds the borrows on these pins for as long as 'd, so making our own
+        // handles to them doesn't alias anything the caller can still touch
+        let optional = |pin: i32| (pin >= 0).then(|| unsafe { AnyIOPin::new(pin) }.into_ref());
+        let input = |pin: i32| unsafe { AnyInputPin::new(pin) };
+        // A sensor that browned out or latched up fails its probe, so power cycle it and try
+        // again before giving up on it
+        let mut attempt = 1;
+        let mut camera = loop {
+            // PWDN can't be left out, but -1 is how the driver spells no pin anyway
+            let result = Driver::new(
+                unsafe { AnyIOPin::new(pins.pwdn) },
+                optional(pins.reset),
+                unsafe { AnyIOPin::new(pins.xclk) },
+                input(pins.data[0]),
+                input(pins.data[1]),
+                input(pins.data[2]),
+                input(pins.data[3]),
+                input(pins.data[4]),
+                input(pins.data[5]),
+                input(pins.data[6]),
+                input(pins.data[7]),
+                input(pins.vsync),
+                input(pins.href),
+                input(pins.pclk),
+                optional(pins.sda),
+                optional(pins.scl),
+            );
+            match result {
+                Ok(driver) => break Camera { _driver: driver },
+                // Power cycling won't conjure up memory
+                Err(e) if !psram_available() => {
+                    return Err(invalid(format!(
+                        "Camera init failed: {}. No PSRAM was found, and the first init \
+                         always asks for UXGA frame buffers there.",
+                        e
+                    )));
+                }
+                Err(e) if attempt < INIT_ATTEMPTS => {
+                    warn!("Camera init attempt {} failed: {:?}", attempt, e);
+                    power_cycle(&pins)?;
+                    attempt += 1;
+                }
+                Err(e) => return Err(CameraError::InitFailed(e)),
+            }
+        };
+        *lock(&ACTIVE_PINS) = pins;
+
+        // If the first init did get by without PSRAM, its buffers still need moving
+        let config = match self.config {
+            None if !psram_available() => Some(CameraConfig::DRIVER_DEFAULT),
+            config => config,
+        };
+        if let Some(config) = config {
+            camera.reconfigure(&config)?;
+        }
+        Ok(camera)
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::{
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::avi::AviWriter;
+use crate::history::FrameHistory;
+use crate::motion::{Motion, MotionEvent, MotionSink};
+use crate::rtp::jpeg_dimensions;
+use crate::sdcard::SdCard;
+
+// In the card's dated layout, see `SdCard::media_name`
+const KIND: &str = "clip";
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClipSettings {
+    // Footage from before the trigger, out of the frame history
+    pub pre: Duration,
+    // And after it. The history has to reach back `pre` plus this for the start to still be in
+    // it by the time the clip is saved.
+    pub post: Duration,
+}
+
+// Saves a clip to the SD card for every motion event. The frame history is already keeping
+// the last few seconds in PSRAM, so the clip can start before whatever set it off.
+pub struct MotionClips {
+    triggers: SyncSender<(Instant, MotionEvent)>,
+}
+
+impl MotionClips {
+    // The name of each clip is added to its event in /api/motion/events
+    pub fn start(
+        history: FrameHistory,
+        sdcard: SdCard,
+        motion: Motion,
+        settings: ClipSettings,
+    ) -> Result<Self> {
+        // Only taken while the task is idle, an event while a clip is waiting for its end is in
+        // that clip already
+        let (triggers, pending) = mpsc::sync_channel(0);
+        thread::Builder::new()
+            .name("clips".into())
+            .stack_size(8192)
+            .spawn(move || save_clips(pending, history, sdcard, motion, settings))?;
+
+        info!(
+            "Saving motion clips from {} s before to {} s after",
+            settings.pre.as_secs(),
+            settings.post.as_secs()
+        );
+        Ok(Self { triggers })
+    }
+}
+
+impl MotionSink for MotionClips {
+    fn motion(&mut self, event: &MotionEvent) -> Result<()> {
+        match self.triggers.try_send((Instant::now(), event.clone())) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => bail!("The clip task has stopped"),
+        }
+    }
+}
+
+fn save_clips(
+    pending: Receiver<(Instant, MotionEvent)>,
+    history: FrameHistory,
+    sdcard: SdCard,
+    motion: Motion,
+    settings: ClipSettings,
+) {
+    for (trigger, event) in pending {
+        if let Some(left) = settings.post.checked_sub(trigger.elapsed()) {
+            thread::sleep(left);
+        }
+        match save(&history, &sdcard, trigger, settings) {
+            Ok(Some(name)) => motion.attach_clip(&event, &format!("/{}", name)),
+            Ok(None) => warn!("No frames in history for a motion clip"),
+            Err(e) => {
+                warn!("Saving a motion clip failed: {:?}", e);
+                sdcard.report(&e);
+            }
+        }
+    }
+}
+
+// None when the history has nothing from around `trigger`
+fn save(
+    history: &FrameHistory,
+    sdcard: &SdCard,
+    trigger: Instant,
+    settings: ClipSettings,
+) -> Result<Option<String>> {
+    let frames: Vec<_> = history
+        .frames()
+        .into_iter()
+        .filter(|frame| {
+            frame.taken + settings.pre >= trigger && frame.taken <= trigger + settings.post
+        })
+        .collect();
+    let (Some(first), Some(last)) = (frames.first(), frames.last()) else {
+        return Ok(None);
+    };
+
+    // Players go by the JPEG headers, these only need to be about right
+    let (width, height) = jpeg_dimensions(&history.open(first)?).unwrap_or((0, 0));
+    let name = sdcard.media_name(KIND, "avi")?;
+    let mut avi = AviWriter::new(sdcard.create(&name)?, width as u32, height as u32, 1)?;
+    for frame in &frames {
+        avi.write_frame(&history.open(frame)?)?;
+    }
+    // At the rate the history actually kept them, which thins out when PSRAM runs short
+    let span = last.taken.duration_since(first.taken).as_millis().max(1) as u64;
+    avi.set_fps(((frames.len() as u64 - 1) * 1000 / span) as u32);
+    avi.finish()?.sync_all()?;
+
+    info!("Saved a motion clip of {} frames to {}", frames.len(), name);
+    Ok(Some(name))
+}
@@ -0,0 +1,163 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::Camera;
+use crate::lock::lock;
+use crate::pipeline::Pipeline;
+
+// Don't spin on a camera that keeps failing
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+// Enough for a VGA JPEG without growing, and big enough that the allocator puts it in PSRAM
+const SLOT_CAPACITY: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Slot {
+    sequence: u64,
+    jpeg: Vec<u8>,
+}
+
+struct State {
+    // Sequence of the frame in the front slot, 0 before the first one
+    sequence: u64,
+    viewers: u32,
+}
+
+struct Shared {
+    slots: [Mutex<Slot>; 2],
+    // The slot holding the newest complete frame, the task fills the other one
+    front: AtomicUsize,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+// Pulls frames through the pipeline for as long as anyone is watching, no faster than `fps`,
+// so a slow client only ever costs itself frames. The task keeps the driver drained, which keeps
+// the frame it hands out fresh without GrabMode::Latest throwing every other one away.
+#[derive(Clone)]
+pub struct Continuous {
+    shared: Arc<Shared>,
+}
+
+impl Continuous {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        fps: u32,
+    ) -> Result<Self> {
+        let frame_time = Duration::from_millis(1000 / fps.max(1) as u64);
+        let slot = || {
+            Mutex::new(Slot {
+                sequence: 0,
+                jpeg: Vec::with_capacity(SLOT_CAPACITY),
+            })
+        };
+        let continuous = Self {
+            shared: Arc::new(Shared {
+                slots: [slot(), slot()],
+                front: AtomicUsize::new(0),
+                state: Mutex::new(State {
+                    sequence: 0,
+                    viewers: 0,
+                }),
+                changed: Condvar::new(),
+            }),
+        };
+
+        let shared = continuous.shared.clone();
+        thread::Builder::new()
+            .name("continuous".into())
+            .stack_size(6144)
+            .spawn(move || {
+                let mut sequence = 0;
+                loop {
+                    {
+                        let state = lock(&shared.state);
+                        let _ = shared.changed.wait_while(state, |state| state.viewers == 0);
+                    }
+
+                    let started = Instant::now();
+                    let back = 1 - shared.front.load(Ordering::Acquire);
+                    let result = {
+                        let mut slot = lock(&shared.slots[back]);
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run_in_place(&cam).map(|jpeg| {
+                            slot.jpeg.clear();
+                            slot.jpeg.extend_from_slice(jpeg.data());
+                            sequence += 1;
+                            slot.sequence = sequence;
+                        })
+                    };
+                    if let Err(e) = result {
+                        warn!("Continuous capture failed: {:?}", e);
+                        thread::sleep(RETRY_DELAY);
+                        continue;
+                    }
+
+                    shared.front.store(back, Ordering::Release);
+                    lock(&shared.state).sequence = sequence;
+                    shared.changed.notify_all();
+
+                    // Nobody gets frames faster than the stream sends them anyway
+                    if let Some(left) = frame_time.checked_sub(started.elapsed()) {
+                        thread::sleep(left);
+                    }
+                }
+            })?;
+
+        info!(
+            "Capturing continuously at up to {} fps while the stream is watched",
+            fps.max(1)
+        );
+        Ok(continuous)
+    }
+
+    // Keeps the task capturing until the returned viewer is dropped
+    pub fn watch(&self) -> Viewer {
+        lock(&self.shared.state).viewers += 1;
+        self.shared.changed.notify_all();
+        Viewer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+pub struct Viewer {
+    shared: Arc<Shared>,
+}
+
+impl Viewer {
+    // A copy of the newest frame once there's one after sequence `after`, None if none
+    // turned up within `wait`
+    pub fn newer(&self, after: u64, wait: Duration) -> Option<(u64, Vec<u8>)> {
+        let state = lock(&self.shared.state);
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, wait, |state| state.sequence <= after)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if state.sequence <= after {
+            return None;
+        }
+        drop(state);
+
+        // The task may have moved on since, whichever frame is in front now is complete
+        let slot = lock(&self.shared.slots[self.shared.front.load(Ordering::Acquire)]);
+        Some((slot.sequence, slot.jpeg.clone()))
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        let mut state = lock(&self.shared.state);
+        state.viewers = state.viewers.saturating_sub(1);
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use embedded_svc::http::{client::Client, server::Connection};
+use esp_idf_svc::{
+    http::client::{
+        Configuration as HttpClientConfiguration, EspHttpConnection as HttpClientConnection,
+    },
+    http::server::{HandlerResult, Request},
+    io::{Read, Write},
+    mdns::{EspMdns, Interface, Protocol, QueryResult},
+};
+use log::{info, warn};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::http::{ApiError, Yielder};
+use crate::lock::lock;
+use crate::mdns::{SERVICE_PROTO, SERVICE_TYPE};
+
+// Other units are expected to announce themselves as esp32cam-XXXX
+const PEER_PREFIX: &str = "esp32cam";
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_RESULTS: usize = 16;
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct Peer {
+    pub name: String,
+    pub addr: SocketAddr,
+    discovered: bool,
+}
+
+// Keeps track of the other cameras on the LAN and proxies requests to them
+#[derive(Clone)]
+pub struct Controller {
+    peers: Arc<Mutex<Vec<Peer>>>,
+}
+
+impl Controller {
+    // `static_peers` looks like "front=192.168.1.20:80;back=192.168.1.21",
+    // anything found over mDNS is added on top of those. `hostname` is our own, which mDNS
+    // finds too but proxying to ourselves would only tie up the server waiting on itself.
+    pub fn start(mdns: Arc<EspMdns>, hostname: &str, static_peers: &str) -> Result<Self> {
+        let mut peers = Vec::new();
+        for entry in static_peers
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (name, addr) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Camera entry '{}' should be name=address", entry))?;
+            let addr = if addr.contains(':') {
+                addr.parse()?
+            } else {
+                SocketAddr::new(addr.parse()?, 80)
+            };
+            peers.push(Peer {
+                name: name.to_string(),
+                addr,
+                discovered: false,
+            });
+        }
+
+        let controller = Self {
+            peers: Arc::new(Mutex::new(peers)),
+        };
+
+        let peers = controller.peers.clone();
+        let hostname = hostname.to_string();
+        thread::Builder::new()
+            .name("mdns-browse".into())
+            .stack_size(6144)
+            .spawn(move || loop {
+                match discover(&mdns, &hostname) {
+                    Ok(found) => {
+                        let mut peers = lock(&peers);
+                        peers.retain(|p| !p.discovered);
+                        for peer in found {
+                            if !peers.iter().any(|p| p.name == peer.name) {
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Camera discovery failed: {:?}", e),
+                }
+
+                thread::sleep(DISCOVERY_INTERVAL);
+            })?;
+
+        Ok(controller)
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        lock(&self.peers).clone()
+    }
+
+    pub fn peer(&self, name: &str) -> Option<Peer> {
+        lock(&self.peers).iter().find(|p| p.name == name).cloned()
+    }
+
+    // Fetches a snapshot from `peer` and streams it back to the client as it arrives
+    pub fn proxy_capture<C: Connection>(&self, peer: &Peer, request: Request<C>) -> HandlerResult {
+        let mut client = Client::wrap(HttpClientConnection::new(&HttpClientConfiguration {
+            timeout: Some(PEER_TIMEOUT),
+            ..Default::default()
+        })?);
+
+        let url = format!("http://{}/", peer.addr);
+        let mut upstream = match client.get(&url).and_then(|r| r.submit()) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                return ApiError::new(
+                    502,
+                    "peer_unreachable",
+                    format!("{} is unreachable: {:?}", peer.name, e),
+                )
+                .retry_after(DISCOVERY_INTERVAL.as_secs() as u32)
+                .send(request);
+            }
+        };
+
+        // Not our snapshot to interpret, pass errors from the unit through as-is
+        let status = upstream.status();
+        let content_type = upstream
+            .header("Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let content_length = upstream.header("Content-Length").map(str::to_string);
+
+        let mut headers = vec![("Content-Type", content_type.as_str())];
+        if let Some(len) = &content_length {
+            headers.push(("Content-Length", len.as_str()));
+        }
+        let mut response = request.into_response(status, None, &headers)?;
+
+        let mut buf = [0u8; 1024];
+        let mut yielder = Yielder::new();
+        loop {
+            yielder.tick();
+            let read = upstream.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            response.write_all(&buf[..read])?;
+        }
+
+        Ok(())
+    }
+}
+
+fn discover(mdns: &EspMdns, ours: &str) -> Result<Vec<Peer>> {
+    let mut results: Vec<QueryResult> = (0..MAX_RESULTS)
+        .map(|_| QueryResult {
+            instance_name: None,
+            hostname: None,
+            port: 0,
+            txt: Vec::new(),
+            addr: Vec::new(),
+            interface: Interface::STA,
+            ip_protocol: Protocol::V4,
+        })
+        .collect();
+
+    let count = mdns.query_ptr(
+        SERVICE_TYPE,
+        SERVICE_PROTO,
+        QUERY_TIMEOUT,
+        MAX_RESULTS,
+        &mut results,
+    )?;
+
+    let mut peers = Vec::new();
+    for result in &results[..count] {
+        let Some(hostname) = &result.hostname else {
+            continue;
+        };
+        if !hostname.starts_with(PEER_PREFIX) || hostname.eq_ignore_ascii_case(ours) {
+            continue;
+        }
+        let Some(ip) = result.addr.first() else {
+            continue;
+        };
+
+        peers.push(Peer {
+            name: hostname.clone(),
+            addr: SocketAddr::new(*ip, result.port),
+            discovered: true,
+        });
+    }
+
+    if !peers.is_empty() {
+        info!("Discovered {} camera(s) over mDNS", peers.len());
+    }
+
+    Ok(peers)
+}
@@ -0,0 +1,103 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs},
+    sys::esp_random,
+};
+use log::info;
+
+const NVS_NAMESPACE: &str = "crypto";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// Sealed frames are laid out as
+//
+//   "TCE1" | 12 byte nonce | ciphertext | 16 byte GCM tag
+//
+// with AES-256-GCM and no associated data. Anything that can do AES-GCM can open them
+// given the key, e.g. with Python's cryptography package:
+//
+//   AESGCM(key).decrypt(blob[4:16], blob[16:], None)
+const MAGIC: &[u8; 4] = b"TCE1";
+// How much bigger sealing makes a frame
+pub const SEAL_OVERHEAD: usize = MAGIC.len() + NONCE_LEN + TAG_LEN;
+
+// Encrypts the frames kept in the history buffer, so a dump of RAM doesn't give them away.
+// Only those, what goes to the SD card or gets uploaded is left as it is. The key lives in NVS.
+#[derive(Clone)]
+pub struct FrameCipher {
+    cipher: Aes256Gcm,
+}
+
+impl FrameCipher {
+    // A device without a key gets a fresh random one. It's never logged, the log can be
+    // mirrored to a file, so set it up front through `frame_key` to keep a copy.
+    pub fn load(nvs: EspDefaultNvsPartition, configured_key: &str) -> Result<Self> {
+        let mut nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+
+        let mut buf = [0u8; KEY_LEN];
+        let key = match nvs.get_blob("frame_key", &mut buf)? {
+            Some(key) if key.len() == KEY_LEN => key.to_vec(),
+            _ => {
+                let key = if configured_key.is_empty() {
+                    info!("Generated a new frame encryption key");
+                    random_bytes(KEY_LEN)
+                } else {
+                    from_hex(configured_key)?
+                };
+                nvs.set_blob("frame_key", &key)?;
+                key
+            }
+        };
+
+        info!("Frame encryption is enabled");
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        })
+    }
+
+    pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>> {
+        let nonce = random_bytes(NONCE_LEN);
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plain)
+            .map_err(|_| anyhow!("Frame encryption failed"))?;
+
+        let mut out = Vec::with_capacity(SEAL_OVERHEAD - TAG_LEN + sealed.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD || !sealed.starts_with(MAGIC) {
+            bail!("Not a sealed frame");
+        }
+
+        let (nonce, data) = sealed[MAGIC.len()..].split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), data)
+            .map_err(|_| anyhow!("Sealed frame is corrupt or was sealed with another key"))
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|_| (unsafe { esp_random() } & 0xff) as u8)
+        .collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        bail!("Frame key should be {} hex digits", KEY_LEN * 2);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
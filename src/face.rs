@@ -0,0 +1,224 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::{Camera, CameraError, CameraExt};
+use crate::http::write_json;
+use crate::lock::lock;
+use crate::motion::{self, Motion, MotionEvent, Source};
+use crate::overlay::Outline;
+use crate::pipeline::{Frame, Pipeline};
+
+// Wide enough to find a face across a room, small enough to search in well under a second
+const DETECT_WIDTH: usize = 320;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Face {
+    #[serde(flatten)]
+    pub outline: Outline,
+    // How sure the detector is, 0-1
+    pub score: f32,
+}
+
+// The detector itself is esp-dl, see components/face_detect. It's only linked in with the
+// face-detection feature as the models add about a megabyte to the firmware.
+#[cfg(feature = "face-detection")]
+mod esp_dl {
+    use anyhow::{bail, Result};
+
+    use super::Face;
+    use crate::overlay::Outline;
+    use crate::pipeline::Frame;
+
+    const MAX_FACES: usize = 8;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct RawFace {
+        x1: i32,
+        y1: i32,
+        x2: i32,
+        y2: i32,
+        score: f32,
+    }
+
+    extern "C" {
+        fn tigercam_detect_faces(
+            rgb565: *const u16,
+            width: i32,
+            height: i32,
+            faces: *mut RawFace,
+            max_faces: i32,
+        ) -> i32;
+    }
+
+    pub fn detect(frame: &Frame) -> Result<Vec<Face>> {
+        if frame.bytes_per_pixel() != Some(2) {
+            bail!("Face detection needs an RGB565 frame");
+        }
+        // esp-dl reads whole pixels, which a Vec<u8> isn't aligned for
+        let pixels: Vec<u16> = frame
+            .data
+            .chunks_exact(2)
+            .map(|pixel| u16::from_ne_bytes([pixel[0], pixel[1]]))
+            .collect();
+
+        let mut raw = [RawFace::default(); MAX_FACES];
+        let count = unsafe {
+            tigercam_detect_faces(
+                pixels.as_ptr(),
+                frame.width as i32,
+                frame.height as i32,
+                raw.as_mut_ptr(),
+                MAX_FACES as i32,
+            )
+        };
+
+        let (width, height) = (frame.width as f32, frame.height as f32);
+        Ok(raw[..count.clamp(0, MAX_FACES as i32) as usize]
+            .iter()
+            .map(|face| Face {
+                outline: Outline {
+                    x: face.x1 as f32 / width,
+                    y: face.y1 as f32 / height,
+                    width: (face.x2 - face.x1) as f32 / width,
+                    height: (face.y2 - face.y1) as f32 / height,
+                },
+                score: face.score,
+            })
+            .collect())
+    }
+}
+
+#[cfg(feature = "face-detection")]
+fn detect(frame: &Frame) -> Result<Vec<Face>> {
+    esp_dl::detect(frame)
+}
+
+#[cfg(not(feature = "face-detection"))]
+fn detect(_frame: &Frame) -> Result<Vec<Face>> {
+    bail!("Built without the face-detection feature")
+}
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    // From the last frame searched
+    faces: Vec<Face>,
+    // When faces were last seen, seconds since the epoch
+    last_seen: Option<u64>,
+    searched: u32,
+}
+
+// Searches a downscaled frame for faces every so often. Frames with any in them raise a motion
+// event carrying their outlines, and the latest ones can be drawn into the stream.
+#[derive(Clone)]
+pub struct Faces {
+    state: Arc<Mutex<State>>,
+}
+
+impl Faces {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        interval: Duration,
+        // Faces that stay in view raise an event at most this often
+        cooldown: Duration,
+        motion: Option<Motion>,
+    ) -> Result<Self> {
+        // Fail here rather than warning forever from the thread
+        if !cfg!(feature = "face-detection") {
+            bail!("Built without the face-detection feature");
+        }
+
+        let faces = Self {
+            state: Arc::new(Mutex::new(State::default())),
+        };
+
+        let task = faces.clone();
+        thread::Builder::new()
+            .name("faces".into())
+            .stack_size(16384)
+            .spawn(move || {
+                let mut last_raised: Option<Instant> = None;
+                loop {
+                    thread::sleep(interval);
+
+                    let frame = {
+                        let _pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        cam.try_capture()
+                            .and_then(|fb| fb.rgb565_within(DETECT_WIDTH))
+                    };
+                    let found = match frame {
+                        Ok(frame) => detect(&frame),
+                        // Nobody to see while the camera sleeps
+                        Err(CameraError::Asleep) => continue,
+                        Err(e) => Err(e.into()),
+                    };
+                    let found = match found {
+                        Ok(found) => found,
+                        Err(e) => {
+                            warn!("Face detection failed: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    {
+                        let mut state = lock(&task.state);
+                        state.searched += 1;
+                        if !found.is_empty() {
+                            state.last_seen = Some(motion::now());
+                        }
+                        state.faces = found.clone();
+                    }
+
+                    let Some(motion) = &motion else {
+                        continue;
+                    };
+                    if found.is_empty() || last_raised.is_some_and(|at| at.elapsed() < cooldown) {
+                        continue;
+                    }
+                    last_raised = Some(Instant::now());
+                    motion.raise(MotionEvent {
+                        timestamp: motion::now(),
+                        source: Source::Face,
+                        score: found.len() as u32,
+                        cells: 0,
+                        faces: found.iter().map(|face| face.outline).collect(),
+                    });
+                }
+            })?;
+
+        info!("Looking for faces every {} ms", interval.as_millis());
+        Ok(faces)
+    }
+
+    // Of the last frame searched, for `Layer::Outlines`
+    pub fn outlines(&self) -> Vec<Outline> {
+        lock(&self.state)
+            .faces
+            .iter()
+            .map(|face| face.outline)
+            .collect()
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, faces: Faces) -> Result<()> {
+    server.fn_handler(
+        "/api/faces",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&faces.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    Ok(())
+}
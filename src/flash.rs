@@ -0,0 +1,182 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    hal::{
+        delay::FreeRtos,
+        gpio::OutputPin,
+        ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver},
+        peripheral::Peripheral,
+        prelude::*,
+    },
+    http::{server::EspHttpServer, Method},
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Auth;
+use crate::http::{read_body, write_json, ApiError};
+use crate::lock::lock;
+use crate::source::FrameSource;
+
+// Long enough for the sensor's auto exposure to notice the light
+const SETTLE_MS: u32 = 150;
+
+#[derive(Clone, Copy, Serialize)]
+struct FlashState {
+    on: bool,
+    // Percent of full duty
+    brightness: u8,
+    // Light up for every capture regardless of `on`
+    auto: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlashUpdate {
+    on: Option<bool>,
+    brightness: Option<u8>,
+    auto: Option<bool>,
+}
+
+struct Inner {
+    driver: LedcDriver<'static>,
+    state: FlashState,
+    // Captures currently holding the LED on, see `Flash::pulse`
+    pulses: u32,
+}
+
+impl Inner {
+    fn apply(&mut self) -> Result<()> {
+        let lit = self.state.on || self.pulses > 0;
+        let duty = if lit {
+            self.driver.get_max_duty() * self.state.brightness as u32 / 100
+        } else {
+            0
+        };
+        self.driver.set_duty(duty)?;
+        Ok(())
+    }
+}
+
+// The AI-Thinker board's flash LED, dimmed with LEDC PWM
+#[derive(Clone)]
+pub struct Flash {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Flash {
+    // The camera driver generates XCLK with LEDC timer 0 and channel 0, so those are taken
+    pub fn new(
+        timer: impl Peripheral<P = impl LedcTimer> + 'static,
+        channel: impl Peripheral<P = impl LedcChannel> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+        auto: bool,
+    ) -> Result<Self> {
+        let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(5.kHz().into()))?;
+        let driver = LedcDriver::new(channel, timer, pin)?;
+
+        let mut inner = Inner {
+            driver,
+            state: FlashState {
+                on: false,
+                brightness: 100,
+                auto,
+            },
+            pulses: 0,
+        };
+        inner.apply()?;
+        info!(
+            "Flash LED ready, auto flash is {}",
+            if auto { "on" } else { "off" }
+        );
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    // With auto flash on, lights the LED until the returned guard is dropped. Call it with
+    // the camera locked, it throws away the frame the driver took in the dark.
+    pub fn pulse(&self, cam: &impl FrameSource) -> Result<Option<Pulse>> {
+        {
+            let mut inner = lock(&self.inner);
+            if !inner.state.auto {
+                return Ok(None);
+            }
+            inner.pulses += 1;
+            inner.apply()?;
+        }
+        let pulse = Pulse {
+            inner: self.inner.clone(),
+        };
+
+        FreeRtos::delay_ms(SETTLE_MS);
+        cam.discard();
+
+        Ok(Some(pulse))
+    }
+
+    fn update(&self, update: &FlashUpdate) -> Result<FlashState> {
+        let mut inner = lock(&self.inner);
+        if let Some(brightness) = update.brightness {
+            if brightness > 100 {
+                bail!("brightness must be between 0 and 100");
+            }
+            inner.state.brightness = brightness;
+        }
+        if let Some(on) = update.on {
+            inner.state.on = on;
+        }
+        if let Some(auto) = update.auto {
+            inner.state.auto = auto;
+        }
+        inner.apply()?;
+        Ok(inner.state)
+    }
+}
+
+pub struct Pulse {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Drop for Pulse {
+    fn drop(&mut self) {
+        let mut inner = lock(&self.inner);
+        inner.pulses -= 1;
+        let _ = inner.apply();
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, flash: Flash) -> Result<()> {
+    let get_flash = flash.clone();
+    server.fn_handler(
+        "/api/flash",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&get_flash.inner).state;
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    server.fn_handler(
+        "/api/flash",
+        Method::Post,
+        auth.protect(move |mut request| {
+            let body = match read_body(&mut request, 128) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let update: FlashUpdate = match serde_json::from_slice(&body) {
+                Ok(update) => update,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            match flash.update(&update) {
+                Ok(state) => write_json(request, 200, &state),
+                Err(e) => ApiError::bad_request(e).send(request),
+            }
+        }),
+    )?;
+
+    Ok(())
+}
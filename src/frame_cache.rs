@@ -0,0 +1,92 @@
+use anyhow::Result;
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::lock::lock;
+use crate::pipeline::Sink;
+
+pub struct CachedFrame {
+    // Goes up by one for every frame, never reused until reboot
+    pub etag: u64,
+    pub taken: Instant,
+    pub jpeg: Vec<u8>,
+}
+
+impl CachedFrame {
+    pub fn etag_header(&self) -> String {
+        format!("\"{}\"", self.etag)
+    }
+}
+
+struct Inner {
+    last: Option<Arc<CachedFrame>>,
+    next_etag: u64,
+}
+
+// Remembers the last frame the pipeline encoded so clients polling faster than `max_age`
+// are answered from it, fed by every capture regardless of who asked for it
+#[derive(Clone)]
+pub struct FrameCache {
+    inner: Arc<(Mutex<Inner>, Condvar)>,
+    max_age: Duration,
+}
+
+impl FrameCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            inner: Arc::new((
+                Mutex::new(Inner {
+                    last: None,
+                    next_etag: 1,
+                }),
+                Condvar::new(),
+            )),
+            max_age,
+        }
+    }
+
+    // The last frame, if it's recent enough to hand out instead of capturing a new one
+    pub fn fresh(&self) -> Option<Arc<CachedFrame>> {
+        lock(&self.inner.0)
+            .last
+            .clone()
+            .filter(|frame| frame.taken.elapsed() < self.max_age)
+    }
+
+    pub fn latest(&self) -> Option<Arc<CachedFrame>> {
+        lock(&self.inner.0).last.clone()
+    }
+
+    // Blocks until there's a frame with an ETag above `after`, or `wait` is up
+    pub fn wait_newer(&self, after: u64, wait: Duration) -> Option<Arc<CachedFrame>> {
+        let (inner, arrived) = &*self.inner;
+        let inner = lock(inner);
+        let (inner, _) = arrived
+            .wait_timeout_while(inner, wait, |inner| {
+                inner
+                    .last
+                    .as_ref()
+                    .map_or(true, |frame| frame.etag <= after)
+            })
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        inner.last.clone().filter(|frame| frame.etag > after)
+    }
+}
+
+impl Sink for FrameCache {
+    fn consume(&mut self, jpeg: &[u8]) -> Result<()> {
+        let (inner, arrived) = &*self.inner;
+        let mut inner = lock(inner);
+        let etag = inner.next_etag;
+        inner.next_etag += 1;
+        inner.last = Some(Arc::new(CachedFrame {
+            etag,
+            taken: Instant::now(),
+            jpeg: jpeg.to_vec(),
+        }));
+        arrived.notify_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+use esp_idf_svc::sys::{heap_caps_get_free_size, MALLOC_CAP_SPIRAM};
+use log::{info, warn};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::Camera;
+use crate::crypto::{FrameCipher, SEAL_OVERHEAD};
+use crate::lock::lock;
+use crate::pipeline::{Pipeline, Sink};
+
+// Always leave this much PSRAM for the camera driver and everyone else
+const PSRAM_RESERVE: usize = 512 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Retention {
+    // Keep a fixed number of frames
+    Frames(usize),
+    // Keep `window` worth of frames sampled at `fps`
+    Duration { window: Duration, fps: u32 },
+}
+
+impl Retention {
+    fn interval(&self) -> Duration {
+        match self {
+            Retention::Frames(_) => Duration::ZERO,
+            Retention::Duration { fps, .. } => Duration::from_millis(1000 / (*fps).max(1) as u64),
+        }
+    }
+}
+
+pub struct HistoryFrame {
+    pub taken: Instant,
+    pub jpeg: Vec<u8>,
+}
+
+struct Inner {
+    retention: Retention,
+    // Sealed with `cipher` when there is one, so a dump of RAM doesn't give the footage away
+    frames: VecDeque<Arc<HistoryFrame>>,
+    cipher: Option<FrameCipher>,
+    thinned: u32,
+}
+
+impl Inner {
+    fn push(&mut self, jpeg: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.frames.back() {
+            if now.duration_since(last.taken) < self.retention.interval() {
+                return Ok(());
+            }
+        }
+
+        match self.retention {
+            Retention::Frames(count) => {
+                while self.frames.len() >= count.max(1) {
+                    self.frames.pop_front();
+                }
+            }
+            Retention::Duration { window, .. } => {
+                while self
+                    .frames
+                    .front()
+                    .map_or(false, |f| now.duration_since(f.taken) > window)
+                {
+                    self.frames.pop_front();
+                }
+            }
+        }
+
+        // Out of PSRAM, give up temporal resolution on the oldest part of the history
+        // before giving up any of its length
+        while free_psram() < PSRAM_RESERVE + jpeg.len() && !self.frames.is_empty() {
+            if !self.thin() {
+                self.frames.pop_front();
+            }
+        }
+
+        let jpeg = match &self.cipher {
+            Some(cipher) => cipher.seal(jpeg)?,
+            None => jpeg.to_vec(),
+        };
+        self.frames
+            .push_back(Arc::new(HistoryFrame { taken: now, jpeg }));
+
+        Ok(())
+    }
+
+    // Drops every other frame from the older half, false if there's nothing left to thin
+    fn thin(&mut self) -> bool {
+        let half = self.frames.len() / 2;
+        if half < 2 {
+            return false;
+        }
+
+        let mut index = 0;
+        self.frames.retain(|_| {
+            index += 1;
+            index > half || index % 2 == 0
+        });
+
+        self.thinned += 1;
+        if self.thinned % 10 == 1 {
+            warn!("PSRAM is tight, thinning frame history");
+        }
+        true
+    }
+}
+
+fn free_psram() -> usize {
+    unsafe { heap_caps_get_free_size(MALLOC_CAP_SPIRAM) }
+}
+
+// An in-RAM history of recent frames, fed from the capture pipeline
+#[derive(Clone)]
+pub struct FrameHistory {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FrameHistory {
+    // Registers the history as a pipeline sink, and in time-based mode keeps capturing at the
+    // configured rate so the window stays full even when nobody is watching
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        retention: Retention,
+        cipher: Option<FrameCipher>,
+    ) -> Result<Self> {
+        let history = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                retention,
+                frames: VecDeque::new(),
+                cipher,
+                thinned: 0,
+            })),
+        };
+
+        lock(&pipeline).add_sink(Box::new(history.clone()));
+        info!("Keeping frame history: {:?}", retention);
+
+        if let Retention::Duration { .. } = retention {
+            let interval = retention.interval();
+            thread::Builder::new()
+                .name("history".into())
+                .stack_size(6144)
+                .spawn(move || loop {
+                    let started = Instant::now();
+                    let result = {
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run(&*cam)
+                    };
+                    if let Err(e) = result {
+                        warn!("History capture failed: {:?}", e);
+                    }
+
+                    if let Some(left) = interval.checked_sub(started.elapsed()) {
+                        thread::sleep(left);
+                    }
+                })?;
+        }
+
+        Ok(history)
+    }
+
+    // `index` 0 is the newest frame
+    pub fn get(&self, index: usize) -> Result<Option<Arc<HistoryFrame>>> {
+        let inner = lock(&self.inner);
+        let Some(frame) = inner.frames.iter().rev().nth(index) else {
+            return Ok(None);
+        };
+
+        match &inner.cipher {
+            Some(cipher) => Ok(Some(Arc::new(HistoryFrame {
+                taken: frame.taken,
+                jpeg: cipher.open(&frame.jpeg)?,
+            }))),
+            None => Ok(Some(frame.clone())),
+        }
+    }
+
+    // Every frame held right now, oldest first and still sealed. Frames that age out of the
+    // history meanwhile stay in memory until the list is dropped.
+    pub fn frames(&self) -> Vec<Arc<HistoryFrame>> {
+        lock(&self.inner).frames.iter().cloned().collect()
+    }
+
+    // The size of a frame from `frames` once opened, without opening it
+    pub fn jpeg_len(&self, frame: &HistoryFrame) -> usize {
+        if lock(&self.inner).cipher.is_some() {
+            frame.jpeg.len().saturating_sub(SEAL_OVERHEAD)
+        } else {
+            frame.jpeg.len()
+        }
+    }
+
+    pub fn open<'a>(&self, frame: &'a HistoryFrame) -> Result<Cow<'a, [u8]>> {
+        let cipher = lock(&self.inner).cipher.clone();
+        match cipher {
+            Some(cipher) => Ok(Cow::Owned(cipher.open(&frame.jpeg)?)),
+            None => Ok(Cow::Borrowed(&frame.jpeg)),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        lock(&self.inner).frames.len()
+    }
+}
+
+impl Sink for FrameHistory {
+    fn consume(&mut self, jpeg: &[u8]) -> Result<()> {
+        lock(&self.inner).push(jpeg)
+    }
+}
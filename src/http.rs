@@ -0,0 +1,452 @@
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::http::{client::Client, server::Connection, Headers, Query};
+use esp_idf_svc::{
+    hal::delay::FreeRtos,
+    http::client::{
+        Configuration as HttpClientConfiguration, EspHttpConnection as HttpClientConnection,
+    },
+    http::server::{HandlerResult, Request},
+    io::{Read, Write},
+    sys::{
+        esp_crt_bundle_attach, esp_task_wdt_reset, httpd_req_t, httpd_req_to_sockfd,
+        lwip_getpeername, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t,
+        AF_INET, AF_INET6,
+    },
+};
+use serde::Serialize;
+use std::{
+    fmt, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::avi::AviLayout;
+use crate::camera::CameraError;
+use crate::flash::Flash;
+use crate::history::FrameHistory;
+use crate::pipeline::{lock_for_capture, CaptureError, ImageFormat, Pipeline, Shot};
+use crate::rtp::jpeg_dimensions;
+use crate::sensor::{framesize_from_name, Unsupported};
+use crate::source::FrameSource;
+use tigercam_core::http::{send_json, Exchange};
+use tigercam_core::settings::Invalid;
+
+// How much goes to the socket in one go, and how long we keep the CPU before stepping aside
+const WRITE_CHUNK: usize = 4096;
+const YIELD_INTERVAL: Duration = Duration::from_millis(100);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Long socket transfers and flash writes can hold the CPU for seconds. Ticking this between
+// pieces of work blocks for a moment every so often, so the idle task gets to run and the
+// task watchdog is fed for tasks that are subscribed to it.
+pub struct Yielder {
+    last: Instant,
+}
+
+impl Yielder {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.last.elapsed() < YIELD_INTERVAL {
+            return;
+        }
+
+        // Fails for tasks the watchdog isn't watching, which is fine
+        unsafe { esp_task_wdt_reset() };
+        FreeRtos::delay_ms(1);
+        self.last = Instant::now();
+    }
+}
+
+impl Default for Yielder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// `write_all`, in chunks with a `Yielder` tick between each of them
+pub fn write_all_yielding<W: Write>(writer: &mut W, data: &[u8]) -> Result<(), W::Error> {
+    let mut yielder = Yielder::new();
+    for chunk in data.chunks(WRITE_CHUNK) {
+        writer.write_all(chunk)?;
+        yielder.tick();
+    }
+    Ok(())
+}
+
+// A request's connection as core's `Exchange`, so responses are written by the same code the
+// host tests drive
+pub struct Conn<C>(pub C);
+
+impl<C: Connection> From<Request<C>> for Conn<C> {
+    fn from(request: Request<C>) -> Self {
+        Self(request.release())
+    }
+}
+
+impl<C: Connection> Exchange for Conn<C> {
+    fn uri(&self) -> &str {
+        Query::uri(&self.0)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        Headers::header(&self.0, name)
+    }
+
+    fn respond(&mut self, status: u16, headers: &[(&str, &str)]) -> Result<()> {
+        self.0
+            .initiate_response(status, None, headers)
+            .map_err(|e| anyhow!("Failed to send response: {:?}", e))
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        write_all_yielding(&mut self.0, data)
+            .map_err(|e| anyhow!("Failed to write response: {:?}", e))
+    }
+}
+
+// The client's address. The server listens on a dual stack socket, so IPv4 clients show up
+// as IPv4-mapped IPv6 and are turned back into plain IPv4 here.
+pub fn peer_ip<C>(request: &mut Request<C>) -> Option<IpAddr>
+where
+    C: Connection<RawConnection = httpd_req_t>,
+{
+    let raw = request.connection().raw_connection().ok()?;
+    let fd = unsafe { httpd_req_to_sockfd(raw) };
+
+    let mut addr: sockaddr_storage = Default::default();
+    let mut len = mem::size_of::<sockaddr_storage>() as socklen_t;
+    if unsafe { lwip_getpeername(fd, &mut addr as *mut _ as *mut sockaddr, &mut len) } != 0 {
+        return None;
+    }
+
+    match addr.ss_family as u32 {
+        AF_INET => {
+            let addr = unsafe { &*(&addr as *const _ as *const sockaddr_in) };
+            // Stored in network order
+            Some(Ipv4Addr::from(addr.sin_addr.s_addr.to_le_bytes()).into())
+        }
+        AF_INET6 => {
+            let addr = unsafe { &*(&addr as *const _ as *const sockaddr_in6) };
+            let ip = Ipv6Addr::from(unsafe { addr.sin6_addr.un.u8_addr });
+            Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4))
+        }
+        _ => None,
+    }
+}
+
+// Pulls a single value out of the query string, no percent-decoding
+pub fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    form_param(query, name)
+}
+
+// Same as `query_param`, for an application/x-www-form-urlencoded body
+pub fn form_param<'a>(form: &'a str, name: &str) -> Option<&'a str> {
+    form.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Undoes form encoding, '+' for spaces and %XX escapes
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                out.push(byte);
+                i += 3;
+            }
+            (None, b'+') => {
+                out.push(b' ');
+                i += 1;
+            }
+            (None, other) => {
+                out.push(other);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Reads the whole request body, refusing anything bigger than `max` bytes
+pub fn read_body<C: Connection>(request: &mut Request<C>, max: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    let mut yielder = Yielder::new();
+
+    loop {
+        yielder.tick();
+        let read = request
+            .read(&mut buf)
+            .map_err(|e| anyhow!("Failed to read request body: {:?}", e))?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > max {
+            bail!("Request body is larger than {} bytes", max);
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+
+    Ok(body)
+}
+
+// POSTs a JSON document to some other service, https URLs are checked against the
+// built-in certificate bundle
+pub fn post_json<T: Serialize>(url: &str, value: &T) -> Result<()> {
+    post(
+        url,
+        &[("Content-Type", "application/json")],
+        &serde_json::to_vec(value)?,
+    )
+}
+
+// Same as `post_json` for any body, `headers` should say what it is
+pub fn post(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<()> {
+    let mut client = Client::wrap(HttpClientConnection::new(&HttpClientConfiguration {
+        timeout: Some(WEBHOOK_TIMEOUT),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?);
+
+    let length = body.len().to_string();
+    let mut all_headers = vec![("Content-Length", length.as_str())];
+    all_headers.extend_from_slice(headers);
+    let mut request = client.post(url, &all_headers)?;
+    request.write_all(body)?;
+    request.flush()?;
+
+    let status = request.submit()?.status();
+    if !(200..300).contains(&status) {
+        bail!("{} answered with status {}", url, status);
+    }
+
+    Ok(())
+}
+
+pub fn write_json<C: Connection, T: Serialize>(
+    request: Request<C>,
+    status: u16,
+    value: &T,
+) -> HandlerResult {
+    send_json(&mut Conn::from(request), status, value, &[])?;
+    Ok(())
+}
+
+// The body every endpoint sends on failure, `code` is stable for clients to match on and
+// `retry_after` is a hint in seconds for errors that are expected to clear up by themselves
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: u16,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u32>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &'static str, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+
+    pub fn bad_request(message: impl fmt::Display) -> Self {
+        Self::new(400, "bad_request", message)
+    }
+
+    pub fn not_found(message: impl fmt::Display) -> Self {
+        Self::new(404, "not_found", message)
+    }
+
+    pub fn retry_after(mut self, seconds: u32) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn send<C: Connection>(self, request: Request<C>) -> HandlerResult {
+        self.send_with_headers(request, &[])
+    }
+
+    pub fn send_with_headers<C: Connection>(
+        self,
+        request: Request<C>,
+        extra_headers: &[(&str, &str)],
+    ) -> HandlerResult {
+        let retry_after = self.retry_after.map(|s| s.to_string());
+        let mut headers = Vec::from(extra_headers);
+        if let Some(retry_after) = &retry_after {
+            headers.push(("Retry-After", retry_after.as_str()));
+        }
+
+        send_json(&mut Conn::from(request), self.status, &self, &headers)?;
+        Ok(())
+    }
+}
+
+impl From<&anyhow::Error> for ApiError {
+    fn from(e: &anyhow::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<CameraError>() {
+            return Self::from(e);
+        }
+        if e.is::<Invalid>() {
+            return Self::bad_request(format!("{:#}", e));
+        }
+        if e.is::<Unsupported>() {
+            return Self::new(422, "sensor_unsupported", format!("{:#}", e));
+        }
+        match e.downcast_ref::<CaptureError>() {
+            Some(CaptureError::Busy) => Self::new(503, "camera_busy", e).retry_after(1),
+            None => Self::new(500, "internal", format!("{:#}", e)),
+        }
+    }
+}
+
+impl From<&CameraError> for ApiError {
+    fn from(e: &CameraError) -> Self {
+        match e {
+            CameraError::NoFrame => Self::new(503, "no_framebuffer", e).retry_after(1),
+            CameraError::ConversionFailed(_) => Self::new(500, "encode_failed", e).retry_after(1),
+            CameraError::Timeout => Self::new(504, "capture_timeout", e).retry_after(5),
+            CameraError::Asleep => Self::new(503, "camera_asleep", e),
+            CameraError::NoSensor(_) => Self::new(503, "sensor_unavailable", e).retry_after(5),
+            CameraError::SensorUnsupported(_) => Self::new(422, "sensor_unsupported", e),
+            CameraError::InvalidConfig(_) => Self::new(400, "invalid_config", e),
+            CameraError::InitFailed(_) => Self::new(503, "camera_init_failed", e).retry_after(5),
+            CameraError::FetchFailed(_) | CameraError::Esp(_) => Self::new(500, "camera_failed", e),
+            CameraError::BufferTooSmall(_) => Self::new(500, "buffer_too_small", e),
+        }
+    }
+}
+
+// The whole history as one MJPEG AVI, oldest frame first. Frames are opened and sent one at a
+// time so only one of them is ever out of the history at once.
+pub fn send_avi<C: Connection>(
+    request: Request<C>,
+    history: &FrameHistory,
+    fps: u32,
+) -> HandlerResult {
+    let frames = history.frames();
+    let Some(first) = frames.first() else {
+        return ApiError::not_found("No frames in history").send(request);
+    };
+    // Players go by the JPEG headers, these only need to be about right
+    let (width, height) = jpeg_dimensions(&history.open(first)?).unwrap_or((0, 0));
+
+    let sizes = frames.iter().map(|f| history.jpeg_len(f)).collect();
+    let layout = AviLayout::new(width as u32, height as u32, fps, sizes);
+
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", "video/x-msvideo"),
+            ("Content-Length", &layout.total_len().to_string()),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"history.avi\"",
+            ),
+        ],
+    )?;
+    response.write_all(&layout.header())?;
+    for frame in &frames {
+        let jpeg = history.open(frame)?;
+        response.write_all(&AviLayout::chunk_header(jpeg.len()))?;
+        write_all_yielding(&mut response, &jpeg)?;
+        response.write_all(AviLayout::padding(jpeg.len()))?;
+    }
+    response.write_all(&layout.index())?;
+
+    Ok(())
+}
+
+// A still, or 304 if the client already has `etag`
+pub fn send_jpeg<C: Connection>(
+    request: Request<C>,
+    jpeg: &[u8],
+    etag: Option<&str>,
+    timing: &str,
+) -> HandlerResult {
+    tigercam_core::http::send_jpeg(&mut Conn::from(request), jpeg, etag, timing)?;
+    Ok(())
+}
+
+// A one-off capture with `shot` applied, sent as whatever format it asks for
+pub fn send_shot<C: Connection, S: FrameSource>(
+    request: Request<C>,
+    pipeline: &Mutex<Pipeline>,
+    cam: &Mutex<S>,
+    flash: Option<&Flash>,
+    shot: Shot,
+) -> HandlerResult {
+    let image = lock_for_capture(pipeline, cam).and_then(|(mut pipeline, cam)| {
+        let _pulse = flash.map(|f| f.pulse(&*cam)).transpose()?;
+        pipeline.run_shot(&*cam, shot)
+    });
+    let image = match image {
+        Ok(image) => image,
+        Err(e) => return ApiError::from(&e).send(request),
+    };
+
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", shot.format.content_type()),
+            ("Content-Length", &image.len().to_string()),
+            ("Vary", "Accept"),
+        ],
+    )?;
+    let _ = write_all_yielding(&mut response, &image);
+
+    Ok(())
+}
+
+// `/capture?quality=90&size=UXGA&format=bmp`, every parameter is optional. Without a format
+// the Accept header decides.
+pub fn parse_shot(uri: &str, accept: Option<&str>) -> Result<Shot> {
+    let mut shot = Shot::default();
+
+    if let Some(quality) = query_param(uri, "quality") {
+        let quality: u8 = quality.parse()?;
+        if !(1..=100).contains(&quality) {
+            bail!("quality must be between 1 and 100");
+        }
+        shot.quality = Some(quality);
+    }
+    if let Some(size) = query_param(uri, "size") {
+        shot.framesize = Some(
+            framesize_from_name(size).ok_or_else(|| anyhow!("Unknown frame size '{}'", size))?,
+        );
+    }
+    match query_param(uri, "format") {
+        Some(format) => shot.format = format.parse()?,
+        None => shot.format = accept.map_or(ImageFormat::Jpeg, ImageFormat::from_accept),
+    }
+
+    Ok(shot)
+}
@@ -0,0 +1,49 @@
+// The camera, networking and HTTP pieces tigercam is built from. None of it reads the
+// binary's config, boards pick their pins and settings in main.rs and pass them in.
+
+pub mod analysis;
+pub mod api;
+pub mod auth;
+pub mod burst;
+pub mod camera;
+pub mod clip;
+pub mod continuous;
+pub mod controller;
+pub mod crypto;
+pub mod face;
+pub mod flash;
+pub mod frame_cache;
+pub mod history;
+pub mod http;
+pub mod lock;
+pub mod log_file;
+pub mod mdns;
+pub mod motion;
+pub mod next_frame;
+pub mod night;
+pub mod onvif;
+pub mod overlay;
+pub mod pipeline;
+pub mod pir;
+pub mod prewarm;
+pub mod provision;
+pub mod qr;
+pub mod rate_limit;
+pub mod recording;
+pub mod report;
+pub mod request_log;
+pub mod retention;
+pub mod rtp;
+pub mod sdcard;
+pub mod sensor;
+pub mod snapshot;
+pub mod source;
+pub mod storage;
+pub mod stream;
+pub mod tamper;
+pub mod timelapse;
+pub mod webhook;
+pub mod wifi;
+pub mod yuv;
+
+pub use tigercam_core::{avi, clock};
@@ -0,0 +1,35 @@
+use log::warn;
+use std::{
+    sync::{Mutex, MutexGuard, TryLockError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Like `lock().unwrap()`, but a holder that panicked doesn't take everyone else down with it.
+// Nothing we keep behind these locks is left half updated by a panic in a way that matters.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering a lock poisoned by a panic");
+        poisoned.into_inner()
+    })
+}
+
+// Waits at most `wait` for the lock, so one slow request can't stall every other client
+pub fn try_lock_for<T>(mutex: &Mutex<T>, wait: Duration) -> Option<MutexGuard<'_, T>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                warn!("Recovering a lock poisoned by a panic");
+                return Some(poisoned.into_inner());
+            }
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::Result;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::sys::esp_timer_get_time;
+use log::{Level, Log, Metadata, Record};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::auth::Auth;
+use crate::clock::{format_time, CLOCK_SET};
+use crate::http::{write_all_yielding, ApiError, Yielder};
+use crate::motion;
+
+// Lines waiting to be written, the oldest dropped past this. Also what's kept of boot before
+// there's a file to write to.
+const MAX_PENDING: usize = 64;
+// How long a line waits at most, about what's lost to a crash
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// Doubled after each failed write up to this, a missing card shouldn't flood the console
+const MAX_BACKOFF: Duration = Duration::from_secs(64);
+// Where the files go on the SD card
+pub const CARD_DIR: &str = "logs";
+pub const CURRENT: &str = "log.txt";
+// What CURRENT is renamed to once it's half the size allowed, replacing the one before
+pub const PREVIOUS: &str = "log.1.txt";
+// Read from a file at a time for /api/log
+const READ_CHUNK: usize = 4096;
+
+struct Pending {
+    lines: VecDeque<String>,
+    dropped: u32,
+}
+
+// Logs to the console like `EspLogger` does, and queues every line for the log file
+struct MirrorLogger {
+    pending: Mutex<Pending>,
+}
+
+static LOGGER: MirrorLogger = MirrorLogger {
+    pending: Mutex::new(Pending {
+        lines: VecDeque::new(),
+        dropped: 0,
+    }),
+};
+
+impl Log for MirrorLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        EspLogger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        EspLogger.log(record);
+
+        let level = match record.level() {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'V',
+        };
+        // Like the console's, with the time of day in front once SNTP has set the clock
+        let uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+        let unix = motion::now();
+        let line = if unix >= CLOCK_SET {
+            format!(
+                "{} {} ({}) {}: {}\n",
+                format_time(unix),
+                level,
+                uptime_ms,
+                record.target(),
+                record.args()
+            )
+        } else {
+            format!(
+                "{} ({}) {}: {}\n",
+                level,
+                uptime_ms,
+                record.target(),
+                record.args()
+            )
+        };
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.lines.len() == MAX_PENDING {
+            pending.lines.pop_front();
+            pending.dropped += 1;
+        }
+        pending.lines.push_back(line);
+    }
+
+    fn flush(&self) {}
+}
+
+// In place of `EspLogger::initialize_default`, first thing in main. With `mirror` lines are
+// kept in memory until `LogFile::start`, without it there's only the console.
+pub fn init(mirror: bool) {
+    if !mirror {
+        EspLogger::initialize_default();
+        return;
+    }
+    log::set_logger(&LOGGER)
+        .map(|()| EspLogger.initialize())
+        .unwrap();
+}
+
+// Every line logged, kept in a file on the SD card or in flash storage so there's something
+// to go on after a crash overnight with no serial console attached. It's rotated into a
+// second file, so the two together stay under the size given.
+#[derive(Clone)]
+pub struct LogFile {
+    current: PathBuf,
+    previous: PathBuf,
+}
+
+impl LogFile {
+    // `dir` has to be there already, SPIFFS can't make one
+    pub fn start(dir: &Path, max_bytes: u64) -> Result<Self> {
+        let log_file = Self {
+            current: dir.join(CURRENT),
+            previous: dir.join(PREVIOUS),
+        };
+
+        let writer = log_file.clone();
+        thread::Builder::new()
+            .name("log_file".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let mut interval = FLUSH_INTERVAL;
+                let mut failures = 0u32;
+                loop {
+                    thread::sleep(interval);
+                    let (lines, dropped) = {
+                        let mut pending = LOGGER.pending.lock().unwrap_or_else(|e| e.into_inner());
+                        (
+                            mem::take(&mut pending.lines),
+                            mem::take(&mut pending.dropped),
+                        )
+                    };
+                    if lines.is_empty() {
+                        continue;
+                    }
+                    // Logging about the log file would only add to what can't be written, so
+                    // what goes wrong is left for the console, once rather than every interval.
+                    // What couldn't be written is counted as dropped.
+                    match writer.append(&lines, dropped, max_bytes / 2) {
+                        Ok(()) if failures > 0 => {
+                            println!(
+                                "Writing the log file works again after {} failures",
+                                failures
+                            );
+                            failures = 0;
+                            interval = FLUSH_INTERVAL;
+                        }
+                        Ok(()) => {}
+                        Err(e) => {
+                            let mut pending =
+                                LOGGER.pending.lock().unwrap_or_else(|e| e.into_inner());
+                            pending.dropped += dropped + lines.len() as u32;
+                            drop(pending);
+                            if failures == 0 {
+                                println!("Writing the log file failed, backing off: {:?}", e);
+                            }
+                            failures += 1;
+                            interval = (interval * 2).min(MAX_BACKOFF);
+                        }
+                    }
+                }
+            })?;
+
+        log::info!("Logging to {}", log_file.current.display());
+        Ok(log_file)
+    }
+
+    fn append(&self, lines: &VecDeque<String>, dropped: u32, rotate_at: u64) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.current)?;
+        if dropped > 0 {
+            writeln!(file, "... {} lines dropped", dropped)?;
+        }
+        for line in lines {
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+
+        if file.metadata()?.len() >= rotate_at {
+            drop(file);
+            // Neither SPIFFS nor FAT will rename over a file
+            match fs::remove_file(&self.previous) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            fs::rename(&self.current, &self.previous)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, log_file: LogFile) -> Result<()> {
+    // Both files as one, oldest line first
+    server.fn_handler(
+        "/api/log",
+        Method::Get,
+        auth.protect(move |request| {
+            let mut files = Vec::new();
+            let mut length = 0;
+            for path in [&log_file.previous, &log_file.current] {
+                match File::open(path) {
+                    Ok(file) => {
+                        let size = file.metadata()?.len();
+                        length += size;
+                        files.push((file, size));
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return ApiError::new(500, "read_failed", e).send(request),
+                }
+            }
+
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", "text/plain; charset=utf-8"),
+                    ("Content-Length", &length.to_string()),
+                ],
+            )?;
+            let mut chunk = vec![0; READ_CHUNK];
+            let mut yielder = Yielder::new();
+            // Only as much as was there when the length was taken, the writer may have added
+            // more since
+            for (mut file, mut left) in files {
+                while left > 0 {
+                    let read = file.read(&mut chunk[..(left as usize).min(READ_CHUNK)])?;
+                    if read == 0 {
+                        break;
+                    }
+                    write_all_yielding(&mut response, &chunk[..read])?;
+                    left -= read as u64;
+                    yielder.tick();
+                }
+            }
+
+            Ok(())
+        }),
+    )?;
+
+    Ok(())
+}
@@ -1,11 +1,10 @@
-pub mod wifi;
-
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use edge_executor::LocalExecutor;
 use embedded_hal_async::delay::DelayUs;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
+        gpio::{AnyIOPin, AnyOutputPin},
         peripheral::Peripheral,
         peripherals::Peripherals,
         reset::{ResetReason, WakeupReason},
@@ -13,17 +12,66 @@ use esp_idf_svc::{
     },
     http::server::{Configuration, EspHttpServer},
     io::Write,
+    mdns::EspMdns,
+    nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
+    sys::cam::pixformat_t_PIXFORMAT_JPEG,
     wifi::EspWifi,
 };
 use log::{info, warn};
 use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 // use crate::camera::{Camera, CameraConfig, FrameSize};
-use crate::wifi::init_wifi;
-use esp_camera_rs::Camera;
+use tigercam::analysis::Analysis;
+use tigercam::auth::Auth;
+use tigercam::camera::{
+    grab_mode_from_name, BoardPreset, Camera, CameraBuilder, CameraConfig, CameraExt,
+};
+use tigercam::clip::{ClipSettings, MotionClips};
+use tigercam::continuous::Continuous;
+use tigercam::controller::Controller;
+use tigercam::crypto::FrameCipher;
+use tigercam::face::Faces;
+use tigercam::flash::Flash;
+use tigercam::frame_cache::FrameCache;
+use tigercam::history::{FrameHistory, Retention};
+use tigercam::http::{
+    parse_shot, query_param, read_body, send_avi, send_jpeg, send_shot, write_all_yielding,
+    write_json, ApiError,
+};
+use tigercam::lock::lock;
+use tigercam::log_file::LogFile;
+use tigercam::motion::{Motion, MotionSettings};
+use tigercam::night::{NightMode, NightThresholds, Schedule, Switching};
+use tigercam::overlay::{Corner, Layer, Overlay};
+use tigercam::pipeline::{lock_for_capture, ImageFormat, Pipeline, Shot};
+use tigercam::pir::{Pir, PirSettings};
+use tigercam::prewarm::Prewarm;
+use tigercam::qr::QrScanner;
+use tigercam::rate_limit::RateLimiter;
+use tigercam::recording::{Recorder, RecordingSettings};
+use tigercam::report::DailyReport;
+use tigercam::request_log::RequestLog;
+use tigercam::retention::{MediaRetention, RetentionSettings};
+use tigercam::rtp::RtpControl;
+use tigercam::sdcard::SdCard;
+use tigercam::sensor::{framesize_from_name, pixformat_name, Sensor, WhiteBalanceMode};
+use tigercam::snapshot::MotionSnapshot;
+use tigercam::storage::Storage;
+use tigercam::tamper::{Tamper, TamperSettings};
+use tigercam::timelapse::TimeLapse;
+use tigercam::webhook::{Attachment, MotionWebhook};
+use tigercam::wifi::init_wifi;
+use tigercam::{
+    api, burst, face, flash, log_file, mdns, motion, next_frame, onvif, pir, provision, qr,
+    recording, retention, rtp, sdcard, snapshot, storage, stream, tamper, timelapse, wifi,
+};
 
 #[toml_cfg::toml_config]
 pub struct Config {
@@ -31,154 +79,1344 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    // Camera wiring: "ai_thinker", "m5stack_timer_cam", "ttgo_t_camera", "freenove_wrover"
+    // or "esp32_s3_eye". It won't boot with a pin below, or the SD card's, on the camera bus,
+    // the defaults are the AI-Thinker's.
+    #[default("ai_thinker")]
+    board: &'static str,
+    // Hold this GPIO low at boot to ignore the stored network and start the setup AP,
+    // -1 disables it
+    #[default(13)]
+    safe_mode_pin: i32,
+    // The flash LED, controllable through /api/flash. -1 if the board doesn't have one.
+    #[default(4)]
+    flash_pin: i32,
+    // Light the flash for every snapshot, also changeable through /api/flash
+    #[default(false)]
+    flash_auto: bool,
+    // Mount the microSD slot in 1-bit mode, which leaves GPIO4 to the flash and 12 and 13 free.
+    // POST /api/save keeps a still on it.
+    #[default(false)]
+    sd_card: bool,
+    // Record to the SD card at this rate around the clock, 0 not to. It's kept in segments of
+    // record_segment_s, the oldest deleted once they add up to record_max_mb.
+    #[default(0)]
+    record_fps: u32,
+    #[default(300)]
+    record_segment_s: u32,
+    #[default(1024)]
+    record_max_mb: u32,
+    // Take time-lapses set going through /api/timelapse, to the SD card or a URL
+    #[default(false)]
+    timelapse: bool,
+    // What's kept on the SD card, 0 leaves a rule out. Dated media and held time-lapse stills
+    // older than retention_max_age_days are deleted, the oldest once it all adds up to
+    // retention_max_mb or the card has less than retention_min_free_mb free, and motion clips
+    // past the newest retention_keep_clips.
+    #[default(0)]
+    retention_max_age_days: u32,
+    #[default(0)]
+    retention_max_mb: u32,
+    #[default(0)]
+    retention_min_free_mb: u32,
+    #[default(0)]
+    retention_keep_clips: u32,
+    // Keep what's logged in a file, "card" or "flash", and serve it at /api/log. It's rotated so
+    // the two files it's kept in stay under log_file_kb together.
+    #[default("")]
+    log_file: &'static str,
+    #[default(256)]
+    log_file_kb: u32,
+    // Leave the user empty to disable authentication, NVS values override these
+    #[default("")]
+    http_user: &'static str,
+    #[default("")]
+    http_pass: &'static str,
+    // "basic" or "digest", digest avoids sending the password in the clear
+    #[default("basic")]
+    http_auth: &'static str,
+    // Accepted in X-Api-Key or ?key=, changeable at runtime through /api/key
+    #[default("")]
+    api_key: &'static str,
+    // "ip:port" to send RTP/JPEG to, empty leaves it unset
+    #[default("")]
+    rtp_dest: &'static str,
+    #[default(false)]
+    rtp_enabled: bool,
+    #[default(10)]
+    rtp_fps: u32,
+    // MJPEG stream at http://<camera>:<stream_port>/stream, 0 disables it
+    #[default(81)]
+    stream_port: u16,
+    // The most frames a second any stream client gets, and the continuous capture task
+    // takes. Clients can ask for fewer with /stream?fps=.
+    #[default(10)]
+    stream_fps: u32,
+    // For a camera mounted upside down turn both on, also changeable through /api/camera
+    #[default(false)]
+    vflip: bool,
+    #[default(false)]
+    hmirror: bool,
+    // Send the sensor's colour bar test pattern instead of the picture, for telling wiring
+    // problems from exposure ones during bring-up. Also changeable through /api/camera.
+    #[default(false)]
+    colorbar: bool,
+    // "auto", "sunny", "cloudy", "office" or "home", also changeable through /api/camera
+    #[default("auto")]
+    wb_mode: &'static str,
+    // Clockwise, 0, 90, 180 or 270. 180 on a JPEG sensor is done with vflip and hmirror, the
+    // rest add a rotate stage, which needs an RGB or grayscale pixel format.
+    #[default(0)]
+    rotation: u32,
+    // Drawn into the top left corner of every frame, lines separated by ';'
+    #[default("")]
+    overlay_text: &'static str,
+    // Capture pipeline stages, e.g. "crop:0,0,320,240;rotate180", "downscale:2" or
+    // "timestamp:top_right"
+    #[default("")]
+    pipeline: &'static str,
+    // Our JPEG encoder's quality, 1-100 and higher is better. Sensors producing JPEG have
+    // their own, both are changeable through /api/camera and per capture with ?quality=.
+    #[default(80)]
+    jpeg_quality: u8,
+    // Largest encoded frame in bytes, bigger ones get redone smaller. 0 means no limit.
+    #[default(0)]
+    jpeg_budget: u32,
+    // "auto", "when_empty" or "latest", also changeable through /api/camera
+    #[default("auto")]
+    grab_mode: &'static str,
+    // How many frame buffers the driver gets and how it fills them, "when_empty" or
+    // "latest". Latest needs two or more. Both changeable through /api/camera.
+    #[default(2)]
+    fb_count: u32,
+    #[default("when_empty")]
+    driver_grab_mode: &'static str,
+    // Answer / from the last encoded frame while it's younger than this, with an ETag so
+    // pollers get a 304 until there's a new one. 0 always captures. /nextframe works either way.
+    #[default(0)]
+    frame_cache_ms: u32,
+    // Answer the basic ONVIF device and media requests so NVRs can find the stream
+    #[default(false)]
+    onvif: bool,
+    // Requests per second each client gets on average, 0 doesn't limit them at all
+    #[default(0)]
+    rate_limit: u32,
+    // How many requests a client can get through at once after it's been quiet
+    #[default(5)]
+    rate_limit_burst: u32,
+    // How many recent requests /api/requests remembers, 0 only logs them
+    #[default(0)]
+    request_log: u32,
+    // Start capturing as soon as a client connects instead of once its request is parsed
+    #[default(false)]
+    prewarm: bool,
+    // Capture back to back into a pair of buffers while the stream is watched, so slow
+    // stream clients don't hold the camera up
+    #[default(false)]
+    continuous_capture: bool,
+    // Switch to a night exposure profile when the average frame brightness, 0-255, drops
+    // below this, and back once it's above it plus the hysteresis. 0 disables night mode.
+    #[default(0)]
+    night_threshold: u8,
+    #[default(30)]
+    night_hysteresis: u8,
+    // How often the brightness is metered
+    #[default(10)]
+    night_meter_s: u32,
+    // Night by the clock instead, like "19:30-06:45" in local time. Takes over from the
+    // threshold when set.
+    #[default("")]
+    night_schedule: &'static str,
+    // Minutes to add to UTC for local time, for the night schedule and the dates media is
+    // filed under on the SD card
+    #[default(0)]
+    utc_offset_minutes: i32,
+    // Lit while in night mode, -1 if there's no IR LED
+    #[default(-1)]
+    ir_led_pin: i32,
+    // High in night mode to take the IR-cut filter out, -1 if there's none
+    #[default(-1)]
+    ir_cut_pin: i32,
+    // Compare frames for motion, state at /api/motion. A cell of the 8x8 grid has changed
+    // when its pixels are further than the sensitivity from before on average, 0-255, and
+    // it's motion when at least the threshold's worth of cells have.
+    #[default(false)]
+    motion: bool,
+    #[default(20)]
+    motion_sensitivity: u8,
+    #[default(3)]
+    motion_threshold: u32,
+    #[default(250)]
+    motion_interval_ms: u32,
+    // How long motion has to go on before it raises an event, 0 for the first frame with it.
+    // /api/motion/config changes this, the sensitivity, threshold and cooldown at runtime.
+    #[default(0)]
+    motion_debounce_ms: u32,
+    // Least time between events while motion goes on
+    #[default(10)]
+    motion_cooldown_s: u32,
+    // Cells of the grid to watch, a row of 0s and 1s per row separated by ';'. Empty watches
+    // them all. Setting it through /api/motion/mask overrides this.
+    #[default("")]
+    motion_mask: &'static str,
+    // Take a still at this size on every motion event, served at /api/motion/snapshot. Empty
+    // takes none.
+    #[default("UXGA")]
+    motion_snapshot: &'static str,
+    // Encoder quality of the snapshot, 1-100
+    #[default(90)]
+    motion_snapshot_quality: u8,
+    // Save a clip to the SD card for every motion event, going on this long after it. It starts
+    // motion_clip_pre_s before, out of the frame history, so history_seconds has to cover both.
+    // Clips are plain AVI, so they can't be had together with encrypt_frames.
+    #[default(0)]
+    motion_clip_s: u32,
+    #[default(5)]
+    motion_clip_pre_s: u32,
+    // Where to POST each motion event as JSON, empty for nowhere
+    #[default("")]
+    motion_webhook: &'static str,
+    // What the event carries of the snapshot: "link" to /api/motion/snapshot, "inline" for
+    // the JPEG as base64 or "none"
+    #[default("link")]
+    motion_webhook_snapshot: &'static str,
+    // A PIR sensor's output, -1 if there's none. Each rising edge captures a burst of frames,
+    // served at /api/pir/frame, and raises a motion event whether or not frames are compared.
+    #[default(-1)]
+    pir_pin: i32,
+    #[default(3)]
+    pir_frames: u32,
+    #[default(500)]
+    pir_interval_ms: u32,
+    // Least time between triggers
+    #[default(10)]
+    pir_cooldown_s: u32,
+    // Look for faces this often, 0 never. Needs a build with the face-detection feature.
+    // Found faces are at /api/faces and raise a motion event.
+    #[default(0)]
+    face_interval_ms: u32,
+    // Least time between events while faces stay in view
+    #[default(30)]
+    face_cooldown_s: u32,
+    // Draw boxes around the faces in every frame
+    #[default(false)]
+    face_outlines: bool,
+    // Raise an event when the picture stays darker than tamper_dark, or flatter than
+    // tamper_uniform, for tamper_hold_s. Both are luma, 0-255.
+    #[default(false)]
+    tamper: bool,
+    #[default(16)]
+    tamper_dark: u8,
+    #[default(6)]
+    tamper_uniform: u8,
+    #[default(5)]
+    tamper_hold_s: u32,
+    // Look for QR codes this often, 0 only when /api/qr asks. The last ones read are at
+    // /api/qr/latest.
+    #[default(0)]
+    qr_scan_ms: u32,
+    // Aggregate the other cameras on the LAN behind /cams/<name>/capture
+    #[default(false)]
+    controller: bool,
+    // Cameras that can't be found over mDNS, "front=192.168.1.20:80;back=192.168.1.21"
+    #[default("")]
+    cams: &'static str,
+    // Time based frame history, takes priority over history_frames when non-zero
+    #[default(0)]
+    history_seconds: u32,
+    #[default(2)]
+    history_fps: u32,
+    #[default(0)]
+    history_frames: u32,
+    // AES-GCM seal the frames held in the history buffer, the key is kept in NVS. Only those,
+    // nothing written to the SD card or uploaded is sealed.
+    #[default(false)]
+    encrypt_frames: bool,
+    // 64 hex digits, only used when NVS has no key yet. Empty generates a random one.
+    #[default("")]
+    frame_key: &'static str,
+    // Compile a summary every 24 hours, served at /report and /api/report. With the SD card
+    // each one is kept under reports/ along with a thumbnail for the first motion events.
+    #[default(false)]
+    daily_report: bool,
+    // Where to POST each summary as JSON, empty to only keep it on the device
+    #[default("")]
+    report_webhook: &'static str,
 }
 
-fn init_http(cam: Arc<Mutex<Camera>>) -> Result<EspHttpServer> {
-    let mut server = EspHttpServer::new(&Configuration::default())?;
+// What the main server has handlers for, None for whatever is turned off
+struct Services {
+    rtp: RtpControl,
+    cache: FrameCache,
+    controller: Option<Controller>,
+    history: Option<FrameHistory>,
+    prewarm: Option<Prewarm>,
+    report: Option<DailyReport>,
+    flash: Option<Flash>,
+    motion: Option<Motion>,
+    snapshot: Option<MotionSnapshot>,
+    pir: Option<Pir>,
+    faces: Option<Faces>,
+    qr: Option<QrScanner>,
+    tamper: Option<Tamper>,
+    night: Option<NightMode>,
+    sdcard: Option<SdCard>,
+    recorder: Option<Recorder>,
+    timelapse: Option<TimeLapse>,
+    retention: Option<MediaRetention>,
+    storage: Option<Storage>,
+    log_file: Option<LogFile>,
+}
 
-    server.fn_handler("/", esp_idf_svc::http::Method::Get, move |request| {
-        let mut time = Instant::now();
+fn init_http(
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    auth: Auth,
+    services: Services,
+    nvs: EspDefaultNvsPartition,
+    reset_reason: ResetReason,
+) -> Result<EspHttpServer> {
+    let Services {
+        rtp,
+        cache,
+        controller,
+        history,
+        prewarm,
+        report,
+        flash,
+        motion,
+        snapshot,
+        pir,
+        faces,
+        qr,
+        tamper,
+        night,
+        sdcard,
+        recorder,
+        timelapse,
+        retention,
+        storage,
+        log_file,
+    } = services;
 
-        let lock = cam.lock().unwrap(); // If a thread gets poisoned we're just fucked anyways
-        let fb = match lock.get_framebuffer() {
-            Some(fb) => fb,
-            None => {
-                let mut response = request.into_status_response(500)?;
-                let _ = writeln!(response, "Error: Unable to get framebuffer");
-                return Ok(());
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    if let Some(prewarm) = &prewarm {
+        prewarm.install(&server)?;
+    }
+
+    let log = auth.request_log().clone();
+    if log.keeps_entries() {
+        server.fn_handler(
+            "/api/requests",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| write_json(request, 200, &log.entries())),
+        )?;
+    }
+
+    if CONFIG.onvif {
+        onvif::register(&mut server, &auth, cam.clone(), CONFIG.stream_port)?;
+    }
+
+    if let Some(flash) = &flash {
+        flash::register(&mut server, &auth, flash.clone())?;
+    }
+
+    if let Some(motion) = motion {
+        motion::register(&mut server, &auth, motion)?;
+    }
+    if let Some(snapshot) = snapshot {
+        snapshot::register(&mut server, &auth, snapshot)?;
+    }
+    if let Some(pir) = pir {
+        pir::register(&mut server, &auth, pir)?;
+    }
+    if let Some(faces) = faces {
+        face::register(&mut server, &auth, faces)?;
+    }
+    if let Some(tamper) = tamper {
+        tamper::register(&mut server, &auth, tamper)?;
+    }
+    if let Some(sdcard) = sdcard {
+        sdcard::register(
+            &mut server,
+            &auth,
+            cam.clone(),
+            pipeline.clone(),
+            flash.clone(),
+            sdcard,
+        )?;
+    }
+    if let Some(recorder) = recorder {
+        recording::register(&mut server, &auth, recorder)?;
+    }
+    if let Some(timelapse) = timelapse {
+        timelapse::register(&mut server, &auth, timelapse)?;
+    }
+    if let Some(retention) = retention {
+        retention::register(&mut server, &auth, retention)?;
+    }
+    storage::register(&mut server, &auth, storage, CONFIG.stream_port)?;
+    if let Some(log_file) = log_file {
+        log_file::register(&mut server, &auth, log_file)?;
+    }
+    provision::register(&mut server, &auth, nvs)?;
+
+    burst::register(&mut server, &auth, cam.clone(), pipeline.clone())?;
+    qr::register(&mut server, &auth, cam.clone(), pipeline.clone(), qr)?;
+    next_frame::register(
+        &mut server,
+        &auth,
+        cam.clone(),
+        pipeline.clone(),
+        cache.clone(),
+    )?;
+
+    api::register(
+        &mut server,
+        &auth,
+        cam.clone(),
+        pipeline.clone(),
+        night,
+        reset_reason,
+    )?;
+
+    let snapshot_cam = cam.clone();
+    let snapshot_pipeline = pipeline.clone();
+    let snapshot_flash = flash.clone();
+    let capture_buf = Mutex::new(Vec::new());
+    server.fn_handler(
+        "/",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let started = Instant::now();
+
+            let accept = request.header("Accept").map(ImageFormat::from_accept);
+            if accept == Some(ImageFormat::Bmp) {
+                let shot = Shot {
+                    format: ImageFormat::Bmp,
+                    ..Default::default()
+                };
+                return send_shot(
+                    request,
+                    &snapshot_pipeline,
+                    &snapshot_cam,
+                    snapshot_flash.as_ref(),
+                    shot,
+                );
             }
-        };
 
-        let jpeg = match fb.data_as_jpeg(80) {
-            Ok(jpeg) => jpeg,
-            Err(e) => {
-                let mut response = request.into_status_response(500)?;
-                let _ = writeln!(response, "Error: {:#?}", e);
-                return Ok(());
+            if let Some(frame) = cache.fresh() {
+                let etag = frame.etag_header();
+                return send_jpeg(request, &frame.jpeg, Some(&etag), "cache;dur=0");
             }
-        };
 
-        info!("Took {}ms to capture_jpeg", time.elapsed().as_millis());
-
-        // Send the image
-        time = Instant::now();
-        let mut response = request.into_response(
-            200,
-            None,
-            &[
-                ("Content-Type", "image/jpeg"),
-                ("Content-Length", &jpeg.len().to_string()),
-            ],
+            // A prewarmed frame went through the sinks before we knew who it was for, so it
+            // goes out without an ETag
+            if let Some((jpeg, saved)) = prewarm.as_ref().and_then(Prewarm::take) {
+                let timing = format!(
+                    "capture;dur={}, prewarm;desc=\"saved\";dur={}",
+                    started.elapsed().as_millis(),
+                    saved.as_millis()
+                );
+                return send_jpeg(request, &jpeg, None, &timing);
+            }
+
+            // Kept from one capture to the next, the camera and the pipeline are let go once
+            // the frame is in it rather than held while a slow client takes it
+            let mut buf = lock(&capture_buf);
+            let captured = lock_for_capture(&snapshot_pipeline, &snapshot_cam).and_then(
+                |(mut pipeline, cam)| {
+                    let _pulse = snapshot_flash
+                        .as_ref()
+                        .map(|f| f.pulse(&*cam))
+                        .transpose()?;
+                    let len = pipeline.run_into(&*cam, &mut buf)?;
+                    // Looked up while we still hold the pipeline, so it's for this frame
+                    Ok((len, cache.latest().map(|f| f.etag_header())))
+                },
+            );
+            let (len, etag) = match captured {
+                Ok(captured) => captured,
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            // The request log has the total, this tells the client how much of it was capture
+            let timing = format!("capture;dur={}", started.elapsed().as_millis());
+            send_jpeg(request, &buf[..len], etag.as_deref(), &timing)
+        }),
+    )?;
+
+    // Link checkers and health checks only want the headers, don't capture for them
+    for (uri, takes_params) in [("/", false), ("/capture", true)] {
+        let head_cam = cam.clone();
+        let head_pipeline = pipeline.clone();
+        server.fn_handler(
+            uri,
+            esp_idf_svc::http::Method::Head,
+            auth.protect(move |request| {
+                let accept = request.header("Accept");
+                let shot = match takes_params.then(|| parse_shot(request.uri(), accept)) {
+                    Some(Ok(shot)) => shot,
+                    Some(Err(e)) => return ApiError::bad_request(e).send(request),
+                    None => Shot {
+                        format: accept.map_or(ImageFormat::Jpeg, ImageFormat::from_accept),
+                        ..Default::default()
+                    },
+                };
+
+                let length = lock_for_capture(&head_pipeline, &head_cam)
+                    .and_then(|(pipeline, _cam)| Ok(pipeline.estimate_size(&Sensor::get()?, shot)));
+                let length = match length {
+                    Ok(length) => length,
+                    Err(e) => return ApiError::from(&e).send(request),
+                };
+
+                request.into_response(
+                    200,
+                    None,
+                    &[
+                        ("Content-Type", shot.format.content_type()),
+                        ("Content-Length", &length.to_string()),
+                    ],
+                )?;
+
+                Ok(())
+            }),
         )?;
+    }
 
-        let _ = response.write_all(jpeg);
-        info!("Took {}ms to send image", time.elapsed().as_millis());
+    let shot_cam = cam.clone();
+    let shot_pipeline = pipeline.clone();
+    let shot_flash = flash.clone();
+    server.fn_handler(
+        "/capture",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let shot = match parse_shot(request.uri(), request.header("Accept")) {
+                Ok(shot) => shot,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
 
-        Ok(())
-    })?;
+            send_shot(
+                request,
+                &shot_pipeline,
+                &shot_cam,
+                shot_flash.as_ref(),
+                shot,
+            )
+        }),
+    )?;
+
+    let raw_cam = cam.clone();
+    let raw_pipeline = pipeline.clone();
+    server.fn_handler(
+        "/raw",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let frame = lock_for_capture(&raw_pipeline, &raw_cam)
+                .and_then(|(mut pipeline, cam)| pipeline.run_raw(&*cam));
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", "application/octet-stream"),
+                    ("Content-Length", &frame.data.len().to_string()),
+                    ("X-Width", &frame.width.to_string()),
+                    ("X-Height", &frame.height.to_string()),
+                    ("X-Pixel-Format", pixformat_name(frame.format)),
+                    ("X-Timestamp-Us", &frame.timestamp.as_micros().to_string()),
+                ],
+            )?;
+            let _ = write_all_yielding(&mut response, &frame.data);
+
+            Ok(())
+        }),
+    )?;
+
+    server.fn_handler(
+        "/rtp",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let uri = request.uri().to_string();
+
+            match query_param(&uri, "enable") {
+                Some("1") | Some("true") => {
+                    let dest = match query_param(&uri, "dest").map(str::parse::<SocketAddr>) {
+                        Some(Ok(dest)) => Some(dest),
+                        Some(Err(e)) => {
+                            return ApiError::bad_request(format!("Invalid destination: {}", e))
+                                .send(request);
+                        }
+                        None => None,
+                    };
+
+                    if let Err(e) = rtp.enable(dest) {
+                        return ApiError::bad_request(e).send(request);
+                    }
+                }
+                Some("0") | Some("false") => rtp.disable(),
+                _ => {}
+            }
+
+            let mut response = request.into_ok_response()?;
+            match rtp.destination() {
+                Some(dest) => {
+                    let _ = writeln!(response, "RTP streaming to {}", dest);
+                }
+                None => {
+                    let _ = writeln!(response, "RTP streaming disabled");
+                }
+            }
+
+            Ok(())
+        }),
+    )?;
+
+    // Replaces the API key, so it needs credentials or a key configured already. The first one
+    // comes from `api_key` in the config.
+    let key_auth = auth.clone();
+    server.fn_handler(
+        "/api/key",
+        esp_idf_svc::http::Method::Post,
+        auth.require(move |mut request| {
+            let key =
+                match read_body(&mut request, 128).and_then(|body| Ok(String::from_utf8(body)?)) {
+                    Ok(key) => key,
+                    Err(e) => return ApiError::bad_request(e).send(request),
+                };
+
+            if let Err(e) = key_auth.set_api_key(key.trim()) {
+                return ApiError::from(&e).send(request);
+            }
+            request.into_ok_response()?;
+
+            Ok(())
+        }),
+    )?;
+
+    if let Some(history) = history {
+        let avi_history = history.clone();
+        server.fn_handler(
+            "/history.avi",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| {
+                // Playback speed, a time-lapse usually wants to go faster than it was taken
+                let fps = match query_param(request.uri(), "fps").map(str::parse::<u32>) {
+                    Some(Ok(fps)) if (1..=60).contains(&fps) => fps,
+                    Some(_) => {
+                        return ApiError::bad_request("fps must be between 1 and 60").send(request)
+                    }
+                    None => 10,
+                };
+                send_avi(request, &avi_history, fps)
+            }),
+        )?;
+
+        server.fn_handler(
+            "/history",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| {
+                // 0 is the newest frame, counting backwards from there
+                let index = query_param(request.uri(), "index")
+                    .and_then(|i| i.parse().ok())
+                    .unwrap_or(0);
+
+                let Some(frame) = history.get(index)? else {
+                    return ApiError::not_found(format!(
+                        "Only {} frames in history",
+                        history.count()
+                    ))
+                    .send(request);
+                };
+
+                let mut response = request.into_response(
+                    200,
+                    None,
+                    &[
+                        ("Content-Type", "image/jpeg"),
+                        ("Content-Length", &frame.jpeg.len().to_string()),
+                        (
+                            "X-Frame-Age-Ms",
+                            &frame.taken.elapsed().as_millis().to_string(),
+                        ),
+                    ],
+                )?;
+                let _ = write_all_yielding(&mut response, &frame.jpeg);
+
+                Ok(())
+            }),
+        )?;
+    }
+
+    if let Some(report) = report {
+        let html_report = report.clone();
+        server.fn_handler(
+            "/report",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| {
+                let Some(summary) = html_report.latest() else {
+                    return ApiError::not_found("No summary has been compiled yet").send(request);
+                };
+
+                let mut response =
+                    request.into_response(200, None, &[("Content-Type", "text/html")])?;
+                let _ = response.write_all(summary.to_html().as_bytes());
+
+                Ok(())
+            }),
+        )?;
+
+        server.fn_handler(
+            "/api/report",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| match report.latest() {
+                Some(summary) => write_json(request, 200, &summary),
+                None => ApiError::not_found("No summary has been compiled yet").send(request),
+            }),
+        )?;
+    }
+
+    if let Some(controller) = controller {
+        let list_controller = controller.clone();
+        server.fn_handler(
+            "/cams",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| {
+                let mut response = request.into_ok_response()?;
+                for peer in list_controller.peers() {
+                    let _ = writeln!(
+                        response,
+                        "{} {} /cams/{}/capture",
+                        peer.name, peer.addr, peer.name
+                    );
+                }
+
+                Ok(())
+            }),
+        )?;
+
+        server.fn_handler(
+            "/cams/*",
+            esp_idf_svc::http::Method::Get,
+            auth.protect(move |request| {
+                let uri = request.uri().to_string();
+                let path = uri.split('?').next().unwrap_or_default();
+                let peer = path
+                    .strip_prefix("/cams/")
+                    .and_then(|rest| rest.strip_suffix("/capture"))
+                    .and_then(|name| controller.peer(name));
+
+                match peer {
+                    Some(peer) => controller.proxy_capture(&peer, request),
+                    None => ApiError::not_found("Unknown camera").send(request),
+                }
+            }),
+        )?;
+    }
 
     Ok(server)
 }
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Nothing is queued for a file that's never going to be written
+    log_file::init(!CONFIG.log_file.is_empty());
 
-    self_test()?;
+    let reset_reason = self_test()?;
 
     let executor: LocalExecutor = Default::default();
-    edge_executor::block_on(executor.run(async_main()))
+    edge_executor::block_on(executor.run(async_main(reset_reason)))
 }
 
-fn self_test() -> Result<()> {
+fn self_test() -> Result<ResetReason> {
     let reset_reason = ResetReason::get();
     info!("Last reset was due to {:#?}", reset_reason);
     let wakeup_reason = WakeupReason::get();
     info!("Last wakeup was due to {:#?}", wakeup_reason);
 
-    Ok(())
+    Ok(reset_reason)
 }
 
-async fn async_main() -> Result<()> {
+async fn async_main(reset_reason: ResetReason) -> Result<()> {
     let mut peripherals = Peripherals::take()?;
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
 
-    let gpio26 = (&mut peripherals.pins.gpio26).into_ref().map_into();
-    let gpio27 = (&mut peripherals.pins.gpio27).into_ref().map_into();
-
-    let camera = esp_camera_rs::Camera::new(
-        &mut peripherals.pins.gpio32,
-        None, // Fake pin
-        &mut peripherals.pins.gpio0,
-        &mut peripherals.pins.gpio5,
-        &mut peripherals.pins.gpio18,
-        &mut peripherals.pins.gpio19,
-        &mut peripherals.pins.gpio21,
-        &mut peripherals.pins.gpio36,
-        &mut peripherals.pins.gpio39,
-        &mut peripherals.pins.gpio34,
-        &mut peripherals.pins.gpio35,
-        &mut peripherals.pins.gpio25,
-        &mut peripherals.pins.gpio23,
-        &mut peripherals.pins.gpio22,
-        Some(gpio26),
-        Some(gpio27),
-    )?;
+    // Before anything drives a pin, the board's camera bus may have some the config wants too
+    let board: BoardPreset = CONFIG.board.parse()?;
+    let pins = board.pins();
+    let mut claimed = vec![
+        ("safe_mode_pin", CONFIG.safe_mode_pin),
+        ("flash_pin", CONFIG.flash_pin),
+        ("ir_led_pin", CONFIG.ir_led_pin),
+        ("ir_cut_pin", CONFIG.ir_cut_pin),
+        ("pir_pin", CONFIG.pir_pin),
+    ];
+    if CONFIG.sd_card {
+        claimed.extend(sdcard::PINS.map(|pin| ("sd_card", pin)));
+    }
+    if let Some((name, pin)) = claimed.into_iter().find(|&(_, pin)| pins.uses(pin)) {
+        bail!(
+            "{} needs GPIO{}, which the {:?} board wires to the camera",
+            name,
+            pin,
+            board
+        );
+    }
+
+    // Checked before the camera is touched, in case the camera is what's broken
+    if provision::forced(CONFIG.safe_mode_pin)? {
+        warn!("Safe mode pin is held low, starting the setup access point");
+        return provision::run(peripherals.modem, sysloop, nvs);
+    }
+
+    let (wifi_ssid, wifi_psk) = match provision::stored_credentials(nvs.clone())? {
+        Some(credentials) => credentials,
+        None if CONFIG.wifi_ssid.is_empty() => {
+            warn!("No WiFi network configured, starting the setup access point");
+            return provision::run(peripherals.modem, sysloop, nvs);
+        }
+        None => (CONFIG.wifi_ssid.to_string(), CONFIG.wifi_psk.to_string()),
+    };
+
+    info!("Camera wired as {:?}", board);
+    let buffers = CameraConfig {
+        fb_count: CONFIG.fb_count as usize,
+        grab_mode: grab_mode_from_name(CONFIG.driver_grab_mode)?,
+        ..CameraConfig::DRIVER_DEFAULT
+    };
+    let mut builder = unsafe { CameraBuilder::for_board(board) };
+    // Only worth bringing the driver up a second time if they aren't its defaults
+    if buffers.fb_count != CameraConfig::DRIVER_DEFAULT.fb_count
+        || buffers.grab_mode != CameraConfig::DRIVER_DEFAULT.grab_mode
+    {
+        builder = builder.config(buffers);
+    }
+    let camera = builder.build()?;
+    let sensor_info = camera.sensor_info()?;
+    info!(
+        "Found a {:?} sensor, PID {:#06x} MID {:#04x}{:02x}",
+        sensor_info.model, sensor_info.pid, sensor_info.midh, sensor_info.midl
+    );
+
+    // Done in the sensor, so it costs nothing per frame unlike a rotate stage
+    let sensor = Sensor::get()?;
+    let jpeg_sensor = sensor.pixformat() == pixformat_t_PIXFORMAT_JPEG;
+    let sensor_rotates = CONFIG.rotation == 180 && jpeg_sensor;
+    // A rotate stage can't do anything with JPEG, every frame would fail rather than the boot
+    if jpeg_sensor && matches!(CONFIG.rotation, 90 | 270) {
+        bail!(
+            "A rotation of {} needs an RGB or grayscale pixel format, the sensor sends JPEG",
+            CONFIG.rotation
+        );
+    }
+    sensor.set_vflip(CONFIG.vflip != sensor_rotates)?;
+    sensor.set_hmirror(CONFIG.hmirror != sensor_rotates)?;
+    if CONFIG.colorbar {
+        warn!("Sending the colour bar test pattern instead of the picture");
+        sensor.set_colorbar(true)?;
+    }
+    let wb_mode: WhiteBalanceMode = CONFIG.wb_mode.parse()?;
+    if wb_mode != WhiteBalanceMode::Auto {
+        sensor.set_wb_mode(wb_mode)?;
+    }
 
     let camera_mutex = Arc::new(Mutex::new(camera));
 
+    let flash = if CONFIG.flash_pin >= 0 {
+        Some(Flash::new(
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            unsafe { AnyOutputPin::new(CONFIG.flash_pin) },
+            CONFIG.flash_auto,
+        )?)
+    } else {
+        None
+    };
+
+    // Flashed without partitions.csv there's no partition for it, which shouldn't stop the camera
+    let storage = match Storage::mount() {
+        Ok(storage) => Some(storage),
+        Err(e) => {
+            warn!("No flash storage: {:?}", e);
+            None
+        }
+    };
+    // Nor should a missing or unreadable card, whatever would have used it goes without
+    let sdcard = if CONFIG.sd_card {
+        match SdCard::mount(CONFIG.utc_offset_minutes) {
+            Ok(sdcard) => Some(sdcard),
+            Err(e) => {
+                warn!("No SD card: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(sdcard) = &sdcard {
+        sdcard.watch()?;
+    }
+    let log_bytes = CONFIG.log_file_kb as u64 * 1024;
+    let log_file = match (CONFIG.log_file, &sdcard, &storage) {
+        ("", _, _) => None,
+        ("card", Some(sdcard), _) => {
+            let dir = sdcard.path(log_file::CARD_DIR)?;
+            fs::create_dir_all(&dir)?;
+            Some(LogFile::start(&dir, log_bytes)?)
+        }
+        ("flash", _, Some(_)) => Some(LogFile::start(Path::new(storage::MOUNT_POINT), log_bytes)?),
+        ("card", None, _) if CONFIG.sd_card => None,
+        ("card", None, _) => bail!("A log file on the card needs sd_card"),
+        ("flash", _, None) => bail!("A log file in flash needs the storage partition"),
+        (other, _, _) => bail!("Unknown log file destination {}", other),
+    };
+
+    let mut pipeline = Pipeline::new(CONFIG.jpeg_quality);
+    if CONFIG.rotation == 0 || sensor_rotates {
+        pipeline.configure(CONFIG.pipeline)?;
+    } else {
+        pipeline.configure(&format!("{};rotate:{}", CONFIG.pipeline, CONFIG.rotation))?;
+    }
+    pipeline.set_budget((CONFIG.jpeg_budget > 0).then_some(CONFIG.jpeg_budget as usize));
+    pipeline.set_grab_mode(CONFIG.grab_mode.parse()?);
+    let overlay = Overlay::new();
+    if !CONFIG.overlay_text.is_empty() {
+        overlay.add(Layer::Text {
+            corner: Corner::TopLeft,
+            lines: CONFIG.overlay_text.split(';').map(str::to_string).collect(),
+            scale: 1,
+        });
+    }
+    pipeline.set_overlay(overlay.clone());
+    let cache = FrameCache::new(Duration::from_millis(CONFIG.frame_cache_ms as u64));
+    pipeline.add_sink(Box::new(cache.clone()));
+    // Motion and tamper look at the stream's frames rather than taking their own when it's on
+    // and the sensor isn't encoding JPEG, see `Analysis`
+    let analysis = Analysis::new();
+    if CONFIG.motion || CONFIG.tamper {
+        pipeline.set_analysis(analysis.clone());
+    }
+    let pipeline = Arc::new(Mutex::new(pipeline));
+
+    let fallback_psk = provision::fallback_password(nvs.clone())?;
     let wifi = init_wifi(
-        CONFIG.wifi_ssid,
-        CONFIG.wifi_psk,
+        &wifi_ssid,
+        &wifi_psk,
+        &fallback_psk,
         &mut peripherals.modem,
         sysloop.clone(),
+        nvs.clone(),
     )
     .await?;
 
-    init_http(camera_mutex)?;
+    let cipher = if CONFIG.encrypt_frames {
+        Some(FrameCipher::load(nvs.clone(), CONFIG.frame_key)?)
+    } else {
+        None
+    };
 
-    main_loop(peripherals.timer00, wifi, sysloop).await
+    let mut auth = Auth::load(
+        nvs.clone(),
+        CONFIG.http_auth.parse()?,
+        CONFIG.http_user,
+        CONFIG.http_pass,
+        CONFIG.api_key,
+    )?
+    .with_request_log(RequestLog::new(CONFIG.request_log as usize));
+    if CONFIG.rate_limit > 0 {
+        auth = auth.with_rate_limit(RateLimiter::new(CONFIG.rate_limit, CONFIG.rate_limit_burst));
+    }
+    auth.set_exposed(wifi::in_fallback(&wifi));
+    let fallback_auth = auth.clone();
+
+    let rtp_dest = match CONFIG.rtp_dest {
+        "" => None,
+        dest => Some(dest.parse()?),
+    };
+    let rtp = rtp::start(
+        camera_mutex.clone(),
+        pipeline.clone(),
+        rtp_dest,
+        CONFIG.rtp_enabled,
+        CONFIG.rtp_fps,
+    )?;
+
+    let retention = if CONFIG.history_seconds > 0 {
+        Some(Retention::Duration {
+            window: Duration::from_secs(CONFIG.history_seconds as u64),
+            fps: CONFIG.history_fps,
+        })
+    } else if CONFIG.history_frames > 0 {
+        Some(Retention::Frames(CONFIG.history_frames as usize))
+    } else {
+        None
+    };
+    let history = retention
+        .map(|r| FrameHistory::start(camera_mutex.clone(), pipeline.clone(), r, cipher.clone()))
+        .transpose()?;
+
+    // Keeps the clock set for timestamp overlays, it stops syncing when dropped
+    let _sntp = EspSntp::new_default()?;
+
+    // Like the server, the responder stops when this is dropped
+    let mut mdns = EspMdns::take()?;
+    let hostname = mdns::hostname(wifi.sta_netif().get_mac()?);
+    mdns::advertise(&mut mdns, &hostname, 80)?;
+    let mdns = Arc::new(mdns);
+
+    let controller = if CONFIG.controller {
+        Some(Controller::start(mdns.clone(), &hostname, CONFIG.cams)?)
+    } else {
+        None
+    };
+
+    let prewarm = if CONFIG.prewarm {
+        Some(Prewarm::start(camera_mutex.clone(), pipeline.clone())?)
+    } else {
+        None
+    };
+
+    let report = if CONFIG.daily_report {
+        Some(DailyReport::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            sdcard.clone(),
+            reset_reason,
+            CONFIG.report_webhook,
+        )?)
+    } else {
+        None
+    };
+
+    let switching = if !CONFIG.night_schedule.is_empty() {
+        Some(Switching::Schedule(Schedule::parse(
+            CONFIG.night_schedule,
+            CONFIG.utc_offset_minutes,
+        )?))
+    } else if CONFIG.night_threshold > 0 {
+        Some(Switching::Brightness(NightThresholds {
+            enter: CONFIG.night_threshold,
+            hysteresis: CONFIG.night_hysteresis,
+            interval: Duration::from_secs(CONFIG.night_meter_s.max(1) as u64),
+        }))
+    } else {
+        None
+    };
+    let night = switching
+        .map(|switching| {
+            NightMode::start(
+                camera_mutex.clone(),
+                pipeline.clone(),
+                switching,
+                (CONFIG.ir_led_pin >= 0).then(|| unsafe { AnyOutputPin::new(CONFIG.ir_led_pin) }),
+                (CONFIG.ir_cut_pin >= 0).then(|| unsafe { AnyOutputPin::new(CONFIG.ir_cut_pin) }),
+            )
+        })
+        .transpose()?;
+
+    let motion = if CONFIG.motion || CONFIG.pir_pin >= 0 || CONFIG.tamper {
+        Some(Motion::new(
+            nvs.clone(),
+            motion::parse_mask(CONFIG.motion_mask)?,
+        )?)
+    } else {
+        None
+    };
+    if let (Some(motion), true) = (&motion, CONFIG.motion) {
+        motion.watch(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            analysis.clone(),
+            MotionSettings {
+                sensitivity: CONFIG.motion_sensitivity,
+                threshold: CONFIG.motion_threshold,
+                interval: Duration::from_millis(CONFIG.motion_interval_ms as u64),
+                debounce: Duration::from_millis(CONFIG.motion_debounce_ms as u64),
+                cooldown: Duration::from_secs(CONFIG.motion_cooldown_s as u64),
+            },
+        )?;
+    }
+    let snapshot = match (&motion, CONFIG.motion_snapshot) {
+        (Some(motion), size) if !size.is_empty() => {
+            let framesize = framesize_from_name(size)
+                .ok_or_else(|| anyhow!("Unknown motion snapshot size {}", size))?;
+            let snapshot = MotionSnapshot::new(
+                camera_mutex.clone(),
+                pipeline.clone(),
+                framesize,
+                CONFIG.motion_snapshot_quality,
+            );
+            motion.add_sink(Box::new(snapshot.clone()));
+            Some(snapshot)
+        }
+        _ => None,
+    };
+    if let (Some(motion), Some(report)) = (&motion, &report) {
+        motion.add_sink(Box::new(report.clone()));
+    }
+    if let (Some(motion), false) = (&motion, CONFIG.motion_webhook.is_empty()) {
+        let attachment = match (CONFIG.motion_webhook_snapshot, &snapshot) {
+            ("none", _) | (_, None) => Attachment::None,
+            ("inline", Some(snapshot)) => Attachment::Inline(snapshot.clone()),
+            ("link", Some(_)) => {
+                Attachment::Link(format!("http://{}.local/api/motion/snapshot", hostname))
+            }
+            (other, _) => bail!("Unknown motion webhook snapshot {}", other),
+        };
+        motion.add_sink(Box::new(MotionWebhook::start(
+            CONFIG.motion_webhook,
+            attachment,
+        )?));
+    }
+    if let (Some(motion), true) = (&motion, CONFIG.motion_clip_s > 0) {
+        let (Some(history), true) = (&history, CONFIG.sd_card) else {
+            bail!("Motion clips need the frame history and the SD card");
+        };
+        if CONFIG.history_seconds < CONFIG.motion_clip_pre_s + CONFIG.motion_clip_s {
+            bail!("history_seconds has to cover motion_clip_pre_s and motion_clip_s");
+        }
+        if CONFIG.encrypt_frames {
+            // They'd be the sealed history written out to the card in the clear
+            bail!("Motion clips can't be saved with encrypt_frames on");
+        }
+        if let Some(sdcard) = &sdcard {
+            motion.add_sink(Box::new(MotionClips::start(
+                history.clone(),
+                sdcard.clone(),
+                motion.clone(),
+                ClipSettings {
+                    pre: Duration::from_secs(CONFIG.motion_clip_pre_s as u64),
+                    post: Duration::from_secs(CONFIG.motion_clip_s as u64),
+                },
+            )?));
+        }
+    }
+    let faces = if CONFIG.face_interval_ms > 0 {
+        let faces = Faces::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            Duration::from_millis(CONFIG.face_interval_ms as u64),
+            Duration::from_secs(CONFIG.face_cooldown_s as u64),
+            motion.clone(),
+        )?;
+        if CONFIG.face_outlines {
+            let outlines = faces.clone();
+            overlay.add(Layer::Outlines(Box::new(move || outlines.outlines())));
+        }
+        Some(faces)
+    } else {
+        None
+    };
+    let tamper = match &motion {
+        Some(motion) if CONFIG.tamper => Some(Tamper::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            analysis.clone(),
+            TamperSettings {
+                dark: CONFIG.tamper_dark,
+                uniform: CONFIG.tamper_uniform,
+                hold: Duration::from_secs(CONFIG.tamper_hold_s as u64),
+            },
+            motion.clone(),
+        )?),
+        _ => None,
+    };
+    let recorder = match &sdcard {
+        Some(sdcard) if CONFIG.record_fps > 0 => Some(Recorder::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            sdcard.clone(),
+            RecordingSettings {
+                fps: CONFIG.record_fps,
+                segment: Duration::from_secs(CONFIG.record_segment_s as u64),
+                max_bytes: CONFIG.record_max_mb as u64 * 1024 * 1024,
+            },
+        )?),
+        _ => None,
+    };
+    let timelapse = if CONFIG.timelapse {
+        Some(TimeLapse::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            sdcard.clone(),
+            storage,
+        )?)
+    } else {
+        None
+    };
+    let retains = CONFIG.retention_max_age_days > 0
+        || CONFIG.retention_max_mb > 0
+        || CONFIG.retention_min_free_mb > 0
+        || CONFIG.retention_keep_clips > 0;
+    let retention = match &sdcard {
+        Some(sdcard) if retains => Some(MediaRetention::start(
+            sdcard.clone(),
+            RetentionSettings {
+                max_age: (CONFIG.retention_max_age_days > 0)
+                    .then(|| Duration::from_secs(CONFIG.retention_max_age_days as u64 * 86400)),
+                max_bytes: (CONFIG.retention_max_mb > 0)
+                    .then(|| CONFIG.retention_max_mb as u64 * 1024 * 1024),
+                keep_clips: CONFIG.retention_keep_clips as usize,
+                min_free: (CONFIG.retention_min_free_mb > 0)
+                    .then(|| CONFIG.retention_min_free_mb as u64 * 1024 * 1024),
+            },
+        )?),
+        _ => None,
+    };
+    let qr = if CONFIG.qr_scan_ms > 0 {
+        Some(QrScanner::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            Duration::from_millis(CONFIG.qr_scan_ms as u64),
+        )?)
+    } else {
+        None
+    };
+    let pir = match &motion {
+        Some(motion) if CONFIG.pir_pin >= 0 => Some(Pir::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            unsafe { AnyIOPin::new(CONFIG.pir_pin) },
+            motion.clone(),
+            PirSettings {
+                frames: CONFIG.pir_frames,
+                interval: Duration::from_millis(CONFIG.pir_interval_ms as u64),
+                cooldown: Duration::from_secs(CONFIG.pir_cooldown_s as u64),
+            },
+        )?),
+        _ => None,
+    };
+
+    let continuous = if CONFIG.continuous_capture && CONFIG.stream_port > 0 {
+        Some(Continuous::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            CONFIG.stream_fps,
+        )?)
+    } else {
+        None
+    };
+
+    // Dropping the servers stops them, so keep them around for the lifetime of the main loop
+    let _stream_server = if CONFIG.stream_port > 0 {
+        Some(stream::start(
+            camera_mutex.clone(),
+            pipeline.clone(),
+            &auth,
+            CONFIG.stream_port,
+            CONFIG.stream_fps,
+            continuous,
+        )?)
+    } else {
+        None
+    };
+    let services = Services {
+        rtp,
+        cache,
+        controller,
+        history,
+        prewarm,
+        report,
+        flash,
+        motion,
+        snapshot,
+        pir,
+        faces,
+        qr,
+        tamper,
+        night,
+        sdcard,
+        recorder,
+        timelapse,
+        retention,
+        storage,
+        log_file,
+    };
+    let _server = init_http(camera_mutex, pipeline, auth, services, nvs, reset_reason)?;
+
+    main_loop(
+        peripherals.timer00,
+        wifi,
+        sysloop,
+        fallback_auth,
+        &wifi_ssid,
+        &wifi_psk,
+        &fallback_psk,
+    )
+    .await
 }
 
 async fn main_loop(
     timer: impl Peripheral<P = impl Timer>,
     mut wifi: Box<EspWifi<'_>>,
     sysloop: EspSystemEventLoop,
+    auth: Auth,
+    ssid: &str,
+    psk: &str,
+    ap_psk: &str,
 ) -> Result<()> {
     let mut delay_driver = TimerDriver::new(timer, &Default::default())?;
 
-    'main: loop {
-        match wifi.is_up() {
-            Ok(false) | Err(_) => {
-                warn!("WiFi died, attempting to reconnect...");
-                let mut counter = 0;
-                loop {
-                    if wifi::connect(
-                        CONFIG.wifi_ssid,
-                        CONFIG.wifi_psk,
-                        sysloop.clone(),
-                        &mut wifi,
-                    )
+    let mut failures = 0;
+    // When to try the configured network again, while the fallback access point is up
+    let mut fallback_retry =
+        wifi::in_fallback(&wifi).then(|| Instant::now() + wifi::FALLBACK_RETRY);
+
+    loop {
+        delay_driver.delay_ms(1000).await;
+
+        if let Ok(true) = wifi.is_up() {
+            if fallback_retry.take().is_some() {
+                info!("Back on {}, taking the fallback access point down", ssid);
+                if let Err(e) = wifi::connect(ssid, psk, sysloop.clone(), &mut wifi).await {
+                    warn!("Failed to connect to wifi: {:?}", e);
+                }
+                auth.set_exposed(wifi::in_fallback(&wifi));
+            }
+            failures = 0;
+            continue;
+        }
+
+        match fallback_retry {
+            Some(at) if Instant::now() < at => {}
+            Some(_) => {
+                fallback_retry = Some(Instant::now() + wifi::FALLBACK_RETRY);
+                if let Err(e) = wifi::retry(sysloop.clone(), &mut wifi).await {
+                    warn!("Still can't join {}: {:?}", ssid, e);
+                }
+            }
+            None => {
+                if failures == 0 {
+                    warn!("WiFi died, attempting to reconnect...");
+                }
+                if wifi::connect(ssid, psk, sysloop.clone(), &mut wifi)
                     .await
                     .is_ok()
-                    {
-                        info!("WiFi reconnected successfully.");
-                        break;
-                    }
-                    counter += 1;
-                    warn!("Failed to connect to wifi, attempt {}", counter);
+                {
+                    info!("WiFi reconnected successfully.");
+                    failures = 0;
+                    continue;
+                }
+                failures += 1;
+                warn!("Failed to connect to wifi, attempt {}", failures);
 
-                    // If we fail to connect for long enough, reset the damn processor
-                    if counter > 10 {
-                        break 'main;
-                    }
+                // Rather than rebooting, which wouldn't help if the network is gone
+                if failures >= wifi::FALLBACK_AFTER {
+                    wifi::start_fallback(ssid, psk, ap_psk, sysloop.clone(), &mut wifi).await?;
+                    auth.set_exposed(true);
+                    fallback_retry = Some(Instant::now() + wifi::FALLBACK_RETRY);
                 }
             }
-            _ => {}
         }
-
-        delay_driver.delay_ms(1000).await
     }
-
-    bail!("Something went horribly wrong!!!")
 }
@@ -0,0 +1,21 @@
+use anyhow::Result;
+use esp_idf_svc::mdns::EspMdns;
+use log::info;
+
+pub const SERVICE_TYPE: &str = "_http";
+pub const SERVICE_PROTO: &str = "_tcp";
+
+// esp32cam-XXXX, the same naming the setup access point and controller discovery use
+pub fn hostname(mac: [u8; 6]) -> String {
+    format!("esp32cam-{:02x}{:02x}", mac[4], mac[5])
+}
+
+// Announces the camera as <hostname>.local with an `_http._tcp` service on `port`
+pub fn advertise(mdns: &mut EspMdns, hostname: &str, port: u16) -> Result<()> {
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name("tigercam")?;
+    mdns.add_service(None, SERVICE_TYPE, SERVICE_PROTO, port, &[("path", "/")])?;
+
+    info!("Advertising http://{}.local:{}/ over mDNS", hostname, port);
+    Ok(())
+}
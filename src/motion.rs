@@ -0,0 +1,432 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    http::{server::EspHttpServer, Method},
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::analysis::Analysis;
+use crate::auth::Auth;
+use crate::camera::{Camera, CameraError, CameraExt, Thumbnail};
+use crate::http::{query_param, read_body, write_json, ApiError};
+use crate::lock::lock;
+use crate::overlay::Outline;
+use crate::pipeline::Pipeline;
+
+pub use tigercam_core::motion::{parse_mask, MotionSettings, FULL_MASK, GRID};
+use tigercam_core::motion::{Mask, Tuning, TuningUpdate};
+
+const NVS_NAMESPACE: &str = "motion";
+// Events kept for /api/motion/events
+const EVENT_HISTORY: usize = 32;
+
+// What noticed the motion
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    // Comparing frames, `score` and `cells` say where
+    Frames,
+    // A PIR sensor, which can't say where so both are 0
+    Pir,
+    // Face detection, `score` is how many faces and `faces` has where they are
+    Face,
+    // The lens covered or the camera knocked off target, /api/tamper says which
+    Tamper,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MotionEvent {
+    // Seconds since the epoch, only right once SNTP has set the clock
+    pub timestamp: u64,
+    pub source: Source,
+    // Changed cells
+    pub score: u32,
+    // Bit `row * GRID + column` is set for every changed cell, masked ones never are
+    pub cells: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub faces: Vec<Outline>,
+}
+
+// For `MotionEvent::timestamp`
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Gets every motion event, on the motion thread. The camera is free while this runs.
+pub trait MotionSink: Send {
+    fn motion(&mut self, event: &MotionEvent) -> Result<()>;
+}
+
+// An event in the history
+#[derive(Clone, Serialize)]
+struct Recorded {
+    // Counts up from 1 since boot, for asking what came after the last one you saw
+    id: u32,
+    #[serde(flatten)]
+    event: MotionEvent,
+    // Where a sink saved a clip of it, see `Motion::attach_clip`
+    clip: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct State {
+    // How many cells differed in the last comparison
+    score: u32,
+    last_event: Option<MotionEvent>,
+    events: u32,
+    // Cells left out of the comparison have their bit cleared, see `Mask`
+    #[serde(skip)]
+    mask: u64,
+}
+
+// The thumbnail earlier frames blended into, so slow changes like the light over a day don't
+// look like motion
+struct Detector {
+    reference: Option<Thumbnail>,
+}
+
+impl Detector {
+    // Changed cells within `mask` as bits and a count, none for the first frame or one of a
+    // new size
+    fn compare(&mut self, frame: Thumbnail, sensitivity: u8, mask: u64) -> (u64, u32) {
+        let reference = match &mut self.reference {
+            Some(reference)
+                if reference.width == frame.width && reference.height == frame.height =>
+            {
+                reference
+            }
+            reference => {
+                *reference = Some(frame);
+                return (0, 0);
+            }
+        };
+
+        let (cell_width, cell_height) = ((frame.width / GRID).max(1), (frame.height / GRID).max(1));
+        let mut cells = 0;
+        for row in 0..GRID {
+            for column in 0..GRID {
+                let mut difference = 0;
+                let mut pixels = 0;
+                for y in row * cell_height..((row + 1) * cell_height).min(frame.height) {
+                    for x in column * cell_width..((column + 1) * cell_width).min(frame.width) {
+                        let index = y * frame.width + x;
+                        difference += frame.luma[index].abs_diff(reference.luma[index]) as u32;
+                        pixels += 1;
+                    }
+                }
+                let bit = 1 << (row * GRID + column);
+                if mask & bit != 0 && pixels > 0 && difference / pixels > sensitivity as u32 {
+                    cells |= bit;
+                }
+            }
+        }
+
+        // A quarter of the way towards the new frame each time
+        for (old, new) in reference.luma.iter_mut().zip(&frame.luma) {
+            *old = (*old as i16 + (*new as i16 - *old as i16) / 4) as u8;
+        }
+
+        (cells, cells.count_ones())
+    }
+}
+
+// Tells its sinks about motion. `watch` compares frames a few times a second against a
+// reference and raises an event whenever enough of the picture has changed.
+#[derive(Clone)]
+pub struct Motion {
+    state: Arc<Mutex<State>>,
+    sinks: Arc<Mutex<Vec<Box<dyn MotionSink>>>>,
+    history: Arc<Mutex<VecDeque<Recorded>>>,
+    // None until `watch`
+    settings: Arc<Mutex<Option<MotionSettings>>>,
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+}
+
+impl Motion {
+    // A mask saved through the API takes over from `configured_mask`. Nothing watches the
+    // frames until `watch`, so a PIR sensor can raise events on its own.
+    pub fn new(nvs: EspDefaultNvsPartition, configured_mask: u64) -> Result<Self> {
+        let nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+        let mut buf = [0u8; 8];
+        let mask = match nvs.get_blob("mask", &mut buf)? {
+            Some(saved) if saved.len() == 8 => u64::from_le_bytes(buf),
+            _ => configured_mask,
+        };
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(State {
+                score: 0,
+                last_event: None,
+                events: 0,
+                mask,
+            })),
+            sinks: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(EVENT_HISTORY))),
+            settings: Arc::new(Mutex::new(None)),
+            nvs: Arc::new(Mutex::new(nvs)),
+        })
+    }
+
+    // Starts comparing frames. Ones the pipeline has taken within an interval are used as
+    // they are, the camera is only asked for more when nothing else is capturing. Tuning
+    // saved through the API takes over from `settings`.
+    pub fn watch(
+        &self,
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        analysis: Analysis,
+        mut settings: MotionSettings,
+    ) -> Result<()> {
+        let mut buf = [0u8; 128];
+        if let Some(saved) = lock(&self.nvs).get_blob("tuning", &mut buf)? {
+            match serde_json::from_slice::<Tuning>(saved) {
+                Ok(tuning) => tuning.apply(&mut settings),
+                Err(e) => warn!("Ignoring the saved motion tuning: {:?}", e),
+            }
+        }
+        *lock(&self.settings) = Some(settings);
+
+        let task = self.clone();
+        thread::Builder::new()
+            .name("motion".into())
+            .stack_size(6144)
+            .spawn(move || {
+                let mut detector = Detector { reference: None };
+                let mut last_raised: Option<Instant> = None;
+                // When the current run of frames over the threshold started
+                let mut moving_since: Option<Instant> = None;
+                loop {
+                    thread::sleep(settings.interval);
+                    let settings = lock(&task.settings).unwrap_or(settings);
+
+                    let thumbnail = match analysis.fresh(settings.interval) {
+                        Some(frame) => Ok(frame),
+                        None => {
+                            let _pipeline = lock(&pipeline);
+                            let cam = lock(&cam);
+                            cam.try_capture().and_then(|fb| Analysis::frame(&fb))
+                        }
+                    };
+                    let thumbnail = match thumbnail {
+                        Ok(thumbnail) => thumbnail,
+                        // Nothing to watch while the camera sleeps
+                        Err(CameraError::Asleep) => continue,
+                        Err(e) => {
+                            warn!("Motion capture failed: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let mask = lock(&task.state).mask;
+                    let (cells, score) = detector.compare(thumbnail, settings.sensitivity, mask);
+                    lock(&task.state).score = score;
+                    if score < settings.threshold {
+                        moving_since = None;
+                        continue;
+                    }
+                    let started = *moving_since.get_or_insert_with(Instant::now);
+                    if started.elapsed() < settings.debounce
+                        || last_raised.is_some_and(|at| at.elapsed() < settings.cooldown)
+                    {
+                        continue;
+                    }
+
+                    last_raised = Some(Instant::now());
+                    task.raise(MotionEvent {
+                        timestamp: now(),
+                        source: Source::Frames,
+                        score,
+                        cells,
+                        faces: Vec::new(),
+                    });
+                }
+            })?;
+
+        info!(
+            "Watching for motion in {} or more of {} cells",
+            settings.threshold,
+            self.mask().count_ones()
+        );
+        Ok(())
+    }
+
+    pub fn mask(&self) -> u64 {
+        lock(&self.state).mask
+    }
+
+    // Takes effect from the next frame and survives a reboot
+    pub fn set_mask(&self, mask: u64) -> Result<()> {
+        lock(&self.nvs).set_blob("mask", &mask.to_le_bytes())?;
+        lock(&self.state).mask = mask;
+        info!("Motion mask now watches {} cells", mask.count_ones());
+        Ok(())
+    }
+
+    // Takes effect from the next frame and survives a reboot
+    fn set_tuning(&self, update: &TuningUpdate) -> Result<Tuning> {
+        let mut settings = lock(&self.settings);
+        let Some(settings) = settings.as_mut() else {
+            bail!("Frames aren't being compared for motion");
+        };
+        let mut tuning = Tuning::of(settings);
+        update.apply(&mut tuning)?;
+        lock(&self.nvs).set_blob("tuning", &serde_json::to_vec(&tuning)?)?;
+        tuning.apply(settings);
+        info!(
+            "Motion now {} or more cells over {} for {} ms",
+            tuning.threshold, tuning.sensitivity, tuning.debounce_ms
+        );
+        Ok(tuning)
+    }
+
+    pub fn add_sink(&self, sink: Box<dyn MotionSink>) {
+        lock(&self.sinks).push(sink);
+    }
+
+    // For a sink that saved a clip of `event` somewhere, shows up in /api/motion/events
+    pub fn attach_clip(&self, event: &MotionEvent, name: &str) {
+        let mut history = lock(&self.history);
+        if let Some(recorded) = history.iter_mut().rev().find(|r| r.event == *event) {
+            recorded.clip = Some(name.to_string());
+        }
+    }
+
+    pub fn raise(&self, event: MotionEvent) {
+        match event.source {
+            Source::Frames => info!("Motion in {} cells", event.score),
+            Source::Pir => info!("Motion on the PIR sensor"),
+            Source::Face => info!("{} faces in view", event.score),
+            Source::Tamper => info!("Tamper event"),
+        }
+        let id = {
+            let mut state = lock(&self.state);
+            state.events += 1;
+            state.last_event = Some(event.clone());
+            state.events
+        };
+        {
+            let mut history = lock(&self.history);
+            if history.len() == EVENT_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(Recorded {
+                id,
+                event: event.clone(),
+                clip: None,
+            });
+        }
+        for sink in lock(&self.sinks).iter_mut() {
+            if let Err(e) = sink.motion(&event) {
+                warn!("Motion sink failed: {:?}", e);
+            }
+        }
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, motion: Motion) -> Result<()> {
+    let status_motion = motion.clone();
+    server.fn_handler(
+        "/api/motion",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&status_motion.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    // `?after=<id>` for only the ones since, oldest first either way
+    let history_motion = motion.clone();
+    server.fn_handler(
+        "/api/motion/events",
+        Method::Get,
+        auth.protect(move |request| {
+            let after = match query_param(request.uri(), "after").map(str::parse) {
+                Some(Ok(after)) => after,
+                None => 0,
+                Some(Err(e)) => return ApiError::bad_request(e).send(request),
+            };
+            let events: Vec<Recorded> = lock(&history_motion.history)
+                .iter()
+                .filter(|recorded| recorded.id > after)
+                .cloned()
+                .collect();
+            write_json(request, 200, &events)
+        }),
+    )?;
+
+    // Sensitivity, threshold, debounce and cooldown, only while frames are compared
+    let get_config_motion = motion.clone();
+    server.fn_handler(
+        "/api/motion/config",
+        Method::Get,
+        auth.protect(move |request| {
+            let settings = *lock(&get_config_motion.settings);
+            match settings {
+                Some(settings) => write_json(request, 200, &Tuning::of(&settings)),
+                None => {
+                    ApiError::not_found("Frames aren't being compared for motion").send(request)
+                }
+            }
+        }),
+    )?;
+
+    let set_config_motion = motion.clone();
+    server.fn_handler(
+        "/api/motion/config",
+        Method::Post,
+        auth.protect(move |mut request| {
+            let body = match read_body(&mut request, 256) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let update: TuningUpdate = match serde_json::from_slice(&body) {
+                Ok(update) => update,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            match set_config_motion.set_tuning(&update) {
+                Ok(tuning) => write_json(request, 200, &tuning),
+                Err(e) => ApiError::bad_request(e).send(request),
+            }
+        }),
+    )?;
+
+    let get_motion = motion.clone();
+    server.fn_handler(
+        "/api/motion/mask",
+        Method::Get,
+        auth.protect(move |request| write_json(request, 200, &Mask::from_bits(get_motion.mask()))),
+    )?;
+
+    server.fn_handler(
+        "/api/motion/mask",
+        Method::Post,
+        auth.protect(move |mut request| {
+            let body = match read_body(&mut request, 1024) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let mask: Mask = match serde_json::from_slice(&body) {
+                Ok(mask) => mask,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            match motion.set_mask(mask.bits()) {
+                Ok(()) => write_json(request, 200, &mask),
+                Err(e) => ApiError::from(&e).send(request),
+            }
+        }),
+    )?;
+
+    Ok(())
+}
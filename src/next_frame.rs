@@ -0,0 +1,89 @@
+use anyhow::Result;
+use esp_idf_svc::http::server::EspHttpServer;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::frame_cache::FrameCache;
+use crate::http::{query_param, write_all_yielding, ApiError};
+use crate::pipeline::{lock_for_capture, CaptureError, Pipeline};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// Give the stream, history or another viewer this long to come up with a frame before we
+// capture one ourselves, so viewers watching together share their captures
+const SHARE_WAIT: Duration = Duration::from_millis(200);
+
+// `/nextframe?after=41&timeout_ms=5000` answers with the first frame newer than sequence 41,
+// waiting for one if need be. The sequence comes back in X-Frame-Sequence for the next call,
+// leaving `after` out gets whatever frame is newest.
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    cache: FrameCache,
+) -> Result<()> {
+    server.fn_handler(
+        "/nextframe",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let uri = request.uri();
+            let after = match query_param(uri, "after").map(str::parse::<u64>).transpose() {
+                Ok(after) => after.unwrap_or(0),
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let timeout = match query_param(uri, "timeout_ms")
+                .map(str::parse::<u64>)
+                .transpose()
+            {
+                Ok(ms) => ms
+                    .map_or(DEFAULT_TIMEOUT, Duration::from_millis)
+                    .min(MAX_TIMEOUT),
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let deadline = Instant::now() + timeout;
+            let frame = loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if let Some(frame) = cache.wait_newer(after, left.min(SHARE_WAIT)) {
+                    break frame;
+                }
+                if left.is_zero() {
+                    return ApiError::new(504, "no_new_frame", "No new frame before the timeout")
+                        .send(request);
+                }
+
+                // Nobody else is capturing, the frame goes through the cache like any other
+                let captured = lock_for_capture(&pipeline, &cam)
+                    .and_then(|(mut pipeline, cam)| pipeline.run(&*cam));
+                match captured {
+                    Ok(_) => {}
+                    // Someone else has the camera, they'll be feeding the cache
+                    Err(e) if matches!(e.downcast_ref(), Some(CaptureError::Busy)) => {}
+                    Err(e) => return ApiError::from(&e).send(request),
+                }
+            };
+
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", "image/jpeg"),
+                    ("Content-Length", &frame.jpeg.len().to_string()),
+                    ("X-Frame-Sequence", &frame.etag.to_string()),
+                    ("ETag", &frame.etag_header()),
+                    ("Cache-Control", "no-store"),
+                ],
+            )?;
+            let _ = write_all_yielding(&mut response, &frame.jpeg);
+
+            Ok(())
+        }),
+    )?;
+
+    Ok(())
+}
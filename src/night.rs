@@ -0,0 +1,226 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    hal::gpio::{AnyOutputPin, Output, PinDriver},
+    sys::cam::{gainceiling_t, gainceiling_t_GAINCEILING_128X},
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::camera::{Camera, CameraError, CameraExt};
+use crate::lock::lock;
+use crate::motion;
+use crate::pipeline::Pipeline;
+use crate::sensor::Sensor;
+
+pub use tigercam_core::schedule::Schedule;
+
+// How often a schedule is checked, a minute late at worst is fine for lights
+const SCHEDULE_CHECK: Duration = Duration::from_secs(30);
+
+// When to switch, all in average frame luma 0-255
+#[derive(Clone, Copy, Debug)]
+pub struct NightThresholds {
+    // Night mode starts once the scene is darker than this
+    pub enter: u8,
+    // And ends once it's brighter than `enter` plus this. The night profile and the IR LED
+    // brighten the picture themselves, so this has to cover what they add.
+    pub hysteresis: u8,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Switching {
+    // Metering the scene
+    Brightness(NightThresholds),
+    Schedule(Schedule),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Day,
+    Night,
+}
+
+// The exposure settings night mode changes, kept so day can be put back as it was
+#[derive(Clone, Copy, Debug)]
+struct Profile {
+    aec2: bool,
+    ae_level: i32,
+    gainceiling: gainceiling_t,
+}
+
+impl Profile {
+    // Let auto exposure go as long and as high as the sensor allows. AEC2 on the OV2640
+    // drops the frame rate to expose for longer.
+    const NIGHT: Self = Self {
+        aec2: true,
+        ae_level: 2,
+        gainceiling: gainceiling_t_GAINCEILING_128X,
+    };
+
+    fn read(sensor: &Sensor) -> Self {
+        let status = sensor.status();
+        Self {
+            aec2: status.aec2 != 0,
+            ae_level: status.ae_level as i32,
+            gainceiling: status.gainceiling as gainceiling_t,
+        }
+    }
+
+    fn apply(&self, sensor: &Sensor) -> Result<()> {
+        sensor.set_aec2(self.aec2)?;
+        sensor.set_ae_level(self.ae_level)?;
+        sensor.set_gainceiling(self.gainceiling)?;
+        Ok(())
+    }
+}
+
+// Both driven high at night. An IR-cut filter's pin takes the filter out of the light path,
+// or the colours go pink in daylight.
+struct Lights {
+    ir_led: Option<PinDriver<'static, AnyOutputPin, Output>>,
+    ir_cut: Option<PinDriver<'static, AnyOutputPin, Output>>,
+}
+
+impl Lights {
+    fn set(&mut self, night: bool) -> Result<()> {
+        for pin in [&mut self.ir_led, &mut self.ir_cut].into_iter().flatten() {
+            if night {
+                pin.set_high()?;
+            } else {
+                pin.set_low()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Switches the sensor to a night profile, the IR LED on and the IR-cut filter out while it's
+// dark, going by the scene's brightness or the time of day
+#[derive(Clone)]
+pub struct NightMode {
+    mode: Arc<Mutex<Mode>>,
+}
+
+impl NightMode {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        switching: Switching,
+        ir_led: Option<AnyOutputPin>,
+        ir_cut: Option<AnyOutputPin>,
+    ) -> Result<Self> {
+        let mut lights = Lights {
+            ir_led: ir_led.map(PinDriver::output).transpose()?,
+            ir_cut: ir_cut.map(PinDriver::output).transpose()?,
+        };
+        lights.set(false)?;
+
+        let night = Self {
+            mode: Arc::new(Mutex::new(Mode::Day)),
+        };
+        let mode = night.mode.clone();
+        let interval = match switching {
+            Switching::Brightness(thresholds) => thresholds.interval,
+            Switching::Schedule(_) => SCHEDULE_CHECK,
+        };
+
+        thread::Builder::new()
+            .name("night".into())
+            .stack_size(4096)
+            .spawn(move || {
+                // The day profile while it's night, None during the day
+                let mut day: Option<Profile> = None;
+                loop {
+                    thread::sleep(interval);
+
+                    let result = {
+                        let _pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        decide(&cam, switching, day.is_some()).and_then(|night| match night {
+                            Some(night) => switch(&mut day, night, &mut lights),
+                            None => Ok(()),
+                        })
+                    };
+                    if let Err(e) = result {
+                        // Nothing to meter while the camera sleeps, it's not a failure
+                        if !matches!(e.downcast_ref::<CameraError>(), Some(CameraError::Asleep)) {
+                            warn!("Night mode switching failed: {:?}", e);
+                        }
+                    }
+                    *lock(&mode) = if day.is_some() {
+                        Mode::Night
+                    } else {
+                        Mode::Day
+                    };
+                }
+            })?;
+
+        match switching {
+            Switching::Brightness(thresholds) => info!(
+                "Night mode below brightness {}, day again above {}",
+                thresholds.enter,
+                thresholds.enter.saturating_add(thresholds.hysteresis)
+            ),
+            Switching::Schedule(schedule) => info!(
+                "Night mode from {:02}:{:02} to {:02}:{:02}",
+                schedule.from / 60,
+                schedule.from % 60,
+                schedule.until / 60,
+                schedule.until % 60
+            ),
+        }
+        Ok(night)
+    }
+
+    pub fn mode(&self) -> Mode {
+        *lock(&self.mode)
+    }
+}
+
+// Whether it should be night now, None to leave things as they are
+fn decide(cam: &Camera, switching: Switching, is_night: bool) -> Result<Option<bool>> {
+    match switching {
+        Switching::Brightness(thresholds) => {
+            let brightness = meter(cam)?;
+            let leave = thresholds.enter.saturating_add(thresholds.hysteresis);
+            Ok(Some(if is_night {
+                brightness <= leave
+            } else {
+                brightness < thresholds.enter
+            }))
+        }
+        Switching::Schedule(schedule) => Ok(schedule.is_night(motion::now())),
+    }
+}
+
+// Average luma of the next frame
+fn meter(cam: &Camera) -> Result<u8> {
+    Ok(cam.try_capture()?.histogram()?.mean())
+}
+
+fn switch(day: &mut Option<Profile>, night: bool, lights: &mut Lights) -> Result<()> {
+    let sensor = Sensor::get()?;
+    match *day {
+        None if night => {
+            info!("Switching to night mode");
+            *day = Some(Profile::read(&sensor));
+            Profile::NIGHT.apply(&sensor)?;
+            lights.set(true)?;
+        }
+        Some(profile) if !night => {
+            info!("Switching to day mode");
+            *day = None;
+            profile.apply(&sensor)?;
+            lights.set(false)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
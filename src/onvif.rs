@@ -0,0 +1,131 @@
+use anyhow::Result;
+use embedded_svc::http::server::Connection;
+use esp_idf_svc::{
+    http::server::{EspHttpServer, HandlerResult, Request},
+    io::Write,
+};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::http::read_body;
+use crate::lock::try_lock_for;
+use crate::pipeline::{CaptureError, CAMERA_WAIT};
+use crate::sensor::framesize_dimensions;
+use crate::source::FrameSource;
+
+const PROFILE_TOKEN: &str = "profile_1";
+// NVRs send the whole profile list request and hardly anything bigger
+const MAX_REQUEST: usize = 4096;
+
+const ENVELOPE_START: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:tds="http://www.onvif.org/ver10/device/wsdl" xmlns:trt="http://www.onvif.org/ver10/media/wsdl" xmlns:tt="http://www.onvif.org/ver10/schema" xmlns:ter="http://www.onvif.org/ver10/error"><s:Body>"#;
+const ENVELOPE_END: &str = "</s:Body></s:Envelope>\n";
+
+// Just enough of the ONVIF device and media services for an NVR to find the MJPEG stream and
+// the snapshot URL. Requests are told apart by the first element in the SOAP body, anything
+// we don't know gets an ActionNotSupported fault.
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    stream_port: u16,
+) -> Result<()> {
+    for uri in ["/onvif/device_service", "/onvif/media_service"] {
+        let cam = cam.clone();
+        server.fn_handler(
+            uri,
+            esp_idf_svc::http::Method::Post,
+            auth.protect(move |mut request| {
+                let body = read_body(&mut request, MAX_REQUEST)?;
+                let body = String::from_utf8_lossy(&body);
+                // The URLs we hand out have to be reachable the same way we were
+                let host = request
+                    .header("Host")
+                    .map(|h| h.rsplit_once(':').map_or(h, |(host, _)| host).to_string())
+                    .unwrap_or_default();
+
+                let reply = match action(&body) {
+                    Some("GetCapabilities") => Some(capabilities(&host)),
+                    Some("GetProfiles") => Some(profiles(&cam)?),
+                    Some("GetStreamUri") => (stream_port > 0).then(|| {
+                        media_uri(
+                            "GetStreamUriResponse",
+                            &format!("http://{}:{}/stream", host, stream_port),
+                        )
+                    }),
+                    Some("GetSnapshotUri") => Some(media_uri(
+                        "GetSnapshotUriResponse",
+                        &format!("http://{}/", host),
+                    )),
+                    _ => None,
+                };
+
+                match reply {
+                    Some(reply) => send(request, 200, &reply),
+                    None => send(request, 400, FAULT),
+                }
+            }),
+        )?;
+    }
+
+    Ok(())
+}
+
+// The local name of the first element inside the SOAP Body, prefixes vary between clients
+fn action(envelope: &str) -> Option<&str> {
+    let body_start = envelope.find(":Body").or_else(|| envelope.find("<Body"))?;
+    let after_body = &envelope[body_start..];
+    let after_body = &after_body[after_body.find('>')? + 1..];
+    let tag = &after_body[after_body.find('<')? + 1..];
+    let name = &tag[..tag.find(|c: char| c.is_whitespace() || c == '/' || c == '>')?];
+    Some(name.rsplit_once(':').map_or(name, |(_, local)| local))
+}
+
+fn capabilities(host: &str) -> String {
+    format!(
+        r#"<tds:GetCapabilitiesResponse><tds:Capabilities><tt:Device><tt:XAddr>http://{host}/onvif/device_service</tt:XAddr></tt:Device><tt:Media><tt:XAddr>http://{host}/onvif/media_service</tt:XAddr><tt:StreamingCapabilities><tt:RTPMulticast>false</tt:RTPMulticast><tt:RTP_TCP>false</tt:RTP_TCP><tt:RTP_RTSP_TCP>false</tt:RTP_RTSP_TCP></tt:StreamingCapabilities></tt:Media></tds:Capabilities></tds:GetCapabilitiesResponse>"#,
+        host = host
+    )
+}
+
+fn profiles(cam: &Mutex<impl FrameSource>) -> Result<String> {
+    let (width, height) = {
+        let cam = try_lock_for(cam, CAMERA_WAIT).ok_or(CaptureError::Busy)?;
+        framesize_dimensions(cam.framesize()?).unwrap_or((0, 0))
+    };
+
+    Ok(format!(
+        r#"<trt:GetProfilesResponse><trt:Profiles token="{token}" fixed="true"><tt:Name>main</tt:Name><tt:VideoSourceConfiguration token="source_1"><tt:Name>camera</tt:Name><tt:UseCount>1</tt:UseCount><tt:SourceToken>source_1</tt:SourceToken><tt:Bounds x="0" y="0" width="{width}" height="{height}"/></tt:VideoSourceConfiguration><tt:VideoEncoderConfiguration token="encoder_1"><tt:Name>jpeg</tt:Name><tt:UseCount>1</tt:UseCount><tt:Encoding>JPEG</tt:Encoding><tt:Resolution><tt:Width>{width}</tt:Width><tt:Height>{height}</tt:Height></tt:Resolution><tt:Quality>80</tt:Quality></tt:VideoEncoderConfiguration></trt:Profiles></trt:GetProfilesResponse>"#,
+        token = PROFILE_TOKEN,
+        width = width,
+        height = height
+    ))
+}
+
+fn media_uri(response: &str, uri: &str) -> String {
+    format!(
+        r#"<trt:{response}><trt:MediaUri><tt:Uri>{uri}</tt:Uri><tt:InvalidAfterConnect>false</tt:InvalidAfterConnect><tt:InvalidAfterReboot>false</tt:InvalidAfterReboot><tt:Timeout>PT0S</tt:Timeout></trt:MediaUri></trt:{response}>"#,
+        response = response,
+        uri = uri
+    )
+}
+
+const FAULT: &str = r#"<s:Fault><s:Code><s:Value>s:Sender</s:Value><s:Subcode><s:Value>ter:ActionNotSupported</s:Value></s:Subcode></s:Code><s:Reason><s:Text xml:lang="en">Not supported by this device</s:Text></s:Reason></s:Fault>"#;
+
+fn send<C: Connection>(request: Request<C>, status: u16, body: &str) -> HandlerResult {
+    let length = ENVELOPE_START.len() + body.len() + ENVELOPE_END.len();
+    let mut response = request.into_response(
+        status,
+        None,
+        &[
+            ("Content-Type", "application/soap+xml; charset=utf-8"),
+            ("Content-Length", &length.to_string()),
+        ],
+    )?;
+    response.write_all(ENVELOPE_START.as_bytes())?;
+    response.write_all(body.as_bytes())?;
+    response.write_all(ENVELOPE_END.as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,455 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::camera;
+use crate::clock::{format_time, CLOCK_SET};
+use crate::lock::lock;
+use crate::pipeline::{Frame, Stage};
+
+// The classic 5x7 font, printable ASCII from ' ' to '~'. Five columns per glyph, the lowest
+// bit is the top row.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5f, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14],
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00],
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08],
+    [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4b, 0x31],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1e],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3e],
+    [0x7e, 0x11, 0x11, 0x11, 0x7e],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7f, 0x01, 0x01],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7f, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7f, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00],
+    [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7f, 0x48, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x08, 0x7e, 0x09, 0x01, 0x02],
+    [0x0c, 0x52, 0x52, 0x52, 0x3e],
+    [0x7f, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7d, 0x40, 0x00],
+    [0x20, 0x40, 0x44, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7f, 0x40, 0x00],
+    [0x7c, 0x04, 0x18, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7c, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7c],
+    [0x7c, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3f, 0x44, 0x40, 0x20],
+    [0x3c, 0x40, 0x40, 0x20, 0x7c],
+    [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0c, 0x50, 0x50, 0x50, 0x3c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7f, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x08, 0x04, 0x08, 0x10, 0x08],
+];
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+// Between glyphs and around the text
+const SPACING: usize = 1;
+
+// Anything the font doesn't have comes out as '?'
+fn glyph(c: char) -> &'static [u8; 5] {
+    let index = (c as usize).wrapping_sub(' ' as usize);
+    FONT.get(index)
+        .unwrap_or(&FONT['?' as usize - ' ' as usize])
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "top_left" => Ok(Self::TopLeft),
+            "top_right" => Ok(Self::TopRight),
+            "" | "bottom_left" => Ok(Self::BottomLeft),
+            "bottom_right" => Ok(Self::BottomRight),
+            other => bail!("Unknown corner '{}'", other),
+        }
+    }
+}
+
+// Size of `lines` drawn at `scale`, padding included
+fn text_size(lines: &[&str], scale: usize) -> (usize, usize) {
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let width = columns * (GLYPH_WIDTH + SPACING) + SPACING;
+    let height = lines.len() * (GLYPH_HEIGHT + SPACING) + SPACING;
+    (width * scale, height * scale)
+}
+
+// Where the top left of a `width` x `height` box goes to sit in `corner`
+pub fn place(frame: &Frame, corner: Corner, width: usize, height: usize) -> (usize, usize) {
+    let right = frame.width.saturating_sub(width);
+    let bottom = frame.height.saturating_sub(height);
+    match corner {
+        Corner::TopLeft => (0, 0),
+        Corner::TopRight => (right, 0),
+        Corner::BottomLeft => (0, bottom),
+        Corner::BottomRight => (right, bottom),
+    }
+}
+
+// White text on a black box, so it reads on any background. JPEG gets decoded to RGB888
+// first, anything cut off by the edge of the frame is left out.
+pub fn draw_text(frame: Frame, corner: Corner, lines: &[&str], scale: usize) -> Result<Frame> {
+    let mut frame = prepare(frame)?;
+    let scale = scale.max(1);
+    let (width, height) = text_size(lines, scale);
+    let (left, top) = place(&frame, corner, width, height);
+    draw_text_at(&mut frame, left, top, lines, scale);
+    Ok(frame)
+}
+
+// Same as `draw_text` with the box's top left at `left`, `top`, into a prepared frame
+fn draw_text_at(frame: &mut Frame, left: usize, top: usize, lines: &[&str], scale: usize) {
+    let (width, height) = text_size(lines, scale);
+    fill(frame, left, top, width, height, false);
+    for (row, line) in lines.iter().enumerate() {
+        let y = top + (SPACING + row * (GLYPH_HEIGHT + SPACING)) * scale;
+        for (column, c) in line.chars().enumerate() {
+            let x = left + (SPACING + column * (GLYPH_WIDTH + SPACING)) * scale;
+            for (dx, bits) in glyph(c).iter().enumerate() {
+                for dy in (0..GLYPH_HEIGHT).filter(|dy| bits >> dy & 1 != 0) {
+                    fill(frame, x + dx * scale, y + dy * scale, scale, scale, true);
+                }
+            }
+        }
+    }
+}
+
+// Overlays can only be drawn into pixels we know the layout of. Fails, rather than aborting,
+// when there isn't the memory to decode a JPEG frame that big.
+pub fn prepare(frame: Frame) -> Result<Frame> {
+    match frame.bytes_per_pixel() {
+        Some(_) => Ok(frame),
+        None => frame.into_rgb888(),
+    }
+}
+
+// Sets a rectangle to white or black, clipped to the frame. Both are every bit set or clear
+// in all the formats we draw into.
+pub fn fill(frame: &mut Frame, x: usize, y: usize, width: usize, height: usize, lit: bool) {
+    let Some(bpp) = frame.bytes_per_pixel() else {
+        return;
+    };
+    let value = if lit { 0xff } else { 0 };
+
+    let stride = frame.width * bpp;
+    for row in y..(y + height).min(frame.height) {
+        let start = row * stride + x.min(frame.width) * bpp;
+        let end = row * stride + (x + width).min(frame.width) * bpp;
+        frame.data[start..end].fill(value);
+    }
+}
+
+// "timestamp" or "timestamp:top_right", the date and time in a corner of every frame. Frames
+// go out unstamped until SNTP has set the clock, rather than claiming it's 1970.
+pub struct Timestamp {
+    corner: Corner,
+}
+
+impl Timestamp {
+    pub fn from_args(args: &str) -> Result<Box<dyn Stage>> {
+        Ok(Box::new(Self {
+            corner: Corner::parse(args.trim())?,
+        }))
+    }
+}
+
+impl Stage for Timestamp {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        let unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if unix < CLOCK_SET {
+            return Ok(frame);
+        }
+
+        // Big enough to read at VGA without covering much of a QVGA frame
+        let scale = (frame.width / 320).max(1);
+        let text = format!("{} UTC", format_time(unix));
+        draw_text(frame, self.corner, &[&text], scale)
+    }
+}
+
+// One bit per pixel, rows padded out to whole bytes and the leftmost pixel in the top bit.
+// Set bits are drawn white, clear ones leave the frame as it is.
+#[derive(Clone)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    pub bits: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(width: usize, height: usize, bits: Vec<u8>) -> Result<Self> {
+        let expected = (width + 7) / 8 * height;
+        if bits.len() != expected {
+            bail!(
+                "A {}x{} bitmap is {} bytes, not {}",
+                width,
+                height,
+                expected,
+                bits.len()
+            );
+        }
+        Ok(Self {
+            width,
+            height,
+            bits,
+        })
+    }
+
+    fn lit(&self, x: usize, y: usize) -> bool {
+        self.bits[y * ((self.width + 7) / 8) + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+// A rectangle in fractions of the frame's width and height, 0-1, so it lands in the same place
+// whatever size the frame is
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Outline {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub enum Layer {
+    Text {
+        corner: Corner,
+        lines: Vec<String>,
+        scale: usize,
+    },
+    // Asked for its lines on every frame, for things like RSSI or a frame counter. Called with
+    // the pipeline locked, so keep it quick.
+    Dynamic {
+        corner: Corner,
+        lines: Box<dyn FnMut() -> Vec<String> + Send>,
+        scale: usize,
+    },
+    Bitmap {
+        x: usize,
+        y: usize,
+        bitmap: Bitmap,
+    },
+    // White boxes around whatever the closure returns, asked for on every frame like Dynamic
+    Outlines(Box<dyn FnMut() -> Vec<Outline> + Send>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LayerId(u32);
+
+#[derive(Default)]
+struct Layers {
+    next_id: u32,
+    layers: Vec<(LayerId, Layer)>,
+    // Frames are going out without the layers, too big to decode for them
+    skipping: bool,
+}
+
+// What the application wants drawn over every frame, applied after the pipeline's stages.
+// Clones share the layers, so keep one to change them while the pipeline has another. Text
+// layers in the same corner stack up in the order they were added.
+#[derive(Clone, Default)]
+pub struct Overlay {
+    inner: Arc<Mutex<Layers>>,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, layer: Layer) -> LayerId {
+        let mut inner = lock(&self.inner);
+        let id = LayerId(inner.next_id);
+        inner.next_id += 1;
+        inner.layers.push((id, layer));
+        id
+    }
+
+    pub fn remove(&self, id: LayerId) {
+        lock(&self.inner).layers.retain(|(i, _)| *i != id);
+    }
+
+    pub fn clear(&self) {
+        lock(&self.inner).layers.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.inner).layers.is_empty()
+    }
+
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        let mut inner = lock(&self.inner);
+        if inner.layers.is_empty() {
+            return Ok(frame);
+        }
+        // The layers are only extras, a JPEG frame there isn't the memory to decode for them
+        // still goes out, just without them
+        if frame.bytes_per_pixel().is_none() && !camera::rgb888_fits(frame.width, frame.height) {
+            if !inner.skipping {
+                warn!(
+                    "No memory to decode {}x{} frames for the overlay, leaving it off",
+                    frame.width, frame.height
+                );
+                inner.skipping = true;
+            }
+            return Ok(frame);
+        }
+        inner.skipping = false;
+        let mut frame = prepare(frame)?;
+
+        // How far into the frame each corner's text has got, indexed like `Corner`
+        let mut used = [0; 4];
+        for (_, layer) in &mut inner.layers {
+            let (corner, lines, scale) = match layer {
+                Layer::Text {
+                    corner,
+                    lines,
+                    scale,
+                } => (*corner, lines.clone(), *scale),
+                Layer::Dynamic {
+                    corner,
+                    lines,
+                    scale,
+                } => (*corner, lines(), *scale),
+                Layer::Bitmap { x, y, bitmap } => {
+                    draw_bitmap(&mut frame, *x, *y, bitmap);
+                    continue;
+                }
+                Layer::Outlines(outlines) => {
+                    for outline in outlines() {
+                        draw_outline(&mut frame, outline);
+                    }
+                    continue;
+                }
+            };
+
+            let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+            let scale = scale.max(1);
+            let (width, height) = text_size(&lines, scale);
+            let (left, top) = place(&frame, corner, width, height);
+            let offset = &mut used[corner as usize];
+            let top = match corner {
+                Corner::TopLeft | Corner::TopRight => top + *offset,
+                Corner::BottomLeft | Corner::BottomRight => top.saturating_sub(*offset),
+            };
+            draw_text_at(&mut frame, left, top, &lines, scale);
+            *offset += height;
+        }
+
+        Ok(frame)
+    }
+}
+
+// Two pixels thick at QVGA, thicker on bigger frames
+fn draw_outline(frame: &mut Frame, outline: Outline) {
+    let thickness = (frame.width / 160).max(1);
+    let x = (outline.x.clamp(0.0, 1.0) * frame.width as f32) as usize;
+    let y = (outline.y.clamp(0.0, 1.0) * frame.height as f32) as usize;
+    let width = (outline.width.max(0.0) * frame.width as f32) as usize;
+    let height = (outline.height.max(0.0) * frame.height as f32) as usize;
+
+    fill(frame, x, y, width, thickness, true);
+    fill(
+        frame,
+        x,
+        (y + height).saturating_sub(thickness),
+        width,
+        thickness,
+        true,
+    );
+    fill(frame, x, y, thickness, height, true);
+    fill(
+        frame,
+        (x + width).saturating_sub(thickness),
+        y,
+        thickness,
+        height,
+        true,
+    );
+}
+
+fn draw_bitmap(frame: &mut Frame, left: usize, top: usize, bitmap: &Bitmap) {
+    for y in 0..bitmap.height {
+        for x in (0..bitmap.width).filter(|x| bitmap.lit(*x, y)) {
+            fill(frame, left + x, top + y, 1, 1, true);
+        }
+    }
+}
@@ -0,0 +1,966 @@
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::sys::cam::{
+    framesize_t, framesize_t_FRAMESIZE_QQVGA, framesize_t_FRAMESIZE_QVGA,
+    framesize_t_FRAMESIZE_SVGA, framesize_t_FRAMESIZE_SXGA, framesize_t_FRAMESIZE_UXGA,
+    framesize_t_FRAMESIZE_VGA, framesize_t_FRAMESIZE_XGA, pixformat_t,
+    pixformat_t_PIXFORMAT_GRAYSCALE, pixformat_t_PIXFORMAT_JPEG, pixformat_t_PIXFORMAT_RGB565,
+    pixformat_t_PIXFORMAT_RGB888,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::analysis::Analysis;
+use crate::camera::{self, Camera, CameraExt, Converted, FrameBuffer};
+use crate::lock::try_lock_for;
+use crate::overlay::{Overlay, Timestamp};
+use crate::sensor::{framesize_dimensions, framesize_pixels, Sensor};
+use crate::source::{BufferTooSmall, FrameSource, RawFrame};
+use crate::yuv::{ToRgb565, ToRgb888};
+
+// Encoder quality is 1-100, higher is better
+const MIN_QUALITY: u8 = 10;
+const QUALITY_STEP: u8 = 15;
+// The sensor's own JPEG quality is 0-63, lower is better
+const WORST_SENSOR_QUALITY: i32 = 63;
+const SENSOR_QUALITY_STEP: i32 = 10;
+// Rough JPEG density to guess with before we've seen a frame
+const JPEG_PIXELS_PER_BYTE: usize = 5;
+const BMP_HEADER_LEN: usize = 54;
+// Sizes to fall back through when quality alone can't get a frame under budget
+const FALLBACK_SIZES: [framesize_t; 7] = [
+    framesize_t_FRAMESIZE_QQVGA,
+    framesize_t_FRAMESIZE_QVGA,
+    framesize_t_FRAMESIZE_VGA,
+    framesize_t_FRAMESIZE_SVGA,
+    framesize_t_FRAMESIZE_XGA,
+    framesize_t_FRAMESIZE_SXGA,
+    framesize_t_FRAMESIZE_UXGA,
+];
+
+// An owned copy of a captured frame, so the driver's buffer can go back as soon as possible
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub format: pixformat_t,
+    // From the driver, see `FrameBuffer::timestamp`
+    pub timestamp: Duration,
+}
+
+// An encoded frame, either still in the driver's buffer or copied out into our own memory
+pub enum Jpeg<'a> {
+    // Goes back to the driver when dropped, so the camera can't capture again until then
+    Framebuffer(FrameBuffer<'a>),
+    // Straight out of the encoder, the driver already has its buffer back
+    Converted(Converted),
+    Owned(Vec<u8>),
+}
+
+// What `grab_and_encode` copies out of the source's buffer before handing it back
+enum Grabbed {
+    Jpeg(Vec<u8>),
+    Converted(Converted),
+    Frame(Frame),
+}
+
+impl Jpeg<'_> {
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Jpeg::Framebuffer(fb) => fb.data(),
+            Jpeg::Converted(jpeg) => jpeg.data(),
+            Jpeg::Owned(jpeg) => jpeg,
+        }
+    }
+}
+
+impl Frame {
+    // A copy in our own memory, so the source can have its buffer back
+    pub fn from_raw(raw: &RawFrame) -> Self {
+        Self {
+            data: raw.data.to_vec(),
+            width: raw.width,
+            height: raw.height,
+            format: raw.format,
+            timestamp: raw.timestamp,
+        }
+    }
+
+    // Only formats where every pixel is self-contained can be cut up and moved around
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        match self.format {
+            pixformat_t_PIXFORMAT_GRAYSCALE => Some(1),
+            pixformat_t_PIXFORMAT_RGB565 => Some(2),
+            pixformat_t_PIXFORMAT_RGB888 => Some(3),
+            _ => None,
+        }
+    }
+
+    // Decodes JPEG, or converts anything else the converters know
+    pub fn into_rgb888(self) -> Result<Frame> {
+        if self.format == pixformat_t_PIXFORMAT_RGB888 {
+            return Ok(self);
+        }
+        Ok(Frame {
+            data: camera::to_rgb888(&self.data, self.width, self.height, self.format)?,
+            format: pixformat_t_PIXFORMAT_RGB888,
+            ..self
+        })
+    }
+
+    // The `width` x `height` region with its top left corner at `x`, `y`
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Result<Frame> {
+        let bpp = self.require_bytes_per_pixel("crop")?;
+        if x + width > self.width || y + height > self.height {
+            bail!(
+                "Crop {}x{}+{}+{} is outside the {}x{} frame",
+                width,
+                height,
+                x,
+                y,
+                self.width,
+                self.height
+            );
+        }
+
+        let stride = self.width * bpp;
+        let mut data = Vec::with_capacity(width * height * bpp);
+        for row in self.data.chunks_exact(stride).skip(y).take(height) {
+            data.extend_from_slice(&row[x * bpp..(x + width) * bpp]);
+        }
+
+        Ok(Frame {
+            data,
+            width,
+            height,
+            format: self.format,
+            timestamp: self.timestamp,
+        })
+    }
+
+    // Averages each `factor` x `factor` block into one pixel, 2 or 4. Edges that don't fill a
+    // whole block are dropped.
+    pub fn downscale(&self, factor: usize) -> Result<Frame> {
+        if factor != 2 && factor != 4 {
+            bail!("downscale factor must be 2 or 4");
+        }
+        let bpp = self.require_bytes_per_pixel("downscale")?;
+        let (width, height) = (self.width / factor, self.height / factor);
+        let stride = self.width * bpp;
+        let samples = (factor * factor) as u32;
+
+        let mut data = Vec::with_capacity(width * height * bpp);
+        for block_y in 0..height {
+            for block_x in 0..width {
+                let pixels = (0..factor).flat_map(|dy| {
+                    let row = (block_y * factor + dy) * stride + block_x * factor * bpp;
+                    (0..factor).map(move |dx| row + dx * bpp)
+                });
+
+                if self.format == pixformat_t_PIXFORMAT_RGB565 {
+                    // Each channel on its own, averaging the packed bytes would bleed them
+                    let mut sum = [0u32; 3];
+                    for at in pixels {
+                        let pixel = u16::from_be_bytes([self.data[at], self.data[at + 1]]);
+                        sum[0] += (pixel >> 11) as u32;
+                        sum[1] += (pixel >> 5 & 0x3f) as u32;
+                        sum[2] += (pixel & 0x1f) as u32;
+                    }
+                    let [r, g, b] = sum.map(|c| (c / samples) as u16);
+                    data.extend_from_slice(&(r << 11 | g << 5 | b).to_be_bytes());
+                } else {
+                    let mut sum = [0u32; 3];
+                    for at in pixels {
+                        for (channel, value) in self.data[at..at + bpp].iter().enumerate() {
+                            sum[channel] += *value as u32;
+                        }
+                    }
+                    data.extend(sum[..bpp].iter().map(|c| (c / samples) as u8));
+                }
+            }
+        }
+
+        Ok(Frame {
+            data,
+            width,
+            height,
+            format: self.format,
+            timestamp: self.timestamp,
+        })
+    }
+
+    // Clockwise by 90, 180 or 270 degrees
+    pub fn rotate(mut self, degrees: u32) -> Result<Frame> {
+        let bpp = self.require_bytes_per_pixel("rotate")?;
+        let (width, height) = (self.width, self.height);
+
+        let source: fn(usize, usize, usize, usize) -> (usize, usize) = match degrees {
+            180 => {
+                // Reversing the pixel order flips both axes at once, and can be done in place
+                let pixels = self.data.len() / bpp;
+                for i in 0..pixels / 2 {
+                    let j = pixels - 1 - i;
+                    for b in 0..bpp {
+                        self.data.swap(i * bpp + b, j * bpp + b);
+                    }
+                }
+                return Ok(self);
+            }
+            // Which pixel of the source ends up at x, y in the rotated frame
+            90 => |x, y, _width, height| (y, height - 1 - x),
+            270 => |x, y, width, _height| (width - 1 - y, x),
+            _ => bail!("rotation must be 90, 180 or 270 degrees"),
+        };
+
+        let mut data = Vec::with_capacity(width * height * bpp);
+        for y in 0..width {
+            for x in 0..height {
+                let (source_x, source_y) = source(x, y, width, height);
+                let at = (source_y * width + source_x) * bpp;
+                data.extend_from_slice(&self.data[at..at + bpp]);
+            }
+        }
+
+        Ok(Frame {
+            data,
+            width: height,
+            height: width,
+            format: self.format,
+            timestamp: self.timestamp,
+        })
+    }
+
+    fn require_bytes_per_pixel(&self, stage: &str) -> Result<usize> {
+        self.bytes_per_pixel()
+            .ok_or_else(|| anyhow!("{} needs an RGB or grayscale frame", stage))
+    }
+
+    fn encode(self, quality: u8) -> Result<Vec<u8>> {
+        if self.format == pixformat_t_PIXFORMAT_JPEG {
+            return Ok(self.data);
+        }
+        let jpeg = Converted::jpeg(&self.data, self.width, self.height, self.format, quality)?;
+        Ok(jpeg.data().to_vec())
+    }
+
+    // Works from any format, JPEG frames get decoded first
+    fn encode_bmp(self) -> Result<Vec<u8>> {
+        let bmp = Converted::bmp(&self.data, self.width, self.height, self.format)?;
+        Ok(bmp.data().to_vec())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Bmp,
+}
+
+impl ImageFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Bmp => "image/bmp",
+        }
+    }
+
+    // BMP only when the client ranks it above everything that would get it JPEG
+    pub fn from_accept(accept: &str) -> Self {
+        let mut jpeg = 0.0f32;
+        let mut bmp = 0.0f32;
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let media = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+
+            match media.as_str() {
+                "image/bmp" | "image/x-bmp" | "image/x-ms-bmp" => bmp = bmp.max(q),
+                "image/jpeg" | "image/*" | "*/*" => jpeg = jpeg.max(q),
+                _ => {}
+            }
+        }
+
+        if bmp > jpeg {
+            Self::Bmp
+        } else {
+            Self::Jpeg
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "bmp" => Ok(Self::Bmp),
+            other => bail!("Unknown image format '{}'", other),
+        }
+    }
+}
+
+// Overrides for a single capture, anything left as None uses the configured settings
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Shot {
+    // Encoder quality, 1-100 and higher is better
+    pub quality: Option<u8>,
+    pub framesize: Option<framesize_t>,
+    pub format: ImageFormat,
+}
+
+// A step between capture and encode, e.g. rotation, cropping or overlays
+pub trait Stage: Send {
+    fn process(&mut self, frame: Frame) -> Result<Frame>;
+}
+
+// Receives every encoded frame that comes out of the pipeline
+pub trait Sink: Send {
+    fn consume(&mut self, jpeg: &[u8]) -> Result<()>;
+}
+
+type StageFactory = fn(&str) -> Result<Box<dyn Stage>>;
+
+// How long a request waits for the camera before giving up with a busy error
+pub const CAMERA_WAIT: Duration = Duration::from_millis(500);
+
+// Capture failures callers may want to tell apart, anything else comes back as a plain error
+#[derive(Debug)]
+pub enum CaptureError {
+    // Someone else had the camera for longer than we were willing to wait
+    Busy,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Busy => write!(f, "Camera is busy"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+// How fresh a captured frame has to be. Changing the driver's own grab mode means taking it
+// down and back up, see `CameraConfig::grab_mode`, so Latest is done here by handing back
+// whatever frame the driver was holding and waiting for the next one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrabMode {
+    // Latest while something is streaming, WhenEmpty for stills
+    Auto,
+    // Take the frame the driver already has, fastest but it may be old
+    WhenEmpty,
+    // Always a frame started after the request, costs up to a frame time
+    Latest,
+}
+
+impl FromStr for GrabMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "when_empty" => Ok(Self::WhenEmpty),
+            "latest" => Ok(Self::Latest),
+            other => bail!("Unknown grab mode '{}'", other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct PipelineStats {
+    pub frames: u32,
+    // Frames that came out over the byte budget and had to be redone
+    pub over_budget: u32,
+    // Frames that were still over budget at the lowest quality and size we'd try
+    pub budget_failures: u32,
+    // Captures that failed outright, no framebuffer or a failed conversion
+    pub failures: u32,
+    // Size of the last regular frame, what HEAD requests base their guess on
+    pub last_jpeg_bytes: u32,
+}
+
+pub struct Pipeline {
+    quality: u8,
+    budget: Option<usize>,
+    grab_mode: GrabMode,
+    // Stream clients currently pulling frames, see `GrabMode::Auto`
+    streams: u32,
+    stats: PipelineStats,
+    stages: Vec<Box<dyn Stage>>,
+    // Drawn after the stages, kept separate so `configure` doesn't replace it
+    overlay: Option<Overlay>,
+    sinks: Vec<Box<dyn Sink>>,
+    analysis: Option<Analysis>,
+    factories: Vec<(&'static str, StageFactory)>,
+}
+
+impl Pipeline {
+    pub fn new(quality: u8) -> Self {
+        let mut pipeline = Self {
+            quality,
+            budget: None,
+            grab_mode: GrabMode::Auto,
+            streams: 0,
+            stats: PipelineStats::default(),
+            stages: Vec::new(),
+            overlay: None,
+            sinks: Vec::new(),
+            analysis: None,
+            factories: Vec::new(),
+        };
+
+        pipeline.register("crop", Crop::from_args);
+        pipeline.register("rotate", Rotate::from_args);
+        pipeline.register("rotate180", |_| Ok(Box::new(Rotate { degrees: 180 })));
+        pipeline.register("downscale", Downscale::from_args);
+        pipeline.register("timestamp", Timestamp::from_args);
+        pipeline.register("rgb888", ToRgb888::from_args);
+        pipeline.register("rgb565", ToRgb565::from_args);
+
+        pipeline
+    }
+
+    // Makes a stage available to `configure` under `name`
+    pub fn register(&mut self, name: &'static str, factory: StageFactory) {
+        self.factories.push((name, factory));
+    }
+
+    // Replaces the stage list from a spec like "crop:0,0,320,240;rotate180",
+    // stages run in the order they are listed
+    pub fn configure(&mut self, spec: &str) -> Result<()> {
+        let mut stages = Vec::new();
+
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, args) = entry.split_once(':').unwrap_or((entry, ""));
+            let factory = self
+                .factories
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, f)| f)
+                .ok_or_else(|| anyhow!("Unknown pipeline stage '{}'", name))?;
+            stages.push(factory(args)?);
+        }
+
+        self.stages = stages;
+        Ok(())
+    }
+
+    pub fn set_overlay(&mut self, overlay: Overlay) {
+        self.overlay = Some(overlay);
+    }
+
+    // Whether frames come out just as the driver hands them over, no stages or overlay
+    fn untouched(&self) -> bool {
+        self.stages.is_empty() && self.overlay.as_ref().map_or(true, Overlay::is_empty)
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    // Keeps a grayscale copy of every regular frame in `analysis` the sensor didn't encode
+    // itself. One-off shots and frames redone to fit the budget are left out, they're not the
+    // size the watchers expect.
+    pub fn set_analysis(&mut self, analysis: Analysis) {
+        self.analysis = Some(analysis);
+    }
+
+    // Caps the size of an encoded frame, e.g. to fit in an MQTT payload
+    pub fn set_budget(&mut self, max_bytes: Option<usize>) {
+        self.budget = max_bytes;
+    }
+
+    // Our encoder's quality, 1-100. Only used for sensors that don't produce JPEG themselves.
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.clamp(1, 100);
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    pub fn set_grab_mode(&mut self, mode: GrabMode) {
+        self.grab_mode = mode;
+    }
+
+    // The configured mode, which may be Auto
+    pub fn grab_mode(&self) -> GrabMode {
+        self.grab_mode
+    }
+
+    pub fn stream_started(&mut self) {
+        self.streams += 1;
+    }
+
+    pub fn stream_stopped(&mut self) {
+        self.streams = self.streams.saturating_sub(1);
+    }
+
+    // What the next capture will actually do
+    pub fn effective_grab_mode(&self) -> GrabMode {
+        match self.grab_mode {
+            GrabMode::Auto if self.streams > 0 => GrabMode::Latest,
+            GrabMode::Auto => GrabMode::WhenEmpty,
+            mode => mode,
+        }
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    // capture -> stages -> encode -> sinks, returns the encoded JPEG
+    pub fn run(&mut self, cam: &impl FrameSource) -> Result<Vec<u8>> {
+        let mut jpeg = self.capture_as(cam, ImageFormat::Jpeg, self.quality, true)?;
+        self.stats.frames += 1;
+
+        if let Some(budget) = self.budget.filter(|b| jpeg.len() > *b) {
+            self.stats.over_budget += 1;
+            jpeg = self.fit_budget(cam, budget, jpeg)?;
+            if jpeg.len() > budget {
+                self.stats.budget_failures += 1;
+                warn!(
+                    "Frame is still {} bytes, over the {} byte budget",
+                    jpeg.len(),
+                    budget
+                );
+            }
+        }
+
+        self.stats.last_jpeg_bytes = jpeg.len() as u32;
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.consume(&jpeg) {
+                warn!("Pipeline sink failed: {:?}", e);
+            }
+        }
+
+        Ok(jpeg)
+    }
+
+    // Same as `run`, but without copying the frame into a Vec when there's nothing to do to it. JPEG
+    // from the sensor is handed out in the driver's own buffer, so hold on to it for as short
+    // a time as possible.
+    pub fn run_in_place<'c>(&mut self, cam: &'c Camera) -> Result<Jpeg<'c>> {
+        if !self.untouched() {
+            return Ok(Jpeg::Owned(self.run(cam)?));
+        }
+
+        self.drop_stale(cam);
+        let fb = match cam.try_capture() {
+            Ok(fb) => fb,
+            Err(e) => {
+                self.stats.failures += 1;
+                return Err(e.into());
+            }
+        };
+        if let Some(analysis) = &self.analysis {
+            analysis.update(&fb.as_raw());
+        }
+        let jpeg = if fb.format() == pixformat_t_PIXFORMAT_JPEG {
+            Jpeg::Framebuffer(fb)
+        } else {
+            let converted = fb.to_jpeg(self.quality);
+            drop(fb);
+            match converted {
+                Ok(converted) => Jpeg::Converted(converted),
+                Err(e) => {
+                    self.stats.failures += 1;
+                    return Err(e.into());
+                }
+            }
+        };
+
+        if self
+            .budget
+            .map_or(false, |budget| jpeg.data().len() > budget)
+        {
+            // Shrinking needs the driver's buffer back to take the next frame
+            drop(jpeg);
+            return Ok(Jpeg::Owned(self.run(cam)?));
+        }
+        self.stats.frames += 1;
+        self.stats.last_jpeg_bytes = jpeg.data().len() as u32;
+
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.consume(jpeg.data()) {
+                warn!("Pipeline sink failed: {:?}", e);
+            }
+        }
+
+        Ok(jpeg)
+    }
+
+    // Same as `run`, but into `buf`, which the caller keeps from frame to frame so a stream
+    // doesn't allocate a new frame-sized Vec for every one. The frame is `buf[..len]`, `buf`
+    // grows as frames need. JPEG needing no work is copied straight out of the driver's
+    // buffer, see `CameraExt::capture_jpeg_into`.
+    pub fn run_into(&mut self, cam: &impl FrameSource, buf: &mut Vec<u8>) -> Result<usize> {
+        if !self.untouched() || self.analysis.is_some() || self.budget.is_some() {
+            *buf = self.run(cam)?;
+            return Ok(buf.len());
+        }
+
+        // A quarter over the last frame leaves room for the scene getting busier
+        let last = self.stats.last_jpeg_bytes as usize;
+        if buf.len() < last + last / 4 {
+            buf.resize(last + last / 4, 0);
+        }
+        self.drop_stale(cam);
+        let mut result = cam.capture_jpeg_into(self.quality, buf);
+        let too_small = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref().copied());
+        if let Some(BufferTooSmall(needed)) = too_small {
+            // That frame is gone, the next one will be about the same size
+            buf.resize(needed + needed / 4, 0);
+            result = cam.capture_jpeg_into(self.quality, buf);
+        }
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                self.stats.failures += 1;
+                return Err(e);
+            }
+        };
+        self.stats.frames += 1;
+        self.stats.last_jpeg_bytes = len as u32;
+
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.consume(&buf[..len]) {
+                warn!("Pipeline sink failed: {:?}", e);
+            }
+        }
+
+        Ok(len)
+    }
+
+    // A guess at how big `shot` would come out, without capturing anything. JPEG sizes are
+    // scaled from the last frame, BMP sizes are exact.
+    pub fn estimate_size(&self, sensor: &Sensor, shot: Shot) -> usize {
+        let current = sensor.status().framesize;
+        let pixels = |framesize| {
+            framesize_dimensions(framesize).map_or(0, |(width, height)| (width * height) as usize)
+        };
+        let shot_pixels = pixels(shot.framesize.unwrap_or(current));
+
+        match shot.format {
+            // fmt2bmp writes 24 bit pixels without any row padding
+            ImageFormat::Bmp => BMP_HEADER_LEN + shot_pixels * 3,
+            ImageFormat::Jpeg => match (self.stats.last_jpeg_bytes as usize, pixels(current)) {
+                (0, _) | (_, 0) => shot_pixels / JPEG_PIXELS_PER_BYTE,
+                (last, current_pixels) => last * shot_pixels / current_pixels,
+            },
+        }
+    }
+
+    // The driver's buffer exactly as it came from the sensor, no stages and no encoding
+    pub fn run_raw(&mut self, cam: &impl FrameSource) -> Result<Frame> {
+        self.drop_stale(cam);
+
+        let frame = match cam.with_frame(Frame::from_raw) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.stats.failures += 1;
+                return Err(e);
+            }
+        };
+        self.stats.frames += 1;
+
+        Ok(frame)
+    }
+
+    // A one-off capture with `shot` applied, the sensor is put back afterwards. Skips the
+    // budget and the sinks, which are there for the regular stream of frames.
+    pub fn run_shot(&mut self, cam: &impl FrameSource, shot: Shot) -> Result<Vec<u8>> {
+        let (framesize, quality) = (cam.framesize()?, cam.quality()?);
+
+        let result = self.take_shot(cam, shot);
+
+        if shot.framesize.is_some() {
+            cam.set_framesize(framesize)?;
+        }
+        if shot.quality.is_some() && cam.sensor_jpeg()? {
+            cam.set_quality(quality)?;
+        }
+
+        result
+    }
+
+    fn take_shot(&mut self, cam: &impl FrameSource, shot: Shot) -> Result<Vec<u8>> {
+        let mut changed = false;
+        if let Some(framesize) = shot.framesize {
+            cam.set_framesize(framesize)?;
+            changed = true;
+        }
+        // A sensor producing JPEG itself ignores our encoder quality. Its own scale runs the
+        // other way, 0-63 with lower being better.
+        let hardware_jpeg = cam.sensor_jpeg()?;
+        if let Some(quality) = shot.quality.filter(|_| hardware_jpeg) {
+            let sensor_quality = (100 - quality.min(100) as i32) * WORST_SENSOR_QUALITY / 100;
+            cam.set_quality(sensor_quality)?;
+            changed = true;
+        }
+        if changed && self.effective_grab_mode() != GrabMode::Latest {
+            cam.discard();
+        }
+
+        let frame = self.capture_as(
+            cam,
+            shot.format,
+            shot.quality.unwrap_or(self.quality),
+            false,
+        )?;
+        self.stats.frames += 1;
+        Ok(frame)
+    }
+
+    // Re-does the frame at lower quality and then smaller sizes until it fits, then puts the
+    // sensor back the way it was so the next frame starts from the configured settings
+    fn fit_budget(
+        &mut self,
+        cam: &impl FrameSource,
+        budget: usize,
+        jpeg: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let (framesize, quality) = (cam.framesize()?, cam.quality()?);
+
+        let result = self.shrink(cam, budget, jpeg);
+
+        cam.set_quality(quality)?;
+        cam.set_framesize(framesize)?;
+
+        result
+    }
+
+    fn shrink(
+        &mut self,
+        cam: &impl FrameSource,
+        budget: usize,
+        mut jpeg: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        // A sensor producing JPEG itself ignores our encoder quality, it has to be told to
+        // compress harder instead
+        let hardware_jpeg = cam.sensor_jpeg()?;
+        let original = cam.framesize()?;
+
+        let mut quality = self.quality;
+        let mut sensor_quality = cam.quality()?;
+        let mut framesize = original;
+
+        while jpeg.len() > budget {
+            if hardware_jpeg && sensor_quality < WORST_SENSOR_QUALITY {
+                sensor_quality = (sensor_quality + SENSOR_QUALITY_STEP).min(WORST_SENSOR_QUALITY);
+                cam.set_quality(sensor_quality)?;
+            } else if !hardware_jpeg && quality > MIN_QUALITY {
+                quality = quality.saturating_sub(QUALITY_STEP).max(MIN_QUALITY);
+            } else if let Some(smaller) = FALLBACK_SIZES
+                .iter()
+                .rev()
+                .find(|s| framesize_pixels(**s) < framesize_pixels(framesize))
+            {
+                framesize = *smaller;
+                cam.set_framesize(framesize)?;
+            } else {
+                break;
+            }
+
+            // The driver may already be holding a frame taken with the old settings,
+            // Latest throws that one away by itself
+            let stale = hardware_jpeg || framesize != original;
+            if stale && self.effective_grab_mode() != GrabMode::Latest {
+                cam.discard();
+            }
+            jpeg = self.capture(cam, quality)?;
+        }
+
+        Ok(jpeg)
+    }
+
+    fn drop_stale(&self, cam: &impl FrameSource) {
+        if self.effective_grab_mode() == GrabMode::Latest {
+            cam.discard();
+        }
+    }
+
+    fn capture(&mut self, cam: &impl FrameSource, quality: u8) -> Result<Vec<u8>> {
+        self.capture_as(cam, ImageFormat::Jpeg, quality, false)
+    }
+
+    fn capture_as(
+        &mut self,
+        cam: &impl FrameSource,
+        format: ImageFormat,
+        quality: u8,
+        analyse: bool,
+    ) -> Result<Vec<u8>> {
+        let result = self.grab_and_encode(cam, format, quality, analyse);
+        if result.is_err() {
+            self.stats.failures += 1;
+        }
+        result
+    }
+
+    fn grab_and_encode(
+        &mut self,
+        cam: &impl FrameSource,
+        format: ImageFormat,
+        quality: u8,
+        analyse: bool,
+    ) -> Result<Vec<u8>> {
+        self.drop_stale(cam);
+        let analysis = self.analysis.as_ref().filter(|_| analyse);
+        let untouched = self.untouched() && format == ImageFormat::Jpeg;
+
+        if untouched && analysis.is_none() {
+            return cam.capture_jpeg(quality);
+        }
+
+        // Only what's needed comes out of the source's buffer, it goes back before the
+        // stages run
+        let grabbed = cam.with_frame(|raw| -> camera::Result<Grabbed> {
+            if let Some(analysis) = analysis {
+                analysis.update(raw);
+            }
+            Ok(if !untouched {
+                Grabbed::Frame(Frame::from_raw(raw))
+            } else if raw.format == pixformat_t_PIXFORMAT_JPEG {
+                Grabbed::Jpeg(raw.data.to_vec())
+            } else {
+                let (data, width, height) = (raw.data, raw.width, raw.height);
+                Grabbed::Converted(Converted::jpeg(data, width, height, raw.format, quality)?)
+            })
+        })??;
+
+        let image = {
+            let mut frame = match grabbed {
+                Grabbed::Jpeg(jpeg) => return Ok(jpeg),
+                Grabbed::Converted(jpeg) => return Ok(jpeg.data().to_vec()),
+                Grabbed::Frame(frame) => frame,
+            };
+
+            for stage in &mut self.stages {
+                frame = stage.process(frame)?;
+            }
+            if let Some(overlay) = &self.overlay {
+                frame = overlay.apply(frame)?;
+            }
+            match format {
+                ImageFormat::Jpeg => frame.encode(quality)?,
+                ImageFormat::Bmp => frame.encode_bmp()?,
+            }
+        };
+
+        Ok(image)
+    }
+}
+
+fn parse_args<const N: usize>(stage: &str, args: &str) -> Result<[usize; N]> {
+    let mut values = [0; N];
+    let mut parts = args.split(',');
+    for value in values.iter_mut() {
+        *value = parts
+            .next()
+            .and_then(|p| p.trim().parse().ok())
+            .ok_or_else(|| anyhow!("{} expects {} numeric arguments", stage, N))?;
+    }
+    if parts.next().is_some() {
+        bail!("{} expects {} numeric arguments", stage, N);
+    }
+    Ok(values)
+}
+
+struct Crop {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Crop {
+    fn from_args(args: &str) -> Result<Box<dyn Stage>> {
+        let [x, y, width, height] = parse_args("crop", args)?;
+        Ok(Box::new(Self {
+            x,
+            y,
+            width,
+            height,
+        }))
+    }
+}
+
+impl Stage for Crop {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        frame.crop(self.x, self.y, self.width, self.height)
+    }
+}
+
+struct Downscale {
+    factor: usize,
+}
+
+impl Downscale {
+    fn from_args(args: &str) -> Result<Box<dyn Stage>> {
+        let [factor] = parse_args("downscale", args)?;
+        if factor != 2 && factor != 4 {
+            bail!("downscale factor must be 2 or 4");
+        }
+        Ok(Box::new(Self { factor }))
+    }
+}
+
+impl Stage for Downscale {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        frame.downscale(self.factor)
+    }
+}
+
+struct Rotate {
+    degrees: u32,
+}
+
+impl Rotate {
+    fn from_args(args: &str) -> Result<Box<dyn Stage>> {
+        let [degrees] = parse_args("rotate", args)?;
+        if ![90, 180, 270].contains(&degrees) {
+            bail!("rotation must be 90, 180 or 270 degrees");
+        }
+        Ok(Box::new(Self {
+            degrees: degrees as u32,
+        }))
+    }
+}
+
+impl Stage for Rotate {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        frame.rotate(self.degrees)
+    }
+}
+
+// Takes the pipeline and then the camera, the order everyone locks them in, giving up with
+// `CaptureError::Busy` if that takes longer than `CAMERA_WAIT`
+pub fn lock_for_capture<'a, S: FrameSource>(
+    pipeline: &'a Mutex<Pipeline>,
+    cam: &'a Mutex<S>,
+) -> Result<(MutexGuard<'a, Pipeline>, MutexGuard<'a, S>)> {
+    let started = Instant::now();
+    let pipeline = try_lock_for(pipeline, CAMERA_WAIT).ok_or(CaptureError::Busy)?;
+    let left = CAMERA_WAIT.saturating_sub(started.elapsed());
+    let cam = try_lock_for(cam, left).ok_or(CaptureError::Busy)?;
+    Ok((pipeline, cam))
+}
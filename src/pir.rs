@@ -0,0 +1,194 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    hal::{
+        delay::BLOCK,
+        gpio::{AnyIOPin, InterruptType, PinDriver, Pull},
+        task::notification::Notification,
+    },
+    http::{server::EspHttpServer, Method},
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::http::{query_param, send_jpeg, write_json, ApiError};
+use crate::lock::lock;
+use crate::motion::{self, Motion, MotionEvent, Source};
+use crate::pipeline::Pipeline;
+use crate::source::FrameSource;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PirSettings {
+    // Frames captured on each trigger, kept until the next one
+    pub frames: u32,
+    pub interval: Duration,
+    // Triggers closer together than this are ignored. Most PIR modules hold their output high
+    // for a few seconds on their own as well.
+    pub cooldown: Duration,
+}
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    triggers: u32,
+    last_trigger: Option<u64>,
+    // Of the last trigger's burst
+    frames: usize,
+}
+
+// Raises a motion event on the rising edge of a PIR sensor's output and captures a burst of
+// frames for it, independent of comparing frames
+#[derive(Clone)]
+pub struct Pir {
+    state: Arc<Mutex<State>>,
+    burst: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Pir {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        pin: AnyIOPin,
+        motion: Motion,
+        settings: PirSettings,
+    ) -> Result<Self> {
+        let mut input = PinDriver::input(pin)?;
+        input.set_pull(Pull::Down)?;
+        input.set_interrupt_type(InterruptType::PosEdge)?;
+
+        let pir = Self {
+            state: Arc::new(Mutex::new(State::default())),
+            burst: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let task = pir.clone();
+        thread::Builder::new()
+            .name("pir".into())
+            .stack_size(6144)
+            .spawn(move || {
+                // The notification wakes whichever task creates it, so it has to be this one
+                let notification = Notification::new();
+                let notifier = notification.notifier();
+                // The handler runs in the ISR, it can't do more than wake us
+                if let Err(e) = unsafe {
+                    input.subscribe(move || {
+                        notifier.notify_and_yield(NonZeroU32::MIN);
+                    })
+                } {
+                    warn!("PIR interrupt failed: {:?}", e);
+                    return;
+                }
+
+                let mut last_trigger: Option<Instant> = None;
+                loop {
+                    // Each interrupt disables itself until it's enabled again
+                    if let Err(e) = input.enable_interrupt() {
+                        warn!("PIR interrupt failed: {:?}", e);
+                        return;
+                    }
+                    notification.wait(BLOCK);
+
+                    if last_trigger.is_some_and(|at| at.elapsed() < settings.cooldown) {
+                        continue;
+                    }
+                    last_trigger = Some(Instant::now());
+
+                    task.capture(&cam, &pipeline, settings);
+                    motion.raise(MotionEvent {
+                        timestamp: motion::now(),
+                        source: Source::Pir,
+                        score: 0,
+                        cells: 0,
+                        faces: Vec::new(),
+                    });
+                }
+            })?;
+
+        info!("Watching the PIR sensor");
+        Ok(pir)
+    }
+
+    fn capture(
+        &self,
+        cam: &Mutex<impl FrameSource>,
+        pipeline: &Mutex<Pipeline>,
+        settings: PirSettings,
+    ) {
+        let started = Instant::now();
+        let mut burst = Vec::with_capacity(settings.frames as usize);
+        for index in 0..settings.frames {
+            if let Some(left) = (settings.interval * index).checked_sub(started.elapsed()) {
+                thread::sleep(left);
+            }
+            // Waits for the camera rather than giving up on a busy one, the frames are the
+            // point of the trigger
+            let jpeg = {
+                let mut pipeline = lock(pipeline);
+                let cam = lock(cam);
+                pipeline.run(&*cam)
+            };
+            match jpeg {
+                Ok(jpeg) => burst.push(jpeg),
+                Err(e) => {
+                    warn!(
+                        "PIR capture {} of {} failed: {:?}",
+                        index + 1,
+                        settings.frames,
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+
+        {
+            let mut state = lock(&self.state);
+            state.triggers += 1;
+            state.last_trigger = Some(motion::now());
+            state.frames = burst.len();
+        }
+        *lock(&self.burst) = burst;
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, pir: Pir) -> Result<()> {
+    let status_pir = pir.clone();
+    server.fn_handler(
+        "/api/pir",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&status_pir.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    // `/api/pir/frame?index=0` for the first frame of the last burst
+    server.fn_handler(
+        "/api/pir/frame",
+        Method::Get,
+        auth.protect(move |request| {
+            let index = match query_param(request.uri(), "index").map(str::parse) {
+                Some(Ok(index)) => index,
+                None => 0,
+                Some(Err(e)) => return ApiError::bad_request(e).send(request),
+            };
+
+            let state = lock(&pir.state).clone();
+            // A copy so the next trigger doesn't wait on a slow client
+            let Some(jpeg) = lock(&pir.burst).get(index).cloned() else {
+                let message = format!("Only {} frames in the last burst", state.frames);
+                return ApiError::not_found(message).send(request);
+            };
+            let etag = format!("\"pir-{}-{}\"", state.triggers, index);
+            send_jpeg(request, &jpeg, Some(&etag), "pir;dur=0")
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    handle::RawHandle,
+    http::server::EspHttpServer,
+    sys::{esp, httpd_get_client_list, httpd_handle_t},
+};
+use log::{info, warn};
+use std::{
+    ffi::c_int,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::Camera;
+use crate::lock::lock;
+use crate::pipeline::Pipeline;
+
+// A frame started longer ago than this belongs to some earlier connection
+const MAX_AGE: Duration = Duration::from_secs(1);
+// Don't hold a request up forever if the capture thread wedges
+const WAIT_LIMIT: Duration = Duration::from_secs(3);
+
+// How often the server's sessions are looked at for new connections
+const POLL: Duration = Duration::from_millis(20);
+// lwIP's socket limit tops out here, httpd wants room for all it might have open
+const MAX_SESSIONS: usize = 16;
+
+enum Slot {
+    Idle,
+    Capturing,
+    Ready {
+        started: Instant,
+        finished: Instant,
+        jpeg: Vec<u8>,
+    },
+}
+
+// Starts a capture the moment a client connects, so the frame is well on its way by the
+// time the request has been received and parsed
+#[derive(Clone)]
+pub struct Prewarm {
+    slot: Arc<(Mutex<Slot>, Condvar)>,
+    trigger: SyncSender<()>,
+}
+
+impl Prewarm {
+    pub fn start(cam: Arc<Mutex<Camera>>, pipeline: Arc<Mutex<Pipeline>>) -> Result<Self> {
+        // One pending trigger is enough, a burst of connections still only wants one frame
+        let (trigger, triggered) = sync_channel(1);
+        let prewarm = Self {
+            slot: Arc::new((Mutex::new(Slot::Idle), Condvar::new())),
+            trigger,
+        };
+
+        let slot = prewarm.slot.clone();
+        thread::Builder::new()
+            .name("prewarm".into())
+            .stack_size(6144)
+            .spawn(move || {
+                let (slot, ready) = &*slot;
+                while triggered.recv().is_ok() {
+                    {
+                        let mut slot = lock(&slot);
+                        if let Slot::Ready { started, .. } = *slot {
+                            if started.elapsed() < MAX_AGE {
+                                continue;
+                            }
+                        }
+                        *slot = Slot::Capturing;
+                    }
+
+                    let started = Instant::now();
+                    let result = {
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run(&*cam)
+                    };
+
+                    *lock(&slot) = match result {
+                        Ok(jpeg) => Slot::Ready {
+                            started,
+                            finished: Instant::now(),
+                            jpeg,
+                        },
+                        Err(e) => {
+                            warn!("Pre-warm capture failed: {:?}", e);
+                            Slot::Idle
+                        }
+                    };
+                    ready.notify_all();
+                }
+            })?;
+
+        info!("Pre-warming captures on connect");
+        Ok(prewarm)
+    }
+
+    // Watches the server's sessions so every newly accepted connection triggers a capture.
+    // esp-idf-svc has no way to set httpd's open callback, so this polls the client list httpd
+    // keeps for anyone to read. The server has to stay up for as long as the firmware runs.
+    pub fn install(&self, server: &EspHttpServer) -> Result<()> {
+        // Raw pointers aren't Send, the handle is only ever passed back to httpd
+        let handle = server.handle() as usize;
+        let trigger = self.trigger.clone();
+        thread::Builder::new()
+            .name("prewarm-watch".into())
+            .stack_size(3072)
+            .spawn(move || {
+                let mut known: Vec<c_int> = Vec::new();
+                loop {
+                    thread::sleep(POLL);
+
+                    let mut fds = [0 as c_int; MAX_SESSIONS];
+                    let mut count = MAX_SESSIONS as _;
+                    let listed = esp!(unsafe {
+                        httpd_get_client_list(
+                            handle as httpd_handle_t,
+                            &mut count,
+                            fds.as_mut_ptr(),
+                        )
+                    });
+                    if listed.is_err() {
+                        continue;
+                    }
+
+                    let open = &fds[..count as usize];
+                    if open.iter().any(|fd| !known.contains(fd)) {
+                        let _ = trigger.try_send(());
+                    }
+                    known.clear();
+                    known.extend_from_slice(open);
+                }
+            })?;
+        Ok(())
+    }
+
+    // Hands over the pre-warmed frame if there is a fresh one, along with how much
+    // capture time it saved the caller
+    pub fn take(&self) -> Option<(Vec<u8>, Duration)> {
+        let requested = Instant::now();
+        let (slot, ready) = &*self.slot;
+
+        let mut slot = lock(&slot);
+        while let Slot::Capturing = *slot {
+            let (next, wait) = ready.wait_timeout(slot, WAIT_LIMIT).unwrap();
+            slot = next;
+            if wait.timed_out() {
+                return None;
+            }
+        }
+
+        match std::mem::replace(&mut *slot, Slot::Idle) {
+            Slot::Ready {
+                started,
+                finished,
+                jpeg,
+            } if started.elapsed() < MAX_AGE => {
+                let saved = requested
+                    .saturating_duration_since(started)
+                    .min(finished - started);
+                Some((jpeg, saved))
+            }
+            _ => None,
+        }
+    }
+}
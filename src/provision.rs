@@ -0,0 +1,268 @@
+use anyhow::Result;
+use embedded_svc::http::server::Connection;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::{
+        delay::FreeRtos,
+        gpio::{AnyIOPin, PinDriver, Pull},
+        modem::Modem,
+        peripheral, reset,
+    },
+    http::{
+        server::{Configuration as HttpConfiguration, EspHttpServer, HandlerResult, Request},
+        Method,
+    },
+    io::Write,
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    wifi::{AccessPointConfiguration, AuthMethod, Configuration, EspWifi},
+};
+use log::{info, warn};
+use qrcode::{
+    render::{svg, unicode},
+    QrCode,
+};
+use std::sync::Mutex;
+
+use crate::auth::Auth;
+use crate::http::{form_param, read_body, url_decode, ApiError};
+use crate::lock::lock;
+use crate::mdns;
+
+const NVS_NAMESPACE: &str = "wifi";
+
+const FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width, initial-scale=1"><title>tigercam setup</title></head>
+<body>
+<h1>tigercam setup</h1>
+<form method="post">
+<p><label>Network <input name="ssid" maxlength="32" required></label></p>
+<p><label>Password <input name="psk" type="password" maxlength="64"></label></p>
+<p><button type="submit">Save and reboot</button></p>
+</form>
+{join}{note}</body>
+</html>
+"#;
+
+// In the form on the setup access point, for joining it from a second phone
+const JOIN: &str = r#"<p>Setting up from another phone? Scan this to join the setup network.</p>
+<p><img src="/qr/wifi.svg" width="200" height="200" alt="Setup network QR code"></p>
+"#;
+
+// On /setup when nothing could authorize saving the form
+const NO_AUTH: &str = r#"<p>Saving needs HTTP credentials or an API key to be configured first.</p>
+"#;
+
+// True if `pin` is being held low, it's pulled up so an unconnected pin reads high
+pub fn forced(pin: i32) -> Result<bool> {
+    if pin < 0 {
+        return Ok(false);
+    }
+
+    let mut driver = PinDriver::input(unsafe { AnyIOPin::new(pin) })?;
+    driver.set_pull(Pull::Up)?;
+    // Give the pull-up a moment to charge whatever is attached to the pin
+    FreeRtos::delay_ms(10);
+
+    Ok(driver.is_low())
+}
+
+// WiFi credentials saved through the provisioning page, if there are any
+pub fn stored_credentials(nvs: EspDefaultNvsPartition) -> Result<Option<(String, String)>> {
+    let nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+
+    let mut ssid_buf = [0u8; 33];
+    let mut psk_buf = [0u8; 65];
+    let Some(ssid) = nvs.get_str("ssid", &mut ssid_buf)? else {
+        return Ok(None);
+    };
+    let psk = nvs.get_str("psk", &mut psk_buf)?.unwrap_or("");
+
+    Ok(Some((ssid.to_string(), psk.to_string())))
+}
+
+// The password for the fallback access point, made up on first use and kept so it can be
+// written down once
+pub fn fallback_password(nvs: EspDefaultNvsPartition) -> Result<String> {
+    let mut nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+
+    let mut buf = [0u8; 65];
+    if let Some(password) = nvs.get_str("ap_psk", &mut buf)? {
+        return Ok(password.to_string());
+    }
+
+    let password = (0..2)
+        .map(|_| format!("{:08x}", unsafe { esp_idf_svc::sys::esp_random() }))
+        .collect::<String>();
+    nvs.set_str("ap_psk", &password)?;
+    Ok(password)
+}
+
+// The WIFI: scheme phone cameras understand, our SSIDs and passwords never have anything
+// needing escaping
+fn join_code(ssid: &str, password: Option<&str>) -> String {
+    match password {
+        Some(password) => format!("WIFI:T:WPA;S:{};P:{};;", ssid, password),
+        None => format!("WIFI:T:nopass;S:{};;", ssid),
+    }
+}
+
+fn qr_svg(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes())?;
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+fn qr_art(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes())?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+// Headless boards only have the serial console to show the codes on
+fn log_qr(label: &str, data: &str) -> Result<()> {
+    info!("{} ({}):\n{}", label, data, qr_art(data)?);
+    Ok(())
+}
+
+// Shows how to join the fallback access point on the serial console only. It has the
+// password in it, so it stays out of the log, which can end up in a file served over HTTP.
+pub fn print_fallback_join(ssid: &str, password: &str) -> Result<()> {
+    let data = join_code(ssid, Some(password));
+    println!(
+        "Fallback access point {}, password {}:\n{}",
+        ssid,
+        password,
+        qr_art(&data)?
+    );
+    Ok(())
+}
+
+fn save_credentials(nvs: &mut EspNvs<NvsDefault>, ssid: &str, psk: &str) -> Result<()> {
+    nvs.set_str("ssid", ssid)?;
+    nvs.set_str("psk", psk)?;
+    Ok(())
+}
+
+fn send_form<C: Connection>(request: Request<C>, join: bool, can_save: bool) -> HandlerResult {
+    let form = FORM
+        .replace("{join}", if join { JOIN } else { "" })
+        .replace("{note}", if can_save { "" } else { NO_AUTH });
+    let mut response = request.into_response(200, None, &[("Content-Type", "text/html")])?;
+    let _ = response.write_all(form.as_bytes());
+    Ok(())
+}
+
+// Saves the credentials POSTed from the form and reboots to join that network
+fn save_form<C: Connection>(
+    mut request: Request<C>,
+    store: &Mutex<EspNvs<NvsDefault>>,
+) -> HandlerResult {
+    let body = match read_body(&mut request, 256) {
+        Ok(body) => body,
+        Err(e) => return ApiError::bad_request(e).send(request),
+    };
+    let body = String::from_utf8_lossy(&body);
+
+    let ssid = form_param(&body, "ssid")
+        .map(url_decode)
+        .unwrap_or_default();
+    let psk = form_param(&body, "psk").map(url_decode).unwrap_or_default();
+    if ssid.is_empty() || ssid.len() > 32 || psk.len() > 64 {
+        return ApiError::bad_request("Invalid network name or password").send(request);
+    }
+
+    save_credentials(&mut lock(store), &ssid, &psk)?;
+    info!("Saved credentials for {}, rebooting", ssid);
+
+    let mut response = request.into_ok_response()?;
+    let _ = writeln!(response, "Saved, rebooting to join {}", ssid);
+    drop(response);
+
+    FreeRtos::delay_ms(1000);
+    reset::restart();
+}
+
+// Brings up an open esp32cam-XXXX access point serving a page to enter WiFi credentials,
+// saving them reboots into normal operation. Never returns unless setup fails.
+pub fn run(
+    modem: impl peripheral::Peripheral<P = Modem>,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+) -> Result<()> {
+    let mut wifi = EspWifi::new(modem, sysloop, Some(nvs.clone()))?;
+
+    let ssid = mdns::hostname(wifi.ap_netif().get_mac()?);
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().into(),
+        auth_method: AuthMethod::None,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    let setup_url = format!("http://{}/", ip);
+    info!("Provisioning AP {} is up, browse to {}", ssid, setup_url);
+
+    let wifi_svg = qr_svg(&join_code(&ssid, None))?;
+    let url_svg = qr_svg(&setup_url)?;
+    log_qr("Scan to join the setup network", &join_code(&ssid, None))?;
+    log_qr("Then scan to open the setup page", &setup_url)?;
+    // For later, the fallback access point goes by the same name but isn't open
+    print_fallback_join(&ssid, &fallback_password(nvs.clone())?)?;
+
+    let store = Mutex::new(EspNvs::new(nvs, NVS_NAMESPACE, true)?);
+    let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+
+    for (uri, svg) in [("/qr/wifi.svg", wifi_svg), ("/qr/setup.svg", url_svg)] {
+        server.fn_handler(uri, Method::Get, move |request| {
+            let mut response =
+                request.into_response(200, None, &[("Content-Type", "image/svg+xml")])?;
+            let _ = response.write_all(svg.as_bytes());
+            Ok(())
+        })?;
+    }
+
+    server.fn_handler("/", Method::Get, |request| send_form(request, true, true))?;
+    server.fn_handler("/", Method::Post, move |request| save_form(request, &store))?;
+
+    loop {
+        FreeRtos::delay_ms(1000);
+        if !wifi.is_started().unwrap_or(false) {
+            warn!("Provisioning AP stopped");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// The same form at /setup on the camera's own server, for changing networks without safe mode.
+// It's what to browse to on the fallback access point when the configured network is gone.
+// Saving it needs HTTP credentials or an API key, on the camera's own server anyone could
+// otherwise point it at a network of their choosing.
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    nvs: EspDefaultNvsPartition,
+) -> Result<()> {
+    let store = Mutex::new(EspNvs::new(nvs, NVS_NAMESPACE, true)?);
+
+    let form_auth = auth.clone();
+    server.fn_handler(
+        "/setup",
+        Method::Get,
+        auth.open(move |request| send_form(request, false, form_auth.is_configured())),
+    )?;
+    server.fn_handler(
+        "/setup",
+        Method::Post,
+        auth.require(move |request| save_form(request, &store)),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use anyhow::Result;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::auth::Auth;
+use crate::camera::{Camera, CameraError, CameraExt, Thumbnail};
+use crate::http::{write_json, ApiError};
+use crate::lock::lock;
+use crate::motion;
+use crate::pipeline::{lock_for_capture, Pipeline};
+
+// A QR code needs a few pixels per module to be read, SVGA leaves room for small ones without
+// the search taking seconds
+const SCAN_WIDTH: usize = 800;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Code {
+    pub payload: String,
+    // 1-40, how many modules across the code is
+    pub version: usize,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Scan {
+    // Seconds since the epoch
+    pub timestamp: u64,
+    pub codes: Vec<Code>,
+}
+
+// Every code that could be read, ones that were found but didn't decode are left out
+pub fn decode(image: &Thumbnail) -> Vec<Code> {
+    let mut prepared =
+        rqrr::PreparedImage::prepare_from_greyscale(image.width, image.height, |x, y| {
+            image.luma[y * image.width + x]
+        });
+    prepared
+        .detect_grids()
+        .iter()
+        .filter_map(|grid| match grid.decode() {
+            Ok((meta, payload)) => Some(Code {
+                payload,
+                version: meta.version.0,
+            }),
+            Err(e) => {
+                warn!("Found a QR code that didn't decode: {:?}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn capture(cam: &Camera) -> Result<Thumbnail, CameraError> {
+    cam.try_capture()?.grayscale(SCAN_WIDTH)
+}
+
+// Scans a frame every so often and keeps whatever it read last, for provisioning or
+// inventory setups that hold codes up to the camera
+#[derive(Clone)]
+pub struct QrScanner {
+    latest: Arc<Mutex<Option<Scan>>>,
+}
+
+impl QrScanner {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        interval: Duration,
+    ) -> Result<Self> {
+        let scanner = Self {
+            latest: Arc::new(Mutex::new(None)),
+        };
+
+        let latest = scanner.latest.clone();
+        thread::Builder::new()
+            .name("qr".into())
+            .stack_size(8192)
+            .spawn(move || loop {
+                thread::sleep(interval);
+
+                let image = {
+                    let _pipeline = lock(&pipeline);
+                    let cam = lock(&cam);
+                    capture(&cam)
+                };
+                let image = match image {
+                    Ok(image) => image,
+                    Err(CameraError::Asleep) => continue,
+                    Err(e) => {
+                        warn!("QR capture failed: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let codes = decode(&image);
+                if codes.is_empty() {
+                    continue;
+                }
+                info!("Read {} QR codes", codes.len());
+                *lock(&latest) = Some(Scan {
+                    timestamp: motion::now(),
+                    codes,
+                });
+            })?;
+
+        info!("Scanning for QR codes every {} ms", interval.as_millis());
+        Ok(scanner)
+    }
+
+    // The last scan that read anything
+    pub fn latest(&self) -> Option<Scan> {
+        lock(&self.latest).clone()
+    }
+}
+
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    scanner: Option<QrScanner>,
+) -> Result<()> {
+    // Scans a fresh frame, an empty `codes` when there's nothing readable in it
+    server.fn_handler(
+        "/api/qr",
+        Method::Get,
+        auth.protect(move |request| {
+            let image =
+                lock_for_capture(&pipeline, &cam).and_then(|(_pipeline, cam)| Ok(capture(&cam)?));
+            let image = match image {
+                Ok(image) => image,
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            let scan = Scan {
+                timestamp: motion::now(),
+                codes: decode(&image),
+            };
+            write_json(request, 200, &scan)
+        }),
+    )?;
+
+    if let Some(scanner) = scanner {
+        server.fn_handler(
+            "/api/qr/latest",
+            Method::Get,
+            auth.protect(move |request| match scanner.latest() {
+                Some(scan) => write_json(request, 200, &scan),
+                None => ApiError::not_found("No QR code read yet").send(request),
+            }),
+        )?;
+    }
+
+    Ok(())
+}
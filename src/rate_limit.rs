@@ -0,0 +1,72 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::lock::lock;
+
+// Clients we keep a bucket for, the one that's been quiet the longest makes room for new ones
+const MAX_CLIENTS: usize = 32;
+
+struct Bucket {
+    ip: IpAddr,
+    tokens: f32,
+    updated: Instant,
+}
+
+// A token bucket per client address. Every request takes a token and each client gets
+// `per_second` of them back every second, up to `burst`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Vec<Bucket>>>,
+    per_second: f32,
+    burst: f32,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(Vec::with_capacity(MAX_CLIENTS))),
+            per_second: per_second.max(1) as f32,
+            burst: burst.max(1) as f32,
+        }
+    }
+
+    // Err with how long until `ip` has a token again
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = lock(&self.buckets);
+
+        let index = match buckets.iter().position(|b| b.ip == ip) {
+            Some(index) => index,
+            None => {
+                if buckets.len() >= MAX_CLIENTS {
+                    if let Some(oldest) = (0..buckets.len()).min_by_key(|i| buckets[*i].updated) {
+                        buckets.swap_remove(oldest);
+                    }
+                }
+                buckets.push(Bucket {
+                    ip,
+                    tokens: self.burst,
+                    updated: now,
+                });
+                buckets.len() - 1
+            }
+        };
+
+        let bucket = &mut buckets[index];
+        let refill = now.duration_since(bucket.updated).as_secs_f32() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f32(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use anyhow::Result;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    fs::File,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::avi::AviWriter;
+use crate::camera::{Camera, CameraError};
+use crate::http::write_json;
+use crate::lock::lock;
+use crate::pipeline::Pipeline;
+use crate::rtp::jpeg_dimensions;
+use crate::sdcard::SdCard;
+
+// In the card's dated layout, see `SdCard::media_name`
+const KIND: &str = "recording";
+// Don't spin on a camera or card that keeps failing
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// FAT only updates a file's size in its directory entry when it's synced, anything written
+// since the last sync is gone if the power goes
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordingSettings {
+    pub fps: u32,
+    // How much goes in one file before the next is started
+    pub segment: Duration,
+    // What all the segments may add up to. The oldest go to make room for the next, which is
+    // expected to come out about as big as the last.
+    pub max_bytes: u64,
+}
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    // The segment being written
+    segment: Option<String>,
+    frames: u64,
+    segments: u32,
+    // Oldest segments deleted for room
+    deleted: u32,
+    // Of the finished segments still on the card
+    bytes: u64,
+}
+
+struct Segment {
+    name: String,
+    avi: AviWriter<File>,
+    started: Instant,
+    synced: Instant,
+    frames: u32,
+    // Of JPEG, about what the file takes up if it can't be finished
+    bytes: u64,
+}
+
+// Records around the clock to the SD card in fixed length MJPEG AVI segments, deleting the
+// oldest to stay under a total size, so the card always holds the latest few hours
+#[derive(Clone)]
+pub struct Recorder {
+    state: Arc<Mutex<State>>,
+}
+
+impl Recorder {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        sdcard: SdCard,
+        settings: RecordingSettings,
+    ) -> Result<Self> {
+        let recorder = Self {
+            state: Arc::new(Mutex::new(State::default())),
+        };
+
+        let state = recorder.state.clone();
+        let frame_time = Duration::from_millis(1000 / settings.fps.max(1) as u64);
+        thread::Builder::new()
+            .name("recording".into())
+            .stack_size(8192)
+            .spawn(move || {
+                let mut segment: Option<Segment> = None;
+                // How big the last finished segment came out, what room is made for the next
+                let mut last_size = 0;
+                loop {
+                    let started = Instant::now();
+                    let jpeg = {
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run(&*cam)
+                    };
+                    let jpeg = match jpeg {
+                        Ok(jpeg) => jpeg,
+                        Err(e) => {
+                            // Only footage of nothing is lost while the camera sleeps
+                            let asleep = matches!(
+                                e.downcast_ref::<CameraError>(),
+                                Some(CameraError::Asleep)
+                            );
+                            if !asleep {
+                                warn!("Recording capture failed: {:?}", e);
+                            }
+                            thread::sleep(RETRY_DELAY);
+                            continue;
+                        }
+                    };
+
+                    if segment
+                        .as_ref()
+                        .is_some_and(|s| s.started.elapsed() >= settings.segment)
+                    {
+                        last_size = finish(segment.take(), &sdcard, &state);
+                    }
+                    if segment.is_none() {
+                        let started = begin(
+                            &sdcard,
+                            &state,
+                            settings.max_bytes,
+                            last_size,
+                            &jpeg,
+                            settings.fps,
+                        );
+                        segment = match started {
+                            Ok(started) => Some(started),
+                            Err(e) => {
+                                warn!("Couldn't start a recording segment: {:?}", e);
+                                sdcard.report(&e);
+                                thread::sleep(RETRY_DELAY);
+                                continue;
+                            }
+                        };
+                    }
+
+                    let Some(current) = segment.as_mut() else {
+                        continue;
+                    };
+                    if let Err(e) = current.write(&jpeg) {
+                        warn!("Writing to {} failed: {:?}", current.name, e);
+                        sdcard.report(&e);
+                        finish(segment.take(), &sdcard, &state);
+                        thread::sleep(RETRY_DELAY);
+                        continue;
+                    }
+                    lock(&state).frames += 1;
+
+                    if let Some(left) = frame_time.checked_sub(started.elapsed()) {
+                        thread::sleep(left);
+                    }
+                }
+            })?;
+
+        info!(
+            "Recording at {} fps in {} s segments, {} MB at most",
+            settings.fps.max(1),
+            settings.segment.as_secs(),
+            settings.max_bytes / (1024 * 1024)
+        );
+        Ok(recorder)
+    }
+}
+
+impl Segment {
+    fn write(&mut self, jpeg: &[u8]) -> Result<()> {
+        self.avi.write_frame(jpeg)?;
+        self.frames += 1;
+        self.bytes += jpeg.len() as u64;
+        if self.synced.elapsed() >= SYNC_INTERVAL {
+            self.avi.get_ref().sync_all()?;
+            self.synced = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+// Makes room for a segment of `expected` bytes and opens it, sized for `first` which is written
+// to it next
+fn begin(
+    sdcard: &SdCard,
+    state: &Mutex<State>,
+    max_bytes: u64,
+    expected: u64,
+    first: &[u8],
+    fps: u32,
+) -> Result<Segment> {
+    let mut segments = sdcard.media(Some(KIND)).unwrap_or_default();
+    let mut total: u64 = segments.iter().map(|entry| entry.size).sum();
+    // Oldest last, to be popped first
+    segments.reverse();
+    while total + expected > max_bytes {
+        let Some(oldest) = segments.pop() else {
+            break;
+        };
+        sdcard.remove(&oldest.name)?;
+        info!("Deleted recording {} for room", oldest.name);
+        total -= oldest.size;
+        lock(state).deleted += 1;
+    }
+
+    // Players go by the JPEG headers, these only need to be about right
+    let (width, height) = jpeg_dimensions(first).unwrap_or((0, 0));
+    let name = sdcard.media_name(KIND, "avi")?;
+    sdcard.set_writing(&name, true);
+    let avi = sdcard
+        .create(&name)
+        .and_then(|file| Ok(AviWriter::new(file, width as u32, height as u32, fps)?));
+    let avi = match avi {
+        Ok(avi) => avi,
+        Err(e) => {
+            sdcard.set_writing(&name, false);
+            return Err(e);
+        }
+    };
+    info!("Recording to {}", name);
+    {
+        let mut state = lock(state);
+        state.segment = Some(name.clone());
+        state.segments += 1;
+        state.bytes = total;
+    }
+    Ok(Segment {
+        name,
+        avi,
+        started: Instant::now(),
+        synced: Instant::now(),
+        frames: 0,
+        bytes: 0,
+    })
+}
+
+// Writes the index and the real frame rate and closes the segment, then says how big it came
+// out. One that can't be finished is left playable, just without seeking.
+fn finish(segment: Option<Segment>, sdcard: &SdCard, state: &Mutex<State>) -> u64 {
+    let Some(mut segment) = segment else {
+        return 0;
+    };
+    let elapsed = segment.started.elapsed().as_millis().max(1) as u64;
+    segment
+        .avi
+        .set_fps(((segment.frames as u64 * 1000 + elapsed / 2) / elapsed) as u32);
+    let finished = segment.avi.finish().and_then(|file| {
+        file.sync_all()?;
+        file.metadata()
+    });
+    let size = match finished {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            warn!("Finishing {} failed: {:?}", segment.name, e);
+            segment.bytes
+        }
+    };
+    sdcard.set_writing(&segment.name, false);
+    let mut state = lock(state);
+    state.segment = None;
+    state.bytes += size;
+    size
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, recorder: Recorder) -> Result<()> {
+    server.fn_handler(
+        "/api/recording",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&recorder.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,358 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    hal::reset::ResetReason,
+    sys::{
+        cam::framesize_t_FRAMESIZE_QQVGA, esp_get_free_heap_size, esp_get_minimum_free_heap_size,
+        esp_timer_get_time,
+    },
+};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::Write,
+    io::Read,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::Camera;
+use crate::clock::{format_time, CLOCK_SET};
+use crate::http::post_json;
+use crate::lock::lock;
+use crate::motion::{self, MotionEvent, MotionSink, Source};
+use crate::pipeline::{ImageFormat, Pipeline, PipelineStats, Shot};
+use crate::sdcard::SdCard;
+
+const PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+// How often to look at whether the period is over
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Motion events listed in a summary, the count goes on past them
+const MAX_EVENTS: usize = 24;
+// Summaries are kept on the SD card here as "<until>.json" and "<until>.html"
+const CARD_DIR: &str = "reports";
+// The period so far, so a reboot picks it up rather than starting over
+const PROGRESS: &str = "reports/progress.json";
+// What thumbnails are saved as, see `SdCard::media_name`
+const THUMBNAIL_KIND: &str = "thumb";
+// Encoder quality of a thumbnail, 1-100
+const THUMBNAIL_QUALITY: u8 = 30;
+
+// A motion event as a summary lists it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReportedEvent {
+    pub timestamp: u64,
+    pub source: Source,
+    pub score: u32,
+    // Where on the SD card its thumbnail went, None without a card
+    pub thumbnail: Option<String>,
+}
+
+// What's been seen of the current period, saved to the SD card as it goes
+#[derive(Default, Serialize, Deserialize)]
+struct Progress {
+    // Seconds since the epoch it started, 0 while SNTP hasn't set the clock
+    from: u64,
+    motion_events: u32,
+    // The first `MAX_EVENTS`
+    motion: Vec<ReportedEvent>,
+}
+
+// What happened over one reporting period. Motion covers the whole period across reboots, the
+// capture counters only what's been since the later of the period starting and the last boot.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub period_s: u64,
+    // Seconds since the epoch, `from` is 0 when the clock wasn't set yet at the start
+    pub from: u64,
+    pub until: u64,
+    pub uptime_s: u64,
+    pub reset_reason: String,
+    pub frames: u32,
+    pub capture_failures: u32,
+    pub over_budget: u32,
+    pub budget_failures: u32,
+    pub motion_events: u32,
+    // The first few of them, see `MAX_EVENTS`
+    pub motion: Vec<ReportedEvent>,
+    // Bytes on the SD card, None without one or while it isn't answering
+    pub storage_total: Option<u64>,
+    pub storage_free: Option<u64>,
+    pub free_heap: u32,
+    // Lowest free heap seen since boot
+    pub min_free_heap: u32,
+}
+
+impl Summary {
+    fn compile(
+        reset_reason: ResetReason,
+        previous: PipelineStats,
+        now: PipelineStats,
+        progress: Progress,
+        sdcard: Option<&SdCard>,
+    ) -> Self {
+        let usage = sdcard.and_then(|sdcard| sdcard.usage().ok());
+        Self {
+            period_s: PERIOD.as_secs(),
+            from: progress.from,
+            until: motion::now(),
+            uptime_s: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+            reset_reason: format!("{:?}", reset_reason),
+            frames: now.frames.wrapping_sub(previous.frames),
+            capture_failures: now.failures.wrapping_sub(previous.failures),
+            over_budget: now.over_budget.wrapping_sub(previous.over_budget),
+            budget_failures: now.budget_failures.wrapping_sub(previous.budget_failures),
+            motion_events: progress.motion_events,
+            motion: progress.motion,
+            storage_total: usage.map(|(total, _)| total),
+            storage_free: usage.map(|(_, free)| free),
+            free_heap: unsafe { esp_get_free_heap_size() },
+            min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let storage = match (self.storage_total, self.storage_free) {
+            (Some(total), Some(free)) => format!(
+                "{} of {} MB",
+                (total - free) / (1024 * 1024),
+                total / (1024 * 1024)
+            ),
+            _ => "No SD card".to_string(),
+        };
+        let rows = [
+            ("Uptime", format!("{}h", self.uptime_s / 3600)),
+            ("Last reset", self.reset_reason.clone()),
+            ("Frames captured", self.frames.to_string()),
+            ("Capture failures", self.capture_failures.to_string()),
+            ("Frames over budget", self.over_budget.to_string()),
+            ("Budget misses", self.budget_failures.to_string()),
+            ("Motion events", self.motion_events.to_string()),
+            ("SD card used", storage),
+            ("Free heap", format!("{} bytes", self.free_heap)),
+            ("Lowest free heap", format!("{} bytes", self.min_free_heap)),
+        ];
+
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head><title>tigercam daily summary</title></head>\n<body>\n\
+             <h1>tigercam daily summary</h1>\n<table>\n",
+        );
+        for (label, value) in rows {
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, value);
+        }
+        html.push_str("</table>\n");
+
+        if !self.motion.is_empty() {
+            html.push_str("<h2>Motion</h2>\n<table>\n");
+            for event in &self.motion {
+                let thumbnail = match &event.thumbnail {
+                    Some(path) => format!("<img src=\"/files{}\" alt=\"\">", path),
+                    None => String::new(),
+                };
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{:?}</td><td>{}</td></tr>",
+                    format_time(event.timestamp),
+                    event.source,
+                    thumbnail
+                );
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+// Compiles a summary once a day, keeps the latest one around and optionally posts it to a
+// webhook. With an SD card every summary is saved there, motion events get a thumbnail, and
+// both the latest summary and the period so far survive a reboot.
+#[derive(Clone)]
+pub struct DailyReport {
+    latest: Arc<Mutex<Option<Summary>>>,
+    progress: Arc<Mutex<Progress>>,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    sdcard: Option<SdCard>,
+}
+
+impl DailyReport {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        sdcard: Option<SdCard>,
+        reset_reason: ResetReason,
+        webhook: &str,
+    ) -> Result<Self> {
+        let progress = sdcard
+            .as_ref()
+            .and_then(|sdcard| read_json::<Progress>(sdcard, PROGRESS))
+            .unwrap_or_else(|| Progress {
+                from: motion::now(),
+                ..Default::default()
+            });
+        let latest = sdcard.as_ref().and_then(newest_summary);
+        let report = Self {
+            latest: Arc::new(Mutex::new(latest)),
+            progress: Arc::new(Mutex::new(progress)),
+            cam,
+            pipeline,
+            sdcard,
+        };
+
+        let task = report.clone();
+        let webhook = (!webhook.is_empty()).then(|| webhook.to_string());
+        thread::Builder::new()
+            .name("report".into())
+            .stack_size(8192)
+            .spawn(move || {
+                let mut previous = lock(&task.pipeline).stats();
+                let mut started = Instant::now();
+                loop {
+                    thread::sleep(CHECK_INTERVAL);
+                    if !task.due(started) {
+                        continue;
+                    }
+
+                    let now = lock(&task.pipeline).stats();
+                    let progress = std::mem::replace(
+                        &mut *lock(&task.progress),
+                        Progress {
+                            from: motion::now(),
+                            ..Default::default()
+                        },
+                    );
+                    let summary = Summary::compile(
+                        reset_reason,
+                        previous,
+                        now,
+                        progress,
+                        task.sdcard.as_ref(),
+                    );
+                    previous = now;
+                    started = Instant::now();
+                    info!(
+                        "Daily summary: {} frames, {} capture failures, {} motion events",
+                        summary.frames, summary.capture_failures, summary.motion_events
+                    );
+
+                    if let Err(e) = task.save(&summary) {
+                        warn!("Failed to save the daily summary: {:?}", e);
+                    }
+                    if let Some(webhook) = &webhook {
+                        if let Err(e) = post_json(webhook, &summary) {
+                            warn!("Failed to send daily summary: {:?}", e);
+                        }
+                    }
+                    *lock(&task.latest) = Some(summary);
+                }
+            })?;
+
+        Ok(report)
+    }
+
+    pub fn latest(&self) -> Option<Summary> {
+        lock(&self.latest).clone()
+    }
+
+    // Whether the period is over. By the clock once SNTP has set it, so a reboot doesn't
+    // restart the day, and by uptime since `started` until then.
+    fn due(&self, started: Instant) -> bool {
+        let now = motion::now();
+        let mut progress = lock(&self.progress);
+        if now < CLOCK_SET {
+            return started.elapsed() >= PERIOD;
+        }
+        if progress.from < CLOCK_SET {
+            progress.from = now.saturating_sub(started.elapsed().as_secs());
+        }
+        now >= progress.from + PERIOD.as_secs()
+    }
+
+    // As JSON and HTML next to each other on the SD card, and the period so far cleared
+    fn save(&self, summary: &Summary) -> Result<()> {
+        let Some(sdcard) = &self.sdcard else {
+            return Ok(());
+        };
+        let stem = format!("{}/{}", CARD_DIR, summary.until);
+        sdcard.write(&format!("{}.json", stem), &serde_json::to_vec(summary)?)?;
+        sdcard.write(&format!("{}.html", stem), summary.to_html().as_bytes())?;
+        self.save_progress()
+    }
+
+    fn save_progress(&self) -> Result<()> {
+        let Some(sdcard) = &self.sdcard else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(&*lock(&self.progress))?;
+        sdcard.write(PROGRESS, &json)
+    }
+
+    // A small still off the sensor, saved to the SD card
+    fn thumbnail(&self, sdcard: &SdCard) -> Result<String> {
+        let jpeg = {
+            let mut pipeline = lock(&self.pipeline);
+            let cam = lock(&self.cam);
+            pipeline.run_shot(
+                &*cam,
+                Shot {
+                    quality: Some(THUMBNAIL_QUALITY),
+                    framesize: Some(framesize_t_FRAMESIZE_QQVGA),
+                    format: ImageFormat::Jpeg,
+                },
+            )?
+        };
+        let name = sdcard.media_name(THUMBNAIL_KIND, "jpg")?;
+        sdcard.write(&name, &jpeg)?;
+        Ok(format!("/{}", name))
+    }
+}
+
+// Counts every event into the period, the first few get a thumbnail
+impl MotionSink for DailyReport {
+    fn motion(&mut self, event: &MotionEvent) -> Result<()> {
+        let listed = {
+            let mut progress = lock(&self.progress);
+            progress.motion_events += 1;
+            progress.motion.len() < MAX_EVENTS
+        };
+        if listed {
+            let thumbnail = self.sdcard.as_ref().and_then(|sdcard| {
+                self.thumbnail(sdcard)
+                    .map_err(|e| warn!("No thumbnail for the daily summary: {:?}", e))
+                    .ok()
+            });
+            lock(&self.progress).motion.push(ReportedEvent {
+                timestamp: event.timestamp,
+                source: event.source,
+                score: event.score,
+                thumbnail,
+            });
+        }
+        self.save_progress()
+    }
+}
+
+// None when it isn't there or can't be read
+fn read_json<T: DeserializeOwned>(sdcard: &SdCard, relative: &str) -> Option<T> {
+    let mut data = Vec::new();
+    sdcard.open(relative).ok()?.read_to_end(&mut data).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Ignoring {} on the SD card: {:?}", relative, e);
+            None
+        }
+    }
+}
+
+// The last one `save` wrote, by the time in its name
+fn newest_summary(sdcard: &SdCard) -> Option<Summary> {
+    let newest = sdcard
+        .list(CARD_DIR)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|entry| entry.name.strip_suffix(".json")?.parse::<u64>().ok())
+        .max()?;
+    read_json(sdcard, &format!("{}/{}.json", CARD_DIR, newest))
+}
@@ -0,0 +1,198 @@
+use embedded_svc::http::{server::Connection, Headers, Query};
+use esp_idf_svc::{
+    http::{
+        server::{EspHttpConnection, HandlerResult, Request},
+        Method,
+    },
+    io::{ErrorType, EspIOError, Read, Write},
+};
+use log::info;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::lock::lock;
+
+// What every handler behind `Auth::protect` actually gets, the connection with the status and
+// byte count recorded on the way through
+pub type LoggedRequest<'x, 'b, 'r> = Request<&'x mut LoggedConnection<'b, 'r>>;
+
+pub struct LoggedConnection<'b, 'r> {
+    inner: &'b mut EspHttpConnection<'r>,
+    status: Option<u16>,
+    sent: usize,
+}
+
+impl ErrorType for LoggedConnection<'_, '_> {
+    type Error = EspIOError;
+}
+
+impl Read for LoggedConnection<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for LoggedConnection<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let written = self.inner.write(buf)?;
+        self.sent += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+impl Query for LoggedConnection<'_, '_> {
+    fn uri(&self) -> &str {
+        self.inner.uri()
+    }
+
+    fn method(&self) -> Method {
+        self.inner.method()
+    }
+}
+
+impl Headers for LoggedConnection<'_, '_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.inner.header(name)
+    }
+}
+
+impl<'b, 'r> Connection for LoggedConnection<'b, 'r> {
+    type Headers = <EspHttpConnection<'r> as Connection>::Headers;
+    type Read = <EspHttpConnection<'r> as Connection>::Read;
+    type RawConnectionError = <EspHttpConnection<'r> as Connection>::RawConnectionError;
+    type RawConnection = <EspHttpConnection<'r> as Connection>::RawConnection;
+
+    fn split(&mut self) -> (&Self::Headers, &mut Self::Read) {
+        self.inner.split()
+    }
+
+    fn initiate_response<'a>(
+        &'a mut self,
+        status: u16,
+        message: Option<&'a str>,
+        headers: &'a [(&'a str, &'a str)],
+    ) -> Result<(), Self::Error> {
+        self.status = Some(status);
+        self.inner.initiate_response(status, message, headers)
+    }
+
+    fn is_response_initiated(&self) -> bool {
+        self.inner.is_response_initiated()
+    }
+
+    fn raw_connection(&mut self) -> Result<&mut Self::RawConnection, Self::Error> {
+        self.inner.raw_connection()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct RequestEntry {
+    method: String,
+    path: String,
+    status: u16,
+    duration_ms: u32,
+    bytes: usize,
+    // Filled in when the log is read
+    ago_ms: u64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+// Logs one line per request and remembers the last `capacity` of them
+#[derive(Clone)]
+pub struct RequestLog {
+    entries: Arc<Mutex<VecDeque<RequestEntry>>>,
+    capacity: usize,
+}
+
+impl RequestLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    // Whether there is anything to show at /api/requests
+    pub fn keeps_entries(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // Oldest first
+    pub fn entries(&self) -> Vec<RequestEntry> {
+        lock(&self.entries)
+            .iter()
+            .map(|e| RequestEntry {
+                ago_ms: e.started.elapsed().as_millis() as u64,
+                ..e.clone()
+            })
+            .collect()
+    }
+
+    fn record(&self, entry: RequestEntry) {
+        info!(
+            "{} {} {} {}ms {} bytes",
+            entry.method, entry.path, entry.status, entry.duration_ms, entry.bytes
+        );
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = lock(&self.entries);
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Wraps a handler so its request is timed and logged once it has been answered
+    pub fn wrap<F>(
+        &self,
+        handler: F,
+    ) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> HandlerResult + Send + 'static
+    where
+        F: for<'x, 'b, 'r> Fn(LoggedRequest<'x, 'b, 'r>) -> HandlerResult + Send + 'static,
+    {
+        let log = self.clone();
+        move |request: Request<&mut EspHttpConnection>| {
+            let started = Instant::now();
+            let method = format!("{:?}", request.method()).to_ascii_uppercase();
+            let path = request
+                .uri()
+                .split('?')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+
+            let mut connection = LoggedConnection {
+                inner: request.release(),
+                status: None,
+                sent: 0,
+            };
+            let result = handler(Request::wrap(&mut connection));
+
+            log.record(RequestEntry {
+                method,
+                path,
+                // The server answers a failed handler that hasn't responded with a 500
+                status: connection
+                    .status
+                    .unwrap_or(if result.is_ok() { 200 } else { 500 }),
+                duration_ms: started.elapsed().as_millis() as u32,
+                bytes: connection.sent,
+                ago_ms: 0,
+                started,
+            });
+
+            result
+        }
+    }
+}
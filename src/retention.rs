@@ -0,0 +1,173 @@
+use anyhow::Result;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::auth::Auth;
+use crate::clock::CLOCK_SET;
+use crate::http::write_json;
+use crate::lock::lock;
+use crate::sdcard::{Entry, SdCard};
+use crate::{log_file, motion, timelapse};
+
+pub use tigercam_core::retention::RetentionSettings;
+use tigercam_core::retention::{plan, Candidate, Plan};
+
+// How often the rules are gone through, a minute's worth of footage over is fine
+const INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    runs: u32,
+    deleted: u32,
+    freed: u64,
+    // Of everything the rules go over after the last run, logs included
+    bytes: u64,
+    last_error: Option<String>,
+}
+
+// Goes over everything saved to the SD card every minute and deletes what the rules say has to
+// go, so a full card doesn't stop recordings and clips without anyone noticing. That's the
+// dated media, time-lapse stills held for a remote, and as a last resort the rotated out log.
+// Nothing still being written is deleted, see `SdCard::is_writing`.
+#[derive(Clone)]
+pub struct MediaRetention {
+    state: Arc<Mutex<State>>,
+}
+
+impl MediaRetention {
+    pub fn start(sdcard: SdCard, settings: RetentionSettings) -> Result<Self> {
+        let retention = Self {
+            state: Arc::new(Mutex::new(State::default())),
+        };
+
+        let state = retention.state.clone();
+        thread::Builder::new()
+            .name("retention".into())
+            .stack_size(8192)
+            .spawn(move || loop {
+                let result = enforce(&sdcard, &settings, &state);
+                let mut state = lock(&state);
+                state.runs += 1;
+                state.last_error = match result {
+                    Ok(bytes) => {
+                        state.bytes = bytes;
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Enforcing retention failed: {:?}", e);
+                        Some(format!("{:#}", e))
+                    }
+                };
+                drop(state);
+
+                thread::sleep(INTERVAL);
+            })?;
+
+        info!("Enforcing retention on the SD card: {:?}", settings);
+        Ok(retention)
+    }
+}
+
+// Deletes whatever the rules don't keep, then says what's left adds up to
+fn enforce(sdcard: &SdCard, settings: &RetentionSettings, state: &Mutex<State>) -> Result<u64> {
+    let mut media = sdcard.media(None)?;
+    for entry in sdcard.list(timelapse::PENDING_DIR).unwrap_or_default() {
+        let name = format!("{}/{}", timelapse::PENDING_DIR, entry.name);
+        media.push(Entry { name, ..entry });
+    }
+    let mut files: Vec<Candidate> = media
+        .into_iter()
+        .map(|entry| Candidate {
+            key: age_key(sdcard, &entry.name),
+            writing: sdcard.is_writing(&entry.name),
+            entry,
+        })
+        .collect();
+    // Stable, so the undated stay in the order `media` found them
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+    let logs: Vec<Entry> = sdcard.list(log_file::CARD_DIR).unwrap_or_default();
+    let log_bytes: u64 = logs.iter().map(|entry| entry.size).sum();
+
+    let now = motion::now();
+    let cutoff = settings
+        .max_age
+        .filter(|_| now >= CLOCK_SET)
+        .map(|max_age| sdcard.dated(now.saturating_sub(max_age.as_secs())));
+    let free = match settings.min_free {
+        Some(_) => sdcard.usage()?.1,
+        None => 0,
+    };
+    let Plan {
+        doomed,
+        mut total,
+        free,
+    } = plan(&files, settings, cutoff.as_deref(), log_bytes, free);
+
+    for (file, doomed) in files.iter().zip(&doomed) {
+        if !doomed {
+            continue;
+        }
+        sdcard.remove(&file.entry.name)?;
+        info!("Deleted {} for retention", file.entry.name);
+        let mut state = lock(state);
+        state.deleted += 1;
+        state.freed += file.entry.size;
+    }
+
+    // With nothing else left to go, the log's rotated out half. The one being written stays.
+    if settings.short(total, free) {
+        if let Some(old) = logs.iter().find(|entry| entry.name == log_file::PREVIOUS) {
+            sdcard.remove(&format!("{}/{}", log_file::CARD_DIR, old.name))?;
+            info!("Deleted the previous log file for retention");
+            total -= old.size;
+            let mut state = lock(state);
+            state.deleted += 1;
+            state.freed += old.size;
+        }
+    }
+    Ok(total)
+}
+
+// What a file is ordered by, see `Candidate::key`. The dated part of a media name, or of when a
+// held time-lapse still was taken.
+fn age_key(sdcard: &SdCard, name: &str) -> String {
+    if let Some(still) = name
+        .strip_prefix(timelapse::PENDING_DIR)
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        let taken: u64 = still
+            .split(['.', '-'])
+            .next()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(0);
+        return if taken >= CLOCK_SET {
+            sdcard.dated(taken)
+        } else {
+            String::new()
+        };
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.to_string()
+    } else {
+        String::new()
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, retention: MediaRetention) -> Result<()> {
+    server.fn_handler(
+        "/api/retention",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&retention.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::Camera;
+use crate::lock::lock;
+use crate::pipeline::Pipeline;
+use crate::source::FrameSource;
+
+const RTP_VERSION: u8 = 2;
+// Static payload type for JPEG, RFC 3551
+const PAYLOAD_TYPE_JPEG: u8 = 26;
+const CLOCK_RATE: u128 = 90_000;
+// Stay under the 1500 byte MTU once IP and UDP headers are added
+const MAX_PACKET: usize = 1400;
+
+// Handle used to turn the RTP sender on and off at runtime
+#[derive(Clone)]
+pub struct RtpControl {
+    configured: Option<SocketAddr>,
+    active: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl RtpControl {
+    // Start streaming to `dest`, or to the configured destination if `None`
+    pub fn enable(&self, dest: Option<SocketAddr>) -> Result<SocketAddr> {
+        let dest = dest
+            .or(self.configured)
+            .ok_or_else(|| anyhow!("No RTP destination configured"))?;
+        *lock(&self.active) = Some(dest);
+        info!("RTP streaming to {}", dest);
+        Ok(dest)
+    }
+
+    pub fn disable(&self) {
+        *lock(&self.active) = None;
+        info!("RTP streaming stopped");
+    }
+
+    pub fn destination(&self) -> Option<SocketAddr> {
+        *lock(&self.active)
+    }
+}
+
+// Spawns the RTP sender thread, nothing is sent until the stream is enabled
+pub fn start(
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    configured: Option<SocketAddr>,
+    enabled: bool,
+    fps: u32,
+) -> Result<RtpControl> {
+    let control = RtpControl {
+        configured,
+        active: Arc::new(Mutex::new(None)),
+    };
+
+    if enabled {
+        control.enable(None)?;
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let frame_time = Duration::from_millis(1000 / fps.max(1) as u64);
+    let task_control = control.clone();
+
+    thread::Builder::new()
+        .name("rtp".into())
+        .stack_size(8192)
+        .spawn(move || {
+            let mut packetizer = Packetizer::new(unsafe { esp_idf_svc::sys::esp_random() });
+            let epoch = Instant::now();
+            let mut streaming = false;
+
+            loop {
+                let dest = task_control.destination();
+                if dest.is_some() != streaming {
+                    streaming = dest.is_some();
+                    let mut pipeline = lock(&pipeline);
+                    if streaming {
+                        pipeline.stream_started();
+                    } else {
+                        pipeline.stream_stopped();
+                    }
+                }
+
+                let Some(dest) = dest else {
+                    thread::sleep(Duration::from_millis(250));
+                    continue;
+                };
+
+                let started = Instant::now();
+                let timestamp = (epoch.elapsed().as_micros() * CLOCK_RATE / 1_000_000) as u32;
+                if let Err(e) =
+                    send_frame(&cam, &pipeline, &socket, dest, &mut packetizer, timestamp)
+                {
+                    warn!("Failed to send RTP frame: {:?}", e);
+                }
+
+                if let Some(left) = frame_time.checked_sub(started.elapsed()) {
+                    thread::sleep(left);
+                }
+            }
+        })?;
+
+    Ok(control)
+}
+
+fn send_frame(
+    cam: &Mutex<impl FrameSource>,
+    pipeline: &Mutex<Pipeline>,
+    socket: &UdpSocket,
+    dest: SocketAddr,
+    packetizer: &mut Packetizer,
+    timestamp: u32,
+) -> Result<()> {
+    let jpeg = {
+        let mut pipeline = lock(&pipeline);
+        let cam = lock(&cam);
+        pipeline.run(&*cam)?
+    };
+
+    let frame = JpegFrame::parse(&jpeg)?;
+    packetizer.send(socket, dest, &frame, timestamp)
+}
+
+// Width and height of a baseline JPEG as the encoder and sensor produce them
+pub fn jpeg_dimensions(data: &[u8]) -> Result<(u16, u16)> {
+    let frame = JpegFrame::parse(data)?;
+    Ok((frame.width, frame.height))
+}
+
+// The parts of a baseline JFIF image that RFC 2435 needs, the receiver rebuilds the headers itself
+struct JpegFrame<'a> {
+    kind: u8,
+    width: u16,
+    height: u16,
+    restart_interval: u16,
+    qtables: Vec<&'a [u8]>,
+    scan: &'a [u8],
+}
+
+impl<'a> JpegFrame<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < 4 || data[0..2] != [0xFF, 0xD8] {
+            bail!("Frame is not a JPEG");
+        }
+
+        let mut kind = None;
+        let mut width = 0;
+        let mut height = 0;
+        let mut restart_interval = 0;
+        let mut qtables = Vec::new();
+
+        let mut pos = 2;
+        while pos + 4 <= data.len() {
+            if data[pos] != 0xFF {
+                bail!("Malformed JPEG marker at offset {}", pos);
+            }
+            let marker = data[pos + 1];
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            let segment = data
+                .get(pos + 4..pos + 2 + len)
+                .ok_or_else(|| anyhow!("Truncated JPEG segment {:#04x}", marker))?;
+
+            match marker {
+                // DQT, can hold more than one table
+                0xDB => {
+                    let mut tables = segment;
+                    while !tables.is_empty() {
+                        if tables[0] >> 4 != 0 {
+                            bail!("16-bit quantization tables are not supported");
+                        }
+                        let table = tables
+                            .get(1..65)
+                            .ok_or_else(|| anyhow!("Truncated quantization table"))?;
+                        qtables.push(table);
+                        tables = &tables[65..];
+                    }
+                }
+                // SOF0
+                0xC0 => {
+                    if segment.len() < 8 {
+                        bail!("Truncated SOF0 segment");
+                    }
+                    height = u16::from_be_bytes([segment[1], segment[2]]);
+                    width = u16::from_be_bytes([segment[3], segment[4]]);
+                    // Luma sampling factors decide between 4:2:2 and 4:2:0
+                    kind = match segment[7] {
+                        0x21 => Some(0),
+                        0x22 => Some(1),
+                        other => bail!("Unsupported JPEG sampling factor {:#04x}", other),
+                    };
+                }
+                // DRI
+                0xDD => {
+                    if segment.len() >= 2 {
+                        restart_interval = u16::from_be_bytes([segment[0], segment[1]]);
+                    }
+                }
+                // SOS, everything after its header is entropy coded data
+                0xDA => {
+                    let start = pos + 2 + len;
+                    let end = if data.ends_with(&[0xFF, 0xD9]) {
+                        data.len() - 2
+                    } else {
+                        data.len()
+                    };
+
+                    let kind = kind.ok_or_else(|| anyhow!("JPEG has no SOF0 segment"))?;
+                    if width > 2040 || height > 2040 {
+                        bail!("{}x{} is too large for RTP/JPEG", width, height);
+                    }
+
+                    return Ok(Self {
+                        kind,
+                        width,
+                        height,
+                        restart_interval,
+                        qtables,
+                        scan: &data[start..end],
+                    });
+                }
+                _ => {}
+            }
+
+            pos += 2 + len;
+        }
+
+        bail!("JPEG has no scan data")
+    }
+}
+
+struct Packetizer {
+    ssrc: u32,
+    seq: u16,
+    buf: Vec<u8>,
+}
+
+impl Packetizer {
+    fn new(ssrc: u32) -> Self {
+        Self {
+            ssrc,
+            seq: 0,
+            buf: Vec::with_capacity(MAX_PACKET),
+        }
+    }
+
+    fn send(
+        &mut self,
+        socket: &UdpSocket,
+        dest: SocketAddr,
+        frame: &JpegFrame,
+        timestamp: u32,
+    ) -> Result<()> {
+        let kind = if frame.restart_interval > 0 {
+            frame.kind | 0x40
+        } else {
+            frame.kind
+        };
+
+        let mut offset = 0;
+        while offset < frame.scan.len() {
+            let buf = &mut self.buf;
+            buf.clear();
+
+            // RTP header, the marker bit gets set on the last packet of the frame
+            buf.extend_from_slice(&[RTP_VERSION << 6, PAYLOAD_TYPE_JPEG]);
+            buf.extend_from_slice(&self.seq.to_be_bytes());
+            buf.extend_from_slice(&timestamp.to_be_bytes());
+            buf.extend_from_slice(&self.ssrc.to_be_bytes());
+
+            // Main JPEG header, Q=255 means the tables are sent in-band
+            let fragment = (offset as u32).to_be_bytes();
+            buf.extend_from_slice(&[
+                0,
+                fragment[1],
+                fragment[2],
+                fragment[3],
+                kind,
+                255,
+                (frame.width / 8) as u8,
+                (frame.height / 8) as u8,
+            ]);
+
+            if frame.restart_interval > 0 {
+                buf.extend_from_slice(&frame.restart_interval.to_be_bytes());
+                // F=1, L=1, restart count 0x3FFF
+                buf.extend_from_slice(&[0xFF, 0xFF]);
+            }
+
+            if offset == 0 {
+                let len = (frame.qtables.len() * 64) as u16;
+                buf.extend_from_slice(&[0, 0]);
+                buf.extend_from_slice(&len.to_be_bytes());
+                for table in &frame.qtables {
+                    buf.extend_from_slice(table);
+                }
+            }
+
+            let room = MAX_PACKET.saturating_sub(buf.len());
+            let end = (offset + room).min(frame.scan.len());
+            if end == frame.scan.len() {
+                buf[1] |= 0x80;
+            }
+            buf.extend_from_slice(&frame.scan[offset..end]);
+            offset = end;
+
+            socket.send_to(buf, dest)?;
+            self.seq = self.seq.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,727 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use esp_idf_svc::sys::{
+    esp, esp_vfs_fat_info, esp_vfs_fat_mount_config_t, esp_vfs_fat_sdcard_format,
+    esp_vfs_fat_sdcard_unmount, esp_vfs_fat_sdmmc_mount, gpio_num_t_GPIO_NUM_NC, sdmmc_card_init,
+    sdmmc_card_t, sdmmc_get_status, sdmmc_host_deinit, sdmmc_host_do_transaction,
+    sdmmc_host_get_slot_width, sdmmc_host_init, sdmmc_host_io_int_enable, sdmmc_host_io_int_wait,
+    sdmmc_host_set_bus_ddr_mode, sdmmc_host_set_bus_width, sdmmc_host_set_card_clk, sdmmc_host_t,
+    sdmmc_host_t__bindgen_ty_1, sdmmc_slot_config_t, sdmmc_slot_config_t__bindgen_ty_1,
+    sdmmc_slot_config_t__bindgen_ty_2, SDMMC_FREQ_DEFAULT, SDMMC_HOST_FLAG_1BIT, SDMMC_HOST_SLOT_1,
+    SDMMC_SLOT_FLAG_INTERNAL_PULLUP,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::clock::{self, CLOCK_SET};
+use crate::flash::Flash;
+use crate::http::{
+    parse_shot, query_param, url_decode, write_all_yielding, write_json, ApiError, Yielder,
+};
+use crate::lock::lock;
+use crate::motion;
+use crate::pipeline::{lock_for_capture, ImageFormat, Pipeline};
+use crate::storage::content_type;
+
+use tigercam_core::media;
+pub use tigercam_core::media::{dated, media_kind, Entry};
+
+// Where the card shows up in the VFS, paths given to `SdCard` are relative to it
+pub const MOUNT_POINT: &str = "/sd";
+// CLK, CMD and D0, all the slot takes in 1-bit mode
+pub const PINS: [i32; 3] = [14, 15, 2];
+// Files open at once across everything writing to the card
+const MAX_FILES: i32 = 5;
+// Where `media_name` puts files while the clock isn't set, numbered rather than dated
+const UNDATED_DIR: &str = "undated";
+// Read from a file at a time for a download, off the heap rather than the handler's stack
+const DOWNLOAD_CHUNK: usize = 8192;
+// How often `watch` asks the card whether it's still there
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+// Written, read back and deleted by `check`
+const CHECK_FILE: &str = ".check";
+const CHECK_SIZE: usize = 16 * 1024;
+// Card events kept for /api/sdcard
+const EVENT_HISTORY: usize = 16;
+
+#[derive(Clone, Default, Serialize)]
+struct Health {
+    // Set while the card isn't answering, to what went wrong
+    failed: Option<String>,
+    // Times it's stopped answering since boot
+    failures: u32,
+    // The latest last, see `SdCard::watch`
+    events: VecDeque<CardEvent>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardEventKind {
+    // Pulled or stopped answering
+    Failed,
+    // Answering again
+    Recovered,
+    Formatted,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CardEvent {
+    // Seconds since the epoch, only right once SNTP has set the clock
+    pub timestamp: u64,
+    pub kind: CardEventKind,
+    // What went wrong, for Failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Health {
+    fn push(&mut self, kind: CardEventKind, error: Option<String>) {
+        if self.events.len() == EVENT_HISTORY {
+            self.events.pop_front();
+        }
+        self.events.push_back(CardEvent {
+            timestamp: motion::now(),
+            kind,
+            error,
+        });
+    }
+}
+
+// How long `check` took
+#[derive(Serialize)]
+pub struct Check {
+    pub write_ms: u32,
+    pub read_ms: u32,
+}
+
+struct Card(*mut sdmmc_card_t);
+
+// The driver takes a lock of its own around every transaction, any task can use it
+unsafe impl Send for Card {}
+unsafe impl Sync for Card {}
+
+impl Drop for Card {
+    fn drop(&mut self) {
+        let mount_point = CString::new(MOUNT_POINT).unwrap();
+        unsafe { esp_vfs_fat_sdcard_unmount(mount_point.as_ptr(), self.0) };
+    }
+}
+
+// The microSD slot, mounted with FAT. It's wired to the SDMMC host's slot 1, run in 1-bit mode
+// so of the data lines only D0 (GPIO2) is used. GPIO4, D1 in 4-bit mode, stays the flash LED
+// and GPIO12 and 13 stay free. Clones share the card, which is unmounted when the last goes.
+#[derive(Clone)]
+pub struct SdCard {
+    card: Arc<Card>,
+    health: Arc<Mutex<Health>>,
+    // The last number `media_name` gave out in UNDATED_DIR, None until it's looked
+    undated: Arc<Mutex<Option<u32>>>,
+    // Files still being written, like the recording segment, which nothing may delete
+    writing: Arc<Mutex<Vec<PathBuf>>>,
+    // Added to UTC for the dates media is filed under
+    utc_offset_minutes: i32,
+}
+
+impl SdCard {
+    // Media is filed under local dates, `utc_offset_minutes` from UTC
+    pub fn mount(utc_offset_minutes: i32) -> Result<Self> {
+        let host = sdmmc_host_t {
+            flags: SDMMC_HOST_FLAG_1BIT,
+            slot: SDMMC_HOST_SLOT_1 as i32,
+            max_freq_khz: SDMMC_FREQ_DEFAULT as i32,
+            io_voltage: 3.3,
+            init: Some(sdmmc_host_init),
+            set_bus_width: Some(sdmmc_host_set_bus_width),
+            get_bus_width: Some(sdmmc_host_get_slot_width),
+            set_bus_ddr_mode: Some(sdmmc_host_set_bus_ddr_mode),
+            set_card_clk: Some(sdmmc_host_set_card_clk),
+            do_transaction: Some(sdmmc_host_do_transaction),
+            __bindgen_anon_1: sdmmc_host_t__bindgen_ty_1 {
+                deinit: Some(sdmmc_host_deinit),
+            },
+            io_int_enable: Some(sdmmc_host_io_int_enable),
+            io_int_wait: Some(sdmmc_host_io_int_wait),
+            command_timeout_ms: 0,
+            ..Default::default()
+        };
+        // Most ESP32-CAM boards have no card detect or write protect line. The internal pullups
+        // are weak but save a card that's fine without external ones from failing to mount.
+        let slot = sdmmc_slot_config_t {
+            __bindgen_anon_1: sdmmc_slot_config_t__bindgen_ty_1 {
+                gpio_cd: gpio_num_t_GPIO_NUM_NC,
+            },
+            __bindgen_anon_2: sdmmc_slot_config_t__bindgen_ty_2 {
+                gpio_wp: gpio_num_t_GPIO_NUM_NC,
+            },
+            width: 1,
+            flags: SDMMC_SLOT_FLAG_INTERNAL_PULLUP,
+            ..Default::default()
+        };
+        // Never formats on its own, a card that won't mount could still have footage on it
+        let mount_config = esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: false,
+            max_files: MAX_FILES,
+            allocation_unit_size: 16 * 1024,
+            disk_status_check_enable: false,
+        };
+
+        let mount_point = CString::new(MOUNT_POINT)?;
+        let mut card: *mut sdmmc_card_t = ptr::null_mut();
+        esp!(unsafe {
+            esp_vfs_fat_sdmmc_mount(
+                mount_point.as_ptr(),
+                &host,
+                &slot as *const _ as *const _,
+                &mount_config,
+                &mut card,
+            )
+        })?;
+
+        let csd = unsafe { (*card).csd };
+        info!(
+            "Mounted a {} MB SD card at {}",
+            csd.capacity as u64 * csd.sector_size as u64 / (1024 * 1024),
+            MOUNT_POINT
+        );
+        Ok(Self {
+            card: Arc::new(Card(card)),
+            health: Arc::new(Mutex::new(Health::default())),
+            undated: Arc::new(Mutex::new(None)),
+            writing: Arc::new(Mutex::new(Vec::new())),
+            utc_offset_minutes,
+        })
+    }
+
+    // Asks the card every few seconds whether it's still there. One that's been pulled or has
+    // stopped answering is kept as a card event, and is taken up again once it answers, which
+    // for a card swapped for another is best done with a reboot. They're the card's own and not
+    // motion events, so nothing that reacts to motion fires for them.
+    pub fn watch(&self) -> Result<()> {
+        let sdcard = self.clone();
+        thread::Builder::new()
+            .name("sdcard".into())
+            .stack_size(4096)
+            .spawn(move || loop {
+                thread::sleep(WATCH_INTERVAL);
+                if lock(&sdcard.health).failed.is_none() {
+                    if let Err(e) = sdcard.status() {
+                        sdcard.fail(e);
+                    }
+                } else if sdcard.reinit().is_ok() {
+                    info!("The SD card is answering again");
+                    let mut health = lock(&sdcard.health);
+                    health.failed = None;
+                    health.push(CardEventKind::Recovered, None);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn status(&self) -> Result<()> {
+        esp!(unsafe { sdmmc_get_status(self.card.0) })?;
+        Ok(())
+    }
+
+    // Goes through the card's setup again, for one that's been put back in
+    fn reinit(&self) -> Result<()> {
+        let card = self.card.0;
+        esp!(unsafe { sdmmc_card_init(&(*card).host, card) })?;
+        self.status()
+    }
+
+    fn fail(&self, error: anyhow::Error) {
+        let mut health = lock(&self.health);
+        if health.failed.is_some() {
+            return;
+        }
+        warn!("The SD card stopped answering: {:?}", error);
+        let error = format!("{:#}", error);
+        health.failed = Some(error.clone());
+        health.failures += 1;
+        health.push(CardEventKind::Failed, Some(error));
+    }
+
+    // For whatever was reading or writing the card when `error` came up. If the card has stopped
+    // answering it's marked failed straight away, rather than at `watch`'s next look.
+    pub fn report(&self, error: &anyhow::Error) {
+        if lock(&self.health).failed.is_some() {
+            return;
+        }
+        if let Err(e) = self.status() {
+            self.fail(e.context(format!("{:#}", error)));
+        }
+    }
+
+    // Errors straight away while the card isn't answering, rather than after the driver's
+    // timeouts for every file
+    fn ensure_healthy(&self) -> Result<()> {
+        match &lock(&self.health).failed {
+            Some(error) => bail!("The SD card isn't answering: {}", error),
+            None => Ok(()),
+        }
+    }
+
+    // Total and free bytes
+    pub fn usage(&self) -> Result<(u64, u64)> {
+        self.ensure_healthy()?;
+        let mount_point = CString::new(MOUNT_POINT)?;
+        let (mut total, mut free) = (0, 0);
+        esp!(unsafe { esp_vfs_fat_info(mount_point.as_ptr(), &mut total, &mut free) })?;
+        Ok((total, free))
+    }
+
+    // Writes a file, reads it back and deletes it
+    pub fn check(&self) -> Result<Check> {
+        self.status()?;
+        let data: Vec<u8> = (0..CHECK_SIZE).map(|i| i as u8).collect();
+
+        let started = Instant::now();
+        let mut file = self.create(CHECK_FILE)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        drop(file);
+        let write_ms = started.elapsed().as_millis() as u32;
+
+        let started = Instant::now();
+        let mut back = Vec::with_capacity(CHECK_SIZE);
+        self.open(CHECK_FILE)?.read_to_end(&mut back)?;
+        let read_ms = started.elapsed().as_millis() as u32;
+
+        self.remove(CHECK_FILE)?;
+        if back != data {
+            bail!("What was read back from the card isn't what was written");
+        }
+        Ok(Check { write_ms, read_ms })
+    }
+
+    // A fresh FAT filesystem over everything on the card. Files open on it, like a recording
+    // segment, fail on their next write and are started over.
+    pub fn format(&self) -> Result<()> {
+        self.status()?;
+        let mount_point = CString::new(MOUNT_POINT)?;
+        esp!(unsafe { esp_vfs_fat_sdcard_format(mount_point.as_ptr(), self.card.0) })?;
+        info!("Formatted the SD card");
+        let mut health = lock(&self.health);
+        health.failed = None;
+        health.push(CardEventKind::Formatted, None);
+        Ok(())
+    }
+
+    // Where `relative` is in the VFS. Anything climbing out of the card with '..' or naming
+    // a path of its own is refused, these come from HTTP requests.
+    pub fn path(&self, relative: &str) -> Result<PathBuf> {
+        media::card_path(MOUNT_POINT, relative)
+    }
+
+    // A name in `dir` that nothing on the card has yet, seconds since the epoch with a count
+    // after it for more than one in the same second
+    pub fn unique_name(&self, dir: &str, extension: &str) -> Result<String> {
+        let timestamp = motion::now();
+        let mut name = format!("{}/{}.{}", dir, timestamp, extension);
+        let mut count = 1;
+        while self.path(&name)?.exists() {
+            count += 1;
+            name = format!("{}/{}-{}.{}", dir, timestamp, count, extension);
+        }
+        Ok(name)
+    }
+
+    // Where to save a new file of `kind`, like "clip". Once SNTP has set the clock that's
+    // "2024/01/31/134507_clip.avi" in local time, before it "undated/000042_clip.avi",
+    // counting on from the highest number already there so a reboot doesn't start over.
+    pub fn media_name(&self, kind: &str, extension: &str) -> Result<String> {
+        let unix = motion::now();
+        let stem = if unix >= CLOCK_SET {
+            format!("{}_{}", self.dated(unix), kind)
+        } else {
+            format!("{}/{:06}_{}", UNDATED_DIR, self.next_undated(), kind)
+        };
+
+        let mut name = format!("{}.{}", stem, extension);
+        let mut count = 1;
+        while self.path(&name)?.exists() {
+            count += 1;
+            name = format!("{}-{}.{}", stem, count, extension);
+        }
+        Ok(name)
+    }
+
+    // `dated` in the local time media is filed under, anything named before `unix` sorts
+    // before this
+    pub fn dated(&self, unix: u64) -> String {
+        dated(clock::local(unix, self.utc_offset_minutes))
+    }
+
+    fn next_undated(&self) -> u32 {
+        let mut last = lock(&self.undated);
+        let next = match *last {
+            Some(last) => last + 1,
+            None => {
+                let entries = self.list(UNDATED_DIR).unwrap_or_default();
+                let highest = entries
+                    .iter()
+                    .filter_map(|entry| entry.name.split('_').next()?.parse().ok())
+                    .max();
+                highest.unwrap_or(0) + 1
+            }
+        };
+        *last = Some(next);
+        next
+    }
+
+    // Every file `media_name` gave out for `kind`, or of every kind for None, oldest first as
+    // far as can be told and named by their paths on the card. The undated come before all the
+    // dated, there's no telling how old they are.
+    pub fn media(&self, kind: Option<&str>) -> Result<Vec<Entry>> {
+        let mut found = Vec::new();
+        self.collect(UNDATED_DIR, 0, kind, &mut found);
+        for year in self.list("")? {
+            if year.dir && year.name.bytes().all(|b| b.is_ascii_digit()) {
+                self.collect(&year.name, 2, kind, &mut found);
+            }
+        }
+        Ok(found)
+    }
+
+    // Files of `kind` in `dir`, and in the numbered directories under it `depth` deep
+    fn collect(&self, dir: &str, depth: usize, kind: Option<&str>, found: &mut Vec<Entry>) {
+        for entry in self.list(dir).unwrap_or_default() {
+            let name = format!("{}/{}", dir, entry.name);
+            if entry.dir {
+                if depth > 0 && entry.name.bytes().all(|b| b.is_ascii_digit()) {
+                    self.collect(&name, depth - 1, kind, found);
+                }
+                continue;
+            }
+            let file_kind = media_kind(&entry.name);
+            if file_kind.is_some() && (kind.is_none() || file_kind == kind) {
+                found.push(Entry { name, ..entry });
+            }
+        }
+    }
+
+    // Truncates a file that's already there, and creates the directories leading to it
+    pub fn create(&self, relative: &str) -> Result<File> {
+        self.ensure_healthy()?;
+        let path = self.path(relative)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(File::create(path)?)
+    }
+
+    pub fn open(&self, relative: &str) -> Result<File> {
+        self.ensure_healthy()?;
+        let path = self.path(relative)?;
+        Ok(File::open(path)?)
+    }
+
+    // Marks a file as being written, or done with, for `is_writing`. FAT would let it be
+    // deleted from under whatever is writing it and then hand its clusters out twice.
+    pub fn set_writing(&self, relative: &str, writing: bool) {
+        let Ok(path) = self.path(relative) else {
+            return;
+        };
+        let mut files = lock(&self.writing);
+        files.retain(|file| *file != path);
+        if writing {
+            files.push(path);
+        }
+    }
+
+    pub fn is_writing(&self, relative: &str) -> bool {
+        self.path(relative)
+            .map_or(false, |path| lock(&self.writing).contains(&path))
+    }
+
+    // Directories it leaves empty on the way up go with it, so old days don't pile up. Files
+    // still being written are refused.
+    pub fn remove(&self, relative: &str) -> Result<()> {
+        self.ensure_healthy()?;
+        if self.is_writing(relative) {
+            bail!("{} is still being written", relative);
+        }
+        let path = self.path(relative)?;
+        fs::remove_file(&path)?;
+        for dir in path.ancestors().skip(1) {
+            if dir == Path::new(MOUNT_POINT) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The whole of `data` in one go, for stills and other small files
+    pub fn write(&self, relative: &str, data: &[u8]) -> Result<()> {
+        let written = self.create(relative).and_then(|mut file| {
+            file.write_all(data)?;
+            file.flush()?;
+            Ok(())
+        });
+        if let Err(e) = &written {
+            self.report(e);
+        }
+        written
+    }
+
+    // Sorted by name, directories and files together
+    pub fn list(&self, relative: &str) -> Result<Vec<Entry>> {
+        self.ensure_healthy()?;
+        let path = self.path(relative)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            entries.push(Entry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+// What's after /files in the request, decoded, "" for the top of the card
+fn file_path(uri: &str) -> String {
+    let path = uri.split('?').next().unwrap_or_default();
+    url_decode(
+        path.strip_prefix("/files")
+            .unwrap_or_default()
+            .trim_matches('/'),
+    )
+}
+
+#[derive(Serialize)]
+struct Status {
+    // None while the card isn't answering
+    total: Option<u64>,
+    free: Option<u64>,
+    #[serde(flatten)]
+    health: Health,
+}
+
+#[derive(Serialize)]
+struct Saved {
+    // On the card
+    path: String,
+    size: usize,
+}
+
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    flash: Option<Flash>,
+    sdcard: SdCard,
+) -> Result<()> {
+    // `/api/save?quality=90&size=UXGA` like /capture, but always a JPEG and kept on the card
+    // for when there's nobody on the network to send it to
+    let save_sdcard = sdcard.clone();
+    server.fn_handler(
+        "/api/save",
+        Method::Post,
+        auth.protect(move |request| {
+            let shot = match parse_shot(request.uri(), None) {
+                Ok(shot) if shot.format == ImageFormat::Jpeg => shot,
+                Ok(_) => return ApiError::bad_request("Only JPEGs are saved").send(request),
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let jpeg = lock_for_capture(&pipeline, &cam).and_then(|(mut pipeline, cam)| {
+                let _pulse = flash.as_ref().map(|f| f.pulse(&*cam)).transpose()?;
+                pipeline.run_shot(&*cam, shot)
+            });
+            let jpeg = match jpeg {
+                Ok(jpeg) => jpeg,
+                Err(e) => return ApiError::from(&e).send(request),
+            };
+
+            let saved = save_sdcard.media_name("snapshot", "jpg").and_then(|name| {
+                save_sdcard.write(&name, &jpeg)?;
+                Ok(name)
+            });
+            match saved {
+                Ok(name) => {
+                    info!("Saved {} bytes to {}", jpeg.len(), name);
+                    let saved = Saved {
+                        path: format!("/{}", name),
+                        size: jpeg.len(),
+                    };
+                    write_json(request, 200, &saved)
+                }
+                Err(e) => ApiError::new(500, "write_failed", format!("{:#}", e)).send(request),
+            }
+        }),
+    )?;
+
+    // `/files/recordings` lists a directory as JSON, `/files/clips/1700000000.avi` downloads a
+    // file, so footage can be had without taking the card out. Only with HTTP credentials or
+    // an API key configured, it's everything the camera has seen.
+    let get_sdcard = sdcard.clone();
+    server.fn_handler(
+        "/files*",
+        Method::Get,
+        auth.require(move |request| {
+            let relative = file_path(request.uri());
+            if let Err(e) = get_sdcard.ensure_healthy() {
+                return ApiError::new(503, "card_failed", e).send(request);
+            }
+            let path = match get_sdcard.path(&relative) {
+                Ok(path) => path,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return ApiError::not_found("No such file or directory").send(request)
+                }
+                Err(e) => return ApiError::new(500, "read_failed", e).send(request),
+            };
+
+            if metadata.is_dir() {
+                return match get_sdcard.list(&relative) {
+                    Ok(entries) => write_json(request, 200, &entries),
+                    Err(e) => ApiError::new(500, "read_failed", format!("{:#}", e)).send(request),
+                };
+            }
+
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => return ApiError::new(500, "read_failed", e).send(request),
+            };
+            let name = relative.rsplit('/').next().unwrap_or_default();
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", content_type(name)),
+                    ("Content-Length", &metadata.len().to_string()),
+                    (
+                        "Content-Disposition",
+                        &format!("attachment; filename=\"{}\"", name),
+                    ),
+                ],
+            )?;
+            let mut chunk = vec![0; DOWNLOAD_CHUNK];
+            let mut yielder = Yielder::new();
+            loop {
+                let read = file.read(&mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                write_all_yielding(&mut response, &chunk[..read])?;
+                yielder.tick();
+            }
+
+            Ok(())
+        }),
+    )?;
+
+    // A file, or a directory once it's empty. Not the recording segment being written.
+    let delete_sdcard = sdcard.clone();
+    server.fn_handler(
+        "/files/*",
+        Method::Delete,
+        auth.require(move |request| {
+            let relative = file_path(request.uri());
+            if let Err(e) = delete_sdcard.ensure_healthy() {
+                return ApiError::new(503, "card_failed", e).send(request);
+            }
+            if relative.is_empty() {
+                return ApiError::bad_request("Name a file or directory to delete").send(request);
+            }
+            if delete_sdcard.is_writing(&relative) {
+                return ApiError::new(409, "in_use", "The file is still being written")
+                    .send(request);
+            }
+            let path = match delete_sdcard.path(&relative) {
+                Ok(path) => path,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            let deleted = fs::metadata(&path).and_then(|metadata| {
+                if metadata.is_dir() {
+                    fs::remove_dir(&path)
+                } else {
+                    fs::remove_file(&path)
+                }
+            });
+            match deleted {
+                Ok(()) => {
+                    info!("Deleted /{} from the SD card", relative);
+                    request.into_ok_response()?;
+                    Ok(())
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    ApiError::not_found("No such file or directory").send(request)
+                }
+                Err(e) => ApiError::new(500, "delete_failed", e).send(request),
+            }
+        }),
+    )?;
+
+    let status_sdcard = sdcard.clone();
+    server.fn_handler(
+        "/api/sdcard",
+        Method::Get,
+        auth.protect(move |request| {
+            let usage = status_sdcard.usage().ok();
+            let status = Status {
+                total: usage.map(|(total, _)| total),
+                free: usage.map(|(_, free)| free),
+                health: lock(&status_sdcard.health).clone(),
+            };
+            write_json(request, 200, &status)
+        }),
+    )?;
+
+    let check_sdcard = sdcard.clone();
+    server.fn_handler(
+        "/api/sdcard/check",
+        Method::Post,
+        auth.protect(move |request| match check_sdcard.check() {
+            Ok(check) => write_json(request, 200, &check),
+            Err(e) => {
+                check_sdcard.report(&e);
+                ApiError::new(500, "check_failed", format!("{:#}", e)).send(request)
+            }
+        }),
+    )?;
+
+    // Everything on the card is lost, so it takes `?confirm=yes`
+    server.fn_handler(
+        "/api/sdcard/format",
+        Method::Post,
+        auth.require(move |request| {
+            if query_param(request.uri(), "confirm") != Some("yes") {
+                return ApiError::bad_request("Formatting wipes the card, add ?confirm=yes")
+                    .send(request);
+            }
+            match sdcard.format() {
+                Ok(()) => {
+                    request.into_ok_response()?;
+                    Ok(())
+                }
+                Err(e) => ApiError::new(500, "format_failed", format!("{:#}", e)).send(request),
+            }
+        }),
+    )?;
+
+    Ok(())
+}
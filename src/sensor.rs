@@ -0,0 +1,452 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::sys::cam::{
+    camera_status_t, esp_camera_sensor_get, framesize_t, framesize_t_FRAMESIZE_240X240,
+    framesize_t_FRAMESIZE_96X96, framesize_t_FRAMESIZE_CIF, framesize_t_FRAMESIZE_FHD,
+    framesize_t_FRAMESIZE_HD, framesize_t_FRAMESIZE_HQVGA, framesize_t_FRAMESIZE_HVGA,
+    framesize_t_FRAMESIZE_P_FHD, framesize_t_FRAMESIZE_P_HD, framesize_t_FRAMESIZE_QCIF,
+    framesize_t_FRAMESIZE_QQVGA, framesize_t_FRAMESIZE_QVGA, framesize_t_FRAMESIZE_QXGA,
+    framesize_t_FRAMESIZE_SVGA, framesize_t_FRAMESIZE_SXGA, framesize_t_FRAMESIZE_UXGA,
+    framesize_t_FRAMESIZE_VGA, framesize_t_FRAMESIZE_XGA, gainceiling_t, pixformat_t,
+    pixformat_t_PIXFORMAT_GRAYSCALE, pixformat_t_PIXFORMAT_JPEG, pixformat_t_PIXFORMAT_RAW,
+    pixformat_t_PIXFORMAT_RGB444, pixformat_t_PIXFORMAT_RGB555, pixformat_t_PIXFORMAT_RGB565,
+    pixformat_t_PIXFORMAT_RGB888, pixformat_t_PIXFORMAT_YUV420, pixformat_t_PIXFORMAT_YUV422,
+    sensor_t,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::camera::CameraError;
+use tigercam_core::source;
+pub use tigercam_core::source::{
+    framesize_dimensions, framesize_from_name, framesize_name, framesize_pixels,
+    framesizes_largest_first,
+};
+
+// tigercam-core has the driver's frame size numbers written out, a driver numbering them
+// differently doesn't build
+const _: () = {
+    assert!(source::FRAMESIZE_96X96 == framesize_t_FRAMESIZE_96X96);
+    assert!(source::FRAMESIZE_QQVGA == framesize_t_FRAMESIZE_QQVGA);
+    assert!(source::FRAMESIZE_QCIF == framesize_t_FRAMESIZE_QCIF);
+    assert!(source::FRAMESIZE_HQVGA == framesize_t_FRAMESIZE_HQVGA);
+    assert!(source::FRAMESIZE_240X240 == framesize_t_FRAMESIZE_240X240);
+    assert!(source::FRAMESIZE_QVGA == framesize_t_FRAMESIZE_QVGA);
+    assert!(source::FRAMESIZE_CIF == framesize_t_FRAMESIZE_CIF);
+    assert!(source::FRAMESIZE_HVGA == framesize_t_FRAMESIZE_HVGA);
+    assert!(source::FRAMESIZE_VGA == framesize_t_FRAMESIZE_VGA);
+    assert!(source::FRAMESIZE_SVGA == framesize_t_FRAMESIZE_SVGA);
+    assert!(source::FRAMESIZE_XGA == framesize_t_FRAMESIZE_XGA);
+    assert!(source::FRAMESIZE_HD == framesize_t_FRAMESIZE_HD);
+    assert!(source::FRAMESIZE_P_HD == framesize_t_FRAMESIZE_P_HD);
+    assert!(source::FRAMESIZE_SXGA == framesize_t_FRAMESIZE_SXGA);
+    assert!(source::FRAMESIZE_UXGA == framesize_t_FRAMESIZE_UXGA);
+    assert!(source::FRAMESIZE_FHD == framesize_t_FRAMESIZE_FHD);
+    assert!(source::FRAMESIZE_P_FHD == framesize_t_FRAMESIZE_P_FHD);
+    assert!(source::FRAMESIZE_QXGA == framesize_t_FRAMESIZE_QXGA);
+    assert!(source::PIXFORMAT_JPEG == pixformat_t_PIXFORMAT_JPEG);
+};
+
+pub fn pixformat_name(format: pixformat_t) -> &'static str {
+    match format {
+        pixformat_t_PIXFORMAT_RGB565 => "RGB565",
+        pixformat_t_PIXFORMAT_YUV422 => "YUV422",
+        pixformat_t_PIXFORMAT_YUV420 => "YUV420",
+        pixformat_t_PIXFORMAT_GRAYSCALE => "GRAYSCALE",
+        pixformat_t_PIXFORMAT_JPEG => "JPEG",
+        pixformat_t_PIXFORMAT_RGB888 => "RGB888",
+        pixformat_t_PIXFORMAT_RAW => "RAW",
+        pixformat_t_PIXFORMAT_RGB444 => "RGB444",
+        pixformat_t_PIXFORMAT_RGB555 => "RGB555",
+        _ => "UNKNOWN",
+    }
+}
+
+// The sensor's special effect register, in the driver's numbering
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialEffect {
+    None,
+    Negative,
+    Grayscale,
+    RedTint,
+    GreenTint,
+    BlueTint,
+    Sepia,
+}
+
+impl SpecialEffect {
+    const ALL: [Self; 7] = [
+        Self::None,
+        Self::Negative,
+        Self::Grayscale,
+        Self::RedTint,
+        Self::GreenTint,
+        Self::BlueTint,
+        Self::Sepia,
+    ];
+
+    // What the sensor reports in `camera_status_t::special_effect`
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Self::ALL.get(raw as usize).copied()
+    }
+}
+
+// Fixed white balance gains in place of AWB, in the driver's numbering. Under LED lighting
+// AWB tends to go green, one of these usually looks better.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhiteBalanceMode {
+    Auto,
+    Sunny,
+    Cloudy,
+    Office,
+    Home,
+}
+
+impl WhiteBalanceMode {
+    const ALL: [Self; 5] = [
+        Self::Auto,
+        Self::Sunny,
+        Self::Cloudy,
+        Self::Office,
+        Self::Home,
+    ];
+
+    // What the sensor reports in `camera_status_t::wb_mode`
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Self::ALL.get(raw as usize).copied()
+    }
+}
+
+impl FromStr for WhiteBalanceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "sunny" => Ok(Self::Sunny),
+            "cloudy" => Ok(Self::Cloudy),
+            "office" => Ok(Self::Office),
+            "home" => Ok(Self::Home),
+            other => bail!("Unknown white balance mode '{}'", other),
+        }
+    }
+}
+
+// The formats worth switching the driver to
+const PIXFORMATS: &[pixformat_t] = &[
+    pixformat_t_PIXFORMAT_JPEG,
+    pixformat_t_PIXFORMAT_RGB565,
+    pixformat_t_PIXFORMAT_YUV422,
+    pixformat_t_PIXFORMAT_GRAYSCALE,
+    pixformat_t_PIXFORMAT_RGB888,
+];
+
+pub fn pixformat_from_name(name: &str) -> Option<pixformat_t> {
+    PIXFORMATS
+        .iter()
+        .copied()
+        .find(|f| pixformat_name(*f).eq_ignore_ascii_case(name))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorModel {
+    Ov2640,
+    Ov3660,
+    Ov5640,
+    Ov7670,
+    Ov7725,
+    Nt99141,
+    Gc2145,
+    Gc032a,
+    Gc0308,
+    Bf3005,
+    Bf20a6,
+    Sc101iot,
+    Sc030iot,
+    Sc031gs,
+    Unknown,
+}
+
+impl SensorModel {
+    // By the product ID the driver read out of the sensor at init
+    pub fn from_pid(pid: u16) -> Self {
+        match pid {
+            0x26 => Self::Ov2640,
+            0x3660 => Self::Ov3660,
+            0x5640 => Self::Ov5640,
+            0x76 => Self::Ov7670,
+            0x77 => Self::Ov7725,
+            0x1410 => Self::Nt99141,
+            0x2145 => Self::Gc2145,
+            0x232a => Self::Gc032a,
+            0x9b => Self::Gc0308,
+            0x30 => Self::Bf3005,
+            0x20a6 => Self::Bf20a6,
+            0xda4a => Self::Sc101iot,
+            0x9a46 => Self::Sc030iot,
+            0x0031 => Self::Sc031gs,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SensorInfo {
+    pub model: SensorModel,
+    pub pid: u16,
+    // Manufacturer ID, high and low byte
+    pub midh: u8,
+    pub midl: u8,
+    pub version: u8,
+}
+
+// What auto exposure and white balance have actually settled on, read from the sensor rather
+// than the driver's cached settings. Units are the sensor's own.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Exposure {
+    // In lines of the frame
+    pub exposure: u32,
+    // The raw gain register, 16 is 1x on the OV2640 and the OV3660/OV5640
+    pub gain: u16,
+    // Whether white balance is being adjusted automatically
+    pub awb: bool,
+}
+
+// A window on the sensor's full resolution, see `Sensor::set_window`. Coordinates are in
+// sensor pixels, the OV2640's full frame being 1600x1200.
+#[derive(Clone, Copy, Debug)]
+pub struct SensorWindow {
+    pub start_x: i32,
+    pub start_y: i32,
+    pub end_x: i32,
+    pub end_y: i32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub total_x: i32,
+    pub total_y: i32,
+    // What comes out after scaling
+    pub output_x: i32,
+    pub output_y: i32,
+    pub scale: bool,
+    pub binning: bool,
+}
+
+// Something the sensor's driver doesn't do, as opposed to the sensor failing to do it
+#[derive(Debug)]
+pub struct Unsupported(pub String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+fn unsupported(what: impl Into<String>) -> anyhow::Error {
+    Unsupported(what.into()).into()
+}
+
+// Generates a setter that calls through one of the sensor's function pointers,
+// drivers leave the ones they don't implement as NULL
+macro_rules! sensor_setter {
+    ($name:ident, $field:ident, $ty:ty) => {
+        pub fn $name(&self, value: $ty) -> Result<()> {
+            let f = unsafe { (*self.raw).$field }.ok_or_else(|| {
+                unsupported(concat!(
+                    stringify!($field),
+                    " is not supported by this sensor"
+                ))
+            })?;
+            if unsafe { f(self.raw, value as _) } != 0 {
+                bail!(concat!(stringify!($field), " failed"));
+            }
+            Ok(())
+        }
+    };
+}
+
+// The image sensor behind the camera driver. Only valid while the camera is initialised,
+// so get it while holding the camera lock and don't keep it around.
+pub struct Sensor {
+    raw: *mut sensor_t,
+}
+
+impl Sensor {
+    pub fn get() -> Result<Self> {
+        let raw = unsafe { esp_camera_sensor_get() };
+        if raw.is_null() {
+            return Err(CameraError::NoSensor("Camera sensor is not initialised".into()).into());
+        }
+        Ok(Self { raw })
+    }
+
+    // The settings the sensor driver currently has applied
+    pub fn status(&self) -> camera_status_t {
+        unsafe { (*self.raw).status }
+    }
+
+    pub fn pixformat(&self) -> pixformat_t {
+        unsafe { (*self.raw).pixformat }
+    }
+
+    pub fn info(&self) -> SensorInfo {
+        let id = unsafe { (*self.raw).id };
+        SensorInfo {
+            model: SensorModel::from_pid(id.PID),
+            pid: id.PID,
+            midh: id.MIDH,
+            midl: id.MIDL,
+            version: id.VER,
+        }
+    }
+
+    // Straight to the sensor over SCCB. `reg` is whatever the driver's get_reg takes: the
+    // register's 16 bit address on the OV3660 and OV5640, the bank in bit 8 and the register
+    // below it on the OV2640.
+    pub fn read_register(&self, reg: u16) -> Result<u8> {
+        let get_reg = unsafe { (*self.raw).get_reg }
+            .ok_or_else(|| unsupported("get_reg is not supported by this sensor"))?;
+        let value = unsafe { get_reg(self.raw, reg as i32, 0xff) };
+        if value < 0 {
+            bail!("Reading register {:#06x} failed", reg);
+        }
+        Ok(value as u8)
+    }
+
+    // Only the OV2640, OV3660 and OV5640 are known, other sensors keep this elsewhere
+    pub fn exposure(&self) -> Result<Exposure> {
+        match self.info().model {
+            SensorModel::Ov2640 => {
+                // AEC is split over three registers in the sensor bank, AWB is in the DSP's
+                let sensor_reg = |reg: u16| self.read_register(0x100 | reg);
+                let exposure = (sensor_reg(0x45)? as u32 & 0x3f) << 10
+                    | (sensor_reg(0x10)? as u32) << 2
+                    | sensor_reg(0x04)? as u32 & 0x03;
+                Ok(Exposure {
+                    exposure,
+                    gain: sensor_reg(0x00)? as u16,
+                    awb: self.read_register(0xc3)? & 0x08 != 0,
+                })
+            }
+            SensorModel::Ov3660 | SensorModel::Ov5640 => {
+                // Exposure is in 1/16 lines, AWB is manual with bit 0 of 0x3406 set
+                let exposure = (self.read_register(0x3500)? as u32 & 0x0f) << 12
+                    | (self.read_register(0x3501)? as u32) << 4
+                    | self.read_register(0x3502)? as u32 >> 4;
+                let gain = (self.read_register(0x350a)? as u16 & 0x03) << 8
+                    | self.read_register(0x350b)? as u16;
+                Ok(Exposure {
+                    exposure,
+                    gain,
+                    awb: self.read_register(0x3406)? & 0x01 == 0,
+                })
+            }
+            model => Err(unsupported(format!(
+                "Reading exposure back is not supported on the {:?}",
+                model
+            ))),
+        }
+    }
+
+    // The OV2640 has two register banks, on other sensors `bank` is the high byte of the
+    // register address
+    pub fn write_register(&self, bank: u8, reg: u8, value: u8) -> Result<()> {
+        let set_reg = unsafe { (*self.raw).set_reg }
+            .ok_or_else(|| unsupported("set_reg is not supported by this sensor"))?;
+        let address = (bank as i32) << 8 | reg as i32;
+        if unsafe { set_reg(self.raw, address, 0xff, value as i32) } != 0 {
+            bail!("Writing register {:#06x} failed", address);
+        }
+        Ok(())
+    }
+
+    // Crops in the sensor itself, so the frame is smaller before it ever reaches us. Only
+    // some drivers have this, and what the numbers mean exactly is down to the sensor.
+    pub fn set_window(&self, window: &SensorWindow) -> Result<()> {
+        let set_res_raw = unsafe { (*self.raw).set_res_raw }
+            .ok_or_else(|| unsupported("set_res_raw is not supported by this sensor"))?;
+        let result = unsafe {
+            set_res_raw(
+                self.raw,
+                window.start_x,
+                window.start_y,
+                window.end_x,
+                window.end_y,
+                window.offset_x,
+                window.offset_y,
+                window.total_x,
+                window.total_y,
+                window.output_x,
+                window.output_y,
+                window.scale,
+                window.binning,
+            )
+        };
+        if result != 0 {
+            bail!("set_res_raw failed");
+        }
+        Ok(())
+    }
+
+    sensor_setter!(set_quality, set_quality, i32);
+    sensor_setter!(set_framesize, set_framesize, framesize_t);
+    // Image adjustments, all -2 to 2 with 0 the driver's default
+    sensor_setter!(set_brightness, set_brightness, i32);
+    sensor_setter!(set_contrast, set_contrast, i32);
+    sensor_setter!(set_saturation, set_saturation, i32);
+    sensor_setter!(set_sharpness, set_sharpness, i32);
+    // Auto exposure. AEC2 is the OV2640's DSP assisted mode, which copes better in the dark.
+    // The AE level biases auto exposure, -2 to 2, and the exposure value is only used with
+    // AEC off, 0 to 1200.
+    sensor_setter!(set_exposure_ctrl, set_exposure_ctrl, bool);
+    sensor_setter!(set_aec2, set_aec2, bool);
+    sensor_setter!(set_ae_level, set_ae_level, i32);
+    sensor_setter!(set_aec_value, set_aec_value, i32);
+
+    // Auto gain. The ceiling caps it, 0 to 6 for 2x up to 128x, and the gain itself is only
+    // used with AGC off, 0 to 30.
+    sensor_setter!(set_gain_ctrl, set_gain_ctrl, bool);
+    sensor_setter!(set_gainceiling, set_gainceiling, gainceiling_t);
+    sensor_setter!(set_agc_gain, set_agc_gain, i32);
+
+    // Auto white balance, and whether its gains get applied
+    sensor_setter!(set_whitebal, set_whitebal, bool);
+    sensor_setter!(set_awb_gain, set_awb_gain, bool);
+    sensor_setter!(set_wb_mode_raw, set_wb_mode, i32);
+
+    pub fn wb_mode(&self) -> Option<WhiteBalanceMode> {
+        WhiteBalanceMode::from_raw(self.status().wb_mode)
+    }
+
+    pub fn set_wb_mode(&self, mode: WhiteBalanceMode) -> Result<()> {
+        self.set_wb_mode_raw(mode as i32)
+    }
+
+    // Noise reduction, lens shading correction and black and white pixel cancellation. Which
+    // ones a sensor has differs, the OV2640 has no denoise and the OV5640 takes it 0-8 with 0
+    // leaving it to the sensor.
+    sensor_setter!(set_denoise, set_denoise, i32);
+    sensor_setter!(set_lenc, set_lenc, bool);
+    sensor_setter!(set_bpc, set_bpc, bool);
+    sensor_setter!(set_wpc, set_wpc, bool);
+
+    sensor_setter!(set_special_effect_raw, set_special_effect, i32);
+
+    pub fn special_effect(&self) -> Option<SpecialEffect> {
+        SpecialEffect::from_raw(self.status().special_effect)
+    }
+
+    pub fn set_special_effect(&self, effect: SpecialEffect) -> Result<()> {
+        self.set_special_effect_raw(effect as i32)
+    }
+
+    // Replaces the picture with the sensor's own test pattern. Bars that come out right mean
+    // the bus and pins are fine and any trouble is in the sensor's settings.
+    sensor_setter!(set_colorbar, set_colorbar, bool);
+
+    sensor_setter!(set_vflip, set_vflip, bool);
+    sensor_setter!(set_hmirror, set_hmirror, bool);
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use esp_idf_svc::{
+    http::{server::EspHttpServer, Method},
+    sys::cam::framesize_t,
+};
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::http::{send_jpeg, ApiError};
+use crate::lock::lock;
+use crate::motion::{MotionEvent, MotionSink};
+use crate::pipeline::{ImageFormat, Pipeline, Shot, Sink};
+
+// Takes a full resolution still whenever motion is raised and hands it to its sinks. The
+// sensor goes back to the streaming settings straight after, see `Pipeline::run_shot`.
+#[derive(Clone)]
+pub struct MotionSnapshot {
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    framesize: framesize_t,
+    quality: u8,
+    sinks: Arc<Mutex<Vec<Box<dyn Sink>>>>,
+    // The newest one, served at /api/motion/snapshot
+    latest: Arc<Mutex<Option<(MotionEvent, Arc<Vec<u8>>)>>>,
+}
+
+impl MotionSnapshot {
+    // `quality` is the encoder's 1-100, see `Shot`
+    pub fn new(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        framesize: framesize_t,
+        quality: u8,
+    ) -> Self {
+        Self {
+            cam,
+            pipeline,
+            framesize,
+            quality,
+            sinks: Arc::new(Mutex::new(Vec::new())),
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn add_sink(&self, sink: Box<dyn Sink>) {
+        lock(&self.sinks).push(sink);
+    }
+
+    // The last snapshot and the event that took it
+    pub fn latest(&self) -> Option<(MotionEvent, Arc<Vec<u8>>)> {
+        lock(&self.latest).clone()
+    }
+}
+
+impl MotionSink for MotionSnapshot {
+    fn motion(&mut self, event: &MotionEvent) -> Result<()> {
+        let jpeg = {
+            let mut pipeline = lock(&self.pipeline);
+            let cam = lock(&self.cam);
+            pipeline.run_shot(
+                &*cam,
+                Shot {
+                    quality: Some(self.quality),
+                    framesize: Some(self.framesize),
+                    format: ImageFormat::Jpeg,
+                },
+            )?
+        };
+        info!("Motion snapshot of {} bytes", jpeg.len());
+
+        for sink in lock(&self.sinks).iter_mut() {
+            if let Err(e) = sink.consume(&jpeg) {
+                warn!("Snapshot sink failed: {:?}", e);
+            }
+        }
+        *lock(&self.latest) = Some((event.clone(), Arc::new(jpeg)));
+        Ok(())
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, snapshot: MotionSnapshot) -> Result<()> {
+    server.fn_handler(
+        "/api/motion/snapshot",
+        Method::Get,
+        auth.protect(move |request| {
+            let Some((event, jpeg)) = snapshot.latest() else {
+                return ApiError::not_found("No motion snapshot yet").send(request);
+            };
+            let etag = format!("\"motion-{}\"", event.timestamp);
+            send_jpeg(request, &jpeg, Some(&etag), "snapshot;dur=0")
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use esp_idf_svc::sys::cam::pixformat_t_PIXFORMAT_JPEG;
+
+use crate::camera::{Camera, CameraError, CameraExt, FrameBuffer};
+use crate::sensor::Sensor;
+
+pub use tigercam_core::source::{BufferTooSmall, FrameSize, FrameSource, MockCamera, RawFrame};
+
+// The sensor settings go through `Sensor`, so errors from there keep saying whether there's
+// no sensor or it can't do something, see `ApiError`
+impl FrameSource for Camera {
+    fn with_frame<R>(&self, f: impl FnOnce(&RawFrame) -> R) -> Result<R> {
+        let fb = self.try_capture()?;
+        Ok(f(&fb.as_raw()))
+    }
+
+    fn capture_jpeg(&self, quality: u8) -> Result<Vec<u8>> {
+        Ok(self.with_jpeg(quality, <[u8]>::to_vec)?)
+    }
+
+    fn capture_jpeg_into(&self, quality: u8, buf: &mut [u8]) -> Result<usize> {
+        match CameraExt::capture_jpeg_into(self, quality, buf) {
+            Err(CameraError::BufferTooSmall(needed)) => Err(BufferTooSmall(needed).into()),
+            result => Ok(result?),
+        }
+    }
+
+    fn discard(&self) {
+        FrameBuffer::discard(self);
+    }
+
+    fn sensor_jpeg(&self) -> Result<bool> {
+        Ok(Sensor::get()?.pixformat() == pixformat_t_PIXFORMAT_JPEG)
+    }
+
+    fn framesize(&self) -> Result<FrameSize> {
+        Ok(Sensor::get()?.status().framesize)
+    }
+
+    fn set_framesize(&self, framesize: FrameSize) -> Result<()> {
+        Sensor::get()?.set_framesize(framesize)
+    }
+
+    fn quality(&self) -> Result<i32> {
+        Ok(Sensor::get()?.status().quality as i32)
+    }
+
+    fn set_quality(&self, quality: i32) -> Result<()> {
+        Sensor::get()?.set_quality(quality)
+    }
+
+    fn vflip(&self) -> Result<bool> {
+        Ok(Sensor::get()?.status().vflip != 0)
+    }
+
+    fn set_vflip(&self, vflip: bool) -> Result<()> {
+        Sensor::get()?.set_vflip(vflip)
+    }
+
+    fn hmirror(&self) -> Result<bool> {
+        Ok(Sensor::get()?.status().hmirror != 0)
+    }
+
+    fn set_hmirror(&self, hmirror: bool) -> Result<()> {
+        Sensor::get()?.set_hmirror(hmirror)
+    }
+}
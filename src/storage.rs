@@ -0,0 +1,206 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use esp_idf_svc::io::Write;
+use esp_idf_svc::sys::{esp, esp_spiffs_info, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register};
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::CString,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::auth::Auth;
+use crate::http::ApiError;
+
+// Where the partition shows up in the VFS, names given to `Storage` are relative to it
+pub const MOUNT_POINT: &str = "/flash";
+// Its label in partitions.csv
+const PARTITION: &str = "storage";
+const MAX_FILES: usize = 4;
+// What `write` puts the new contents in before they take the old file's place
+const NEW_SUFFIX: &str = ".new";
+// SPIFFS keeps names this long at most, counting the '/' in front
+const MAX_NAME: usize = 31;
+// Web UI files served at /ui/, "ui/index.html" for /ui/
+const UI_PREFIX: &str = "ui/";
+// Built into the firmware and served at /ui/ until the partition has an index.html of its own
+const BUILT_IN_UI: &str = include_str!("ui/index.html");
+
+// The small SPIFFS partition in the module's own flash, for JSON config too big or too
+// structured for NVS, the web UI and short logs. SPIFFS has no directories, a name like
+// "ui/app.js" is just a name.
+#[derive(Clone, Copy)]
+pub struct Storage {
+    _mounted: (),
+}
+
+impl Storage {
+    // An empty or unreadable partition is formatted, there's nothing on it yet that matters
+    // more than booting
+    pub fn mount() -> Result<Self> {
+        let base_path = CString::new(MOUNT_POINT)?;
+        let partition = CString::new(PARTITION)?;
+        let conf = esp_vfs_spiffs_conf_t {
+            base_path: base_path.as_ptr(),
+            partition_label: partition.as_ptr(),
+            max_files: MAX_FILES,
+            format_if_mount_failed: true,
+        };
+        esp!(unsafe { esp_vfs_spiffs_register(&conf) })?;
+
+        let storage = Self { _mounted: () };
+        let (total, used) = storage.usage()?;
+        info!(
+            "Mounted flash storage at {}, {} of {} KB used",
+            MOUNT_POINT,
+            used / 1024,
+            total / 1024
+        );
+        Ok(storage)
+    }
+
+    // Total and used bytes
+    pub fn usage(&self) -> Result<(usize, usize)> {
+        let partition = CString::new(PARTITION)?;
+        let (mut total, mut used) = (0, 0);
+        esp!(unsafe { esp_spiffs_info(partition.as_ptr(), &mut total, &mut used) })?;
+        Ok((total, used))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let name = name.trim_start_matches('/');
+        if name.is_empty() || name.split('/').any(|part| part == "..") {
+            bail!("'{}' isn't a name in flash storage", name);
+        }
+        if 1 + name.len() + NEW_SUFFIX.len() > MAX_NAME {
+            bail!("'{}' is too long a name for flash storage", name);
+        }
+        Ok(PathBuf::from(format!("{}/{}", MOUNT_POINT, name)))
+    }
+
+    // None if there's no such file. One whose `write` was cut short between removing the old
+    // contents and renaming the new is read from where the new ones are.
+    pub fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(name)?;
+        let new = new_path(&path);
+        for path in [path, new] {
+            match fs::read(&path) {
+                Ok(data) => return Ok(Some(data)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    // A reader sees either the old contents or all of the new, never half of them. SPIFFS
+    // won't rename over a file, so the old one is removed first, see `read`.
+    pub fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(name)?;
+        let new = new_path(&path);
+        fs::write(&new, data)?;
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        fs::rename(&new, &path)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        for path in [new_path(&path), path] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match self.read(name)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn write_json<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        self.write(name, &serde_json::to_vec(value)?)
+    }
+}
+
+fn new_path(path: &Path) -> PathBuf {
+    let mut new = path.as_os_str().to_owned();
+    new.push(NEW_SUFFIX);
+    new.into()
+}
+
+// Going by the extension
+pub fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().unwrap_or_default() {
+        "html" => "text/html",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "jpg" => "image/jpeg",
+        "avi" => "video/x-msvideo",
+        "txt" | "log" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+// The web UI, from the partition when it's mounted and has the file. Without one /ui/ is the
+// page built into the firmware, so there's always something to open.
+pub fn register(
+    server: &mut EspHttpServer,
+    auth: &Auth,
+    storage: Option<Storage>,
+    stream_port: u16,
+) -> Result<()> {
+    let built_in = BUILT_IN_UI.replace("STREAM_PORT", &stream_port.to_string());
+    // Whatever's been put in the partition as ui/<name>, see the README
+    server.fn_handler(
+        "/ui/*",
+        Method::Get,
+        auth.protect(move |request| {
+            let uri = request.uri().to_string();
+            let path = uri.split('?').next().unwrap_or_default();
+            let name = match path.strip_prefix("/ui/").unwrap_or_default() {
+                "" => "index.html",
+                name => name,
+            };
+            let name = format!("{}{}", UI_PREFIX, name);
+
+            let stored = match storage.map(|storage| storage.read(&name)).transpose() {
+                Ok(stored) => stored.flatten(),
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let data = match stored {
+                Some(data) => data,
+                None if name == "ui/index.html" => built_in.as_bytes().to_vec(),
+                None => return ApiError::not_found("No such file").send(request),
+            };
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", content_type(&name)),
+                    ("Content-Length", &data.len().to_string()),
+                ],
+            )?;
+            response.write_all(&data)?;
+
+            Ok(())
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use log::info;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::{Camera, CameraError};
+use crate::continuous::Continuous;
+use crate::http::{query_param, Conn};
+use crate::lock::lock;
+use crate::pipeline::{ImageFormat, Pipeline, Shot};
+use tigercam_core::http::Multipart;
+
+// What a struggling client gets stepped down through, the first level is the configured
+// stream as-is. Only the quality and how many frames are sent change, a framesize switch would
+// have the driver reallocate its buffers and drop frames for every frame of every client.
+struct Level {
+    quality: Option<u8>,
+    // Every how manyth frame time a frame is sent
+    every: u32,
+}
+
+const LEVELS: [Level; 4] = [
+    Level {
+        quality: None,
+        every: 1,
+    },
+    Level {
+        quality: Some(60),
+        every: 1,
+    },
+    Level {
+        quality: Some(50),
+        every: 2,
+    },
+    Level {
+        quality: Some(40),
+        every: 4,
+    },
+];
+// Step down quickly so the client catches up, but only step back up once it has kept up
+// for a good while, so we don't flap between levels
+const STEP_DOWN_AFTER: u32 = 3;
+const STEP_UP_AFTER: u32 = 30;
+// How long to wait on the continuous capture task before giving up on the client
+const FRAME_WAIT: Duration = Duration::from_secs(3);
+
+// Picks a level for one client from how long its frames take to write
+struct Adaptive {
+    level: usize,
+    slow: u32,
+    fast: u32,
+}
+
+impl Adaptive {
+    fn new() -> Self {
+        Self {
+            level: 0,
+            slow: 0,
+            fast: 0,
+        }
+    }
+
+    fn shot(&self) -> Option<Shot> {
+        LEVELS[self.level].quality.map(|quality| Shot {
+            quality: Some(quality),
+            framesize: None,
+            format: ImageFormat::Jpeg,
+        })
+    }
+
+    // How long a frame is given, including those skipped
+    fn interval(&self, frame_time: Duration) -> Duration {
+        frame_time * LEVELS[self.level].every
+    }
+
+    fn record(&mut self, write: Duration, frame_time: Duration) {
+        if write > frame_time {
+            self.slow += 1;
+            self.fast = 0;
+        } else if write < frame_time / 4 {
+            self.fast += 1;
+            self.slow = 0;
+        } else {
+            self.slow = 0;
+            self.fast = 0;
+        }
+
+        if self.slow >= STEP_DOWN_AFTER && self.level + 1 < LEVELS.len() {
+            self.level += 1;
+            self.slow = 0;
+            info!(
+                "Stream client is falling behind, stepping down to level {}",
+                self.level
+            );
+        } else if self.fast >= STEP_UP_AFTER && self.level > 0 {
+            self.level -= 1;
+            self.fast = 0;
+            info!(
+                "Stream client caught up, stepping up to level {}",
+                self.level
+            );
+        }
+    }
+}
+
+// The MJPEG stream gets its own server, a stream holds its handler for as long as the client
+// watches and would lock everything else on the main server out. `fps` caps every client,
+// they can ask for less with `/stream?fps=`.
+pub fn start(
+    cam: Arc<Mutex<Camera>>,
+    pipeline: Arc<Mutex<Pipeline>>,
+    auth: &Auth,
+    port: u16,
+    fps: u32,
+    continuous: Option<Continuous>,
+) -> Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: port,
+        // Has to differ from the main server's
+        ctrl_port: 32769,
+        ..Default::default()
+    })?;
+    let max_fps = fps.max(1);
+
+    server.fn_handler(
+        "/stream",
+        esp_idf_svc::http::Method::Get,
+        auth.protect(move |request| {
+            let fps = query_param(request.uri(), "fps")
+                .and_then(|fps| fps.parse::<u32>().ok())
+                .map_or(max_fps, |fps| fps.clamp(1, max_fps));
+            let frame_time = Duration::from_millis(1000 / fps as u64);
+            let mut conn = Conn::from(request);
+            let mut stream = Multipart::start(&mut conn)?;
+
+            info!("Stream client connected at {} fps", fps);
+            // The continuous task keeps its frames fresh by itself, only streams capturing
+            // their own frames need Latest
+            let viewer = continuous.as_ref().map(Continuous::watch);
+            if viewer.is_none() {
+                lock(&pipeline).stream_started();
+            }
+
+            let mut adaptive = Adaptive::new();
+            let mut sequence = 0;
+            // Kept for the whole stream, frames are captured into it rather than each getting
+            // a Vec of its own
+            let mut buf = Vec::new();
+            let result = loop {
+                let started = Instant::now();
+
+                // Frames from the continuous task are shared with everyone else watching, a
+                // slow client on them only steps down through `every`
+                let len = match (&viewer, adaptive.shot()) {
+                    (Some(viewer), _) => match viewer.newer(sequence, FRAME_WAIT) {
+                        Some((newest, jpeg)) => {
+                            sequence = newest;
+                            buf = jpeg;
+                            Ok(buf.len())
+                        }
+                        None => Err(CameraError::Timeout.into()),
+                    },
+                    (None, Some(shot)) => {
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run_shot(&*cam, shot).map(|jpeg| {
+                            buf = jpeg;
+                            buf.len()
+                        })
+                    }
+                    (None, None) => {
+                        let mut pipeline = lock(&pipeline);
+                        let cam = lock(&cam);
+                        pipeline.run_into(&*cam, &mut buf)
+                    }
+                };
+                let jpeg = match len {
+                    Ok(len) => &buf[..len],
+                    Err(e) => break Err(e),
+                };
+
+                let written = Instant::now();
+                // Fails once the client has gone, which ends the stream rather than failing it
+                if stream.send(jpeg).is_err() {
+                    break Ok(());
+                }
+                let interval = adaptive.interval(frame_time);
+                adaptive.record(written.elapsed(), interval);
+
+                if let Some(left) = interval.checked_sub(started.elapsed()) {
+                    thread::sleep(left);
+                }
+            };
+
+            if viewer.is_none() {
+                lock(&pipeline).stream_stopped();
+            }
+            info!("Stream client disconnected");
+
+            result?;
+            Ok(())
+        }),
+    )?;
+
+    info!("MJPEG stream on port {}", port);
+    Ok(server)
+}
@@ -0,0 +1,166 @@
+use anyhow::Result;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::analysis::Analysis;
+use crate::auth::Auth;
+use crate::camera::{Camera, CameraError, CameraExt, Thumbnail};
+use crate::http::write_json;
+use crate::lock::lock;
+use crate::motion::{self, Motion, MotionEvent, Source};
+use crate::pipeline::Pipeline;
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+// All in luma 0-255
+#[derive(Clone, Copy, Debug)]
+pub struct TamperSettings {
+    // A frame darker than this on average looks covered
+    pub dark: u8,
+    // A frame whose pixels are within this of the average on the whole looks like a wall or
+    // a hand right up against the lens
+    pub uniform: u8,
+    // How long it has to stay that way, so a light switched off or a passing truck isn't it
+    pub hold: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    Dark,
+    Uniform,
+}
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    // Of the last frame
+    brightness: u8,
+    spread: u8,
+    // Set while the camera looks tampered with
+    tampered: Option<Reason>,
+    events: u32,
+}
+
+// Average luma, and how far pixels are from it on average
+fn measure(frame: &Thumbnail) -> (u8, u8) {
+    let count = frame.luma.len().max(1) as u32;
+    let mean = frame.luma.iter().map(|&l| l as u32).sum::<u32>() / count;
+    let spread = frame
+        .luma
+        .iter()
+        .map(|&l| (l as u32).abs_diff(mean))
+        .sum::<u32>()
+        / count;
+    (mean as u8, spread as u8)
+}
+
+// Watches for the lens being covered or the camera being turned to face a wall, and raises a
+// motion event from `Source::Tamper` once it's lasted `hold`
+#[derive(Clone)]
+pub struct Tamper {
+    state: Arc<Mutex<State>>,
+}
+
+impl Tamper {
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        analysis: Analysis,
+        settings: TamperSettings,
+        motion: Motion,
+    ) -> Result<Self> {
+        let tamper = Self {
+            state: Arc::new(Mutex::new(State::default())),
+        };
+
+        let state = tamper.state.clone();
+        thread::Builder::new()
+            .name("tamper".into())
+            .stack_size(4096)
+            .spawn(move || {
+                // When the scene started looking wrong
+                let mut since: Option<Instant> = None;
+                loop {
+                    thread::sleep(INTERVAL);
+
+                    let thumbnail = match analysis.fresh(INTERVAL) {
+                        Some(frame) => Ok(frame),
+                        None => {
+                            let _pipeline = lock(&pipeline);
+                            let cam = lock(&cam);
+                            cam.try_capture().and_then(|fb| Analysis::frame(&fb))
+                        }
+                    };
+                    let thumbnail = match thumbnail {
+                        Ok(thumbnail) => thumbnail,
+                        Err(CameraError::Asleep) => continue,
+                        Err(e) => {
+                            warn!("Tamper capture failed: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let (brightness, spread) = measure(&thumbnail);
+                    let reason = if brightness < settings.dark {
+                        Some(Reason::Dark)
+                    } else if spread < settings.uniform {
+                        Some(Reason::Uniform)
+                    } else {
+                        None
+                    };
+
+                    let mut state = lock(&state);
+                    state.brightness = brightness;
+                    state.spread = spread;
+                    let Some(reason) = reason else {
+                        if state.tampered.take().is_some() {
+                            info!("Camera no longer looks tampered with");
+                        }
+                        since = None;
+                        continue;
+                    };
+                    let started = *since.get_or_insert_with(Instant::now);
+                    if state.tampered.is_some() || started.elapsed() < settings.hold {
+                        continue;
+                    }
+
+                    warn!("Camera looks tampered with: {:?}", reason);
+                    state.tampered = Some(reason);
+                    state.events += 1;
+                    drop(state);
+                    motion.raise(MotionEvent {
+                        timestamp: motion::now(),
+                        source: Source::Tamper,
+                        score: 0,
+                        cells: 0,
+                        faces: Vec::new(),
+                    });
+                }
+            })?;
+
+        info!(
+            "Watching for tampering, darker than {} or flatter than {}",
+            settings.dark, settings.uniform
+        );
+        Ok(tamper)
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, tamper: Tamper) -> Result<()> {
+    server.fn_handler(
+        "/api/tamper",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&tamper.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,468 @@
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::Read,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Auth;
+use crate::camera::Camera;
+use crate::clock::CLOCK_SET;
+use crate::http::{post, read_body, write_json, ApiError};
+use crate::lock::lock;
+use crate::motion;
+use crate::pipeline::{ImageFormat, Pipeline, Shot};
+use crate::sdcard::SdCard;
+use crate::sensor::framesize_from_name;
+use crate::storage::Storage;
+
+// How often the task looks whether a still is due
+const TICK: Duration = Duration::from_secs(1);
+// Where stills for a remote that can't be reached wait on the card
+pub const PENDING_DIR: &str = "timelapse/pending";
+// How many wait there at most, the oldest go first. A UXGA still is a few hundred KB.
+const CARD_PENDING: usize = 256;
+// How many wait in memory instead when there's no card, the oldest go first
+const MEMORY_PENDING: usize = 8;
+// The running time-lapse in flash storage, so a reboot carries on with it
+const SAVED_JOB: &str = "timelapse.json";
+// Held stills sent along with each new one once the remote answers again, so catching up
+// doesn't hold up the schedule
+const CATCH_UP: usize = 4;
+
+// What POST /api/timelapse takes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub interval_s: u32,
+    // 0 to keep going until it's stopped
+    #[serde(default)]
+    pub duration_s: u32,
+    // A frame size like "UXGA", the stream's when left out
+    #[serde(default)]
+    pub size: Option<String>,
+    #[serde(default)]
+    pub quality: Option<u8>,
+    // "card", or a URL every still is POSTed to as image/jpeg
+    pub destination: String,
+}
+
+#[derive(Clone)]
+enum Destination {
+    // With everything else in the card's dated layout, see `SdCard::media_name`
+    Card,
+    Remote(String),
+}
+
+struct Job {
+    shot: Shot,
+    interval: Duration,
+    // None to go on until stopped
+    until: Option<Instant>,
+    // The same by the clock, for one carried on with after a reboot. It only counts once SNTP
+    // has set the clock.
+    ends: Option<u64>,
+    next: Instant,
+    destination: Destination,
+}
+
+impl Job {
+    fn new(spec: &Spec, sdcard: Option<&SdCard>) -> Result<Self> {
+        if spec.interval_s == 0 {
+            bail!("interval_s has to be at least 1");
+        }
+        let framesize = match &spec.size {
+            Some(size) => Some(
+                framesize_from_name(size)
+                    .ok_or_else(|| anyhow!("Unknown frame size '{}'", size))?,
+            ),
+            None => None,
+        };
+        if spec.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            bail!("quality must be between 1 and 100");
+        }
+        let destination = match spec.destination.as_str() {
+            "card" if sdcard.is_none() => bail!("There's no SD card to keep the stills on"),
+            "card" => Destination::Card,
+            url if url.starts_with("http://") || url.starts_with("https://") => {
+                Destination::Remote(url.to_string())
+            }
+            other => bail!("A destination is \"card\" or a URL, not '{}'", other),
+        };
+
+        let now = Instant::now();
+        Ok(Self {
+            shot: Shot {
+                quality: spec.quality,
+                framesize,
+                format: ImageFormat::Jpeg,
+            },
+            interval: Duration::from_secs(spec.interval_s as u64),
+            until: (spec.duration_s > 0).then(|| now + Duration::from_secs(spec.duration_s as u64)),
+            ends: None,
+            next: now,
+            destination,
+        })
+    }
+
+    fn finished(&self, now: Instant) -> bool {
+        let clock = motion::now();
+        self.until.is_some_and(|until| now >= until)
+            || self
+                .ends
+                .is_some_and(|ends| clock >= CLOCK_SET && clock >= ends)
+    }
+}
+
+// Stills a remote destination couldn't take yet, on the card when there is one
+struct Backlog {
+    sdcard: Option<SdCard>,
+    memory: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Backlog {
+    // On the card they're named for `timestamp`, which is now
+    fn hold(&mut self, timestamp: u64, jpeg: Vec<u8>) -> Result<()> {
+        if let Some(sdcard) = &self.sdcard {
+            let held = sdcard.list(PENDING_DIR).unwrap_or_default();
+            for oldest in &held[..(held.len() + 1).saturating_sub(CARD_PENDING)] {
+                warn!(
+                    "Dropping held time-lapse still {}, too many waiting",
+                    oldest.name
+                );
+                sdcard.remove(&format!("{}/{}", PENDING_DIR, oldest.name))?;
+            }
+            return sdcard.write(&sdcard.unique_name(PENDING_DIR, "jpg")?, &jpeg);
+        }
+        if self.memory.len() == MEMORY_PENDING {
+            warn!("Dropping a held time-lapse still, there's no card to keep it on");
+            self.memory.pop_front();
+        }
+        self.memory.push_back((timestamp, jpeg));
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        match &self.sdcard {
+            Some(sdcard) => sdcard.list(PENDING_DIR).map_or(0, |entries| entries.len()),
+            None => self.memory.len(),
+        }
+    }
+
+    // Oldest first, stopping at the first that doesn't go. Says how many did.
+    fn send(&mut self, url: &str, count: usize) -> Result<u32> {
+        let mut sent = 0;
+        match &self.sdcard {
+            Some(sdcard) => {
+                for entry in sdcard.list(PENDING_DIR)?.into_iter().take(count) {
+                    let name = format!("{}/{}", PENDING_DIR, entry.name);
+                    let mut jpeg = Vec::with_capacity(entry.size as usize);
+                    sdcard.open(&name)?.read_to_end(&mut jpeg)?;
+                    let timestamp = entry.name.split(['.', '-']).next().unwrap_or_default();
+                    send(url, timestamp.parse().unwrap_or_default(), &jpeg)?;
+                    sdcard.remove(&name)?;
+                    sent += 1;
+                }
+            }
+            None => {
+                while let Some((timestamp, jpeg)) = self.memory.front() {
+                    if sent as usize == count {
+                        break;
+                    }
+                    send(url, *timestamp, jpeg)?;
+                    self.memory.pop_front();
+                    sent += 1;
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
+
+// `X-Timestamp` is when it was taken, seconds since the epoch, which for a held still isn't
+// when it arrives
+fn send(url: &str, timestamp: u64, jpeg: &[u8]) -> Result<()> {
+    post(
+        url,
+        &[
+            ("Content-Type", "image/jpeg"),
+            ("X-Timestamp", &timestamp.to_string()),
+        ],
+        jpeg,
+    )
+}
+
+// What's kept in SAVED_JOB
+#[derive(Serialize, Deserialize)]
+struct SavedJob {
+    spec: Spec,
+    // When it's to finish, seconds since the epoch. None for one that goes on until stopped, or
+    // when the clock wasn't set to tell.
+    #[serde(default)]
+    ends: Option<u64>,
+}
+
+#[derive(Clone, Default, Serialize)]
+struct State {
+    // Of the time-lapse running, if there is one
+    spec: Option<Spec>,
+    taken: u32,
+    sent: u32,
+    // Waiting for the remote to be reachable again
+    pending: usize,
+    failures: u32,
+    last_error: Option<String>,
+}
+
+// Takes a still every so often for a while, set going through /api/timelapse. They're kept on
+// the SD card or POSTed somewhere, and held back while the network is down.
+#[derive(Clone)]
+pub struct TimeLapse {
+    job: Arc<Mutex<Option<Job>>>,
+    state: Arc<Mutex<State>>,
+    sdcard: Option<SdCard>,
+    // Where the running one is saved, see SAVED_JOB
+    storage: Option<Storage>,
+}
+
+impl TimeLapse {
+    // Carries on with the time-lapse that was running before a reboot, if it had more to go
+    pub fn start(
+        cam: Arc<Mutex<Camera>>,
+        pipeline: Arc<Mutex<Pipeline>>,
+        sdcard: Option<SdCard>,
+        storage: Option<Storage>,
+    ) -> Result<Self> {
+        let timelapse = Self {
+            job: Arc::new(Mutex::new(None)),
+            state: Arc::new(Mutex::new(State::default())),
+            sdcard: sdcard.clone(),
+            storage,
+        };
+        timelapse.resume();
+
+        let task = timelapse.clone();
+        let mut backlog = Backlog {
+            sdcard,
+            memory: VecDeque::new(),
+        };
+        thread::Builder::new()
+            .name("timelapse".into())
+            .stack_size(12288)
+            .spawn(move || loop {
+                thread::sleep(TICK);
+
+                let due = {
+                    let mut job = lock(&task.job);
+                    let now = Instant::now();
+                    match job.as_mut() {
+                        Some(running) if running.finished(now) => {
+                            info!("Time-lapse finished");
+                            *job = None;
+                            lock(&task.state).spec = None;
+                            task.forget();
+                            None
+                        }
+                        Some(running) if now >= running.next => {
+                            // A still that took longer than the interval skips the ones it
+                            // held up rather than taking them late
+                            while running.next <= now {
+                                running.next += running.interval;
+                            }
+                            Some((running.shot, running.destination.clone()))
+                        }
+                        _ => None,
+                    }
+                };
+                let Some((shot, destination)) = due else {
+                    continue;
+                };
+
+                let jpeg = {
+                    let mut pipeline = lock(&pipeline);
+                    let cam = lock(&cam);
+                    pipeline.run_shot(&*cam, shot)
+                };
+                let result = jpeg.and_then(|jpeg| {
+                    lock(&task.state).taken += 1;
+                    task.deliver(&mut backlog, destination, jpeg)
+                });
+
+                let mut state = lock(&task.state);
+                state.pending = backlog.len();
+                if let Err(e) = result {
+                    warn!("Time-lapse still failed: {:?}", e);
+                    state.failures += 1;
+                    state.last_error = Some(format!("{:#}", e));
+                }
+            })?;
+
+        Ok(timelapse)
+    }
+
+    fn deliver(
+        &self,
+        backlog: &mut Backlog,
+        destination: Destination,
+        jpeg: Vec<u8>,
+    ) -> Result<()> {
+        let timestamp = motion::now();
+        match destination {
+            Destination::Card => {
+                let Some(sdcard) = &self.sdcard else {
+                    bail!("There's no SD card");
+                };
+                sdcard.write(&sdcard.media_name("timelapse", "jpg")?, &jpeg)
+            }
+            Destination::Remote(url) => {
+                if let Err(e) = send(&url, timestamp, &jpeg) {
+                    backlog.hold(timestamp, jpeg)?;
+                    return Err(e);
+                }
+                lock(&self.state).sent += 1;
+                let caught_up = backlog.send(&url, CATCH_UP);
+                if let Ok(sent) = caught_up {
+                    lock(&self.state).sent += sent;
+                }
+                caught_up.map(|_| ())
+            }
+        }
+    }
+
+    // Takes over from whatever was running, the first still is taken straight away
+    pub fn begin(&self, spec: Spec) -> Result<()> {
+        let job = Job::new(&spec, self.sdcard.as_ref())?;
+        info!(
+            "Time-lapse every {} s to {}",
+            spec.interval_s, spec.destination
+        );
+        if let Some(storage) = &self.storage {
+            let now = motion::now();
+            let saved = SavedJob {
+                ends: (spec.duration_s > 0 && now >= CLOCK_SET)
+                    .then(|| now + spec.duration_s as u64),
+                spec: spec.clone(),
+            };
+            if let Err(e) = storage.write_json(SAVED_JOB, &saved) {
+                warn!(
+                    "Couldn't save the time-lapse, it won't survive a reboot: {:?}",
+                    e
+                );
+            }
+        }
+        self.start_job(spec, job);
+        Ok(())
+    }
+
+    fn start_job(&self, spec: Spec, job: Job) {
+        *lock(&self.job) = Some(job);
+        let mut state = lock(&self.state);
+        *state = State {
+            spec: Some(spec),
+            pending: state.pending,
+            ..Default::default()
+        };
+    }
+
+    // The saved time-lapse, for what was left of it by the clock. Without the clock set when
+    // it was started, it's started over from the beginning of its duration.
+    fn resume(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let saved = match storage.read_json::<SavedJob>(SAVED_JOB) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Couldn't read the saved time-lapse: {:?}", e);
+                return;
+            }
+        };
+        match Job::new(&saved.spec, self.sdcard.as_ref()) {
+            Ok(mut job) => {
+                if saved.ends.is_some() {
+                    job.until = None;
+                    job.ends = saved.ends;
+                }
+                info!(
+                    "Carrying on with the time-lapse every {} s to {}",
+                    saved.spec.interval_s, saved.spec.destination
+                );
+                self.start_job(saved.spec, job);
+            }
+            Err(e) => {
+                warn!("Dropping the saved time-lapse: {:?}", e);
+                self.forget();
+            }
+        }
+    }
+
+    fn forget(&self) {
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.remove(SAVED_JOB) {
+                warn!("Couldn't remove the saved time-lapse: {:?}", e);
+            }
+        }
+    }
+
+    // Held stills go out with the next time-lapse that POSTs somewhere
+    pub fn stop(&self) {
+        if lock(&self.job).take().is_some() {
+            info!("Time-lapse stopped");
+        }
+        lock(&self.state).spec = None;
+        self.forget();
+    }
+}
+
+pub fn register(server: &mut EspHttpServer, auth: &Auth, timelapse: TimeLapse) -> Result<()> {
+    let get_timelapse = timelapse.clone();
+    server.fn_handler(
+        "/api/timelapse",
+        Method::Get,
+        auth.protect(move |request| {
+            let state = lock(&get_timelapse.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    // `{"interval_s": 60, "duration_s": 86400, "size": "UXGA", "destination": "card"}`
+    let begin_timelapse = timelapse.clone();
+    server.fn_handler(
+        "/api/timelapse",
+        Method::Post,
+        auth.protect(move |mut request| {
+            let body = match read_body(&mut request, 1024) {
+                Ok(body) => body,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+            let spec: Spec = match serde_json::from_slice(&body) {
+                Ok(spec) => spec,
+                Err(e) => return ApiError::bad_request(e).send(request),
+            };
+
+            if let Err(e) = begin_timelapse.begin(spec) {
+                return ApiError::bad_request(e).send(request);
+            }
+            let state = lock(&begin_timelapse.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    server.fn_handler(
+        "/api/timelapse/stop",
+        Method::Post,
+        auth.protect(move |request| {
+            timelapse.stop();
+            let state = lock(&timelapse.state).clone();
+            write_json(request, 200, &state)
+        }),
+    )?;
+
+    Ok(())
+}
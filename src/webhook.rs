@@ -0,0 +1,97 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::warn;
+use serde::Serialize;
+use std::{
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+};
+
+use crate::http::post_json;
+use crate::motion::{MotionEvent, MotionSink};
+use crate::snapshot::MotionSnapshot;
+
+// What the webhook gets of the snapshot taken for the event
+pub enum Attachment {
+    None,
+    // The JPEG itself as base64. A UXGA one makes for a few hundred KB of JSON.
+    Inline(MotionSnapshot),
+    // Where to fetch it, /api/motion/snapshot on this camera
+    Link(String),
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    #[serde(flatten)]
+    event: &'a MotionEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jpeg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_url: Option<&'a str>,
+}
+
+// Events waiting to be POSTed, any more while the server is slow or down are dropped
+const QUEUE: usize = 4;
+
+// POSTs every motion event as JSON, for Home Assistant, Node-RED and the like. It has to be
+// added after the `MotionSnapshot` for an attachment to belong to the same event. The POST is
+// made on a task of its own, a slow server mustn't hold up the sinks after it.
+pub struct MotionWebhook {
+    events: SyncSender<(MotionEvent, Option<Arc<Vec<u8>>>)>,
+    attachment: Attachment,
+}
+
+impl MotionWebhook {
+    pub fn start(url: &str, attachment: Attachment) -> Result<Self> {
+        let (events, pending) = mpsc::sync_channel(QUEUE);
+        let url = url.to_string();
+        let link = match &attachment {
+            Attachment::Link(link) => Some(link.clone()),
+            _ => None,
+        };
+        thread::Builder::new()
+            .name("webhook".into())
+            .stack_size(8192)
+            .spawn(move || notify(pending, &url, link.as_deref()))?;
+
+        Ok(Self { events, attachment })
+    }
+}
+
+impl MotionSink for MotionWebhook {
+    fn motion(&mut self, event: &MotionEvent) -> Result<()> {
+        // Taken now, the next event's snapshot could replace it before the task gets to it.
+        // Left out if the snapshot failed rather than sending an older one.
+        let jpeg = match &self.attachment {
+            Attachment::Inline(snapshot) => snapshot
+                .latest()
+                .filter(|(taken_for, _)| taken_for == event)
+                .map(|(_, jpeg)| jpeg),
+            _ => None,
+        };
+        match self.events.try_send((event.clone(), jpeg)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                warn!("Webhook is behind, dropping a motion event");
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => bail!("The webhook task has stopped"),
+        }
+    }
+}
+
+fn notify(pending: Receiver<(MotionEvent, Option<Arc<Vec<u8>>>)>, url: &str, link: Option<&str>) {
+    for (event, jpeg) in pending {
+        let notification = Notification {
+            event: &event,
+            jpeg: jpeg.map(|jpeg| STANDARD.encode(&*jpeg)),
+            snapshot_url: link,
+        };
+        if let Err(e) = post_json(url, &notification) {
+            warn!("Motion webhook failed: {:?}", e);
+        }
+    }
+}
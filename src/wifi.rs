@@ -4,21 +4,34 @@ use esp_idf_svc::{
     hal::peripheral,
     nvs::EspDefaultNvsPartition,
     timer::EspTaskTimerService,
-    wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi},
+    wifi::{
+        AccessPointConfiguration, AsyncWifi, AuthMethod, ClientConfiguration, Configuration,
+        EspWifi,
+    },
 };
 use log::{info, warn};
+use std::time::Duration;
 
+use crate::mdns;
+use crate::provision;
+
+// Failed tries at the configured network before the fallback access point comes up
+pub const FALLBACK_AFTER: u32 = 10;
+// How often the configured network is tried again while it's up. Every try can take the
+// access point off the air for a moment while the radio scans.
+pub const FALLBACK_RETRY: Duration = Duration::from_secs(300);
+
+// Boot carries on with the fallback access point up if the network can't be joined, see
+// `start_fallback`
 pub async fn init_wifi<'a>(
     ssid: &str,
     pass: &str,
+    ap_pass: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'a,
     sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
 ) -> Result<Box<EspWifi<'a>>> {
-    let mut esp_wifi = EspWifi::new(
-        modem,
-        sysloop.clone(),
-        Some(EspDefaultNvsPartition::take()?),
-    )?;
+    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs))?;
 
     let mut counter = 0;
 
@@ -31,11 +44,86 @@ pub async fn init_wifi<'a>(
         }
         counter += 1;
         warn!("Failed to connect to wifi, try {}", counter);
+        if counter >= FALLBACK_AFTER {
+            start_fallback(ssid, pass, ap_pass, sysloop.clone(), &mut esp_wifi).await?;
+            break;
+        }
     }
 
     Ok(Box::new(esp_wifi))
 }
 
+fn client_configuration(ssid: &str, pass: &str, channel: Option<u8>) -> ClientConfiguration {
+    let auth_method = if pass.is_empty() {
+        info!("Wifi password is empty");
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
+    };
+
+    ClientConfiguration {
+        ssid: ssid.into(),
+        password: pass.into(),
+        channel,
+        auth_method,
+        ..Default::default()
+    }
+}
+
+// Brings up an esp32cam-XXXX access point alongside the station, named like the setup one,
+// so the camera and /setup can still be reached from right next to it when the network it's
+// configured for is gone. Unlike the setup one it's WPA2 with the device's own password, see
+// `provision::fallback_password`. `retry` keeps trying that network.
+pub async fn start_fallback(
+    ssid: &str,
+    pass: &str,
+    ap_pass: &str,
+    sysloop: EspSystemEventLoop,
+    esp_wifi: &mut EspWifi<'_>,
+) -> Result<()> {
+    let ap_ssid = mdns::hostname(esp_wifi.ap_netif().get_mac()?);
+    let mut wifi = AsyncWifi::wrap(esp_wifi, sysloop, EspTaskTimerService::new()?)?;
+
+    wifi.set_configuration(&Configuration::Mixed(
+        client_configuration(ssid, pass, None),
+        AccessPointConfiguration {
+            ssid: ap_ssid.as_str().into(),
+            password: ap_pass.into(),
+            auth_method: AuthMethod::WPA2Personal,
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start().await?;
+
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    warn!(
+        "Couldn't join {}, fallback access point {} is up, browse to http://{}/",
+        ssid, ap_ssid, ip
+    );
+    provision::print_fallback_join(&ap_ssid, ap_pass)?;
+    Ok(())
+}
+
+pub fn in_fallback(esp_wifi: &EspWifi<'_>) -> bool {
+    matches!(esp_wifi.get_configuration(), Ok(Configuration::Mixed(..)))
+}
+
+// Tries the configured network again with the fallback access point left up. Unlike
+// `connect` it doesn't set anything up again, which would drop whoever is on the access point.
+pub async fn retry(sysloop: EspSystemEventLoop, esp_wifi: &mut EspWifi<'_>) -> Result<()> {
+    let mut wifi = AsyncWifi::wrap(esp_wifi, sysloop, EspTaskTimerService::new()?)?;
+
+    info!("Trying the configured network again...");
+    wifi.connect().await?;
+    wifi.wait_netif_up().await?;
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    info!("Wifi DHCP info: {:?}", ip_info);
+
+    Ok(())
+}
+
 pub async fn connect(
     ssid: &str,
     pass: &str,
@@ -46,13 +134,6 @@ pub async fn connect(
         panic!("Missing WiFi name")
     }
 
-    let auth_method = if pass.is_empty() {
-        info!("Wifi password is empty");
-        AuthMethod::None
-    } else {
-        AuthMethod::WPA2Personal
-    };
-
     let mut wifi = AsyncWifi::wrap(esp_wifi, sysloop, EspTaskTimerService::new()?)?;
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
@@ -81,13 +162,9 @@ pub async fn connect(
         None
     };
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: pass.into(),
-        channel,
-        auth_method,
-        ..Default::default()
-    }))?;
+    wifi.set_configuration(&Configuration::Client(client_configuration(
+        ssid, pass, channel,
+    )))?;
 
     info!("Connecting wifi...");
 
@@ -0,0 +1,129 @@
+use anyhow::{bail, Result};
+use esp_idf_svc::sys::cam::{
+    fmt2rgb888, pixformat_t_PIXFORMAT_RGB565, pixformat_t_PIXFORMAT_RGB888,
+    pixformat_t_PIXFORMAT_YUV422,
+};
+use log::warn;
+
+use crate::pipeline::{Frame, Stage};
+
+// YUV422 comes from the sensor as Y0 U Y1 V, two pixels sharing their colour. RGB888 is written
+// blue first like esp32-camera's own converters, RGB565 high byte first like the sensor sends it.
+
+pub fn to_rgb888(frame: &Frame) -> Result<Frame> {
+    require_yuv422(frame)?;
+
+    let mut data = vec![0; frame.width * frame.height * 3];
+    let ok = unsafe {
+        fmt2rgb888(
+            frame.data.as_ptr(),
+            frame.data.len(),
+            frame.format,
+            data.as_mut_ptr(),
+        )
+    };
+    if !ok {
+        warn!("fmt2rgb888 failed, converting YUV422 ourselves");
+        for (pair, out) in frame.data.chunks_exact(4).zip(data.chunks_exact_mut(6)) {
+            let (first, second) = convert_pair(pair);
+            out[..3].copy_from_slice(&[first[2], first[1], first[0]]);
+            out[3..].copy_from_slice(&[second[2], second[1], second[0]]);
+        }
+    }
+
+    Ok(Frame {
+        data,
+        format: pixformat_t_PIXFORMAT_RGB888,
+        ..*frame
+    })
+}
+
+// The converters have nothing for RGB565, so this one is all ours
+pub fn to_rgb565(frame: &Frame) -> Result<Frame> {
+    require_yuv422(frame)?;
+
+    let mut data = Vec::with_capacity(frame.width * frame.height * 2);
+    for pair in frame.data.chunks_exact(4) {
+        let (first, second) = convert_pair(pair);
+        data.extend_from_slice(&pack_rgb565(first).to_be_bytes());
+        data.extend_from_slice(&pack_rgb565(second).to_be_bytes());
+    }
+
+    Ok(Frame {
+        data,
+        format: pixformat_t_PIXFORMAT_RGB565,
+        ..*frame
+    })
+}
+
+fn require_yuv422(frame: &Frame) -> Result<()> {
+    if frame.format != pixformat_t_PIXFORMAT_YUV422 {
+        bail!("Expected a YUV422 frame");
+    }
+    if frame.data.len() < frame.width * frame.height * 2 {
+        bail!(
+            "YUV422 frame is {} bytes, too short for {}x{}",
+            frame.data.len(),
+            frame.width,
+            frame.height
+        );
+    }
+    Ok(())
+}
+
+// Both pixels of a Y0 U Y1 V group as R, G, B
+fn convert_pair(pair: &[u8]) -> ([u8; 3], [u8; 3]) {
+    let (u, v) = (pair[1] as i32 - 128, pair[3] as i32 - 128);
+    (to_rgb(pair[0], u, v), to_rgb(pair[2], u, v))
+}
+
+// BT.601 in fixed point, scaled by 256
+fn to_rgb(y: u8, u: i32, v: i32) -> [u8; 3] {
+    let y = (y as i32) << 8;
+    let clamp = |c: i32| (c >> 8).clamp(0, 255) as u8;
+    [
+        clamp(y + 359 * v),
+        clamp(y - 88 * u - 183 * v),
+        clamp(y + 454 * u),
+    ]
+}
+
+fn pack_rgb565([r, g, b]: [u8; 3]) -> u16 {
+    (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+}
+
+// Pipeline stages for "rgb888" and "rgb565", so a sensor in YUV mode can feed stages that
+// only understand RGB
+pub struct ToRgb888;
+
+impl ToRgb888 {
+    pub fn from_args(_args: &str) -> Result<Box<dyn Stage>> {
+        Ok(Box::new(Self))
+    }
+}
+
+impl Stage for ToRgb888 {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        if frame.format == pixformat_t_PIXFORMAT_RGB888 {
+            return Ok(frame);
+        }
+        to_rgb888(&frame)
+    }
+}
+
+pub struct ToRgb565;
+
+impl ToRgb565 {
+    pub fn from_args(_args: &str) -> Result<Box<dyn Stage>> {
+        Ok(Box::new(Self))
+    }
+}
+
+impl Stage for ToRgb565 {
+    fn process(&mut self, frame: Frame) -> Result<Frame> {
+        if frame.format == pixformat_t_PIXFORMAT_RGB565 {
+            return Ok(frame);
+        }
+        to_rgb565(&frame)
+    }
+}